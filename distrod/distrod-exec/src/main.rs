@@ -8,7 +8,7 @@ use std::os::unix::prelude::OsStrExt;
 use std::path::Path;
 use structopt::StructOpt;
 
-use libs::passwd::get_real_credential;
+use libs::passwd::{check_root_privilege, get_real_credential, RootPrivilege};
 
 /// Distrod-exec is a small helper command to allow a non-root user to run programs under the systemd container.
 /// It implements the subset features of distrod's exec subcommand, but has the setuid bit set.
@@ -50,6 +50,13 @@ fn init_logger(opts: &Opts) {
     }) {
         logger_initializer.with_log_level(log_level);
     }
+    if let Some(log_file) = distrod_config
+        .as_ref()
+        .ok()
+        .and_then(|config| config.distrod.log_file.clone())
+    {
+        logger_initializer.with_log_file(log_file);
+    }
     logger_initializer.with_kmsg(true);
     if let Some(kmsg_log_level) = opts.kmsg_log_level.as_ref().cloned().or_else(|| {
         distrod_config
@@ -62,7 +69,15 @@ fn init_logger(opts: &Opts) {
 }
 
 fn run(opts: Opts) -> Result<()> {
-    if distro::is_inside_running_distro() {
+    if let Err(e) = check_root_privilege(RootPrivilege::Setuid) {
+        // Fail as distrod does, rather than run the command outside the container silently.
+        if !distro::is_systemd_disabled_by_env() {
+            return Err(e);
+        }
+        // The user has opted out of systemd, and there is no privilege to drop.
+        return exec_path(&opts.command, &opts.arg0, &opts.args);
+    }
+    if distro::is_inside_running_distro() || distro::is_systemd_disabled_by_env() {
         exec_command(&opts.command, &opts.arg0, &opts.args).with_context(|| "exec_command failed.")
     } else {
         exec_command_in_distro(&opts.command, &opts.arg0, &opts.args)
@@ -79,7 +94,15 @@ where
     log::debug!("distrod-exec: exec_command");
     let cred = get_real_credential().with_context(|| "Failed to get the real credential.")?;
     cred.drop_privilege();
+    exec_path(command, arg0, args)
+}
 
+fn exec_path<P1, S1, S2>(command: P1, arg0: S1, args: &[S2]) -> Result<()>
+where
+    P1: AsRef<Path>,
+    S1: AsRef<OsStr>,
+    S2: AsRef<OsStr>,
+{
     let path = CString::new(command.as_ref().as_os_str().as_bytes()).with_context(|| {
         format!(
             "Failed to construct a CString for the alias command.: '{:?}'",
@@ -112,18 +135,22 @@ where
             None => launch_distro()?,
         };
 
+        let host_wd = std::env::current_dir().with_context(|| "Failed to get the current dir.")?;
+        let wd = distro.translate_host_working_directory(host_wd);
+
         log::debug!("Executing a command in the distro.");
         set_noninheritable_sig_ign();
         let mut waiter = distro.exec_command(
             command.as_ref(),
             args,
-            Some(std::env::current_dir().with_context(|| "Failed to get the current dir.")?),
+            Some(wd),
             Some(arg0.as_ref()),
             Some(&cred),
+            None,
         )?;
         cred.drop_privilege();
         let status = waiter.wait();
-        std::process::exit(status as i32)
+        std::process::exit(status.to_exit_code())
     };
 
     if let Err(e) = inner() {
@@ -153,21 +180,15 @@ static DISTROD_EXEC_DELAY_ENV_NAME: &str = "DISTROD_EXEC_INIT_LAUNCH_DELAY";
 /// On some distros, starting Systemd during WSL's /init being initialized on Windows startup
 /// makes /init crash. So launch Systemd after some delay.
 fn delay_init_launch() {
-    let delay_sec_str = match std::env::var(DISTROD_EXEC_DELAY_ENV_NAME) {
-        Ok(delay_sec_str) => delay_sec_str,
-        _ => return,
-    };
-    let delay_sec: u32 = match delay_sec_str.parse() {
-        Ok(delay_sec) => delay_sec,
-        Err(e) => {
-            log::warn!(
-                "[BUG] Invalid {} was given: {:?}. {:?}",
-                DISTROD_EXEC_DELAY_ENV_NAME,
-                delay_sec_str,
-                e
-            );
-            return;
-        }
+    let config_delay_sec = DistrodConfig::get()
+        .ok()
+        .and_then(|config| config.distrod.init_launch_delay_sec);
+    let delay_sec = match get_init_launch_delay_sec(
+        std::env::var(DISTROD_EXEC_DELAY_ENV_NAME).ok(),
+        config_delay_sec,
+    ) {
+        Some(delay_sec) => delay_sec,
+        None => return,
     };
 
     log::debug!(
@@ -181,6 +202,27 @@ fn delay_init_launch() {
     log::debug!("delay finished {:?}", std::time::Instant::now());
 }
 
+/// The environment variable takes precedence over the config.
+fn get_init_launch_delay_sec(
+    env_delay_sec: Option<String>,
+    config_delay_sec: Option<u32>,
+) -> Option<u32> {
+    if let Some(delay_sec_str) = env_delay_sec {
+        match delay_sec_str.parse() {
+            Ok(delay_sec) => return Some(delay_sec),
+            Err(e) => {
+                log::warn!(
+                    "[BUG] Invalid {} was given: {:?}. {:?}",
+                    DISTROD_EXEC_DELAY_ENV_NAME,
+                    delay_sec_str,
+                    e
+                );
+            }
+        }
+    }
+    config_delay_sec
+}
+
 fn strip_wslenv_for_distod_exec_delay() {
     let inner = || -> Result<()> {
         let wslenv = std::env::var("WSLENV")?;
@@ -191,3 +233,26 @@ fn strip_wslenv_for_distod_exec_delay() {
     };
     let _ = inner();
 }
+
+#[cfg(test)]
+mod test_delay_init_launch {
+    use super::*;
+
+    #[test]
+    fn test_get_init_launch_delay_sec() {
+        assert_eq!(None, get_init_launch_delay_sec(None, None));
+        assert_eq!(Some(10), get_init_launch_delay_sec(None, Some(10)));
+        assert_eq!(
+            Some(20),
+            get_init_launch_delay_sec(Some("20".to_owned()), Some(10))
+        );
+        assert_eq!(
+            Some(20),
+            get_init_launch_delay_sec(Some("20".to_owned()), None)
+        );
+        assert_eq!(
+            Some(10),
+            get_init_launch_delay_sec(Some("invalid".to_owned()), Some(10))
+        );
+    }
+}
@@ -0,0 +1,64 @@
+// Set DISTROD_VERSION, the version string shown by --version, such as "0.1.7 (abc1234 2021-09-01)".
+// The commit hash is omitted if the source is not in a git repository.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    let build_date = get_build_date();
+    let version = match get_git_short_hash() {
+        Some(hash) => format!("{} ({} {})", env!("CARGO_PKG_VERSION"), hash, build_date),
+        None => format!("{} ({})", env!("CARGO_PKG_VERSION"), build_date),
+    };
+    println!("cargo:rustc-env=DISTROD_VERSION={}", version);
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in get_git_head_paths() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
+
+fn get_git_short_hash() -> Option<String> {
+    run_git(&["rev-parse", "--short", "HEAD"])
+}
+
+/// The files which change when a new commit is checked out.
+fn get_git_head_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(head) = run_git(&["rev-parse", "--git-path", "HEAD"]) {
+        paths.push(PathBuf::from(head));
+    }
+    if let Some(branch) = run_git(&["symbolic-ref", "-q", "HEAD"]) {
+        if let Some(branch_ref) = run_git(&["rev-parse", "--git-path", &branch]) {
+            paths.push(PathBuf::from(branch_ref));
+        }
+    }
+    // A path which doesn't exist makes the build script run every time.
+    paths.into_iter().filter(|path| path.exists()).collect()
+}
+
+fn run_git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let output = output.trim();
+    if output.is_empty() {
+        return None;
+    }
+    Some(output.to_owned())
+}
+
+/// SOURCE_DATE_EPOCH is respected for reproducible builds.
+fn get_build_date() -> String {
+    let date = match std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<i64>().ok())
+    {
+        Some(epoch) => chrono::NaiveDateTime::from_timestamp(epoch, 0).date(),
+        None => chrono::Utc::now().naive_utc().date(),
+    };
+    date.format("%Y-%m-%d").to_string()
+}
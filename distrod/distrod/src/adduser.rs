@@ -0,0 +1,240 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Context, Result};
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{Distro, DistroLauncher};
+use libs::multifork::set_noninheritable_sig_ign;
+use libs::passwd::PasswdFile;
+use libs::wsl_conf::{WslConf, WSL_CONF_PATH};
+
+use crate::AddUserOpts;
+
+/// The same limit as useradd of shadow-utils.
+const MAX_USER_NAME_LEN: usize = 32;
+
+/// Create a user in the running distro by useradd, and print its uid.
+pub fn add_user(opts: AddUserOpts) -> Result<()> {
+    validate_user_name(&opts.name)?;
+    let password = if opts.password_stdin {
+        let mut password = String::new();
+        std::io::stdin()
+            .read_to_string(&mut password)
+            .with_context(|| "Failed to read the password from stdin.")?;
+        Some(parse_password(&password)?.to_owned())
+    } else {
+        None
+    };
+
+    let distro = match DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to get the running distro.")?
+    {
+        Some(distro) => distro,
+        None => bail!("No distro is currently running."),
+    };
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+    let passwd_path = ContainerPath::new("/etc/passwd")?.to_host_path(&rootfs);
+    if PasswdFile::open(passwd_path.as_path())?
+        .get_ent_by_name(&opts.name)?
+        .is_some()
+    {
+        bail!("User '{}' already exists.", &opts.name);
+    }
+
+    set_noninheritable_sig_ign();
+    // The inputs are passed as arguments, not via a shell, so that they are not interpreted.
+    let mut useradd = Command::new("useradd");
+    useradd.args(["-m", "--shell"]);
+    useradd.arg(&opts.shell);
+    useradd.arg(&opts.name);
+    run_in_distro(&distro, useradd, None).with_context(|| "Failed to run useradd.")?;
+
+    if let Some(password) = password {
+        let input = format!("{}:{}\n", &opts.name, &password);
+        run_in_distro(&distro, Command::new("chpasswd"), Some(input.as_bytes()))
+            .with_context(|| "Failed to set the password by chpasswd.")?;
+    }
+
+    add_to_sudoers(&rootfs, &opts.name).with_context(|| "Failed to update /etc/sudoers.")?;
+
+    let uid = PasswdFile::open(passwd_path.as_path())?
+        .get_ent_by_name(&opts.name)?
+        .ok_or_else(|| anyhow!("The user '{}' is not found after useradd.", &opts.name))?
+        .uid;
+
+    if opts.set_default {
+        let wsl_conf_path = ContainerPath::new(WSL_CONF_PATH)?.to_host_path(&rootfs);
+        WslConf::open(wsl_conf_path.as_path())?
+            .set_value("user", "default", &opts.name)
+            .write()?;
+        log::info!(
+            "'{}' has been set as the default user. It takes effect on the next launch.",
+            &opts.name
+        );
+    }
+    println!("{}", uid);
+    Ok(())
+}
+
+fn run_in_distro(distro: &Distro, mut command: Command, input: Option<&[u8]>) -> Result<()> {
+    if let Some(input) = input {
+        let (read_end, write_end) = nix::unistd::pipe().with_context(|| "Failed to pipe.")?;
+        // The input is small enough to fit in the pipe buffer, so it can be written beforehand.
+        let mut write_end = unsafe { File::from_raw_fd(write_end) };
+        write_end.write_all(input)?;
+        drop(write_end);
+        command.stdin(unsafe { Stdio::from_raw_fd(read_end) });
+    }
+    // Keep the output of the command out of our stdout, where only the uid is printed so that
+    // scripts can read it.
+    let (read_end, write_end) = nix::unistd::pipe().with_context(|| "Failed to pipe.")?;
+    let mut output = unsafe { File::from_raw_fd(read_end) };
+    command.stdout(unsafe { Stdio::from_raw_fd(write_end) });
+    log::debug!("Executing {:?} in the distro.", &command);
+    // The command, which owns the write end, is dropped here so that the read end gets EOF when
+    // the child exits.
+    let mut waiter = distro.exec_std_command(command, None)?;
+    // Read the output before waiting, or the child may block on the full pipe forever.
+    let mut buf = vec![];
+    output
+        .read_to_end(&mut buf)
+        .with_context(|| "Failed to read the output of the command.")?;
+    std::io::stderr().write_all(&buf)?;
+    let status = waiter.wait();
+    if status.to_exit_code() != 0 {
+        bail!("The command exited with {:?}.", status);
+    }
+    Ok(())
+}
+
+fn add_to_sudoers(rootfs: &HostPath, user_name: &str) -> Result<()> {
+    let sudoers_path = ContainerPath::new("/etc/sudoers")?.to_host_path(rootfs);
+    let sudoers = match std::fs::read_to_string(sudoers_path.as_path()) {
+        Ok(sudoers) => sudoers,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::warn!("/etc/sudoers is not found. Is sudo installed? Skipping adding to sudoers.");
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", sudoers_path)),
+    };
+    let line = format!("{} ALL=(ALL:ALL) ALL", user_name);
+    if has_sudoers_rule_for(&sudoers, user_name) {
+        log::info!("/etc/sudoers already has a rule for '{}'.", user_name);
+        return Ok(());
+    }
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(sudoers_path.as_path())
+        .with_context(|| format!("Failed to open {:?}.", sudoers_path))?;
+    if !sudoers.is_empty() && !sudoers.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+/// Whether the sudoers has a rule whose user is the user, such as "alice ALL=(ALL:ALL) ALL".
+fn has_sudoers_rule_for(sudoers: &str, user_name: &str) -> bool {
+    sudoers
+        .lines()
+        .map(|line| line.trim_start())
+        .filter(|line| !line.starts_with('#'))
+        .any(|line| line.split_whitespace().next() == Some(user_name))
+}
+
+/// Check that the name is a portable user name, so that it can't be interpreted as an option
+/// or break the lines of /etc/sudoers.
+fn validate_user_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let is_valid = match chars.next() {
+        Some(c) if c.is_ascii_lowercase() || c == '_' => {
+            chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        }
+        _ => false,
+    };
+    if !is_valid || name.len() > MAX_USER_NAME_LEN {
+        bail!(
+            "Invalid user name '{}'. It must start with a lowercase letter or '_', \
+             and consist of at most {} lowercase letters, digits, '_', or '-'.",
+            name,
+            MAX_USER_NAME_LEN
+        );
+    }
+    Ok(())
+}
+
+/// Take the first line of the input as the password.
+fn parse_password(input: &str) -> Result<&str> {
+    let password = input.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        bail!("The password given from stdin is empty.");
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod test_adduser {
+    use super::*;
+
+    #[test]
+    fn test_validate_user_name() {
+        for name in &["alice", "_apt", "user-1", "a_b-c2"] {
+            assert!(validate_user_name(name).is_ok(), "{}", name);
+        }
+        for name in &[
+            "",
+            "-o",
+            "Alice",
+            "1user",
+            "user name",
+            "user;rm",
+            "user'",
+            "user\nroot ALL=(ALL) ALL",
+            &"a".repeat(MAX_USER_NAME_LEN + 1),
+        ] {
+            assert!(validate_user_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_parse_password() {
+        assert_eq!("pass:word", parse_password("pass:word\n").unwrap());
+        assert_eq!(
+            "password",
+            parse_password("password\nroot:hacked\n").unwrap()
+        );
+        assert!(parse_password("").is_err());
+        assert!(parse_password("\n").is_err());
+    }
+
+    #[test]
+    fn test_add_to_sudoers() {
+        let rootfs_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs_dir.path().join("etc")).unwrap();
+        let rootfs = HostPath::new(rootfs_dir.path()).unwrap();
+        // Nothing is done if sudo is not installed.
+        add_to_sudoers(&rootfs, "alice").unwrap();
+        assert!(!rootfs_dir.path().join("etc/sudoers").exists());
+
+        let sudoers = rootfs_dir.path().join("etc/sudoers");
+        std::fs::write(&sudoers, "root ALL=(ALL:ALL) ALL").unwrap();
+        add_to_sudoers(&rootfs, "alice").unwrap();
+        add_to_sudoers(&rootfs, "alice").unwrap();
+        assert_eq!(
+            "root ALL=(ALL:ALL) ALL\nalice ALL=(ALL:ALL) ALL\n",
+            std::fs::read_to_string(&sudoers).unwrap()
+        );
+
+        // The existing rule of the user is kept as it is, even if it's written differently.
+        let cont = "# bob ALL=(ALL:ALL) ALL\nbobby ALL=(ALL) ALL\n  bob\tALL=(ALL) NOPASSWD: ALL\n";
+        std::fs::write(&sudoers, cont).unwrap();
+        add_to_sudoers(&rootfs, "bob").unwrap();
+        assert_eq!(cont, std::fs::read_to_string(&sudoers).unwrap());
+        add_to_sudoers(&rootfs, "bo").unwrap();
+        assert!(std::fs::read_to_string(&sudoers)
+            .unwrap()
+            .ends_with("\nbo ALL=(ALL:ALL) ALL\n"));
+    }
+}
@@ -66,16 +66,10 @@ fn get_user_name(drive_path: &Path) -> Result<String> {
 }
 
 fn generate_task_xml(user_name: &str, distro_name: &str) -> Result<(NamedTempFile, String)> {
-    let bytes = include_bytes!("../resources/distrod_autostart.xml");
-    let mut task_xml = Template::new(String::from_utf8_lossy(bytes).into_owned());
-    task_xml
-        .assign("USER_NAME", user_name)
-        .assign("DISTRO_NAME", distro_name)
-        .assign("TASK_NAME", &format!("StartDistrod_{}", &distro_name));
     let mut task_xml_file = NamedTempFile::new().with_context(|| "Failed to create temp file.")?;
 
     task_xml_file
-        .write_all(task_xml.render().as_bytes())
+        .write_all(render_task_xml(user_name, distro_name).as_bytes())
         .with_context(|| "Failed to make the task xml file.")?;
     let mut perm = task_xml_file.as_file().metadata()?.permissions();
     perm.set_mode(0o644);
@@ -111,6 +105,36 @@ fn generate_task_xml(user_name: &str, distro_name: &str) -> Result<(NamedTempFil
     Ok((task_xml_file, task_xml_win_path))
 }
 
+fn render_task_xml(user_name: &str, distro_name: &str) -> String {
+    let bytes = include_bytes!("../resources/distrod_autostart.xml");
+    let mut task_xml = Template::new(String::from_utf8_lossy(bytes).into_owned());
+    task_xml
+        .assign("USER_NAME", user_name)
+        .assign("DISTRO_NAME", &escape_distro_name_in_task_xml(distro_name))
+        .assign(
+            "TASK_NAME",
+            &format!("StartDistrod_{}", sanitize_for_task_name(distro_name)),
+        );
+    task_xml.render()
+}
+
+/// Escape the distro name for the PowerShell single-quoted string in the double-quoted argument of
+/// powershell.exe, which is in the XML of the task.
+fn escape_distro_name_in_task_xml(distro_name: &str) -> String {
+    distro_name
+        .replace('\'', "''")
+        .replace('"', "\\\"")
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Replace the characters which need quoting in the arguments of schtasks.exe, such as spaces.
+fn sanitize_for_task_name(name: &str) -> String {
+    let unsafe_chars = Regex::new("[^a-zA-Z0-9._-]").unwrap();
+    unsafe_chars.replace_all(name, "-").into_owned()
+}
+
 fn generate_schedule_posh_command(
     user_name: &str,
     task_file_path: &str,
@@ -135,5 +159,39 @@ fn generate_unschedule_posh_command(user_name: &str, distro_name: &str) -> Strin
 fn get_schedule_task_name(user_name: &str, distro_name: &str) -> String {
     let nonlatin = Regex::new("[^a-zA-Z0-9]").unwrap();
     let user_name = nonlatin.replace_all(user_name, "-");
-    format!("StartWSL_{}_for_{}", distro_name, user_name)
+    format!(
+        "StartWSL_{}_for_{}",
+        sanitize_for_task_name(distro_name),
+        user_name
+    )
+}
+
+#[cfg(test)]
+mod test_autostart {
+    use super::*;
+
+    #[test]
+    fn test_task_name_with_spaces() {
+        assert_eq!(
+            "StartWSL_My-Distro_for_DESKTOP-user",
+            get_schedule_task_name("DESKTOP\\user", "My Distro")
+        );
+        assert_eq!(
+            "StartWSL_Ubuntu-20.04_for_user",
+            get_schedule_task_name("user", "Ubuntu-20.04")
+        );
+        let command = generate_schedule_posh_command("user", "C:\\tmp\\task.xml", "My Distro");
+        assert!(command.contains("/tn StartWSL_My-Distro_for_user "));
+    }
+
+    #[test]
+    fn test_task_xml_with_special_chars() {
+        let task_xml = render_task_xml("user", "My Distro");
+        assert!(task_xml.contains("WslLaunchInteractive('My Distro', 'exit'"));
+        assert!(task_xml.contains("<URI>\\StartDistrod_My-Distro</URI>"));
+
+        let task_xml = render_task_xml("user", "Tom's <Distro> & \"Co\"");
+        assert!(task_xml
+            .contains("WslLaunchInteractive('Tom''s &lt;Distro&gt; &amp; \\\"Co\\\"', 'exit'"));
+    }
 }
@@ -0,0 +1,339 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use libs::command_alias::CommandAlias;
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{self, DistroLauncher};
+use libs::distrod_config::DistrodConfig;
+use libs::mount_info::{find_mount_entry, get_mount_entries};
+use libs::passwd::PasswdFile;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+struct CheckResult {
+    status: CheckStatus,
+    message: String,
+    hint: Option<&'static str>,
+}
+
+impl CheckResult {
+    fn pass<S: Into<String>>(message: S) -> Self {
+        CheckResult {
+            status: CheckStatus::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn<S: Into<String>>(message: S, hint: &'static str) -> Self {
+        CheckResult {
+            status: CheckStatus::Warn,
+            message: message.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail<S: Into<String>>(message: S, hint: &'static str) -> Self {
+        CheckResult {
+            status: CheckStatus::Fail,
+            message: message.into(),
+            hint: Some(hint),
+        }
+    }
+}
+
+/// Run the diagnostics and print the results. Fails if any hard check fails.
+pub fn run_diagnostics() -> Result<()> {
+    let is_inside = distro::is_inside_running_distro();
+    let running_distro = if is_inside {
+        None
+    } else {
+        DistroLauncher::get_running_distro().unwrap_or_else(|e| {
+            log::debug!("Failed to get the running distro. {:?}", e);
+            None
+        })
+    };
+    let rootfs = running_distro
+        .as_ref()
+        .map_or_else(|| PathBuf::from("/"), |d| d.get_rootfs().to_owned());
+    let keeps_resolved = DistrodConfig::get()
+        .map(|config| config.distrod.keep_systemd_resolved)
+        .unwrap_or_default();
+
+    let checks: Vec<(&str, CheckResult)> = vec![
+        ("Distrod is enabled", check_enabled(&rootfs)),
+        (
+            "Systemd is running",
+            check_systemd(is_inside, running_distro.as_ref().map(|d| d.get_init_pid())),
+        ),
+        ("/etc/environment", check_system_envs(&rootfs)),
+        ("/run/distrod", check_run_dir(is_inside)),
+        (
+            "/etc/resolv.conf",
+            check_resolv_conf(&rootfs, keeps_resolved),
+        ),
+        ("Filesystem of the rootfs", check_rootfs_fs(&rootfs)),
+    ];
+
+    let mut n_failures = 0;
+    for (name, result) in checks {
+        let label = match result.status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => {
+                n_failures += 1;
+                "FAIL"
+            }
+        };
+        println!("[{}] {}: {}", label, name, result.message);
+        if let Some(hint) = result.hint {
+            println!("       hint: {}", hint);
+        }
+    }
+    if n_failures > 0 {
+        bail!("{} check(s) failed.", n_failures);
+    }
+    Ok(())
+}
+
+fn check_enabled(rootfs: &Path) -> CheckResult {
+    let passwd_path = rootfs.join("etc/passwd");
+    let mut passwd_file = match PasswdFile::open(&passwd_path) {
+        Ok(passwd_file) => passwd_file,
+        Err(e) => {
+            return CheckResult::fail(
+                format!("Failed to open {:?}. {}", &passwd_path, e),
+                "Make sure that /etc/passwd exists and is readable.",
+            )
+        }
+    };
+    let hooked_users: Vec<String> = passwd_file
+        .entries()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| CommandAlias::is_alias(entry.shell))
+        .map(|entry| entry.name.to_owned())
+        .collect();
+    if hooked_users.is_empty() {
+        return CheckResult::fail(
+            "The login shell of no user is hooked by Distrod.",
+            "Run `sudo /opt/distrod/bin/distrod enable`.",
+        );
+    }
+    CheckResult::pass(format!(
+        "The login shells are hooked for {}.",
+        hooked_users.join(", ")
+    ))
+}
+
+fn check_systemd(is_inside: bool, running_init_pid: Option<u32>) -> CheckResult {
+    let init_pid = match (is_inside, running_init_pid) {
+        (true, _) => 1,
+        (false, Some(pid)) => pid,
+        (false, None) => {
+            return CheckResult::fail(
+                "No Distrod's systemd is running.",
+                "Open a new WSL session, or run `sudo /opt/distrod/bin/distrod start`.",
+            )
+        }
+    };
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", init_pid))
+        .map(|comm| comm.trim().to_owned())
+        .unwrap_or_default();
+    if comm != "systemd" {
+        return CheckResult::fail(
+            format!("PID 1 of the container is '{}', not systemd.", comm),
+            "Make sure that /sbin/init in the rootfs is systemd.",
+        );
+    }
+    CheckResult::pass("PID 1 of the container is systemd.")
+}
+
+fn check_system_envs(rootfs: &Path) -> CheckResult {
+    let missing = match HostPath::new(rootfs).and_then(distro::get_missing_system_envs) {
+        Ok(missing) => missing,
+        Err(e) => {
+            return CheckResult::warn(
+                format!("Failed to read /etc/environment. {}", e),
+                "Make sure that /etc/environment exists and is readable.",
+            )
+        }
+    };
+    if !missing.is_empty() {
+        return CheckResult::warn(
+            format!("{} is missing.", missing.join(", ")),
+            "Restart WSL by `wsl --shutdown` so that Distrod writes them on launch.",
+        );
+    }
+    CheckResult::pass("The distrod bin path and the WSL inter-op variables are set.")
+}
+
+fn check_run_dir(is_inside: bool) -> CheckResult {
+    if is_inside {
+        return CheckResult::pass("Skipped inside the container.");
+    }
+    if !Path::new("/run/distrod").is_dir() {
+        return CheckResult::warn(
+            "/run/distrod does not exist. Distrod has not been launched.",
+            "Open a new WSL session, or run `sudo /opt/distrod/bin/distrod start`.",
+        );
+    }
+    CheckResult::pass("/run/distrod exists.")
+}
+
+fn check_resolv_conf(rootfs: &Path, keeps_resolved: bool) -> CheckResult {
+    let resolv_conf_path = rootfs.join("etc/resolv.conf");
+    let link_to = match std::fs::read_link(&resolv_conf_path) {
+        Ok(link_to) => link_to,
+        Err(_) => return CheckResult::pass("/etc/resolv.conf is not a symlink."),
+    };
+    let rootfs = match HostPath::new(rootfs) {
+        Ok(rootfs) => rootfs,
+        Err(e) => {
+            return CheckResult::warn(
+                format!("Failed to get the rootfs {:?}. {}", rootfs, e),
+                "Make sure that the rootfs exists.",
+            )
+        }
+    };
+    // Follow the link inside the rootfs, not on the host, where an absolute link points to
+    // the host's files.
+    let exists = matches!(
+        ContainerPath::new("/etc/resolv.conf")
+            .ok()
+            .and_then(|path| path.resolve_in_rootfs(&rootfs)),
+        Some(target) if target.as_path().exists()
+    );
+    if !exists && distro::is_runtime_resolv_conf_link(&rootfs, &link_to, keeps_resolved) {
+        return CheckResult::fail(
+            format!("/etc/resolv.conf is a dangling symlink to {:?}.", link_to),
+            "Remove /etc/resolv.conf and restart WSL by `wsl --shutdown` so that WSL generates it.",
+        );
+    }
+    if !exists {
+        return CheckResult::pass(format!(
+            "/etc/resolv.conf links to {:?}, which systemd-resolved generates since \
+             keep_systemd_resolved is set.",
+            link_to
+        ));
+    }
+    CheckResult::pass(format!("/etc/resolv.conf links to {:?}.", link_to))
+}
+
+fn check_rootfs_fs(rootfs: &Path) -> CheckResult {
+    let entries = match get_mount_entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            return CheckResult::warn(
+                format!("Failed to read the mount entries. {}", e),
+                "Make sure that /proc is mounted.",
+            )
+        }
+    };
+    match find_mount_entry(&entries, rootfs) {
+        Some(entry) if distro::is_windows_fs_type(&entry.fstype) => CheckResult::warn(
+            format!(
+                "The rootfs is on a Windows drive ({}), which is slow.",
+                entry.fstype
+            ),
+            "Install the distro on the WSL filesystem instead of under /mnt.",
+        ),
+        Some(entry) => CheckResult::pass(format!("The rootfs is on {}.", entry.fstype)),
+        None => CheckResult::pass("The rootfs is not on a Windows drive."),
+    }
+}
+
+#[cfg(test)]
+mod test_doctor {
+    use super::*;
+    use libs::distrod_config;
+    use libs::wsl_interop::REQUIRED_WSL_INTEROP_ENV_NAMES;
+
+    fn make_rootfs() -> tempfile::TempDir {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc")).unwrap();
+        std::fs::create_dir_all(rootfs.path().join("run/systemd/resolve")).unwrap();
+        rootfs
+    }
+
+    #[test]
+    fn test_check_resolv_conf() {
+        let rootfs = make_rootfs();
+        let resolv_conf = rootfs.path().join("etc/resolv.conf");
+        std::fs::write(&resolv_conf, "nameserver 1.1.1.1\n").unwrap();
+        assert_eq!(
+            CheckStatus::Pass,
+            check_resolv_conf(rootfs.path(), false).status
+        );
+
+        std::fs::remove_file(&resolv_conf).unwrap();
+        std::os::unix::fs::symlink("../run/systemd/resolve/stub-resolv.conf", &resolv_conf)
+            .unwrap();
+        assert_eq!(
+            CheckStatus::Fail,
+            check_resolv_conf(rootfs.path(), false).status
+        );
+        // The link is expected if systemd-resolved is kept.
+        assert_eq!(
+            CheckStatus::Pass,
+            check_resolv_conf(rootfs.path(), true).status
+        );
+        std::fs::write(
+            rootfs.path().join("run/systemd/resolve/stub-resolv.conf"),
+            "",
+        )
+        .unwrap();
+        assert_eq!(
+            CheckStatus::Pass,
+            check_resolv_conf(rootfs.path(), false).status
+        );
+    }
+
+    #[test]
+    fn test_check_resolv_conf_follows_links_in_rootfs() {
+        let rootfs = make_rootfs();
+        let resolv_conf = rootfs.path().join("etc/resolv.conf");
+        // The link is dangling in the rootfs even if the host has /etc/hostname.
+        std::os::unix::fs::symlink("/etc/hostname", &resolv_conf).unwrap();
+        assert_eq!(
+            CheckStatus::Fail,
+            check_resolv_conf(rootfs.path(), false).status
+        );
+
+        std::fs::remove_file(&resolv_conf).unwrap();
+        std::fs::create_dir_all(rootfs.path().join("run/NetworkManager")).unwrap();
+        std::fs::write(rootfs.path().join("run/NetworkManager/resolv.conf"), "").unwrap();
+        std::os::unix::fs::symlink("/run/NetworkManager/resolv.conf", &resolv_conf).unwrap();
+        assert_eq!(
+            CheckStatus::Pass,
+            check_resolv_conf(rootfs.path(), false).status
+        );
+    }
+
+    #[test]
+    fn test_check_system_envs() {
+        let rootfs = make_rootfs();
+        let environment = rootfs.path().join("etc/environment");
+        let mut cont = format!(
+            "PATH=/usr/bin:{}\n",
+            distrod_config::get_distrod_bin_dir_path()
+        );
+        for name in REQUIRED_WSL_INTEROP_ENV_NAMES.iter() {
+            cont.push_str(&format!("{}=value\n", name));
+        }
+        std::fs::write(&environment, &cont).unwrap();
+        assert_eq!(CheckStatus::Pass, check_system_envs(rootfs.path()).status);
+
+        std::fs::write(&environment, "PATH=/usr/bin\n").unwrap();
+        let result = check_system_envs(rootfs.path());
+        assert_eq!(CheckStatus::Warn, result.status);
+        assert!(result.message.contains("WSL_INTEROP"));
+        assert!(result
+            .message
+            .contains(distrod_config::get_distrod_bin_dir_path()));
+    }
+}
@@ -0,0 +1,254 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use libs::distro::{self, DistroLauncher};
+use libs::distrod_config::{self, DistrodConfig};
+
+use crate::ExportOpts;
+
+/// The paths which are runtime or virtual, or which are the mount points of the other filesystems.
+const EXCLUDED_PATHS: &[&str] = &["/proc", "/sys", "/run", "/mnt", "/dev"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn from_path(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Compression::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(Compression::Zstd)
+        } else {
+            bail!(
+                "Unsupported archive format {:?}. Use .tar.gz or .tar.zst.",
+                path
+            )
+        }
+    }
+}
+
+/// Export the rootfs of the distro to a compressed tarball, which can be imported by
+/// `wsl --import` or `distrod create --image-path`.
+pub fn export_distro(opts: ExportOpts) -> Result<()> {
+    let compression = Compression::from_path(&opts.output)?;
+    let rootfs = match opts.rootfs {
+        Some(ref rootfs) => PathBuf::from(rootfs),
+        // The rootfs directories on the host are not visible inside the distro, so export the
+        // running distro, whose rootfs is the root there.
+        None if distro::is_inside_running_distro() => PathBuf::from("/"),
+        None => DistrodConfig::get()
+            .with_context(|| "Failed to acquire the Distrod config.")?
+            .distrod
+            .default_distro_image
+            .clone(),
+    };
+    // Canonicalize it so that the paths such as "rootfs/" or a symlink to the rootfs are
+    // recognized as the running distro.
+    let rootfs = rootfs
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", &rootfs))?;
+
+    if is_distro_running(&rootfs)? {
+        if !opts.live {
+            bail!(
+                "The distro is running. Stop it first, or pass --live to export it while running."
+            );
+        }
+        log::warn!(
+            "Exporting the running distro. The files being written while exporting may be inconsistent."
+        );
+    }
+
+    log::info!(
+        "Exporting {:?} to {:?}. This may take a while...",
+        &rootfs,
+        &opts.output
+    );
+    let output = File::create(&opts.output)
+        .with_context(|| format!("Failed to create {:?}.", &opts.output))?;
+    let result = write_archive(output, compression, &rootfs, &opts);
+    if result.is_err() {
+        // Don't leave the broken archive, which may be mistaken for a complete one.
+        if let Err(e) = std::fs::remove_file(&opts.output) {
+            log::warn!("Failed to remove {:?}. {}", &opts.output, e);
+        }
+    }
+    result?;
+    log::info!("Exported the distro to {:?}.", &opts.output);
+    Ok(())
+}
+
+fn write_archive(
+    output: File,
+    compression: Compression,
+    rootfs: &Path,
+    opts: &ExportOpts,
+) -> Result<()> {
+    let exporter = RootfsExporter::new(rootfs, &opts.output, opts.live)?;
+    match compression {
+        Compression::Gzip => {
+            let encoder = GzEncoder::new(BufWriter::new(output), flate2::Compression::default());
+            let encoder = exporter.export(encoder)?;
+            encoder
+                .finish()
+                .and_then(|mut writer| writer.flush())
+                .with_context(|| format!("Failed to finish writing {:?}.", &opts.output))?;
+        }
+        Compression::Zstd => {
+            let mut zstd = Command::new("zstd")
+                .args(["-q", "-T0", "-c"])
+                .stdin(Stdio::piped())
+                .stdout(output)
+                .spawn()
+                .with_context(|| "Failed to run zstd. Make sure that zstd is installed.")?;
+            let stdin = zstd.stdin.take().expect("stdin should be piped.");
+            // The writer is dropped in both cases, which closes stdin so that zstd exits.
+            let exported = exporter
+                .export(BufWriter::new(stdin))
+                .and_then(|mut writer| writer.flush().with_context(|| "Failed to write to zstd."));
+            let status = zstd.wait().with_context(|| "Failed to wait for zstd.")?;
+            exported?;
+            if !status.success() {
+                bail!("zstd exited with an error. {:?}", status);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the rootfs, which must be canonicalized, is the one of the running distro.
+fn is_distro_running(rootfs: &Path) -> Result<bool> {
+    if distro::is_inside_running_distro() {
+        // The rootfs of the running distro is the root of the current mount namespace.
+        return Ok(rootfs == Path::new("/"));
+    }
+    Ok(DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to see if there's a running distro.")?
+        .filter(|distro| {
+            let running_rootfs = distro.get_rootfs();
+            running_rootfs == rootfs
+                || matches!(running_rootfs.canonicalize(), Ok(path) if path == rootfs)
+        })
+        .is_some())
+}
+
+struct RootfsExporter {
+    rootfs: PathBuf,
+    excluded: Vec<PathBuf>,
+    is_live: bool,
+}
+
+impl RootfsExporter {
+    fn new(rootfs: &Path, output: &Path, is_live: bool) -> Result<Self> {
+        let rootfs = rootfs
+            .canonicalize()
+            .with_context(|| format!("Failed to canonicalize {:?}.", rootfs))?;
+        let mut excluded: Vec<PathBuf> = EXCLUDED_PATHS
+            .iter()
+            .map(|path| rootfs.join(path.trim_start_matches('/')))
+            .collect();
+        let old_root_path = distrod_config::get_distro_old_root_path()
+            .with_context(|| "Failed to get the old root path.")?;
+        excluded.push(rootfs.join(old_root_path.strip_prefix("/").unwrap_or(&old_root_path)));
+        // Don't archive the archive itself if it's written in the rootfs.
+        excluded.push(
+            output
+                .canonicalize()
+                .with_context(|| format!("Failed to canonicalize {:?}.", output))?,
+        );
+        Ok(RootfsExporter {
+            rootfs,
+            excluded,
+            is_live,
+        })
+    }
+
+    fn export<W: Write>(&self, writer: W) -> Result<W> {
+        let mut builder = tar::Builder::new(writer);
+        builder.follow_symlinks(false);
+        self.append_dir(&mut builder, &self.rootfs)?;
+        builder
+            .into_inner()
+            .with_context(|| "Failed to finish the archive.")
+    }
+
+    fn append_dir<W: Write>(&self, builder: &mut tar::Builder<W>, dir: &Path) -> Result<()> {
+        let mut entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read the directory {:?}.", dir))?
+            .collect::<std::io::Result<Vec<_>>>()
+            .with_context(|| format!("Failed to read an entry of {:?}.", dir))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if let Err(e) = self.append_entry(builder, &path) {
+                if !self.is_live {
+                    return Err(e);
+                }
+                // The files of a running distro may vanish while exporting.
+                log::warn!("Skipping {:?}. {:?}", &path, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn append_entry<W: Write>(&self, builder: &mut tar::Builder<W>, path: &Path) -> Result<()> {
+        let metadata = std::fs::symlink_metadata(path)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", path))?;
+        let file_type = metadata.file_type();
+        // The sockets are created by the running processes, and tar cannot archive them.
+        if file_type.is_socket() {
+            return Ok(());
+        }
+        let name = path
+            .strip_prefix(&self.rootfs)
+            .expect("the path should be under the rootfs.");
+        let is_excluded = self.excluded.iter().any(|excluded| excluded == path);
+        if is_excluded && !file_type.is_dir() {
+            return Ok(());
+        }
+        if file_type.is_file() {
+            append_file_of_metadata_size(builder, path, name, &metadata)?;
+        } else {
+            builder
+                .append_path_with_name(path, name)
+                .with_context(|| format!("Failed to append {:?} to the archive.", path))?;
+        }
+        // Keep the excluded directories themselves as the mount points.
+        if file_type.is_dir() && !is_excluded {
+            self.append_dir(builder, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append a regular file with exactly the size in the metadata, which is the size recorded in the
+/// header. A file of the running distro may grow or shrink after its metadata is taken, and
+/// writing a different size of content than the header says would corrupt the archive.
+/// Such a file is truncated, or padded with zeros.
+fn append_file_of_metadata_size<W: Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}.", path))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(metadata);
+    let size = metadata.len();
+    let content = file.take(size).chain(std::io::repeat(0)).take(size);
+    builder
+        .append_data(&mut header, name, content)
+        .with_context(|| format!("Failed to append {:?} to the archive.", path))
+}
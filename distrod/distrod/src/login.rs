@@ -0,0 +1,143 @@
+use std::ffi::OsStr;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use libs::passwd::{Passwd, PasswdFile};
+
+/// The PATH of a new login session before the profile scripts extend it.
+const DEFAULT_LOGIN_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// The variables of the caller kept even in a new login environment, as login(1) does.
+const KEPT_ENV_NAMES: &[&str] = &["TERM", "COLORTERM", "LANG"];
+/// The script to exec the command given as $0 and $@, which avoids quoting the arguments.
+const EXEC_ARGS_SCRIPT: &str = "exec \"$0\" \"$@\"";
+
+/// Get the passwd entry of the user who runs the command by --login.
+pub fn get_login_user<P: AsRef<Path>>(passwd_path: P, uid: u32) -> Result<Passwd> {
+    let passwd_path = passwd_path.as_ref();
+    let mut passwd_file = PasswdFile::open(passwd_path)
+        .with_context(|| format!("Failed to open the passwd file. {:?}", passwd_path))?;
+    let entry = passwd_file
+        .get_ent_by_uid(uid)?
+        .ok_or_else(|| anyhow!("The user of uid {} is not in {:?}.", uid, passwd_path))?;
+    Ok(Passwd::from_view(entry))
+}
+
+/// Build the command which runs the given command through the login shell of the user,
+/// so that /etc/profile and the profile of the user are run beforehand.
+/// The environment is a new login environment unless `preserves_env` is true.
+pub fn build_login_command(
+    user: &Passwd,
+    command: &OsStr,
+    args: &[String],
+    preserves_env: bool,
+) -> Command {
+    let user_shell = if user.shell.is_empty() {
+        "/bin/sh"
+    } else {
+        &user.shell
+    };
+    let shell = get_posix_login_shell(user_shell);
+    let shell_name = Path::new(shell)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "sh".to_owned());
+
+    let mut login = Command::new(shell);
+    // A leading '-' in argv[0] makes the shell a login shell.
+    login.arg0(format!("-{}", shell_name));
+    login.args(["-c", EXEC_ARGS_SCRIPT]);
+    login.arg(command);
+    login.args(args);
+    if !preserves_env {
+        login.env_clear();
+        for name in KEPT_ENV_NAMES {
+            if let Some(value) = std::env::var_os(name) {
+                login.env(name, value);
+            }
+        }
+        login.env("PATH", DEFAULT_LOGIN_PATH);
+    }
+    login.env("HOME", &user.dir);
+    login.env("USER", &user.name);
+    login.env("LOGNAME", &user.name);
+    login.env("SHELL", user_shell);
+    login
+}
+
+/// fish can't run the script for POSIX shells, so /bin/sh is used for it instead.
+fn get_posix_login_shell(shell: &str) -> &str {
+    match Path::new(shell).file_name().and_then(|name| name.to_str()) {
+        Some("fish") => "/bin/sh",
+        None => "/bin/sh",
+        Some(_) => shell,
+    }
+}
+
+#[cfg(test)]
+mod test_login {
+    use super::*;
+    use std::ffi::OsString;
+
+    fn user(shell: &str) -> Passwd {
+        Passwd {
+            name: "alice".to_owned(),
+            passwd: "x".to_owned(),
+            uid: 1000,
+            gid: 1000,
+            gecos: String::new(),
+            dir: "/home/alice".to_owned(),
+            shell: shell.to_owned(),
+        }
+    }
+
+    fn get_env(command: &Command, name: &str) -> Option<Option<OsString>> {
+        command
+            .get_envs()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.map(OsStr::to_owned))
+    }
+
+    #[test]
+    fn test_build_login_command() {
+        let command = build_login_command(
+            &user("/bin/bash"),
+            OsStr::new("echo"),
+            &["a b".to_owned(), "$HOME".to_owned()],
+            false,
+        );
+        assert_eq!("/bin/bash", command.get_program());
+        assert_eq!(
+            vec!["-c", EXEC_ARGS_SCRIPT, "echo", "a b", "$HOME"],
+            command.get_args().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Some(Some(OsString::from("/home/alice"))),
+            get_env(&command, "HOME")
+        );
+        assert_eq!(
+            Some(Some(OsString::from(DEFAULT_LOGIN_PATH))),
+            get_env(&command, "PATH")
+        );
+    }
+
+    #[test]
+    fn test_build_login_command_preserving_env() {
+        let command = build_login_command(&user("/bin/zsh"), OsStr::new("env"), &[], true);
+        assert_eq!("/bin/zsh", command.get_program());
+        // The caller's PATH is kept.
+        assert_eq!(None, get_env(&command, "PATH"));
+        assert_eq!(
+            Some(Some(OsString::from("alice"))),
+            get_env(&command, "USER")
+        );
+    }
+
+    #[test]
+    fn test_get_posix_login_shell() {
+        assert_eq!("/bin/sh", get_posix_login_shell("/usr/bin/fish"));
+        assert_eq!("/bin/sh", get_posix_login_shell(""));
+        assert_eq!("/usr/bin/zsh", get_posix_login_shell("/usr/bin/zsh"));
+    }
+}
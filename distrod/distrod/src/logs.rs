@@ -0,0 +1,226 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{Distro, DistroLauncher, WaitStatus};
+use libs::multifork::set_noninheritable_sig_ign;
+use nix::sys::termios;
+
+use crate::LogsOpts;
+
+const JOURNALCTL_PATHS: &[&str] = &["/usr/bin/journalctl", "/bin/journalctl"];
+const SYSLOG_PATHS: &[&str] = &["/var/log/syslog", "/var/log/messages"];
+const JOURNALD_UNIT_NAME: &str = "systemd-journald.service";
+/// The directories where systemd looks for the units, in the order of precedence.
+const SYSTEMD_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/run/systemd/system",
+    "/usr/local/lib/systemd/system",
+    "/lib/systemd/system",
+    "/usr/lib/systemd/system",
+];
+
+/// Show the logs of the running distro by journalctl, or by tail of the syslog file if
+/// journalctl is not available in the distro.
+pub fn show_logs(opts: LogsOpts) -> Result<()> {
+    let distro = match DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to get the running distro.")?
+    {
+        Some(distro) => distro,
+        None => bail!("No distro is currently running."),
+    };
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+
+    let journalctl = if is_journald_installed(&rootfs)? {
+        find_in_rootfs(&rootfs, JOURNALCTL_PATHS)?
+    } else {
+        None
+    };
+    let command = match journalctl {
+        Some(journalctl) => build_journalctl_command(journalctl, &opts),
+        None => {
+            let syslog = match find_in_rootfs(&rootfs, SYSLOG_PATHS)? {
+                Some(syslog) => syslog,
+                None => bail!(
+                    "Neither journalctl nor the syslog file ({}) is found in the distro.",
+                    SYSLOG_PATHS.join(", ")
+                ),
+            };
+            log::warn!(
+                "Journald is not the logging backend of the distro. Showing {} instead.",
+                syslog
+            );
+            if !opts.unit.is_empty() || opts.boot {
+                log::warn!("--unit and --boot are ignored for {}.", syslog);
+            }
+            build_tail_command(syslog, &opts)
+        }
+    };
+
+    log::debug!("Executing {:?} in the distro.", &command);
+    set_noninheritable_sig_ign();
+    let status = if opts.follow {
+        exec_with_pty(&distro, command)?
+    } else {
+        let mut waiter = distro.exec_std_command(command, None)?;
+        waiter.wait()
+    };
+    std::process::exit(status.to_exit_code())
+}
+
+fn find_in_rootfs(rootfs: &HostPath, candidates: &[&'static str]) -> Result<Option<&'static str>> {
+    for candidate in candidates {
+        if matches!(
+            ContainerPath::new(candidate)?.resolve_in_rootfs(rootfs),
+            Some(path) if path.as_path().exists()
+        ) {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+/// Whether the unit of journald is installed and not masked in the rootfs. journalctl may exist
+/// without journald running, for example in a distro whose init is not systemd.
+fn is_journald_installed(rootfs: &HostPath) -> Result<bool> {
+    for dir in SYSTEMD_UNIT_DIRS {
+        let unit = ContainerPath::new(format!("{}/{}", dir, JOURNALD_UNIT_NAME))?;
+        let unit_path = unit.to_host_path(rootfs);
+        // A masked unit is a symlink to /dev/null, which takes precedence over the unit files
+        // in the directories after it.
+        let is_masked = matches!(
+            std::fs::read_link(unit_path.as_path()),
+            Ok(target) if target == Path::new("/dev/null")
+        );
+        if is_masked {
+            return Ok(false);
+        }
+        if let Some(path) = unit.resolve_in_rootfs(rootfs) {
+            if let Ok(metadata) = std::fs::metadata(path.as_path()) {
+                // An empty unit file is also treated as masked by systemd.
+                return Ok(metadata.is_file() && metadata.len() > 0);
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn build_journalctl_command(journalctl: &str, opts: &LogsOpts) -> Command {
+    let mut args: Vec<OsString> = vec!["--no-pager".into()];
+    if opts.follow {
+        args.push("--follow".into());
+    }
+    for unit in &opts.unit {
+        args.push("--unit".into());
+        args.push(unit.into());
+    }
+    if opts.boot {
+        args.push("--boot".into());
+    }
+    if let Some(lines) = opts.lines {
+        args.push("--lines".into());
+        args.push(lines.to_string().into());
+    }
+    let mut command = Command::new(journalctl);
+    command.args(args);
+    command
+}
+
+fn build_tail_command(syslog: &str, opts: &LogsOpts) -> Command {
+    let mut command = Command::new("tail");
+    command.arg("-n").arg(opts.lines.unwrap_or(10).to_string());
+    if opts.follow {
+        command.arg("-F");
+    }
+    command.arg(syslog);
+    command
+}
+
+/// Exec the command with its stdout and stderr connected to a new PTY, and relay the output
+/// to our stdout, so that the command flushes every line as it does on a terminal.
+fn exec_with_pty(distro: &Distro, mut command: Command) -> Result<WaitStatus> {
+    let pty = nix::pty::openpty(None, None).with_context(|| "Failed to open a PTY.")?;
+    // Safe because the fds are newly opened and owned only by these Files.
+    let mut master = unsafe { File::from_raw_fd(pty.master) };
+    let slave = unsafe { File::from_raw_fd(pty.slave) };
+    // Stop the PTY from converting "\n" into "\r\n" so that the output is the same as
+    // the one without the PTY.
+    let mut attrs = termios::tcgetattr(pty.slave).with_context(|| "Failed to tcgetattr.")?;
+    termios::cfmakeraw(&mut attrs);
+    termios::tcsetattr(pty.slave, termios::SetArg::TCSANOW, &attrs)
+        .with_context(|| "Failed to tcsetattr.")?;
+
+    command
+        .stdin(Stdio::null())
+        .stdout(
+            slave
+                .try_clone()
+                .with_context(|| "Failed to dup the PTY.")?,
+        )
+        .stderr(slave);
+    // The command, which owns the slave, is dropped here so that the master gets EOF when
+    // the child exits.
+    let mut waiter = distro.exec_std_command(command, None)?;
+
+    let mut stdout = std::io::stdout();
+    let mut buf = [0; 4096];
+    loop {
+        match master.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                stdout
+                    .write_all(&buf[..n])
+                    .and_then(|_| stdout.flush())
+                    .with_context(|| "Failed to write the logs.")?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            // Reading the master fails with EIO once all the slave fds are closed.
+            Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EIO as i32) => break,
+            Err(e) => return Err(e).with_context(|| "Failed to read the PTY."),
+        }
+    }
+    Ok(waiter.wait())
+}
+
+#[cfg(test)]
+mod test_logs {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_is_journald_installed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        let rootfs = HostPath::new(root).unwrap();
+        assert!(!is_journald_installed(&rootfs).unwrap());
+
+        std::fs::create_dir_all(root.join("lib/systemd/system")).unwrap();
+        std::fs::create_dir_all(root.join("etc/systemd/system")).unwrap();
+        let unit = root.join("lib/systemd/system/systemd-journald.service");
+        std::fs::write(
+            &unit,
+            "[Service]\nExecStart=/lib/systemd/systemd-journald\n",
+        )
+        .unwrap();
+        assert!(is_journald_installed(&rootfs).unwrap());
+
+        let masked = root.join("etc/systemd/system/systemd-journald.service");
+        symlink("/dev/null", &masked).unwrap();
+        assert!(!is_journald_installed(&rootfs).unwrap());
+
+        std::fs::remove_file(&masked).unwrap();
+        std::fs::write(&masked, "").unwrap();
+        assert!(!is_journald_installed(&rootfs).unwrap());
+
+        // The unit is looked up in the rootfs, not on the host.
+        std::fs::remove_file(&masked).unwrap();
+        std::fs::remove_file(&unit).unwrap();
+        symlink("/lib/systemd/system/none.service", &unit).unwrap();
+        assert!(!is_journald_installed(&rootfs).unwrap());
+    }
+}
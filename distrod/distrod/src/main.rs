@@ -1,37 +1,62 @@
 use anyhow::{anyhow, bail, Context, Result};
-use libs::cli_ui::{build_progress_bar, choose_from_list, init_logger, prompt_path};
-use libs::container::{ContainerPath, HostPath};
+use libs::cli_ui::{
+    build_progress_bar, choose_from_list, init_logger, prompt_path, prompt_string,
+    LoggerInitializer,
+};
+use libs::container::{ContainerPath, HostPath, UserNamespace};
 use libs::distrod_config::{self, DistrodConfig};
 use libs::local_image::LocalDistroImage;
+use libs::mount_info;
 use libs::multifork::set_noninheritable_sig_ign;
+use nix::sys::stat::Mode;
 use nix::unistd::{Gid, Uid};
-use std::ffi::{CString, OsString};
+use std::ffi::{CString, OsStr, OsString};
 use std::fs::File;
-use std::io::{stdin, Cursor, Read};
-use std::os::unix::prelude::OsStrExt;
-use std::path::Path;
+use std::io::{stdin, Cursor, Read, Seek, SeekFrom};
+use std::os::unix::prelude::{CommandExt, OsStrExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use xz2::read::XzDecoder;
 
-use libs::command_alias::CommandAlias;
+use libs::command_alias::{self, CommandAlias};
 use libs::container_org_image::ContainerOrgImageList;
-use libs::distro::{self, DistroLauncher};
+use libs::distro::{self, Distro, DistroLauncher, SystemRunningState};
 use libs::distro_image::{
-    self, download_file_with_progress, DistroImage, DistroImageFetcher, DistroImageFetcherGen,
-    DistroImageFile,
+    self, download_file_with_progress, fetch_image_list_by_names, DistroImage, DistroImageFetcher,
+    DistroImageFetcherGen, DistroImageFile, DistroImageList, ListChooserByNames,
 };
-use libs::passwd::{self, get_credential_from_passwd_file, Credential};
+use libs::first_boot;
+use libs::oci_image::{self, OciImageReference};
+use libs::passwd::{self, get_credential_from_passwd_file, Credential, PasswdFile, RootPrivilege};
+use libs::procfile::ProcFile;
+use libs::rootfs_image;
+use libs::tree_copy::TreeCopier;
+use libs::wsl_conf::{WslConf, WSL_CONF_PATH};
 use libs::wsl_interop;
 
+mod adduser;
 mod autostart;
+mod doctor;
+mod export;
+mod login;
+mod logs;
+mod self_update;
 mod shell_hook;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "distrod")]
+#[structopt(name = "distrod", version = env!("DISTROD_VERSION"))]
 pub struct Opts {
     /// Log level in the env_logger format. Simple levels: trace, debug, info(default), warn, error.
     #[structopt(short, long)]
     pub log_level: Option<String>,
+    /// Don't colorize the output. Setting the NO_COLOR environment variable does the same.
+    #[structopt(long)]
+    pub no_color: bool,
+    /// Append the logs to this file as well. It's rotated when it gets large.
+    #[structopt(long)]
+    pub log_file: Option<PathBuf>,
     #[structopt(subcommand)]
     pub command: Subcommand,
 }
@@ -44,6 +69,18 @@ pub enum Subcommand {
     Start(StartOpts),
     Exec(ExecOpts),
     Stop(StopOpts),
+    Restart(RestartOpts),
+    Images(ImagesOpts),
+    Alias(AliasOpts),
+    Doctor(DoctorOpts),
+    Logs(LogsOpts),
+    Config(ConfigOpts),
+    Export(ExportOpts),
+    Clone(CloneOpts),
+    Adopt(AdoptOpts),
+    #[structopt(name = "adduser")]
+    AddUser(AddUserOpts),
+    SelfUpdate(SelfUpdateOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -51,6 +88,49 @@ pub enum Subcommand {
 pub struct StartOpts {
     #[structopt(short, long)]
     rootfs: Option<OsString>,
+    /// Start the distro of this name in the distro images directory instead of the default one.
+    /// A part of the name is accepted if it matches only one distro.
+    #[structopt(long, conflicts_with = "rootfs")]
+    distro: Option<String>,
+    /// Don't warn even if the rootfs is on a slow Windows drive.
+    #[structopt(long)]
+    allow_slow_fs: bool,
+    /// Run the distro in a new user namespace. "identity" maps all the IDs as they are, and
+    /// "UID:GID" maps only root in the distro to the given user and group.
+    #[structopt(long, value_name = "identity|UID:GID")]
+    user_namespace: Option<UserNamespace>,
+    /// Don't launch the init system of the distro. Only the mounts are set up so that commands
+    /// can be run by exec. Systemd services are not available in this mode.
+    #[structopt(long)]
+    no_init: bool,
+    /// The systemd target to boot the distro with, such as graphical.target.
+    /// The default is multi-user.target, or the default_init_target in the config.
+    #[structopt(long, value_name = "TARGET", conflicts_with = "no-init")]
+    unit: Option<String>,
+    /// Set a system-wide environment variable for all the services and their sessions in
+    /// addition to the system_envs in the config. This option can be given multiple times.
+    #[structopt(
+        long,
+        value_name = "KEY=VALUE",
+        number_of_values = 1,
+        conflicts_with = "no-init",
+        parse(try_from_str = distro::parse_env_assignment)
+    )]
+    setenv: Vec<(String, String)>,
+    /// Wait up to the seconds until systemd reports that the system is running, and fail if it
+    /// doesn't in time. Failed units are shown if any.
+    #[structopt(long, value_name = "SECONDS", conflicts_with = "no-init")]
+    wait: Option<u64>,
+    /// Limit the memory usage of the system services and the user sessions in the distro, such
+    /// as 2G or 50%. "infinity"
+    /// removes the limit. The limits are kept until they are given again.
+    #[structopt(long, value_name = "SIZE", conflicts_with = "no-init")]
+    memory_max: Option<String>,
+    /// Limit the CPU usage of the system services and the user sessions in the distro, such as
+    /// 150% for 1.5 CPUs. "infinity"
+    /// removes the limit.
+    #[structopt(long, value_name = "PERCENTAGE", conflicts_with = "no-init")]
+    cpu_quota: Option<String>,
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -59,20 +139,57 @@ pub struct ExecOpts {
     command: OsString,
     args: Vec<String>,
 
-    #[structopt(short, long)]
+    #[structopt(short, long, conflicts_with = "login")]
     arg0: Option<OsString>,
 
+    /// The user to run the command as, optionally followed by the group to replace the primary
+    /// group of the user, such as "alice:docker". Each of them is a name or an id.
     #[structopt(short, long)]
     user: Option<String>,
 
     #[structopt(short = "i", long)]
     uid: Option<u32>,
 
-    #[structopt(short, long)]
+    /// Run the command with the group given by --user even if the user is not a member of it.
+    #[structopt(long, requires = "user")]
+    force_group: bool,
+
+    #[structopt(short, long, alias = "cwd")]
     working_directory: Option<OsString>,
 
+    /// Use the given path of the WSL environment, such as /mnt/c/..., as the working directory.
+    /// If it's not accessible in the distro, / is used instead.
+    #[structopt(long, value_name = "PATH", conflicts_with = "working-directory")]
+    workdir_host: Option<PathBuf>,
+
+    /// Create the working directory owned by the user if it doesn't exist.
+    #[structopt(long)]
+    cwd_create: bool,
+
     #[structopt(short, long)]
     rootfs: Option<OsString>,
+
+    /// Start the distro of this name in the distro images directory if no distro is running.
+    #[structopt(long, conflicts_with = "rootfs")]
+    distro: Option<String>,
+
+    /// Run the command directly without entering the systemd container.
+    /// DISTROD_NO_SYSTEMD=1 has the same effect.
+    #[structopt(long)]
+    no_systemd: bool,
+
+    /// Run the command through the login shell of the user so that the profile scripts run.
+    /// The command starts in the home directory with a new login environment.
+    #[structopt(short, long)]
+    login: bool,
+
+    /// Keep the environment variables of the caller with --login.
+    #[structopt(long, requires = "login")]
+    preserve_env: bool,
+
+    /// Set the umask of the command in octal, such as 022.
+    #[structopt(long, parse(try_from_str = distro::parse_umask))]
+    umask: Option<Mode>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -82,13 +199,229 @@ pub struct StopOpts {
     sigkill: bool,
 }
 
+/// Stop the running distro and start it again with the same rootfs.
+/// If no distro is running, start the default distro.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct RestartOpts {
+    /// Kill the distro by SIGKILL instead of stopping it gracefully.
+    #[structopt(short = "9", long)]
+    sigkill: bool,
+    /// Seconds to wait for the distro to stop before killing it by SIGKILL.
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+    /// Wait up to the seconds until systemd reports that the system is running again.
+    #[structopt(long, value_name = "SECONDS")]
+    wait: Option<u64>,
+}
+
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
 pub struct CreateOpts {
     #[structopt(short = "d", long)]
     install_dir: Option<OsString>,
+    /// The path to a .tar.xz image file, or an http(s):// URL to download it from.
     #[structopt(short = "i", long)]
     image_path: Option<OsString>,
+    /// The SHA-256 digest of the image given by --image-path, which is verified before unpacking.
+    #[structopt(long, requires = "image-path", parse(try_from_str = distro_image::parse_sha256))]
+    image_sha256: Option<String>,
+    /// Fetch the image listings again instead of using the cache.
+    #[structopt(long)]
+    refresh: bool,
+    /// The name of a known mirror or the URL of a mirror of linuxcontainers.org image server.
+    #[structopt(long)]
+    mirror: Option<String>,
+    /// The architecture of the image to download. amd64, arm64, or armhf.
+    #[structopt(long, default_value = "amd64")]
+    arch: String,
+    /// Don't warn even if the install directory is on a slow Windows drive.
+    #[structopt(long)]
+    allow_slow_fs: bool,
+    /// Create a sparse ext4 image file of this size (e.g. 64G) next to the install directory,
+    /// and unpack the rootfs into it by mounting it at the install directory.
+    #[structopt(long, value_name = "size")]
+    vhdx: Option<String>,
+    /// Unpack the rootfs into a plain directory even if default_rootfs_format is "vhdx" in the
+    /// config.
+    #[structopt(long, conflicts_with = "vhdx")]
+    no_vhdx: bool,
+    /// The hostname of the new distro. The hostname of WSL is used by default.
+    #[structopt(long)]
+    hostname: Option<String>,
+    /// Download the distro from linuxcontainers.org without prompting. A part of the name is
+    /// accepted if it matches only one distro.
+    #[structopt(long, conflicts_with = "image-path")]
+    distro: Option<String>,
+    /// The version of the distro given by --distro. The default version is used if not given.
+    #[structopt(long, requires = "distro")]
+    version: Option<String>,
+    /// Remove the contents of the install directory first if it's not empty.
+    #[structopt(long)]
+    force: bool,
+    /// Don't ask for confirmation before removing the contents with --force.
+    #[structopt(short, long, requires = "force")]
+    yes: bool,
+    /// Pull the image from an OCI registry, e.g. "ubuntu:22.04" from Docker Hub or
+    /// "ghcr.io/owner/image:tag", and flatten its layers into the rootfs.
+    #[structopt(long, value_name = "ref", conflicts_with_all = &["image-path", "distro"], parse(try_from_str = OciImageReference::parse))]
+    from_oci: Option<OciImageReference>,
+    /// Show the image and the install directory without downloading or changing anything.
+    #[structopt(long)]
+    dry_run: bool,
+    /// Load the image from a tarball made by `docker save` and flatten its layers into the rootfs.
+    #[structopt(long, value_name = "path", conflicts_with_all = &["image-path", "distro", "from-oci"])]
+    from_docker_archive: Option<OsString>,
+}
+
+/// List the distro images available on linuxcontainers.org without prompting.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ImagesOpts {
+    /// Show the versions of the distro.
+    #[structopt(short, long)]
+    distro: Option<String>,
+    /// Show the URL of the rootfs image of the version.
+    #[structopt(short, long, requires = "distro")]
+    version: Option<String>,
+    /// Output in JSON.
+    #[structopt(long)]
+    json: bool,
+    /// Fetch the image listings again instead of using the cache.
+    #[structopt(long)]
+    refresh: bool,
+    /// The name of a known mirror or the URL of a mirror of linuxcontainers.org image server.
+    #[structopt(long)]
+    mirror: Option<String>,
+    /// The architecture of the image to download. amd64, arm64, or armhf.
+    #[structopt(long, default_value = "amd64")]
+    arch: String,
+}
+
+/// Manage the aliases which run commands in the Distrod's container.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub enum AliasOpts {
+    /// Add an alias which runs the target command in the container.
+    Add {
+        name: String,
+        /// The absolute path to the command in the rootfs.
+        target: OsString,
+    },
+    /// Remove an alias.
+    Remove { name: String },
+    /// List the aliases.
+    List,
+}
+
+/// Diagnose the common problems of the Distrod's installation.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct DoctorOpts {}
+
+/// Show the systemd journal of the running distro.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct LogsOpts {
+    /// Keep showing new logs as they are written.
+    #[structopt(short, long)]
+    follow: bool,
+    /// Show the logs of the unit. Can be given multiple times.
+    #[structopt(short, long)]
+    unit: Vec<String>,
+    /// Show the logs of the current boot only.
+    #[structopt(short, long)]
+    boot: bool,
+    /// The number of the most recent lines to show.
+    #[structopt(short = "n", long)]
+    lines: Option<u32>,
+}
+
+/// Configure the distro. The changes take effect on the next launch of the distro.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ConfigOpts {
+    /// The name or uid of the user which WSL logs in as by default. It's written to /etc/wsl.conf.
+    #[structopt(long)]
+    default_user: Option<String>,
+    /// The login shell of the default user.
+    #[structopt(long, parse(from_os_str))]
+    default_shell: Option<PathBuf>,
+}
+
+/// Create a user in the running distro, and print its uid. The user is added to sudoers.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AddUserOpts {
+    /// The name of the new user.
+    name: String,
+    /// The login shell of the new user.
+    #[structopt(long, default_value = "/bin/bash")]
+    shell: String,
+    /// Read the password of the new user from the first line of stdin.
+    #[structopt(long)]
+    password_stdin: bool,
+    /// Set the new user as the default user of WSL in /etc/wsl.conf.
+    #[structopt(long)]
+    set_default: bool,
+}
+
+/// Update Distrod to the latest release on GitHub.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SelfUpdateOpts {
+    /// Replace /opt/distrod with the new version. Without this, only the versions are checked.
+    #[structopt(short, long)]
+    yes: bool,
+}
+
+/// Export the rootfs of the distro to a .tar.gz or .tar.zst file.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ExportOpts {
+    /// The path of the archive. The compression is chosen by its extension.
+    #[structopt(parse(from_os_str))]
+    output: PathBuf,
+    /// The rootfs to export. The default distro is exported if it's not given.
+    #[structopt(short, long)]
+    rootfs: Option<OsString>,
+    /// Export the distro even if it's running, though the files may be inconsistent.
+    #[structopt(long)]
+    live: bool,
+}
+
+/// Copy an installed distro to a new install directory.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CloneOpts {
+    /// The name of the distro under the distro images directory, or the path to its rootfs.
+    source: OsString,
+    /// The name of the new distro, which is also used as its hostname by default.
+    name: String,
+    /// Install the new distro into this directory instead of the distro images directory.
+    #[structopt(short = "d", long)]
+    install_dir: Option<OsString>,
+    /// The hostname of the new distro.
+    #[structopt(long)]
+    hostname: Option<String>,
+}
+
+/// Make an existing rootfs, such as an unpacked WSL distro, a distro managed by Distrod in place.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AdoptOpts {
+    /// The path to the rootfs to adopt.
+    #[structopt(parse(from_os_str))]
+    rootfs: PathBuf,
+    /// The hostname of the distro. The hostname of WSL is used by default.
+    #[structopt(long)]
+    hostname: Option<String>,
+    /// Make the distro the default one, which is started when --rootfs is not given.
+    #[structopt(long)]
+    make_default: bool,
+    /// Show what would be changed in the rootfs without changing anything.
+    #[structopt(long)]
+    dry_run: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -98,15 +431,29 @@ pub struct EnableOpts {
     start_on_windows_boot: bool,
     #[structopt(short, long)]
     do_full_initialization: bool,
+    /// Hook only the users whose login shell is this shell, such as /usr/bin/zsh.
+    #[structopt(long)]
+    shell: Option<PathBuf>,
+    /// Delay launching systemd by this many seconds on every launch, which works around the crash
+    /// of WSL's /init on some distros. 0 disables the delay.
+    #[structopt(long, value_name = "SECS")]
+    delay: Option<u32>,
+    /// Show what would be changed in the rootfs without changing anything.
+    #[structopt(long)]
+    dry_run: bool,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
-pub struct DisableOpts {}
+pub struct DisableOpts {
+    /// Show what would be changed in the rootfs without changing anything.
+    #[structopt(long)]
+    dry_run: bool,
+}
 
 fn main() {
     if is_executed_as_alias() {
-        init_logger("Distrod".to_owned(), None);
+        init_logger("Distrod".to_owned(), None, false);
         if let Err(err) = run_as_command_alias() {
             log::error!("{:?}", err);
         }
@@ -114,12 +461,7 @@ fn main() {
     }
 
     let opts = Opts::from_args();
-    let log_level = opts.log_level.as_ref().cloned().or_else(|| {
-        DistrodConfig::get()
-            .ok()
-            .and_then(|config| config.distrod.log_level.clone())
-    });
-    init_logger("Distrod".to_owned(), log_level);
+    init_logger_with_opts(&opts);
 
     if let Err(err) = run(opts) {
         log::error!("{:?}", err);
@@ -127,6 +469,28 @@ fn main() {
     }
 }
 
+fn init_logger_with_opts(opts: &Opts) {
+    let mut logger_initializer = LoggerInitializer::default();
+    let distrod_config = DistrodConfig::get();
+    if let Some(log_level) = opts.log_level.as_ref().cloned().or_else(|| {
+        distrod_config
+            .as_ref()
+            .ok()
+            .and_then(|config| config.distrod.log_level.clone())
+    }) {
+        logger_initializer.with_log_level(log_level);
+    }
+    if let Some(log_file) = opts.log_file.as_ref().cloned().or_else(|| {
+        distrod_config
+            .ok()
+            .and_then(|config| config.distrod.log_file.clone())
+    }) {
+        logger_initializer.with_log_file(log_file);
+    }
+    logger_initializer.with_no_color(opts.no_color);
+    logger_initializer.init("Distrod".to_owned());
+}
+
 fn is_executed_as_alias() -> bool {
     let inner = || -> Result<bool> {
         let self_path =
@@ -167,8 +531,9 @@ fn run_as_command_alias() -> Result<()> {
 }
 
 fn run(opts: Opts) -> Result<()> {
-    if !nix::unistd::getuid().is_root() {
-        bail!("Distrod needs the root permission.");
+    // Listing images doesn't touch the system.
+    if !matches!(opts.command, Subcommand::Images(_)) {
+        passwd::check_root_privilege(RootPrivilege::RealRoot)?;
     }
 
     match opts.command {
@@ -190,15 +555,83 @@ fn run(opts: Opts) -> Result<()> {
         Subcommand::Stop(stop_opts) => {
             stop_distro(stop_opts)?;
         }
+        Subcommand::Restart(restart_opts) => {
+            restart_distro(restart_opts)?;
+        }
+        Subcommand::Images(images_opts) => {
+            list_images(images_opts)?;
+        }
+        Subcommand::Alias(alias_opts) => {
+            manage_aliases(alias_opts)?;
+        }
+        Subcommand::Doctor(_doctor_opts) => {
+            doctor::run_diagnostics()?;
+        }
+        Subcommand::Logs(logs_opts) => {
+            logs::show_logs(logs_opts)?;
+        }
+        Subcommand::Config(config_opts) => {
+            configure_distro(config_opts)?;
+        }
+        Subcommand::Export(export_opts) => {
+            export::export_distro(export_opts)?;
+        }
+        Subcommand::Clone(clone_opts) => {
+            clone_distro(clone_opts)?;
+        }
+        Subcommand::Adopt(adopt_opts) => {
+            adopt_distro(adopt_opts)?;
+        }
+        Subcommand::AddUser(adduser_opts) => {
+            adduser::add_user(adduser_opts)?;
+        }
+        Subcommand::SelfUpdate(self_update_opts) => {
+            self_update::self_update(self_update_opts)?;
+        }
     }
     Ok(())
 }
 
 fn enable_wsl_exec_hook(opts: EnableOpts) -> Result<()> {
-    distro::initialize_distro_rootfs(HostPath::new("/")?, opts.do_full_initialization)
-        .with_context(|| "Failed to initialize the rootfs.")?;
-    shell_hook::enable_default_shell_hook()
+    wsl_interop::check_wsl2_kernel_features()?;
+    distro::initialize_distro_rootfs(
+        HostPath::new("/")?,
+        opts.do_full_initialization,
+        opts.dry_run,
+    )
+    .with_context(|| "Failed to initialize the rootfs.")?;
+    shell_hook::enable_default_shell_hook(opts.shell.as_deref(), opts.dry_run)
         .with_context(|| "Failed to enable the hook to the default shell.")?;
+    fix_distrod_exec_permission(opts.dry_run)
+        .with_context(|| "Failed to make distrod-exec a setuid binary.")?;
+    if opts.dry_run {
+        if let Some(delay_sec) = opts.delay {
+            log::info!(
+                "Would save the delay of launching systemd, {} seconds, in the Distrod config.",
+                delay_sec
+            );
+        }
+        if opts.start_on_windows_boot {
+            log::info!("Would schedule the task to start Distrod on Windows startup.");
+        }
+        log::info!("Dry run done. Nothing has been changed.");
+        return Ok(());
+    }
+    if let Some(delay_sec) = opts.delay {
+        let mut config = (*DistrodConfig::get()?).clone();
+        config.distrod.set_init_launch_delay_sec(delay_sec);
+        config
+            .update()
+            .with_context(|| "Failed to save the init launch delay in the Distrod config.")?;
+        if delay_sec == 0 {
+            log::info!("The delay of launching systemd is disabled.");
+        } else {
+            log::info!(
+                "Launching systemd will be delayed by {} seconds.",
+                delay_sec
+            );
+        }
+    }
     log::info!("Distrod has been enabled. Now your shell will start under systemd.");
     if opts.start_on_windows_boot {
         log::info!(
@@ -216,15 +649,49 @@ fn enable_wsl_exec_hook(opts: EnableOpts) -> Result<()> {
     Ok(())
 }
 
-fn disable_wsl_exec_hook(_opts: DisableOpts) -> Result<()> {
-    shell_hook::disable_default_shell_hook()
+/// Make distrod-exec owned by root and setuid, which the aliases run commands in the distro with.
+fn fix_distrod_exec_permission(dry_run: bool) -> Result<()> {
+    let distrod_exec = distrod_config::get_distrod_exec_bin_path();
+    // Otherwise non-root users could replace it with anything run as root by the setuid bit.
+    passwd::check_only_root_can_modify(distrod_exec).with_context(|| {
+        format!(
+            "Refusing to make {} a setuid binary. Reinstall Distrod as root.",
+            distrod_exec
+        )
+    })?;
+    if passwd::is_setuid_root(distrod_exec)? {
+        return Ok(());
+    }
+    if dry_run {
+        log::info!("Would make {} a setuid binary owned by root.", distrod_exec);
+        return Ok(());
+    }
+    nix::unistd::chown(distrod_exec, Some(Uid::from_raw(0)), Some(Gid::from_raw(0)))
+        .with_context(|| format!("Failed to chown {}.", distrod_exec))?;
+    // chown clears the setuid bit, so set the mode afterwards.
+    std::fs::set_permissions(distrod_exec, std::fs::Permissions::from_mode(0o4755))
+        .with_context(|| format!("Failed to chmod {}.", distrod_exec))?;
+    log::info!("Made {} a setuid binary owned by root.", distrod_exec);
+    Ok(())
+}
+
+fn disable_wsl_exec_hook(opts: DisableOpts) -> Result<()> {
+    shell_hook::disable_default_shell_hook(opts.dry_run)
         .with_context(|| "Failed to disable the hook to the default shell.")?;
-    if let Err(e) = distro::cleanup_distro_rootfs(HostPath::new("/")?) {
+    if opts.dry_run {
+        distro::cleanup_distro_rootfs(HostPath::new("/")?, true)
+            .with_context(|| "Failed to check the rootfs.")?;
+        log::info!("Would unschedule the task to start Distrod on Windows startup if any.");
+        log::info!("Dry run done. Nothing has been changed.");
+        return Ok(());
+    }
+    if let Err(e) = distro::cleanup_distro_rootfs(HostPath::new("/")?, false) {
         log::warn!(
             "Failed to clean up the rootfs. Some garbage might not be removed.: {:?}",
             e
         );
     }
+    cleanup_runtime_files();
     log::info!("Distrod has been disabled. Now systemd will not start automatically.");
     if let Err(e) = autostart::disable_autostart_on_windows_boot(
         &wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?,
@@ -236,12 +703,199 @@ fn disable_wsl_exec_hook(_opts: DisableOpts) -> Result<()> {
 
 #[tokio::main]
 async fn create_distro(opts: CreateOpts) -> Result<()> {
-    let image = match opts.image_path {
+    let image_size = match get_vhdx_size(&opts)? {
+        Some(ref size) => {
+            rootfs_image::check_rootfs_image_support()
+                .with_context(|| "The ext4 image file for --vhdx is not supported.")?;
+            Some(rootfs_image::parse_size(size)?)
+        }
+        None => None,
+    };
+    if let Some(ref hostname) = opts.hostname {
+        distro::validate_hostname(hostname)?;
+    }
+    if opts.dry_run {
+        return dry_run_create_distro(&opts, image_size).await;
+    }
+    let (image_name, rootfs_source) = if let Some(ref reference) = opts.from_oci {
+        log::info!("Pulling '{}'...", reference);
+        let layers = oci_image::pull_image_layers(reference, &opts.arch)
+            .await
+            .with_context(|| format!("Failed to pull '{}'.", reference))?;
+        (reference.get_distro_name(), RootfsSource::Layers(layers))
+    } else if let Some(ref path) = opts.from_docker_archive {
+        let layers = oci_image::read_docker_archive_layers(Path::new(path))?;
+        (
+            get_docker_archive_distro_name(path)?,
+            RootfsSource::Layers(layers),
+        )
+    } else {
+        let image = choose_distro_image(&opts).await?;
+        let tar_xz = open_distro_image(image.image, opts.image_sha256.as_deref()).await?;
+        (image.name, RootfsSource::TarXz(tar_xz))
+    };
+
+    log::info!("Unpacking...");
+    let install_dir = get_install_dir(&opts, &image_name)?;
+    let install_dir = install_dir.as_path();
+    if !install_dir.exists() {
+        std::fs::create_dir_all(&install_dir)
+            .with_context(|| format!("Failed to make a directory: {:?}.", &install_dir))?;
+    } else {
+        clear_install_dir(install_dir, opts.force, opts.yes, &prompt_string)?;
+    }
+    if let Some(image_size) = image_size {
+        create_rootfs_image_at(install_dir, image_size)?;
+    } else if !opts.allow_slow_fs {
+        warn_if_on_windows_fs(install_dir);
+    }
+    match rootfs_source {
+        RootfsSource::TarXz(tar_xz) => {
+            let tar = XzDecoder::new(tar_xz);
+            let mut archive = tar::Archive::new(tar);
+            archive.set_preserve_permissions(true);
+            archive.set_unpack_xattrs(true);
+            archive
+                .unpack(&install_dir)
+                .with_context(|| format!("Failed to unpack the image to '{:?}'.", &install_dir))?;
+        }
+        RootfsSource::Layers(layers) => {
+            oci_image::flatten_layers(&layers, install_dir).with_context(|| {
+                format!("Failed to flatten the image into '{:?}'.", &install_dir)
+            })?;
+        }
+    }
+
+    distro::initialize_distro_rootfs_with_hostname(
+        HostPath::new(&install_dir.canonicalize().with_context(|| {
+            format!("Failed to get the canonicalized path of {:?}", &install_dir)
+        })?)?,
+        true,
+        opts.hostname.as_deref(),
+        false,
+    )
+    .with_context(|| "Failed to initialize the rootfs.")?;
+
+    log::info!("{} is created at {:?}", &image_name, install_dir);
+    Ok(())
+}
+
+enum RootfsSource {
+    TarXz(Box<dyn Read>),
+    Layers(Vec<File>),
+}
+
+/// The size of the ext4 image file given by --vhdx, or the default one in the config unless
+/// --no-vhdx is given.
+fn get_vhdx_size(opts: &CreateOpts) -> Result<Option<String>> {
+    if opts.vhdx.is_some() || opts.no_vhdx {
+        return Ok(opts.vhdx.clone());
+    }
+    // Don't silently create a plain directory when the config asks for an image file.
+    let config = DistrodConfig::get().with_context(|| {
+        "Failed to read the default rootfs format in the Distrod config. \
+         Pass --vhdx <size> or --no-vhdx to choose it explicitly."
+    })?;
+    let size = config
+        .distrod
+        .get_default_vhdx_size()
+        .with_context(|| "Invalid default_rootfs_format in the Distrod config.")?;
+    Ok(size.map(|size| size.to_owned()))
+}
+
+/// Show what `create` would do without downloading the image or changing anything.
+async fn dry_run_create_distro(opts: &CreateOpts, image_size: Option<u64>) -> Result<()> {
+    let (image_name, image) = if let Some(ref reference) = opts.from_oci {
+        (reference.get_distro_name(), format!("'{}'", reference))
+    } else if let Some(ref path) = opts.from_docker_archive {
+        (get_docker_archive_distro_name(path)?, format!("{:?}", path))
+    } else {
+        let image = choose_distro_image(opts).await?;
+        let source = match image.image {
+            DistroImageFile::Local(path) => format!("{:?}", path),
+            DistroImageFile::Url(url) => format!("'{}'", url),
+        };
+        (image.name, source)
+    };
+    log::info!("Would create {} from {}.", image_name, image);
+    let install_dir = get_install_dir(opts, &image_name)?;
+    let is_empty = match std::fs::read_dir(&install_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("Would create the directory {:?}.", &install_dir);
+            true
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", &install_dir)),
+    };
+    if !is_empty {
+        if !opts.force {
+            bail!(
+                "The install dir {:?} is not empty. Give --force to remove its contents first.",
+                &install_dir
+            );
+        }
+        log::info!("Would remove the contents of {:?}.", &install_dir);
+    }
+    if let Some(image_size) = image_size {
+        log::info!(
+            "Would create an ext4 image file of {} bytes and mount it at {:?}.",
+            image_size,
+            &install_dir
+        );
+    }
+    log::info!(
+        "Would unpack the image into {:?} and initialize it.",
+        &install_dir
+    );
+    log::info!("Dry run done. Nothing has been changed.");
+    Ok(())
+}
+
+fn get_install_dir(opts: &CreateOpts, image_name: &str) -> Result<PathBuf> {
+    match opts.install_dir {
+        Some(ref install_dir) => Ok(PathBuf::from(install_dir)),
+        None => {
+            let config =
+                DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+            Ok(config.distrod.distro_images_dir.join(image_name))
+        }
+    }
+}
+
+fn get_docker_archive_distro_name(path: &OsStr) -> Result<String> {
+    Ok(format!(
+        "docker-{}",
+        Path::new(path)
+            .file_stem()
+            .ok_or_else(|| anyhow!("image {:?} should be a file.", path))?
+            .to_string_lossy()
+    ))
+}
+
+async fn choose_distro_image(opts: &CreateOpts) -> Result<DistroImage> {
+    let image = match opts.image_path.clone() {
+        None if opts.distro.is_some() => {
+            let chooser =
+                ListChooserByNames::new(opts.distro.iter().chain(opts.version.iter()).cloned());
+            distro_image::fetch_image_from(
+                Box::new(build_container_org_image_list(
+                    opts.mirror.as_deref(),
+                    &opts.arch,
+                    opts.refresh,
+                )?),
+                &|list| chooser.choose(list),
+            )
+            .await
+            .with_context(|| "Failed to fetch the image.")?
+        }
         None => {
             let local_image_fetcher =
                 || Ok(Box::new(LocalDistroImage::new(&prompt_path)) as Box<dyn DistroImageFetcher>);
-            let container_org_image_fetcher =
-                || Ok(Box::new(ContainerOrgImageList::default()) as Box<dyn DistroImageFetcher>);
+            let container_org_image_list =
+                build_container_org_image_list(opts.mirror.as_deref(), &opts.arch, opts.refresh)?;
+            let container_org_image_fetcher = move || {
+                Ok(Box::new(container_org_image_list.clone()) as Box<dyn DistroImageFetcher>)
+            };
             let fetchers = vec![
                 Box::new(local_image_fetcher) as DistroImageFetcherGen,
                 Box::new(container_org_image_fetcher) as DistroImageFetcherGen,
@@ -250,75 +904,382 @@ async fn create_distro(opts: CreateOpts) -> Result<()> {
                 .await
                 .with_context(|| "Failed to fetch the image list.")?
         }
-        Some(path) => {
-            let name = format!(
-                "local-{}",
-                Path::new(&path)
-                    .file_stem()
-                    .ok_or_else(|| anyhow!("image {:?} should be a file.", &path))?
-                    .to_string_lossy()
-                    .replace(".tar", "")
-            );
-            DistroImage {
-                image: DistroImageFile::Local(path),
-                name,
-            }
-        }
+        Some(path) => get_distro_image_by_path(path)?,
     };
+    Ok(image)
+}
 
-    let image_name = image.name;
-    let tar_xz = match image.image {
-        DistroImageFile::Local(path) => Box::new(
-            File::open(&path)
-                .with_context(|| format!("Failed to open the distro image file: {:?}.", &path))?,
-        ) as Box<dyn Read>,
+/// Get the image given by --image-path, which is a local file or an http(s) URL.
+fn get_distro_image_by_path(path: OsString) -> Result<DistroImage> {
+    let url = path
+        .to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"));
+    if let Some(url) = url {
+        let parsed =
+            reqwest::Url::parse(url).with_context(|| format!("Invalid image URL '{}'.", url))?;
+        let file_name = parsed
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .unwrap_or_default();
+        let stem = file_name.split(".tar").next().unwrap_or_default();
+        // The images on linuxcontainers.org and its mirrors are all named rootfs.tar.xz.
+        let name = if stem.is_empty() || stem == "rootfs" {
+            parsed.host_str().unwrap_or("image")
+        } else {
+            stem
+        };
+        return Ok(DistroImage {
+            name: format!("remote-{}", name),
+            image: DistroImageFile::Url(url.to_owned()),
+        });
+    }
+    let name = format!(
+        "local-{}",
+        Path::new(&path)
+            .file_stem()
+            .ok_or_else(|| anyhow!("image {:?} should be a file.", &path))?
+            .to_string_lossy()
+            .replace(".tar", "")
+    );
+    Ok(DistroImage {
+        image: DistroImageFile::Local(path),
+        name,
+    })
+}
+
+/// Open the image, and verify it against the SHA-256 digest if it's given.
+async fn open_distro_image(
+    image: DistroImageFile,
+    expected_sha256: Option<&str>,
+) -> Result<Box<dyn Read>> {
+    let tar_xz = match image {
+        DistroImageFile::Local(path) => {
+            let mut file = File::open(&path)
+                .with_context(|| format!("Failed to open the distro image file: {:?}.", &path))?;
+            distro_image::verify_xz_stream(&mut file)
+                .with_context(|| format!("The distro image file {:?} is broken.", &path))?;
+            if let Some(expected_sha256) = expected_sha256 {
+                file.seek(SeekFrom::Start(0))
+                    .with_context(|| format!("Failed to seek {:?}.", &path))?;
+                distro_image::verify_sha256(&mut file, expected_sha256)
+                    .with_context(|| format!("Failed to verify {:?}.", &path))?;
+            }
+            file.seek(SeekFrom::Start(0))
+                .with_context(|| format!("Failed to seek {:?}.", &path))?;
+            Box::new(file) as Box<dyn Read>
+        }
         DistroImageFile::Url(url) => {
+            if url.starts_with("http://") && expected_sha256.is_none() {
+                log::warn!(
+                    "'{}' is downloaded over plain HTTP, which can be tampered with on the way. \
+                     Use https://, or give its SHA-256 digest by --image-sha256.",
+                    url
+                );
+            }
             log::info!("Downloading '{}'...", url);
             let mut bytes = vec![];
             download_file_with_progress(&url, build_progress_bar, &mut bytes).await?;
+            distro_image::verify_xz_stream(&bytes[..])
+                .with_context(|| "The download appears truncated. Re-run the command to retry.")?;
+            if let Some(expected_sha256) = expected_sha256 {
+                distro_image::verify_sha256(&bytes[..], expected_sha256)
+                    .with_context(|| format!("Failed to verify '{}'.", url))?;
+            }
             log::info!("Download done.");
             Box::new(Cursor::new(bytes)) as Box<dyn Read>
         }
     };
+    Ok(tar_xz)
+}
 
-    log::info!("Unpacking...");
-    let install_dir = match opts.install_dir {
-        Some(install_dir) => install_dir,
-        None => {
-            let def_install_path =
-                DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
-            def_install_path
-                .distrod
-                .distro_images_dir
-                .join(&image_name)
-                .into()
+/// Make sure that the new rootfs is not unpacked over an existing one. The contents of the
+/// directory are removed only with force and after the confirmation unless yes is given.
+fn clear_install_dir<F>(install_dir: &Path, force: bool, yes: bool, prompt: &F) -> Result<()>
+where
+    F: Fn(&str, &str, Option<&str>) -> Result<String>,
+{
+    let entries: Vec<_> = std::fs::read_dir(install_dir)
+        .with_context(|| format!("Failed to read {:?}.", install_dir))?
+        .collect::<std::io::Result<_>>()
+        .with_context(|| format!("Failed to read {:?}.", install_dir))?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+    if !force {
+        bail!(
+            "The install dir {:?} is not empty. Give --force to remove its contents first.",
+            install_dir
+        );
+    }
+    let canonical_install_dir = install_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", install_dir))?;
+    if canonical_install_dir == Path::new("/") {
+        bail!("The install dir must not be /.");
+    }
+    if let Some(distro) = DistroLauncher::get_running_distro()? {
+        if distro.get_rootfs() == canonical_install_dir {
+            bail!(
+                "The install dir {:?} is the rootfs of the running distro. Stop it first.",
+                install_dir
+            );
+        }
+    }
+    // Removing the contents would go into the mounted filesystems, such as a bind-mounted
+    // home directory.
+    let mount_entries = mount_info::get_mount_entries()?;
+    let mount_points = mount_info::get_mount_points_under(&mount_entries, &canonical_install_dir);
+    if !mount_points.is_empty() {
+        bail!(
+            "The install dir {:?} has filesystems mounted on {:?}. Unmount them first.",
+            install_dir,
+            mount_points
+        );
+    }
+    if !yes {
+        let answer = prompt(
+            &format!(
+                "All the files in {:?} will be deleted. Are you sure?",
+                install_dir
+            ),
+            "y/N",
+            Some("N"),
+        )?;
+        if !matches!(answer.to_lowercase().as_str(), "y" | "yes") {
+            bail!("Aborted.");
+        }
+    }
+    log::info!("Removing the contents of {:?}...", install_dir);
+    for entry in entries {
+        let path = entry.path();
+        let result = if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        result.with_context(|| format!("Failed to remove {:?}.", &path))?;
+    }
+    Ok(())
+}
+
+fn create_rootfs_image_at(install_dir: &Path, size: u64) -> Result<()> {
+    let is_empty = std::fs::read_dir(install_dir)
+        .with_context(|| format!("Failed to read {:?}.", install_dir))?
+        .next()
+        .is_none();
+    if !is_empty {
+        bail!(
+            "{:?} is not empty. The image file can't be mounted on it.",
+            install_dir
+        );
+    }
+    let image = install_dir.with_extension("img");
+    log::info!("Creating the ext4 image file {:?}...", &image);
+    rootfs_image::create_rootfs_image(&image, size)?;
+    if let Err(e) = rootfs_image::mount_rootfs_image(&image, install_dir) {
+        rootfs_image::remove_rootfs_image(&image);
+        return Err(e);
+    }
+    if let Err(e) = rootfs_image::register_rootfs_image(&image, install_dir) {
+        if let Err(e) = nix::mount::umount(install_dir) {
+            log::warn!("Failed to unmount {:?}. {}", install_dir, e);
+        } else {
+            rootfs_image::remove_rootfs_image(&image);
         }
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn clone_distro(opts: CloneOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let source = if Path::new(&opts.source).is_dir() {
+        PathBuf::from(&opts.source)
+    } else {
+        config.distrod.distro_images_dir.join(&opts.source)
     };
-    let install_dir = Path::new(&install_dir);
-    if !install_dir.exists() {
-        std::fs::create_dir_all(&install_dir)
-            .with_context(|| format!("Failed to make a directory: {:?}.", &install_dir))?;
+    if !source.is_dir() {
+        bail!("No distro is installed at {:?}.", &source);
+    }
+    // Check it before copying the whole tree.
+    let hostname = opts.hostname.as_ref().unwrap_or(&opts.name);
+    distro::validate_hostname(hostname).with_context(|| {
+        format!(
+            "'{}' can't be the hostname. Give a valid one by --hostname.",
+            hostname
+        )
+    })?;
+    let install_dir = match opts.install_dir {
+        Some(ref install_dir) => PathBuf::from(install_dir),
+        None => config.distrod.distro_images_dir.join(&opts.name),
+    };
+    let canonical_source = source
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", &source))?;
+    if canonicalize_nonexistent_path(&install_dir)?.starts_with(&canonical_source) {
+        // The copy would copy itself endlessly.
+        bail!(
+            "{:?} is inside the distro {:?} to clone.",
+            &install_dir,
+            &source
+        );
+    }
+    if install_dir.exists()
+        && std::fs::read_dir(&install_dir)
+            .with_context(|| format!("Failed to read {:?}.", &install_dir))?
+            .next()
+            .is_some()
+    {
+        bail!("{:?} already exists and is not empty.", &install_dir);
+    }
+    if let Some(parent) = install_dir.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to make a directory: {:?}.", parent))?;
     }
-    let tar = XzDecoder::new(tar_xz);
-    let mut archive = tar::Archive::new(tar);
-    archive.set_preserve_permissions(true);
-    archive.set_unpack_xattrs(true);
-    archive
-        .unpack(&install_dir)
-        .with_context(|| format!("Failed to unpack the image to '{:?}'.", &install_dir))?;
 
-    distro::initialize_distro_rootfs(
-        HostPath::new(&install_dir.canonicalize().with_context(|| {
+    log::info!(
+        "Copying {:?} to {:?}. This may take a while...",
+        &source,
+        &install_dir
+    );
+    let mut copier = TreeCopier::default();
+    copier
+        .copy(&source, &install_dir)
+        .with_context(|| format!("Failed to copy {:?} to {:?}.", &source, &install_dir))?;
+    if copier.has_used_reflink() {
+        log::debug!("The files have been cloned by reflink.");
+    }
+
+    let rootfs =
+        HostPath::new(install_dir.canonicalize().with_context(|| {
             format!("Failed to get the canonicalized path of {:?}", &install_dir)
-        })?)?,
-        true,
+        })?)?;
+    distro::initialize_distro_rootfs(&rootfs, false, false)
+        .with_context(|| "Failed to initialize the rootfs.")?;
+    distro::set_distro_hostname(&rootfs, hostname)
+        .with_context(|| format!("Failed to set the hostname to '{}'.", hostname))?;
+    // The clone is a new distro, so its first-boot scripts should run on its first launch.
+    first_boot::reset_first_boot_stamp(&rootfs)
+        .with_context(|| "Failed to reset the first-boot stamp.")?;
+
+    log::info!("{} is created at {:?}", &opts.name, &install_dir);
+    Ok(())
+}
+
+/// Canonicalize the path which may not exist yet, by canonicalizing its nearest existing ancestor.
+fn canonicalize_nonexistent_path(path: &Path) -> Result<PathBuf> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir()
+            .with_context(|| "Failed to get the current directory.")?
+            .join(path)
+    };
+    let mut existing = path.as_path();
+    let mut rest = vec![];
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            // ".." can't be resolved without its existing parent.
+            _ => bail!("Failed to resolve {:?}.", &path),
+        }
+    }
+    let mut canonical = existing
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", existing))?;
+    canonical.extend(rest.into_iter().rev());
+    Ok(canonical)
+}
+
+fn adopt_distro(opts: AdoptOpts) -> Result<()> {
+    if distro::is_inside_running_distro() {
+        bail!("A distro can't be adopted from inside a distro. Run it outside the distro, for example via `wsl -u root -e`.");
+    }
+    if let Some(ref hostname) = opts.hostname {
+        distro::validate_hostname(hostname)?;
+    }
+    let rootfs = opts
+        .rootfs
+        .canonicalize()
+        .with_context(|| format!("Failed to get the canonicalized path of {:?}", &opts.rootfs))?;
+    let running_distro = DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to see if there's a running distro.")?;
+    check_adoptable_rootfs(
+        &rootfs,
+        running_distro.as_ref().map(|distro| distro.get_rootfs()),
+    )?;
+
+    distro::initialize_distro_rootfs_with_hostname(
+        HostPath::new(&rootfs)?,
+        false,
+        opts.hostname.as_deref(),
+        opts.dry_run,
     )
     .with_context(|| "Failed to initialize the rootfs.")?;
+    fix_distrod_exec_permission(opts.dry_run)
+        .with_context(|| "Failed to make distrod-exec a setuid binary.")?;
+    if opts.dry_run {
+        if opts.make_default {
+            log::info!("Would make {:?} the default distro.", &rootfs);
+        }
+        log::info!("Dry run done. Nothing has been changed.");
+        return Ok(());
+    }
+    if opts.make_default {
+        let mut config = (*DistrodConfig::get()?).clone();
+        config.distrod.default_distro_image = rootfs.clone();
+        config
+            .update()
+            .with_context(|| "Failed to save the default distro in the Distrod config.")?;
+        log::info!("{:?} is now the default distro.", &rootfs);
+    }
+    log::info!(
+        "{:?} has been adopted. Start it by `distrod start --rootfs {}`.",
+        &rootfs,
+        rootfs.to_string_lossy()
+    );
+    Ok(())
+}
 
-    log::info!("{} is created at {:?}", &image_name, install_dir);
+/// Make sure that the rootfs is a distro which can be adopted, not the root of this WSL distro
+/// nor the running distro.
+fn check_adoptable_rootfs(rootfs: &Path, running_rootfs: Option<&Path>) -> Result<()> {
+    // Compare the files rather than the paths, which can differ by bind mounts.
+    if is_same_file(rootfs, Path::new("/")) {
+        bail!("The rootfs is the root of this WSL distro. Run `distrod enable` to run systemd in it instead.");
+    }
+    if matches!(running_rootfs, Some(running_rootfs) if is_same_file(rootfs, running_rootfs)) {
+        bail!(
+            "{:?} is the rootfs of the running distro. Stop it first.",
+            rootfs
+        );
+    }
+    if !rootfs.is_dir() {
+        bail!("{:?} is not a directory.", rootfs);
+    }
+    let has_os_release = ["etc/os-release", "usr/lib/os-release"]
+        .iter()
+        .any(|path| std::fs::symlink_metadata(rootfs.join(path)).is_ok());
+    if !has_os_release || !rootfs.join("etc/passwd").is_file() {
+        bail!(
+            "{:?} doesn't look like the rootfs of a distro. It should have /etc/os-release and /etc/passwd.",
+            rootfs
+        );
+    }
     Ok(())
 }
 
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
 fn launch_distro(opts: StartOpts) -> Result<()> {
     if distro::is_inside_running_distro()
         || DistroLauncher::get_running_distro()
@@ -328,7 +1289,7 @@ fn launch_distro(opts: StartOpts) -> Result<()> {
         bail!("There is already a running distro.");
     }
     let mut distro_launcher = DistroLauncher::new()?;
-    if let Some(rootfs) = opts.rootfs {
+    if let Some(rootfs) = get_rootfs_opt(opts.rootfs, opts.distro.as_deref())? {
         distro_launcher
             .with_rootfs(&rootfs)
             .with_context(|| format!("Failed to set {:?} to the rootfs of the distro.", &rootfs))?;
@@ -337,34 +1298,312 @@ fn launch_distro(opts: StartOpts) -> Result<()> {
             .from_default_distro()
             .with_context(|| "Failed to get the default distro.")?;
     }
-    distro_launcher
+    if let (false, Some(rootfs)) = (opts.allow_slow_fs, distro_launcher.get_rootfs()) {
+        warn_if_on_windows_fs(rootfs);
+    }
+    if let Some(user_namespace) = opts.user_namespace {
+        distro_launcher.with_user_namespace(user_namespace);
+    }
+    if opts.no_init {
+        distro_launcher.without_init();
+    }
+    // DistroLauncher::new has applied default_init_target in the config.
+    if let Some(ref init_target) = opts.unit {
+        distro_launcher
+            .with_init_target(init_target)
+            .with_context(|| format!("Invalid target: {}", init_target))?;
+    }
+    // So has system_envs in the config.
+    for (key, value) in &opts.setenv {
+        distro_launcher
+            .with_systemd_setenv(key, value)
+            .with_context(|| format!("Invalid --setenv {}.", key))?;
+    }
+    if let Some(ref memory_max) = opts.memory_max {
+        distro_launcher
+            .with_memory_max(memory_max)
+            .with_context(|| "Invalid --memory-max.")?;
+    }
+    if let Some(ref cpu_quota) = opts.cpu_quota {
+        distro_launcher
+            .with_cpu_quota(cpu_quota)
+            .with_context(|| "Invalid --cpu-quota.")?;
+    }
+    let distro = distro_launcher
         .launch()
         .with_context(|| "Failed to launch the distro.")?;
+    if let Some(wait_secs) = opts.wait {
+        wait_until_system_running(&distro, Duration::from_secs(wait_secs))?;
+    }
+    Ok(())
+}
+
+fn wait_until_system_running(distro: &Distro, timeout: Duration) -> Result<()> {
+    log::info!("Waiting for systemd to start the system...");
+    match distro::wait_until_system_running(distro, timeout)? {
+        SystemRunningState::Running => log::info!("The system is running."),
+        SystemRunningState::Degraded => {
+            let failed_units = distro
+                .get_failed_systemd_units()
+                .map(|units| {
+                    units
+                        .into_iter()
+                        .map(|unit| unit.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                })
+                .unwrap_or_else(|e| format!("unknown ({:?})", e));
+            log::warn!(
+                "The system is running, but some units failed: {}",
+                failed_units
+            );
+        }
+        SystemRunningState::TimedOut(state) => bail!(
+            "The system didn't get running in {} seconds. The state is '{}'.",
+            timeout.as_secs(),
+            state
+        ),
+    }
     Ok(())
 }
 
+/// The rootfs given by --rootfs, or the one of the distro given by --distro.
+fn get_rootfs_opt(rootfs: Option<OsString>, distro_name: Option<&str>) -> Result<Option<OsString>> {
+    let distro_name = match distro_name {
+        Some(distro_name) => distro_name,
+        None => return Ok(rootfs),
+    };
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let rootfs = resolve_distro_name(&config.distrod.distro_images_dir, distro_name)?;
+    Ok(Some(rootfs.into_os_string()))
+}
+
+/// Find the rootfs of the distro by its name in the distro images directory. A part of the name
+/// is accepted if it matches only one distro.
+fn resolve_distro_name(distro_images_dir: &Path, distro_name: &str) -> Result<PathBuf> {
+    if distro_name.is_empty()
+        || distro_name.contains('/')
+        || distro_name == "."
+        || distro_name == ".."
+    {
+        bail!("Invalid distro name '{}'.", distro_name);
+    }
+    let exact = distro_images_dir.join(distro_name);
+    if exact.is_dir() {
+        return Ok(exact);
+    }
+    let mut installed = vec![];
+    match std::fs::read_dir(distro_images_dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let entry =
+                    entry.with_context(|| format!("Failed to read {:?}.", distro_images_dir))?;
+                if entry.path().is_dir() {
+                    installed.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {:?}.", distro_images_dir))
+        }
+    }
+    installed.sort();
+    let matched: Vec<_> = installed
+        .iter()
+        .filter(|name| name.contains(distro_name))
+        .collect();
+    match matched.as_slice() {
+        [name] => Ok(distro_images_dir.join(name)),
+        [] if installed.is_empty() => bail!(
+            "No distro named '{}' is found. No distro is installed in {:?}.",
+            distro_name,
+            distro_images_dir
+        ),
+        [] => bail!(
+            "No distro named '{}' is found in {:?}. The installed distros are: {}",
+            distro_name,
+            distro_images_dir,
+            installed.join(", ")
+        ),
+        _ => bail!(
+            "'{}' matches multiple distros: {}. Give the full name.",
+            distro_name,
+            matched
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn warn_if_on_windows_fs(rootfs: &Path) {
+    let fstype = rootfs
+        .canonicalize()
+        .map_err(anyhow::Error::from)
+        .and_then(distro::get_windows_fs_type);
+    match fstype {
+        Ok(Some(fstype)) => log::warn!(
+            "The rootfs {:?} is on a Windows drive ({}). The file I/O will be very slow.\n\
+             Consider placing it on the WSL filesystem. Pass --allow-slow-fs to suppress this warning.",
+            rootfs,
+            fstype
+        ),
+        Ok(None) => {}
+        Err(e) => log::debug!("Failed to get the filesystem of {:?}. {:?}", rootfs, e),
+    }
+}
+
 fn exec_command(opts: ExecOpts) -> Result<()> {
+    if opts.no_systemd || distro::is_systemd_disabled_by_env() {
+        return exec_command_without_systemd(opts);
+    }
+
     let distro = DistroLauncher::get_running_distro()
         .with_context(|| "Failed to get the running distro.")?;
+    let rootfs = get_rootfs_opt(opts.rootfs.clone(), opts.distro.as_deref())?;
     if distro.is_none() {
-        if let Some(ref rootfs) = opts.rootfs {
+        if let Some(rootfs) = rootfs {
             launch_distro(StartOpts {
-                rootfs: Some(rootfs.clone()),
+                rootfs: Some(rootfs),
+                distro: None,
+                allow_slow_fs: false,
+                user_namespace: None,
+                no_init: false,
+                unit: None,
+                setenv: vec![],
+                wait: None,
+                memory_max: None,
+                cpu_quota: None,
             })?;
             return exec_command(opts);
         }
         bail!("No distro is currently running.");
     }
     let distro = distro.unwrap();
+    if let Some(rootfs) = rootfs {
+        // Don't run the command in another distro than the one the user has asked for.
+        if !is_same_file(Path::new(&rootfs), distro.get_rootfs()) {
+            bail!(
+                "Another distro {:?} is running, not {:?}. Stop it first.",
+                distro.get_rootfs(),
+                &rootfs
+            );
+        }
+    }
 
     let passwd_path =
         ContainerPath::new("/etc/passwd")?.to_host_path(&HostPath::new(distro.get_rootfs())?);
-    let cred = opts
-        .uid
+    let cred = get_exec_credential(&opts, &passwd_path)?;
+
+    if opts.cwd_create {
+        let wd = opts
+            .working_directory
+            .as_ref()
+            .ok_or_else(|| anyhow!("--cwd-create requires --working-directory."))?;
+        distro
+            .create_working_directory(wd, cred.as_ref())
+            .with_context(|| format!("Failed to create the working directory {:?}.", wd))?;
+    }
+
+    let working_directory = match opts.workdir_host {
+        Some(ref host_wd) => Some(distro.translate_host_working_directory(host_wd).into()),
+        None => opts.working_directory.clone(),
+    };
+
+    log::debug!("Executing a command in the distro.");
+    set_noninheritable_sig_ign();
+    let mut waiter = if opts.login {
+        let user = login::get_login_user(&passwd_path, get_exec_uid(cred.as_ref()))?;
+        let mut command =
+            login::build_login_command(&user, &opts.command, &opts.args, opts.preserve_env);
+        command.current_dir(working_directory.unwrap_or_else(|| OsString::from(&user.dir)));
+        if let Some(umask) = opts.umask {
+            distro::set_umask_on_exec(&mut command, umask);
+        }
+        distro.exec_std_command(command, cred.as_ref())?
+    } else {
+        distro.exec_command(
+            &opts.command,
+            &opts.args,
+            working_directory,
+            opts.arg0,
+            cred.as_ref(),
+            opts.umask,
+        )?
+    };
+    if let Some(cred) = cred {
+        cred.drop_privilege();
+    }
+    let status = waiter.wait();
+    std::process::exit(status.to_exit_code())
+}
+
+/// Exec the command in the current mount namespace, as WSL does without Distrod.
+/// Unlike in the systemd container, the WSL environment variables are the ones which the
+/// caller has, rather than the ones Distrod sets up for the systemd session.
+fn exec_command_without_systemd(opts: ExecOpts) -> Result<()> {
+    if opts.cwd_create {
+        bail!("--cwd-create is not supported with --no-systemd.");
+    }
+    let cred = get_exec_credential(&opts, Path::new("/etc/passwd"))?;
+
+    log::debug!("Executing a command without systemd.");
+    let (mut command, home) = if opts.login {
+        let user = login::get_login_user("/etc/passwd", get_exec_uid(cred.as_ref()))?;
+        let command =
+            login::build_login_command(&user, &opts.command, &opts.args, opts.preserve_env);
+        (command, Some(user.dir))
+    } else {
+        let mut command = Command::new(&opts.command);
+        command.args(&opts.args);
+        if let Some(ref arg0) = opts.arg0 {
+            command.arg0(arg0);
+        }
+        (command, None)
+    };
+    // The host paths are the ones seen by the command as it is in the same mount namespace.
+    let wd = opts
+        .working_directory
+        .as_deref()
+        .or_else(|| opts.workdir_host.as_deref().map(Path::as_os_str))
+        .or_else(|| home.as_deref().map(std::ffi::OsStr::new));
+    if let Some(wd) = wd {
+        command.current_dir(wd);
+    }
+    if let Some(umask) = opts.umask {
+        nix::sys::stat::umask(umask);
+    }
+    if let Some(cred) = cred {
+        cred.drop_privilege();
+    }
+    let e = command.exec();
+    Err(e).with_context(|| format!("Failed to exec {:?}.", &opts.command))
+}
+
+/// The uid which the command of exec runs as. It's root if no user is given.
+fn get_exec_uid(cred: Option<&Credential>) -> u32 {
+    cred.map(|cred| cred.uid.as_raw()).unwrap_or(0)
+}
+
+fn get_exec_credential<P: AsRef<Path>>(
+    opts: &ExecOpts,
+    passwd_path: P,
+) -> Result<Option<Credential>> {
+    let passwd_path = passwd_path.as_ref();
+    if let Some(ref user) = opts.user {
+        let group_path = passwd_path.with_file_name("group");
+        let cred =
+            passwd::get_credential_from_user_spec(user, passwd_path, &group_path, opts.force_group)
+                .with_context(|| format!("Failed to get the credential of '{}'.", user))?;
+        return Ok(Some(cred));
+    }
+    opts.uid
         .map(|uid| {
             Ok(
-                get_credential_from_passwd_file(opts.user.as_ref(), Some(uid), &passwd_path)
-                    .with_context(|| format!("Failed to open the passwd file. {:?}", &passwd_path))?
+                get_credential_from_passwd_file(None, Some(uid), passwd_path)
+                    .with_context(|| format!("Failed to open the passwd file. {:?}", passwd_path))?
                     .unwrap_or(Credential {
                         uid: Uid::from_raw(uid),
                         gid: Gid::from_raw(uid),
@@ -373,22 +1612,50 @@ fn exec_command(opts: ExecOpts) -> Result<()> {
             )
         })
         .map_or(Ok(None), |v: Result<_>| v.map(Some))
-        .with_context(|| "Failed to get credentail.")?;
+        .with_context(|| "Failed to get credentail.")
+}
 
-    log::debug!("Executing a command in the distro.");
-    set_noninheritable_sig_ign();
-    let mut waiter = distro.exec_command(
-        &opts.command,
-        &opts.args,
-        opts.working_directory,
-        opts.arg0,
-        cred.as_ref(),
-    )?;
-    if let Some(cred) = cred {
-        cred.drop_privilege();
+fn configure_distro(opts: ConfigOpts) -> Result<()> {
+    if opts.default_user.is_none() && opts.default_shell.is_none() {
+        bail!("Specify --default-user or --default-shell.");
     }
-    let status = waiter.wait();
-    std::process::exit(status as i32)
+    let mut wsl_conf = WslConf::open(WSL_CONF_PATH)?;
+    let default_user = match opts.default_user {
+        Some(ref user) => {
+            let mut passwd_file = PasswdFile::open("/etc/passwd")?;
+            let entry = match user.parse::<u32>() {
+                Ok(uid) => passwd_file.get_ent_by_uid(uid)?,
+                Err(_) => passwd_file.get_ent_by_name(user)?,
+            };
+            let name = entry
+                .ok_or_else(|| anyhow!("User '{}' does not exist.", user))?
+                .name
+                .to_owned();
+            wsl_conf.set_value("user", "default", &name).write()?;
+            log::info!("The default user has been set to '{}'.", &name);
+            Some(name)
+        }
+        None => wsl_conf.get_value("user", "default").map(str::to_owned),
+    };
+    if let Some(ref shell) = opts.default_shell {
+        let user = default_user.ok_or_else(|| {
+            anyhow!(
+                "The default user is not set in {}. Specify --default-user.",
+                WSL_CONF_PATH
+            )
+        })?;
+        shell_hook::set_login_shell(&user, shell)
+            .with_context(|| format!("Failed to set the login shell of '{}'.", &user))?;
+        log::info!(
+            "The login shell of '{}' has been set to {:?}.",
+            &user,
+            shell
+        );
+    }
+    log::info!(
+        "The changes take effect on the next launch. Run `wsl --shutdown` to apply them now."
+    );
+    Ok(())
 }
 
 fn stop_distro(opts: StopOpts) -> Result<()> {
@@ -398,6 +1665,412 @@ fn stop_distro(opts: StopOpts) -> Result<()> {
         bail!("No distro is currently running.");
     }
     let distro = distro.unwrap();
-    log::debug!("Executing a command in the distro.");
-    distro.stop(opts.sigkill)
+    if distro::is_inside_running_distro() {
+        return distro.stop(opts.sigkill);
+    }
+    let mut init = ProcFile::from_pid(distro.get_init_pid())?;
+    distro.stop(opts.sigkill)?;
+    // The runtime files are in use until the distro stops.
+    if let Some(ref mut init) = init {
+        if wait_for_exit(init, Duration::from_secs(STOP_CLEANUP_WAIT_SECS)) {
+            cleanup_runtime_files();
+        }
+    }
+    Ok(())
+}
+
+/// How long `stop` waits for the distro to exit to clean up the runtime files.
+const STOP_CLEANUP_WAIT_SECS: u64 = 10;
+
+fn cleanup_runtime_files() {
+    if let Err(e) = distro::cleanup_per_user_envs_init_scripts() {
+        log::warn!(
+            "Failed to clean up the runtime files of the distro.: {:?}",
+            e
+        );
+    }
+}
+
+fn restart_distro(opts: RestartOpts) -> Result<()> {
+    if distro::is_inside_running_distro() {
+        // Stopping the distro would kill this process before it starts the distro again.
+        bail!("The distro can't be restarted from inside. Run it outside the distro, for example via `wsl -u root -e`.");
+    }
+    let distro = DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to get the running distro.")?;
+    // Start the distro again in the same way as it's running.
+    let (rootfs, user_namespace, no_init) = match distro {
+        Some(distro) => {
+            let rootfs = distro.get_rootfs().as_os_str().to_owned();
+            let user_namespace = distro.get_user_namespace().cloned();
+            let no_init = !distro.launches_init();
+            stop_distro_and_wait(distro, opts.sigkill, Duration::from_secs(opts.timeout))?;
+            (Some(rootfs), user_namespace, no_init)
+        }
+        None => {
+            log::info!("No distro is running. Starting the default distro.");
+            (None, None, false)
+        }
+    };
+    let wait = match opts.wait {
+        Some(_) if no_init => {
+            log::warn!("Not waiting for the system since the distro runs without init.");
+            None
+        }
+        wait => wait,
+    };
+    launch_distro(StartOpts {
+        rootfs,
+        distro: None,
+        allow_slow_fs: false,
+        user_namespace,
+        no_init,
+        unit: None,
+        setenv: vec![],
+        wait,
+        memory_max: None,
+        cpu_quota: None,
+    })
+}
+
+fn stop_distro_and_wait(distro: Distro, sigkill: bool, timeout: Duration) -> Result<()> {
+    let init_pid = distro.get_init_pid();
+    // Open the init beforehand so that a reused PID is not mistaken for it.
+    let mut init = match ProcFile::from_pid(init_pid)? {
+        Some(init) => init,
+        None => return Ok(()),
+    };
+    log::info!("Stopping the distro...");
+    distro.stop(sigkill)?;
+    if wait_for_exit(&mut init, timeout) {
+        cleanup_runtime_files();
+        return Ok(());
+    }
+    log::warn!(
+        "The distro didn't stop in {} seconds. Killing it by SIGKILL.",
+        timeout.as_secs()
+    );
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(init_pid as i32),
+        nix::sys::signal::SIGKILL,
+    )
+    .with_context(|| "Failed to kill the init process of the distro.")?;
+    if !wait_for_exit(&mut init, Duration::from_secs(5)) {
+        bail!(
+            "The init process of the distro (PID {}) didn't exit.",
+            init_pid
+        );
+    }
+    cleanup_runtime_files();
+    Ok(())
+}
+
+fn wait_for_exit(process: &mut ProcFile, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while process.is_live() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+#[tokio::main]
+async fn list_images(opts: ImagesOpts) -> Result<()> {
+    let names: Vec<_> = opts
+        .distro
+        .iter()
+        .chain(opts.version.iter())
+        .map(String::as_str)
+        .collect();
+    let list = fetch_image_list_by_names(
+        Box::new(build_container_org_image_list(
+            opts.mirror.as_deref(),
+            &opts.arch,
+            opts.refresh,
+        )?),
+        &names,
+    )
+    .await
+    .with_context(|| "Failed to fetch the image list.")?;
+    match list {
+        DistroImageList::Fetcher(_, fetchers, _) => {
+            let names: Vec<_> = fetchers.iter().map(|fetcher| fetcher.get_name()).collect();
+            if opts.json {
+                println!("{}", serde_json::to_string(&names)?);
+            } else {
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+        }
+        DistroImageList::Image(image) => {
+            let url = match image.image {
+                DistroImageFile::Url(url) => url,
+                DistroImageFile::Local(path) => bail!("Unexpected local image {:?}.", path),
+            };
+            if opts.json {
+                println!("{}", serde_json::json!({ "name": image.name, "url": url }));
+            } else {
+                println!("{}", url);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn manage_aliases(opts: AliasOpts) -> Result<()> {
+    match opts {
+        AliasOpts::Add { name, target } => {
+            let target = Path::new(&target);
+            if !target.is_absolute() {
+                bail!("The alias target must be an absolute path: {:?}", target);
+            }
+            let rootfs = get_alias_rootfs()?;
+            let alias = CommandAlias::create_named(&name, target, &rootfs)
+                .with_context(|| format!("Failed to add the alias '{}'.", name))?;
+            log::info!(
+                "The alias '{}' has been added at {:?}.",
+                name,
+                command_alias::get_named_alias_dir().join(&name)
+            );
+            log::debug!("The alias links to {:?}.", alias.get_link_path());
+        }
+        AliasOpts::Remove { name } => {
+            CommandAlias::remove_named(&name)
+                .with_context(|| format!("Failed to remove the alias '{}'.", name))?;
+        }
+        AliasOpts::List => {
+            for (name, alias) in CommandAlias::list_named()? {
+                println!("{} -> {}", name, alias.get_source_path().to_string_lossy());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The rootfs of the distro the aliases run in, which is the running one or the default one.
+fn get_alias_rootfs() -> Result<HostPath> {
+    if let Some(distro) =
+        DistroLauncher::get_running_distro().with_context(|| "Failed to get the running distro.")?
+    {
+        return HostPath::new(distro.get_rootfs());
+    }
+    let mut distro_launcher = DistroLauncher::new()?;
+    distro_launcher
+        .from_default_distro()
+        .with_context(|| "Failed to get the default distro.")?;
+    let rootfs = distro_launcher
+        .get_rootfs()
+        .ok_or_else(|| anyhow!("The default distro has no rootfs."))?;
+    HostPath::new(rootfs)
+}
+
+fn build_container_org_image_list(
+    mirror: Option<&str>,
+    arch: &str,
+    refreshes_cache: bool,
+) -> Result<ContainerOrgImageList> {
+    let mut list = ContainerOrgImageList::default();
+    list.with_cache_refreshed(refreshes_cache);
+    list.with_arch(arch)?;
+    if let Some(mirror) = mirror {
+        list.with_mirror(mirror)
+            .with_context(|| format!("Invalid mirror: '{}'.", mirror))?;
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test_adopt {
+    use super::*;
+
+    #[test]
+    fn test_check_adoptable_rootfs() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let rootfs = rootfs.path();
+        // An empty directory is not a rootfs.
+        assert!(check_adoptable_rootfs(rootfs, None).is_err());
+
+        std::fs::create_dir_all(rootfs.join("etc")).unwrap();
+        std::fs::write(rootfs.join("etc/passwd"), "root:x:0:0:root:/root:/bin/sh\n").unwrap();
+        assert!(check_adoptable_rootfs(rootfs, None).is_err());
+        // os-release is often a symlink to ../usr/lib/os-release.
+        std::os::unix::fs::symlink("../usr/lib/os-release", rootfs.join("etc/os-release")).unwrap();
+        check_adoptable_rootfs(rootfs, None).unwrap();
+        check_adoptable_rootfs(rootfs, Some(Path::new("/var/lib/distrod/other"))).unwrap();
+
+        assert!(check_adoptable_rootfs(rootfs, Some(rootfs)).is_err());
+        // The same directory by another path.
+        let link = tempfile::tempdir().unwrap();
+        let link = link.path().join("link");
+        std::os::unix::fs::symlink(rootfs, &link).unwrap();
+        assert!(check_adoptable_rootfs(rootfs, Some(&link)).is_err());
+        assert!(check_adoptable_rootfs(Path::new("/"), None).is_err());
+        assert!(check_adoptable_rootfs(Path::new("/etc/.."), None).is_err());
+        assert!(check_adoptable_rootfs(&rootfs.join("etc/passwd"), None).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_resolve_distro_name {
+    use super::*;
+
+    #[test]
+    fn test_resolve_distro_name() {
+        let images_dir = tempfile::tempdir().unwrap();
+        for name in &["ubuntu-focal", "ubuntu-jammy", "debian-bullseye"] {
+            std::fs::create_dir(images_dir.path().join(name)).unwrap();
+        }
+        std::fs::write(images_dir.path().join("alpine"), "not a distro").unwrap();
+        let resolve = |name| resolve_distro_name(images_dir.path(), name);
+
+        assert_eq!(
+            images_dir.path().join("ubuntu-focal"),
+            resolve("ubuntu-focal").unwrap()
+        );
+        assert_eq!(
+            images_dir.path().join("ubuntu-jammy"),
+            resolve("jammy").unwrap()
+        );
+        assert_eq!(
+            images_dir.path().join("debian-bullseye"),
+            resolve("debian").unwrap()
+        );
+
+        let ambiguous = format!("{:?}", resolve("ubuntu").unwrap_err());
+        assert!(ambiguous.contains("ubuntu-focal, ubuntu-jammy"));
+        let unknown = format!("{:?}", resolve("fedora").unwrap_err());
+        assert!(unknown.contains("debian-bullseye, ubuntu-focal, ubuntu-jammy"));
+        // Files are not distros.
+        assert!(resolve("alpine").is_err());
+        assert!(resolve("../ubuntu-focal").is_err());
+        assert!(resolve("").is_err());
+        // The images directory itself and its parent are not distros.
+        assert!(resolve(".").is_err());
+        assert!(resolve("..").is_err());
+
+        let no_images_dir = images_dir.path().join("nonexistent");
+        assert!(resolve_distro_name(&no_images_dir, "ubuntu").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_create {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn test_open_distro_image_with_sha256() {
+        use std::io::Write;
+
+        let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+        encoder.write_all(b"rootfs").unwrap();
+        let tar_xz = encoder.finish().unwrap();
+        let sha256 = distro_image::compute_sha256(&tar_xz[..]).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &tar_xz).unwrap();
+        let image = || DistroImageFile::Local(file.path().as_os_str().to_owned());
+
+        let mut opened = open_distro_image(image(), Some(&sha256)).await.unwrap();
+        let mut cont = vec![];
+        opened.read_to_end(&mut cont).unwrap();
+        assert_eq!(tar_xz, cont);
+        assert!(open_distro_image(image(), None).await.is_ok());
+        let wrong_sha256 = distro_image::compute_sha256(&b"other"[..]).unwrap();
+        assert!(open_distro_image(image(), Some(&wrong_sha256))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_get_distro_image_by_path() {
+        let image = get_distro_image_by_path(OsString::from("/tmp/ubuntu.tar.xz")).unwrap();
+        assert_eq!("local-ubuntu", image.name);
+        assert!(
+            matches!(image.image, DistroImageFile::Local(path) if path == "/tmp/ubuntu.tar.xz")
+        );
+
+        let image = get_distro_image_by_path(OsString::from(
+            "https://example.com/images/debian-bullseye.tar.xz?token=abc",
+        ))
+        .unwrap();
+        assert_eq!("remote-debian-bullseye", image.name);
+        assert!(matches!(
+            image.image,
+            DistroImageFile::Url(url) if url == "https://example.com/images/debian-bullseye.tar.xz?token=abc"
+        ));
+
+        let image = get_distro_image_by_path(OsString::from(
+            "http://mirror.example.org/images/ubuntu/focal/amd64/default/20211020_07:42/rootfs.tar.xz",
+        ))
+        .unwrap();
+        assert_eq!("remote-mirror.example.org", image.name);
+
+        // A relative path which looks like a URL without the scheme is a local file.
+        let image = get_distro_image_by_path(OsString::from("example.com/alpine.tar.xz")).unwrap();
+        assert_eq!("local-alpine", image.name);
+
+        assert!(get_distro_image_by_path(OsString::from("https://")).is_err());
+    }
+
+    fn populate(dir: &Path) {
+        std::fs::create_dir_all(dir.join("etc/systemd")).unwrap();
+        std::fs::write(dir.join("etc/os-release"), "ID=old\n").unwrap();
+        std::os::unix::fs::symlink("/etc/os-release", dir.join("os-release")).unwrap();
+    }
+
+    fn answer(answer: &'static str) -> impl Fn(&str, &str, Option<&str>) -> Result<String> {
+        move |_, _, _| Ok(answer.to_owned())
+    }
+
+    fn is_empty(dir: &Path) -> bool {
+        std::fs::read_dir(dir).unwrap().next().is_none()
+    }
+
+    #[test]
+    fn test_clear_install_dir() {
+        let install_dir = tempfile::tempdir().unwrap();
+        // An empty directory is fine without --force.
+        clear_install_dir(install_dir.path(), false, false, &answer("n")).unwrap();
+
+        populate(install_dir.path());
+        let err = clear_install_dir(install_dir.path(), false, false, &answer("y")).unwrap_err();
+        assert!(format!("{}", err).contains("not empty"), "{:?}", err);
+        assert!(install_dir.path().join("etc/os-release").exists());
+
+        assert!(clear_install_dir(install_dir.path(), true, false, &answer("n")).is_err());
+        assert!(install_dir.path().join("etc/os-release").exists());
+
+        clear_install_dir(install_dir.path(), true, false, &answer("y")).unwrap();
+        assert!(is_empty(install_dir.path()));
+
+        populate(install_dir.path());
+        let prompted = Cell::new(false);
+        let prompt = |_: &str, _: &str, _: Option<&str>| -> Result<String> {
+            prompted.set(true);
+            Ok("n".to_owned())
+        };
+        clear_install_dir(install_dir.path(), true, true, &prompt).unwrap();
+        assert!(!prompted.get());
+        assert!(is_empty(install_dir.path()));
+    }
+
+    #[test]
+    fn test_canonicalize_nonexistent_path() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let dir = tmpdir.path().canonicalize().unwrap();
+        std::fs::create_dir(dir.join("source")).unwrap();
+        std::os::unix::fs::symlink(dir.join("source"), dir.join("link")).unwrap();
+        assert_eq!(
+            dir.join("source/clone/rootfs"),
+            canonicalize_nonexistent_path(&dir.join("link/clone/rootfs")).unwrap()
+        );
+        assert_eq!(
+            dir.join("source"),
+            canonicalize_nonexistent_path(&dir.join("link")).unwrap()
+        );
+        assert!(canonicalize_nonexistent_path(&dir.join("none/../clone")).is_err());
+    }
 }
@@ -0,0 +1,396 @@
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use libs::cli_ui::build_progress_bar;
+use libs::distro_image::{download_file_with_progress, verify_gz_stream, verify_sha256};
+use libs::distrod_config;
+use libs::http_client;
+use libs::tree_copy::TreeCopier;
+use serde::Deserialize;
+
+use crate::SelfUpdateOpts;
+
+const LATEST_RELEASE_API_URL: &str =
+    "https://api.github.com/repos/nullpo-head/wsl-distrod/releases/latest";
+const OPT_DISTROD_ASSET_NAME: &str = "opt_distrod.tar.gz";
+/// The output of `sha256sum opt_distrod.tar.gz`, published with the release.
+const OPT_DISTROD_SHA256_ASSET_NAME: &str = "opt_distrod.tar.gz.sha256";
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The directories whose contents are made by users, which are carried over to the new version.
+const CONF_DIR_NAME: &str = "conf";
+const ALIAS_DIR_NAME: &str = "alias";
+/// renameat2(2) flag to exchange the two paths atomically.
+const RENAME_EXCHANGE: nix::libc::c_uint = 1 << 1;
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+    size: u64,
+}
+
+/// Update /opt/distrod to the latest release on GitHub.
+pub fn self_update(opts: SelfUpdateOpts) -> Result<()> {
+    let release = fetch_latest_release().with_context(|| "Failed to get the latest release.")?;
+    let latest_version = release.tag_name.trim_start_matches('v');
+    println!("Current version: {}", CURRENT_VERSION);
+    println!("Latest version:  {}", latest_version);
+    if !is_newer_version(latest_version, CURRENT_VERSION)? {
+        println!("Distrod is up to date.");
+        return Ok(());
+    }
+    if !opts.yes {
+        println!("Run `distrod self-update --yes` to update Distrod.");
+        return Ok(());
+    }
+
+    let asset = find_asset(&release, OPT_DISTROD_ASSET_NAME)?;
+    let sha256_asset = find_asset(&release, OPT_DISTROD_SHA256_ASSET_NAME)?;
+    let expected_sha256 = parse_sha256sum_output(&fetch_text(&sha256_asset.browser_download_url)?)?;
+    let mut tar_gz = tempfile::tempfile().with_context(|| "Failed to create a temporary file.")?;
+    download_asset(asset, &mut tar_gz)?;
+    tar_gz.seek(SeekFrom::Start(0))?;
+    verify_gz_stream(BufReader::new(&tar_gz))?;
+    // Nothing in the archive is unpacked or run before it's verified.
+    tar_gz.seek(SeekFrom::Start(0))?;
+    verify_sha256(BufReader::new(&tar_gz), &expected_sha256)
+        .with_context(|| format!("Failed to verify {}.", OPT_DISTROD_ASSET_NAME))?;
+
+    let root_dir = Path::new(distrod_config::get_distrod_root_dir());
+    let staging_dir = get_staging_dir(root_dir);
+    if staging_dir.exists() {
+        fs::remove_dir_all(&staging_dir)
+            .with_context(|| format!("Failed to remove the old {:?}.", &staging_dir))?;
+    }
+    tar_gz.seek(SeekFrom::Start(0))?;
+    unpack_tar_gz(BufReader::new(&tar_gz), &staging_dir)?;
+
+    let new_version = get_distrod_version(&staging_dir.join("bin/distrod"))?;
+    if new_version == CURRENT_VERSION {
+        fs::remove_dir_all(&staging_dir)?;
+        bail!(
+            "The downloaded Distrod is the same version as the current one, {}. Aborting the update.",
+            CURRENT_VERSION
+        );
+    }
+    carry_over_user_files(root_dir, &staging_dir)
+        .with_context(|| "Failed to carry over the configuration and the aliases.")?;
+
+    exchange_paths(root_dir, &staging_dir)
+        .with_context(|| format!("Failed to replace {:?} with {:?}.", root_dir, &staging_dir))?;
+    // The staging directory has the old version now.
+    fs::remove_dir_all(&staging_dir)
+        .with_context(|| format!("Failed to remove the old version at {:?}.", &staging_dir))?;
+    run_post_update(root_dir)?;
+    println!(
+        "Distrod has been updated: {} -> {}",
+        CURRENT_VERSION, new_version
+    );
+    println!("Restart the distro to use the new version.");
+    Ok(())
+}
+
+#[tokio::main]
+async fn fetch_latest_release() -> Result<Release> {
+    let client = http_client::build_http_client()?;
+    let response = client
+        .get(LATEST_RELEASE_API_URL)
+        .header("User-Agent", "distrod")
+        .header("Accept", "application/vnd.github.v3+json")
+        .send()
+        .await
+        .with_context(|| format!("Failed to request {}.", LATEST_RELEASE_API_URL))?
+        .error_for_status()?;
+    let body = response.text().await?;
+    serde_json::from_str(&body).with_context(|| "Failed to parse the release.")
+}
+
+fn find_asset<'a>(release: &'a Release, name: &str) -> Result<&'a ReleaseAsset> {
+    release
+        .assets
+        .iter()
+        .find(|asset| asset.name == name)
+        .ok_or_else(|| anyhow!("The release {} doesn't have {}.", &release.tag_name, name))
+}
+
+#[tokio::main]
+async fn fetch_text(url: &str) -> Result<String> {
+    let client = http_client::build_http_client()?;
+    let response = client
+        .get(url)
+        .header("User-Agent", "distrod")
+        .send()
+        .await
+        .with_context(|| format!("Failed to request {}.", url))?
+        .error_for_status()?;
+    Ok(response.text().await?)
+}
+
+/// Parse the output of `sha256sum`, such as "<64 hex digits>  opt_distrod.tar.gz".
+fn parse_sha256sum_output(output: &str) -> Result<String> {
+    let digest = output.split_whitespace().next().unwrap_or_default();
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid SHA-256 checksum: '{}'", output.trim());
+    }
+    Ok(digest.to_ascii_lowercase())
+}
+
+#[tokio::main]
+async fn download_asset(asset: &ReleaseAsset, out: &mut File) -> Result<()> {
+    log::info!("Downloading '{}'...", &asset.browser_download_url);
+    download_file_with_progress(&asset.browser_download_url, build_progress_bar, out).await?;
+    let downloaded_size = out.metadata()?.len();
+    if downloaded_size != asset.size {
+        bail!(
+            "The size of the downloaded file is {} bytes, but it should be {} bytes.",
+            downloaded_size,
+            asset.size
+        );
+    }
+    Ok(())
+}
+
+fn get_staging_dir(root_dir: &Path) -> PathBuf {
+    let mut staging_dir = root_dir.as_os_str().to_owned();
+    staging_dir.push(".update");
+    PathBuf::from(staging_dir)
+}
+
+fn unpack_tar_gz<R: std::io::Read>(tar_gz: R, dst: &Path) -> Result<()> {
+    fs::create_dir(dst).with_context(|| format!("Failed to create {:?}.", dst))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(tar_gz));
+    // distrod-exec is a setuid binary.
+    archive.set_preserve_permissions(true);
+    archive
+        .unpack(dst)
+        .with_context(|| format!("Failed to unpack the new version to {:?}.", dst))
+}
+
+fn get_distrod_version(distrod_bin: &Path) -> Result<String> {
+    let output = Command::new(distrod_bin)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("Failed to run {:?}.", distrod_bin))?;
+    if !output.status.success() {
+        bail!("{:?} --version failed. {:?}", distrod_bin, output.status);
+    }
+    parse_version_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse the output of `distrod --version`, such as "distrod 0.1.7".
+fn parse_version_output(output: &str) -> Result<String> {
+    output
+        .split_whitespace()
+        .nth(1)
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("Unexpected output of --version: '{}'", output.trim()))
+}
+
+fn is_newer_version(version: &str, than: &str) -> Result<bool> {
+    Ok(parse_version(version)? > parse_version(than)?)
+}
+
+fn parse_version(version: &str) -> Result<Vec<u64>> {
+    version
+        .split('.')
+        .map(|n| n.parse::<u64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid version '{}'.", version))
+}
+
+/// Copy the files made by users from the current installation to the new one.
+/// The files in conf/ of the current installation take precedence over the new ones, as
+/// install.sh does, and the aliases are linked to the new distrod binary.
+fn carry_over_user_files(root_dir: &Path, staging_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(root_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let (src, dst) = (entry.path(), staging_dir.join(&name));
+        if name == ALIAS_DIR_NAME {
+            relink_aliases(&src, &dst, &staging_dir.join("bin/distrod"))?;
+        } else if name == CONF_DIR_NAME {
+            copy_overwriting(&src, &dst)?;
+        } else if dst.symlink_metadata().is_err() {
+            // Such as the cache directory, which the new version doesn't have.
+            copy_overwriting(&src, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_overwriting(src: &Path, dst: &Path) -> Result<()> {
+    let metadata = src.symlink_metadata()?;
+    if metadata.is_dir() {
+        if dst.symlink_metadata().is_err() {
+            return TreeCopier::default().copy(src, dst);
+        }
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_overwriting(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        return Ok(());
+    }
+    if dst.symlink_metadata().is_ok() {
+        fs::remove_file(dst).with_context(|| format!("Failed to remove {:?}.", dst))?;
+    }
+    if metadata.file_type().is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dst)?;
+    } else {
+        fs::copy(src, dst).with_context(|| format!("Failed to copy {:?} to {:?}.", src, dst))?;
+    }
+    Ok(())
+}
+
+/// Recreate the alias tree, whose regular files are the hard links to the distrod binary.
+fn relink_aliases(src: &Path, dst: &Path, distrod_bin: &Path) -> Result<()> {
+    if !dst.exists() {
+        fs::create_dir(dst).with_context(|| format!("Failed to create {:?}.", dst))?;
+    }
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let (src, dst) = (entry.path(), dst.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if dst.symlink_metadata().is_ok() {
+            continue;
+        }
+        if file_type.is_dir() {
+            relink_aliases(&src, &dst, distrod_bin)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&src)?, &dst)?;
+        } else {
+            fs::hard_link(distrod_bin, &dst)
+                .with_context(|| format!("Failed to link the alias {:?}.", &dst))?;
+        }
+    }
+    Ok(())
+}
+
+fn exchange_paths(a: &Path, b: &Path) -> Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let res = unsafe {
+        nix::libc::syscall(
+            nix::libc::SYS_renameat2,
+            nix::libc::AT_FDCWD,
+            a.as_ptr(),
+            nix::libc::AT_FDCWD,
+            b.as_ptr(),
+            RENAME_EXCHANGE,
+        )
+    };
+    nix::errno::Errno::result(res)?;
+    Ok(())
+}
+
+fn run_post_update(root_dir: &Path) -> Result<()> {
+    let post_update = root_dir.join("misc/distrod-post-update");
+    if !post_update.exists() {
+        return Ok(());
+    }
+    log::info!("Running the post-update actions...");
+    let status = Command::new(&post_update)
+        .status()
+        .with_context(|| format!("Failed to run {:?}.", &post_update))?;
+    if !status.success() {
+        bail!("{:?} failed. {:?}", &post_update, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_self_update {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        assert!(is_newer_version("0.1.8", "0.1.7").unwrap());
+        assert!(is_newer_version("0.10.0", "0.9.1").unwrap());
+        assert!(!is_newer_version("0.1.7", "0.1.7").unwrap());
+        assert!(!is_newer_version("0.1.6", "0.1.7").unwrap());
+        assert!(is_newer_version("v0.1.8", "0.1.7").is_err());
+        assert_eq!("0.1.7", parse_version_output("distrod 0.1.7\n").unwrap());
+        assert!(parse_version_output("").is_err());
+    }
+
+    #[test]
+    fn test_parse_sha256sum_output() {
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(
+            digest,
+            parse_sha256sum_output(&format!("{}  opt_distrod.tar.gz\n", digest)).unwrap()
+        );
+        assert_eq!(
+            digest,
+            parse_sha256sum_output(&digest.to_uppercase()).unwrap()
+        );
+        assert!(parse_sha256sum_output("").is_err());
+        assert!(parse_sha256sum_output(&digest[1..]).is_err());
+        assert!(parse_sha256sum_output("<!DOCTYPE html>").is_err());
+    }
+
+    #[test]
+    fn test_carry_over_user_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (root, staging) = (
+            dir.path().join("distrod"),
+            dir.path().join("distrod.update"),
+        );
+        for d in &["bin", "conf", "alias/usr/bin", "alias/.names", "cache"] {
+            fs::create_dir_all(root.join(d)).unwrap();
+        }
+        for d in &["bin", "conf", "alias"] {
+            fs::create_dir_all(staging.join(d)).unwrap();
+        }
+        fs::write(root.join("bin/distrod"), "old").unwrap();
+        fs::write(staging.join("bin/distrod"), "new").unwrap();
+        fs::write(root.join("conf/distrod.toml"), "user").unwrap();
+        fs::write(staging.join("conf/distrod.toml"), "default").unwrap();
+        fs::write(staging.join("conf/new.toml"), "new").unwrap();
+        fs::write(root.join("cache/list"), "cache").unwrap();
+        fs::hard_link(root.join("bin/distrod"), root.join("alias/usr/bin/code")).unwrap();
+        std::os::unix::fs::symlink(
+            "/opt/distrod/alias/usr/bin/code",
+            root.join("alias/.names/code"),
+        )
+        .unwrap();
+
+        carry_over_user_files(&root, &staging).unwrap();
+
+        let read = |path: &str| fs::read_to_string(staging.join(path)).unwrap();
+        assert_eq!("new", read("bin/distrod"));
+        assert_eq!("user", read("conf/distrod.toml"));
+        assert_eq!("new", read("conf/new.toml"));
+        assert_eq!("cache", read("cache/list"));
+        // The alias is linked to the new binary.
+        assert_eq!("new", read("alias/usr/bin/code"));
+        assert_eq!(
+            Path::new("/opt/distrod/alias/usr/bin/code"),
+            fs::read_link(staging.join("alias/.names/code")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_exchange_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(a.join("file"), "a").unwrap();
+        fs::write(b.join("file"), "b").unwrap();
+        exchange_paths(&a, &b).unwrap();
+        assert_eq!("b", fs::read_to_string(a.join("file")).unwrap());
+        assert_eq!("a", fs::read_to_string(b.join("file")).unwrap());
+    }
+}
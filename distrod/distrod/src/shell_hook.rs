@@ -1,36 +1,335 @@
 use std::{
     collections::HashSet,
     io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 use crate::passwd::{Passwd, PasswdFile};
 use libs::command_alias::CommandAlias;
 
-pub fn enable_default_shell_hook() -> Result<()> {
+/// Hook the login shells of the users so that they start under systemd.
+/// If `target_shell` is given, only the users whose login shell is it are hooked.
+/// If `dry_run` is true, the users to be hooked are only logged.
+pub fn enable_default_shell_hook(target_shell: Option<&Path>, dry_run: bool) -> Result<()> {
+    if let Some(target_shell) = target_shell {
+        ensure_executable(target_shell)?;
+    }
+    if dry_run {
+        return log_shell_hooks_to_enable(target_shell);
+    }
     let mut shells = HashSet::new();
+    let mut source_shells = HashSet::new();
+    let mut is_backed_up = false;
     let mut passwd_file = PasswdFile::open("/etc/passwd")?;
     passwd_file.update(&mut |passwd| {
         if CommandAlias::is_alias(passwd.shell) {
             return Ok(None);
         }
+        if let Some(target_shell) = target_shell {
+            if !is_same_shell(Path::new(passwd.shell), target_shell) {
+                return Ok(None);
+            }
+        }
+        if !is_backed_up {
+            back_up_file(Path::new("/etc/passwd"))?;
+            is_backed_up = true;
+        }
         let alias = CommandAlias::open_from_source(passwd.shell, true)?
             .expect("an alias should be created.");
+        source_shells.insert(PathBuf::from(passwd.shell));
         let mut new_passwd = Passwd::from_view(passwd);
         let shell = alias.get_link_path().to_string_lossy().to_string();
         shells.insert(shell.clone());
         new_passwd.shell = shell;
         Ok(Some(new_passwd))
     })?;
+    if let (Some(target_shell), true) = (target_shell, source_shells.is_empty()) {
+        log::warn!("No user has {:?} as the login shell.", target_shell);
+    }
     if let Err(e) = register_shells_to_system(shells) {
         log::warn!("Failed to register shells to system. {}", e);
     }
+    for shell in source_shells
+        .iter()
+        .map(PathBuf::as_path)
+        .chain(target_shell)
+    {
+        if let Err(e) = install_shell_profile_hook(Path::new("/"), shell) {
+            log::warn!(
+                "Failed to install the profile hook for {:?}. {:?}",
+                shell,
+                e
+            );
+        }
+    }
     Ok(())
 }
 
-pub fn disable_default_shell_hook() -> Result<()> {
+fn log_shell_hooks_to_enable(target_shell: Option<&Path>) -> Result<()> {
     let mut passwd_file = PasswdFile::open("/etc/passwd")?;
+    let mut source_shells = HashSet::new();
+    for passwd in passwd_file.entries().flatten() {
+        if CommandAlias::is_alias(passwd.shell)
+            || matches!(target_shell, Some(target_shell) if !is_same_shell(Path::new(passwd.shell), target_shell))
+        {
+            continue;
+        }
+        log::info!(
+            "Would change the login shell of {} from {} to its alias by Distrod.",
+            passwd.name,
+            passwd.shell
+        );
+        source_shells.insert(PathBuf::from(passwd.shell));
+    }
+    for shell in source_shells
+        .iter()
+        .map(PathBuf::as_path)
+        .chain(target_shell)
+    {
+        if ShellKind::of(shell) != ShellKind::Posix {
+            log::info!("Would install the profile hook for {:?}.", shell);
+        }
+    }
+    Ok(())
+}
+
+fn is_same_shell(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+fn ensure_executable(shell: &Path) -> Result<()> {
+    let metadata =
+        std::fs::metadata(shell).with_context(|| format!("{:?} does not exist.", shell))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        bail!("{:?} is not an executable file.", shell);
+    }
+    Ok(())
+}
+
+/// The script in /etc/profile.d which loads the WSL environment variables of the session.
+const PROFILE_DOT_D_SCRIPT: &str = "/etc/profile.d/distrod-user-wsl-envs.sh";
+const ZSH_PROFILE_HOOK_BEGIN: &str = "# BEGIN distrod-user-wsl-envs";
+const ZSH_PROFILE_HOOK_END: &str = "# END distrod-user-wsl-envs";
+const FISH_PROFILE_HOOK_NAME: &str = "distrod-user-wsl-envs.fish";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShellKind {
+    Zsh,
+    Fish,
+    /// The shells which read /etc/profile.d as a login shell, such as bash and dash.
+    Posix,
+}
+
+impl ShellKind {
+    fn of(shell: &Path) -> ShellKind {
+        match shell.file_name().and_then(|name| name.to_str()) {
+            Some("zsh") => ShellKind::Zsh,
+            Some("fish") => ShellKind::Fish,
+            _ => ShellKind::Posix,
+        }
+    }
+}
+
+/// Make the login shell load the WSL environment variables of the session as the
+/// sh-compatible shells do through /etc/profile.d. It's no-op if the hook is already installed.
+fn install_shell_profile_hook(rootfs: &Path, shell: &Path) -> Result<()> {
+    match ShellKind::of(shell) {
+        ShellKind::Zsh => install_zsh_profile_hook(rootfs),
+        ShellKind::Fish => install_fish_profile_hook(rootfs),
+        ShellKind::Posix => Ok(()),
+    }
+}
+
+fn install_zsh_profile_hook(rootfs: &Path) -> Result<()> {
+    // Debian and Arch read /etc/zsh/zprofile, while Fedora and openSUSE read /etc/zprofile.
+    let zprofile = if rootfs.join("etc/zsh").is_dir() {
+        rootfs.join("etc/zsh/zprofile")
+    } else {
+        rootfs.join("etc/zprofile")
+    };
+    let cont = read_to_string_if_exists(&zprofile)?.unwrap_or_default();
+    if cont.lines().any(|line| line == ZSH_PROFILE_HOOK_BEGIN) {
+        return Ok(());
+    }
+    back_up_file(&zprofile)?;
+    // The hook always starts with a newline so that removing it restores the original bytes.
+    let hook = format!(
+        "\n{}\n\
+         if [ -e {script} ]; then\n\
+         \x20   emulate sh -c '. {script}'\n\
+         fi\n\
+         {}\n",
+        ZSH_PROFILE_HOOK_BEGIN,
+        ZSH_PROFILE_HOOK_END,
+        script = PROFILE_DOT_D_SCRIPT
+    );
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&zprofile)
+        .with_context(|| format!("Failed to open {:?}.", &zprofile))?;
+    file.write_all(hook.as_bytes())
+        .with_context(|| format!("Failed to write to {:?}.", &zprofile))?;
+    Ok(())
+}
+
+fn install_fish_profile_hook(rootfs: &Path) -> Result<()> {
+    let conf_d = rootfs.join("etc/fish/conf.d");
+    std::fs::create_dir_all(&conf_d).with_context(|| format!("Failed to create {:?}.", &conf_d))?;
+    // Fish can't source the sh script, so let sh evaluate it and import the result.
+    let hook = format!(
+        "if status is-login; and test -e {script}\n\
+         \x20   for kv in (sh -c '. {script}; env -0' | string split0)\n\
+         \x20       set -l kv (string split -m 1 = -- $kv)\n\
+         \x20       if test \"$kv[1]\" = PATH\n\
+         \x20           set -gx PATH (string split : -- $kv[2])\n\
+         \x20       else if not set -q $kv[1]\n\
+         \x20           set -gx $kv[1] $kv[2]\n\
+         \x20       end\n\
+         \x20   end\n\
+         end\n",
+        script = PROFILE_DOT_D_SCRIPT
+    );
+    let hook_path = conf_d.join(FISH_PROFILE_HOOK_NAME);
+    std::fs::write(&hook_path, hook)
+        .with_context(|| format!("Failed to write {:?}.", &hook_path))?;
+    Ok(())
+}
+
+/// Remove the profile hooks of zsh and fish, leaving the rest of the files as they were.
+fn uninstall_shell_profile_hooks(rootfs: &Path) -> Result<()> {
+    for zprofile in &["etc/zsh/zprofile", "etc/zprofile"] {
+        let zprofile = rootfs.join(zprofile);
+        let cont = match read_to_string_if_exists(&zprofile)? {
+            Some(cont) => cont,
+            None => continue,
+        };
+        let new_cont = match remove_zsh_profile_hook(&cont) {
+            Some(new_cont) => new_cont,
+            None => continue,
+        };
+        if new_cont.is_empty() {
+            // The file was created by the hook.
+            std::fs::remove_file(&zprofile)
+                .with_context(|| format!("Failed to remove {:?}.", &zprofile))?;
+        } else {
+            std::fs::write(&zprofile, new_cont)
+                .with_context(|| format!("Failed to write to {:?}.", &zprofile))?;
+        }
+    }
+    let fish_hook = rootfs.join("etc/fish/conf.d").join(FISH_PROFILE_HOOK_NAME);
+    if fish_hook.exists() {
+        std::fs::remove_file(&fish_hook)
+            .with_context(|| format!("Failed to remove {:?}.", &fish_hook))?;
+    }
+    Ok(())
+}
+
+/// Remove the lines between the markers of the hook, and the newline before them.
+/// Returns None if the hook is not found.
+fn remove_zsh_profile_hook(cont: &str) -> Option<String> {
+    let begin = cont.find(&format!("{}\n", ZSH_PROFILE_HOOK_BEGIN))?;
+    let end_marker = format!("{}\n", ZSH_PROFILE_HOOK_END);
+    let end = begin + cont[begin..].find(&end_marker)? + end_marker.len();
+    let begin = if cont[..begin].ends_with('\n') {
+        begin - 1
+    } else {
+        begin
+    };
+    Some(format!("{}{}", &cont[..begin], &cont[end..]))
+}
+
+fn read_to_string_if_exists(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(cont) => Ok(Some(cont)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {:?}.", path)),
+    }
+}
+
+/// Copy the file to `<path>.distrod.orig.bak` before Distrod modifies it for the first time,
+/// or to `<path>.distrod.<timestamp>.bak` after that. The first backup is never overwritten so
+/// that the file before Distrod can always be restored, and only the latest of the timestamped
+/// ones is kept so that enabling Distrod repeatedly doesn't pile them up.
+fn back_up_file(path: &Path) -> Result<Option<PathBuf>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut original_backup = path.as_os_str().to_owned();
+    original_backup.push(".distrod.orig.bak");
+    let original_backup = PathBuf::from(original_backup);
+    if original_backup.symlink_metadata().is_err() {
+        std::fs::copy(path, &original_backup)
+            .with_context(|| format!("Failed to back up {:?} to {:?}.", path, &original_backup))?;
+        log::info!("Backed up {:?} to {:?}.", path, &original_backup);
+        return Ok(Some(original_backup));
+    }
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(
+        ".distrod.{}.bak",
+        chrono::Local::now().format("%Y%m%d%H%M%S")
+    ));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)
+        .with_context(|| format!("Failed to back up {:?} to {:?}.", path, &backup))?;
+    log::info!("Backed up {:?} to {:?}.", path, &backup);
+    if let Err(e) = remove_old_backups(path, &backup) {
+        log::warn!("Failed to remove the old backups of {:?}. {:?}", path, e);
+    }
+    Ok(Some(backup))
+}
+
+/// Remove the timestamped backups of the file made by `back_up_file` except the latest one.
+fn remove_old_backups(path: &Path, latest: &Path) -> Result<()> {
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(file_name)) => (dir, file_name.to_string_lossy()),
+        _ => return Ok(()),
+    };
+    let prefix = format!("{}.distrod.", file_name);
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {:?}.", dir))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let timestamp = match name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|rest| rest.strip_suffix(".bak"))
+        {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+        let is_backup = timestamp.len() == 14 && timestamp.chars().all(|c| c.is_ascii_digit());
+        if !is_backup || entry.path() == latest {
+            continue;
+        }
+        std::fs::remove_file(entry.path())
+            .with_context(|| format!("Failed to remove {:?}.", entry.path()))?;
+        log::info!("Removed the old backup {:?}.", entry.path());
+    }
+    Ok(())
+}
+
+pub fn disable_default_shell_hook(dry_run: bool) -> Result<()> {
+    let mut passwd_file = PasswdFile::open("/etc/passwd")?;
+    if dry_run {
+        for passwd in passwd_file.entries().flatten() {
+            if CommandAlias::is_alias(passwd.shell) {
+                let alias = CommandAlias::open_from_link(passwd.shell)?;
+                log::info!(
+                    "Would restore the login shell of {} to {:?}.",
+                    passwd.name,
+                    alias.get_source_path()
+                );
+            }
+        }
+        log::info!("Would remove the profile hooks of zsh and fish if any.");
+        return Ok(());
+    }
     passwd_file.update(&mut |passwd| {
         if !CommandAlias::is_alias(passwd.shell) {
             return Ok(None);
@@ -40,6 +339,49 @@ pub fn disable_default_shell_hook() -> Result<()> {
         new_passwd.shell = alias.get_source_path().to_string_lossy().to_string();
         Ok(Some(new_passwd))
     })?;
+    uninstall_shell_profile_hooks(Path::new("/"))
+        .with_context(|| "Failed to remove the profile hooks of the shells.")?;
+    Ok(())
+}
+
+/// Set the login shell of the user. If the current shell is hooked by Distrod, the new shell
+/// is hooked as well.
+pub fn set_login_shell(user_name: &str, shell: &Path) -> Result<()> {
+    ensure_executable(shell)?;
+    let shell = shell.to_string_lossy().to_string();
+
+    let mut passwd_file = PasswdFile::open("/etc/passwd")?;
+    if passwd_file.get_ent_by_name(user_name)?.is_none() {
+        bail!("User '{}' does not exist.", user_name);
+    }
+    let mut shells = HashSet::new();
+    shells.insert(shell.clone());
+    passwd_file.update(&mut |passwd| {
+        if passwd.name != user_name {
+            return Ok(None);
+        }
+        let is_hooked = CommandAlias::is_alias(passwd.shell);
+        let mut new_passwd = Passwd::from_view(passwd);
+        new_passwd.shell = if is_hooked {
+            let alias =
+                CommandAlias::open_from_source(&shell, true)?.expect("an alias should be created.");
+            alias.get_link_path().to_string_lossy().to_string()
+        } else {
+            shell.clone()
+        };
+        shells.insert(new_passwd.shell.clone());
+        Ok(Some(new_passwd))
+    })?;
+    if let Err(e) = register_shells_to_system(shells) {
+        log::warn!("Failed to register shells to system. {}", e);
+    }
+    if let Err(e) = install_shell_profile_hook(Path::new("/"), Path::new(&shell)) {
+        log::warn!(
+            "Failed to install the profile hook for {:?}. {:?}",
+            &shell,
+            e
+        );
+    }
     Ok(())
 }
 
@@ -77,3 +419,170 @@ fn register_shells_to_system(mut shell_paths: HashSet<String>) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test_shell_hook {
+    use super::*;
+
+    #[test]
+    fn test_zsh_profile_hook() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc/zsh")).unwrap();
+        let zprofile = rootfs.path().join("etc/zsh/zprofile");
+        std::fs::write(&zprofile, "# existing\nemulate sh -c '. /etc/profile'").unwrap();
+
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+        let cont = std::fs::read_to_string(&zprofile).unwrap();
+        assert!(cont.starts_with("# existing\nemulate sh -c '. /etc/profile'\n"));
+        assert!(cont.contains(&format!("    emulate sh -c '. {}'\n", PROFILE_DOT_D_SCRIPT)));
+
+        // Installing it again doesn't duplicate the hook.
+        install_shell_profile_hook(rootfs.path(), Path::new("/bin/zsh")).unwrap();
+        assert_eq!(cont, std::fs::read_to_string(&zprofile).unwrap());
+        assert!(!rootfs.path().join("etc/zprofile").exists());
+    }
+
+    #[test]
+    fn test_zsh_profile_hook_without_etc_zsh() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc")).unwrap();
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+        let cont = std::fs::read_to_string(rootfs.path().join("etc/zprofile")).unwrap();
+        assert_eq!(1, cont.matches(ZSH_PROFILE_HOOK_BEGIN).count());
+    }
+
+    #[test]
+    fn test_fish_profile_hook() {
+        let rootfs = tempfile::tempdir().unwrap();
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/fish")).unwrap();
+        let hook_path = rootfs
+            .path()
+            .join("etc/fish/conf.d/distrod-user-wsl-envs.fish");
+        let cont = std::fs::read_to_string(&hook_path).unwrap();
+        assert!(cont.contains(PROFILE_DOT_D_SCRIPT));
+
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/fish")).unwrap();
+        assert_eq!(cont, std::fs::read_to_string(&hook_path).unwrap());
+        assert_eq!(
+            1,
+            std::fs::read_dir(rootfs.path().join("etc/fish/conf.d"))
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_enable_and_disable_restore_original_bytes() {
+        let originals = [
+            "# existing\nemulate sh -c '. /etc/profile'\n",
+            "# without the last newline",
+            "\n\n# with blank lines\n\n",
+        ];
+        for original in &originals {
+            let rootfs = tempfile::tempdir().unwrap();
+            std::fs::create_dir_all(rootfs.path().join("etc/zsh")).unwrap();
+            let zprofile = rootfs.path().join("etc/zsh/zprofile");
+            std::fs::write(&zprofile, original).unwrap();
+
+            install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+            install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/fish")).unwrap();
+            assert_ne!(*original, std::fs::read_to_string(&zprofile).unwrap());
+            uninstall_shell_profile_hooks(rootfs.path()).unwrap();
+
+            assert_eq!(original.as_bytes(), &std::fs::read(&zprofile).unwrap()[..]);
+            assert!(!rootfs
+                .path()
+                .join("etc/fish/conf.d")
+                .join(FISH_PROFILE_HOOK_NAME)
+                .exists());
+        }
+    }
+
+    #[test]
+    fn test_disable_removes_created_zprofile() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc")).unwrap();
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+        assert!(rootfs.path().join("etc/zprofile").exists());
+        uninstall_shell_profile_hooks(rootfs.path()).unwrap();
+        assert!(!rootfs.path().join("etc/zprofile").exists());
+    }
+
+    #[test]
+    fn test_zsh_profile_is_backed_up() {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc/zsh")).unwrap();
+        let original = "# existing\n";
+        std::fs::write(rootfs.path().join("etc/zsh/zprofile"), original).unwrap();
+        install_shell_profile_hook(rootfs.path(), Path::new("/usr/bin/zsh")).unwrap();
+
+        let backups: Vec<_> = std::fs::read_dir(rootfs.path().join("etc/zsh"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".bak"))
+            .collect();
+        assert_eq!(1, backups.len());
+        assert!(backups[0]
+            .to_string_lossy()
+            .contains("/etc/zsh/zprofile.distrod."));
+        assert_eq!(original, std::fs::read_to_string(&backups[0]).unwrap());
+    }
+
+    #[test]
+    fn test_only_original_and_latest_backups_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("zprofile");
+        std::fs::write(&path, "# original\n").unwrap();
+        let original_backup = back_up_file(&path).unwrap().unwrap();
+        assert_eq!(
+            dir.path().join("zprofile.distrod.orig.bak"),
+            original_backup
+        );
+
+        std::fs::write(&path, "# current\n").unwrap();
+        let old_backup = dir.path().join("zprofile.distrod.20200101000000.bak");
+        std::fs::write(&old_backup, "# old\n").unwrap();
+        let others = [
+            "zprofile.distrod.bak",
+            "zprofile.distrod.2020.bak",
+            "zshrc.distrod.20200101000000.bak",
+        ];
+        for other in &others {
+            std::fs::write(dir.path().join(other), "").unwrap();
+        }
+
+        let backup = back_up_file(&path).unwrap().unwrap();
+        assert_ne!(original_backup, backup);
+        assert_eq!("# current\n", std::fs::read_to_string(&backup).unwrap());
+        assert!(!old_backup.exists());
+        for other in &others {
+            assert!(dir.path().join(other).exists(), "{}", other);
+        }
+        assert_eq!(
+            "# original\n",
+            std::fs::read_to_string(&original_backup).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_posix_shells_need_no_profile_hook() {
+        let rootfs = tempfile::tempdir().unwrap();
+        for shell in &["/bin/bash", "/bin/sh", "/usr/bin/dash"] {
+            install_shell_profile_hook(rootfs.path(), Path::new(shell)).unwrap();
+        }
+        assert_eq!(0, std::fs::read_dir(rootfs.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_is_same_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell = dir.path().join("zsh");
+        std::fs::write(&shell, "").unwrap();
+        let link = dir.path().join("zsh-link");
+        std::os::unix::fs::symlink(&shell, &link).unwrap();
+        assert!(is_same_shell(&shell, &shell));
+        assert!(is_same_shell(&link, &shell));
+        assert!(!is_same_shell(&shell, &dir.path().join("bash")));
+    }
+}
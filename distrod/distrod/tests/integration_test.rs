@@ -1,4 +1,10 @@
-use std::{fs::File, io::BufWriter, path::PathBuf, process::Command, time::Duration};
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
 
 use anyhow::{Context, Result};
 use libs::{
@@ -15,7 +21,6 @@ static DISTROD_SETUP: Lazy<DistrodSetup> = Lazy::new(|| {
     let distrod_setup = DistrodSetup::new(&TestEnvironment::distro_in_testing());
     distrod_setup.create();
     distrod_setup.start();
-    std::thread::sleep(Duration::from_secs(5));
     distrod_setup
 });
 
@@ -27,6 +32,165 @@ fn test_exec_cmd() {
     assert_eq!("foo\n", String::from_utf8_lossy(&output.stdout));
 }
 
+#[test]
+fn test_exec_umask() {
+    let mut touch = DISTROD_SETUP.new_command();
+    touch.args(&[
+        "exec",
+        "--umask",
+        "027",
+        "--",
+        "sh",
+        "-c",
+        "rm -f /tmp/distrod_umask_test && touch /tmp/distrod_umask_test && stat -c %a /tmp/distrod_umask_test",
+    ]);
+    let output = touch.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!("640\n", String::from_utf8_lossy(&output.stdout));
+
+    let mut invalid = DISTROD_SETUP.new_command();
+    invalid.args(&["exec", "--umask", "888", "--", "true"]);
+    let output = invalid.output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_exec_cwd_create() {
+    let mut stat = DISTROD_SETUP.new_command();
+    stat.args(&[
+        "exec",
+        "--uid",
+        "4321",
+        "--cwd",
+        "/tmp/distrod_cwd_create_test/nested",
+        "--cwd-create",
+        "--",
+        "stat",
+        "-c",
+        "%n %u:%g",
+        ".",
+        "..",
+    ]);
+    let output = stat.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        ". 4321:4321\n.. 4321:4321\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let mut pwd = DISTROD_SETUP.new_command();
+    pwd.args(&[
+        "exec",
+        "--cwd",
+        "/usr/distrod_cwd_create_test",
+        "--cwd-create",
+        "pwd",
+    ]);
+    let output = pwd.output().unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_exec_no_systemd() {
+    let is_in_container = |no_systemd: bool| -> bool {
+        let mut grep = DISTROD_SETUP.new_command();
+        grep.arg("exec");
+        if no_systemd {
+            grep.arg("--no-systemd");
+        }
+        grep.args(&[
+            "--",
+            "grep",
+            "-q",
+            " /mnt/distrod_root ",
+            "/proc/self/mountinfo",
+        ]);
+        grep.status().unwrap().success()
+    };
+    assert!(is_in_container(false));
+    assert!(!is_in_container(true));
+}
+
+#[test]
+fn test_adduser() {
+    let mut adduser = DISTROD_SETUP.new_command();
+    adduser.args(&["adduser", "--shell", "/bin/sh", "distrod_test_user"]);
+    let output = adduser.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+    let uid = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    let mut id = DISTROD_SETUP.new_command();
+    id.args(&["exec", "--", "id", "-u", "distrod_test_user"]);
+    let output = id.output().unwrap();
+    assert_eq!(uid, String::from_utf8_lossy(&output.stdout).trim());
+
+    // The same user can't be added twice.
+    let mut adduser = DISTROD_SETUP.new_command();
+    adduser.args(&["adduser", "distrod_test_user"]);
+    assert!(!adduser.status().unwrap().success());
+}
+
+#[test]
+fn test_exec_with_supplementary_groups() {
+    let mut setup = DISTROD_SETUP.new_command();
+    setup.args(&[
+        "exec",
+        "--",
+        "sh",
+        "-c",
+        "groupadd -f distrod_test_group1 && groupadd -f distrod_test_group2 && \
+         (id distrod_groups_test > /dev/null 2>&1 || \
+          useradd -G distrod_test_group1,distrod_test_group2 distrod_groups_test)",
+    ]);
+    let output = setup.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+
+    let mut id = DISTROD_SETUP.new_command();
+    id.args(&["exec", "--user", "distrod_groups_test", "--", "id", "-Gn"]);
+    let output = id.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let groups: Vec<_> = stdout.split_whitespace().collect();
+    assert_eq!(
+        vec![
+            "distrod_groups_test",
+            "distrod_test_group1",
+            "distrod_test_group2"
+        ],
+        groups
+    );
+}
+
+#[test]
+fn test_exec_login() {
+    let mut echo = DISTROD_SETUP.new_command();
+    echo.env_remove("WSL_DISTRO_NAME");
+    echo.args(&["exec", "--login", "--", "sh", "-c", "echo $WSL_DISTRO_NAME; pwd"]);
+    let output = echo.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut lines = output.lines();
+    // WSL_DISTRO_NAME is set by the profile script of Distrod.
+    assert!(!lines.next().unwrap().is_empty());
+    assert_eq!(Some("/root"), lines.next());
+
+    let mut echo = DISTROD_SETUP.new_command();
+    echo.env("DISTROD_TEST_ENV", "preserved");
+    echo.args(&[
+        "exec",
+        "--login",
+        "--preserve-env",
+        "--",
+        "sh",
+        "-c",
+        "echo $DISTROD_TEST_ENV",
+    ]);
+    let output = echo.output().unwrap();
+    assert_eq!("preserved\n", String::from_utf8_lossy(&output.stdout));
+}
+
 #[test]
 fn test_init_is_sytemd() {
     let mut cat = DISTROD_SETUP.new_command();
@@ -35,6 +199,25 @@ fn test_init_is_sytemd() {
     assert!(String::from_utf8_lossy(&output.stdout).contains("(systemd)"));
 }
 
+#[test]
+fn test_cgroup_hierarchy_is_mounted() {
+    let host_fstype = match nix::sys::statfs::statfs("/sys/fs/cgroup") {
+        Ok(stat) => stat.filesystem_type(),
+        // The kernel doesn't support cgroups, or they are not mounted on the host.
+        Err(_) => return,
+    };
+    let mut stat = DISTROD_SETUP.new_command();
+    stat.args(&["exec", "--", "stat", "-fc", "%T", "/sys/fs/cgroup"]);
+    let output = stat.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let expected = if host_fstype == nix::sys::statfs::CGROUP2_SUPER_MAGIC {
+        "cgroup2fs\n"
+    } else {
+        "tmpfs\n"
+    };
+    assert_eq!(expected, String::from_utf8_lossy(&output.stdout));
+}
+
 #[test]
 fn test_no_systemd_unit_is_failing() {
     let query_systemctl = || -> std::process::Output {
@@ -73,6 +256,32 @@ fn test_no_systemd_unit_is_failing() {
     assert!(String::from_utf8_lossy(&output.stdout).contains("State: running"));
 }
 
+#[test]
+fn test_newer_incompatible_units_are_not_running() {
+    for unit in &["systemd-oomd.service", "systemd-pstore.service"] {
+        let mut systemctl = DISTROD_SETUP.new_command();
+        systemctl.args(&["exec", "--", "systemctl", "is-enabled", unit]);
+        let output = systemctl.output().unwrap();
+        // is-enabled fails if the unit is masked or not installed.
+        assert!(
+            !output.status.success(),
+            "{} is {}",
+            unit,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+
+    let mut systemctl = DISTROD_SETUP.new_command();
+    systemctl.args(&["exec", "--", "systemctl", "--failed", "--no-legend", "--plain"]);
+    let output = systemctl.output().unwrap();
+    let failed_units = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        !failed_units.contains("systemd-oomd"),
+        "failed units: {}",
+        failed_units
+    );
+}
+
 fn show_debug_systemd_info() {
     let inner = || -> Result<()> {
         let mut systemctl = DISTROD_SETUP.new_command();
@@ -341,6 +550,26 @@ fn test_wslg_socket_is_available() {
     assert!(result_old.success() || result_new.success());
 }
 
+#[test]
+fn test_wslg_pulse_socket_is_reachable() {
+    if !Path::new("/mnt/wslg/PulseServer").exists() {
+        // WSLg is not available on this WSL.
+        return;
+    }
+
+    let mut test_socket = DISTROD_SETUP.new_command();
+    test_socket.args(&["exec", "--", "test", "-S", "/mnt/wslg/PulseServer"]);
+    assert!(test_socket.status().unwrap().success());
+
+    let mut show_environment = DISTROD_SETUP.new_command();
+    show_environment.args(&["exec", "--", "systemctl", "show-environment"]);
+    let output = show_environment.output().unwrap();
+    let envs = String::from_utf8_lossy(&output.stdout);
+    assert!(envs
+        .lines()
+        .any(|line| line.starts_with("PULSE_SERVER=") && line.contains("/mnt/wslg/PulseServer")));
+}
+
 #[test]
 fn test_profile_initializes_additional_wsl_envs() {
     let mut echo_distro_name = DISTROD_SETUP.new_command();
@@ -418,6 +647,192 @@ fn test_distrod_is_in_path() {
     assert!(output.contains("/opt/distrod/bin"));
 }
 
+#[test]
+fn test_images_resolves_rootfs_url() {
+    let distro_name = TestEnvironment::distro_in_testing();
+    let mut images = DISTROD_SETUP.new_command();
+    images.args(&["images", "--distro", &distro_name, "--json"]);
+    let output = images.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let versions: Vec<String> = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(!versions.is_empty());
+
+    let mut images = DISTROD_SETUP.new_command();
+    images.args(&[
+        "images",
+        "--distro",
+        &distro_name,
+        "--version",
+        &versions[0],
+        "--json",
+    ]);
+    let output = images.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    let image: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(image["url"].as_str().unwrap().ends_with("rootfs.tar.xz"));
+}
+
+/// This test launches its own distro, so run it separately from the others by
+/// `cargo test -- --ignored test_adopt`.
+#[test]
+#[ignore]
+fn test_adopt() {
+    let rootfs = TestEnvironment::install_dir().with_file_name("distrod_adopted_rootfs");
+    std::fs::create_dir_all(&rootfs).unwrap();
+    let distro_name = TestEnvironment::distro_in_testing();
+    let image = setup_distro_image(&distro_name);
+    let status = Command::new("sudo")
+        .args(&["tar", "-xJf", image.to_str().unwrap(), "-C"])
+        .arg(&rootfs)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let distrod_setup = DistrodSetup::new(&distro_name);
+    let mut adopt = distrod_setup.new_command();
+    adopt.args(&["adopt", rootfs.to_str().unwrap()]);
+    assert!(adopt.status().unwrap().success());
+
+    let mut start = distrod_setup.new_command();
+    start.args(&[
+        "start",
+        "--rootfs",
+        rootfs.to_str().unwrap(),
+        "--wait",
+        "120",
+    ]);
+    assert!(start.status().unwrap().success());
+
+    let mut cat = distrod_setup.new_command();
+    cat.args(&["exec", "cat", "/proc/1/stat"]);
+    let output = cat.output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("(systemd)"));
+
+    // The running distro can't be adopted again.
+    let mut adopt = distrod_setup.new_command();
+    adopt.args(&["adopt", rootfs.to_str().unwrap()]);
+    assert!(!adopt.status().unwrap().success());
+
+    let mut stop = distrod_setup.new_command();
+    stop.arg("stop");
+    assert!(stop.status().unwrap().success());
+}
+
+/// This test launches its own distro, so run it separately from the others by
+/// `cargo test -- --ignored test_start_no_init`.
+#[test]
+#[ignore]
+fn test_start_no_init() {
+    let rootfs = TestEnvironment::install_dir().with_file_name("distrod_no_init_rootfs");
+    std::fs::create_dir_all(&rootfs).unwrap();
+    let image = setup_distro_image("alpine");
+    let status = Command::new("sudo")
+        .args(&["tar", "-xJf", image.to_str().unwrap(), "-C"])
+        .arg(&rootfs)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let distrod_setup = DistrodSetup::new("alpine");
+    let mut start = distrod_setup.new_command();
+    start.args(&["start", "--no-init", "--rootfs", rootfs.to_str().unwrap()]);
+    assert!(start.status().unwrap().success());
+
+    let mut echo = distrod_setup.new_command();
+    echo.args(&["exec", "--", "/bin/busybox", "echo", "foo"]);
+    let output = echo.output().unwrap();
+    eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!("foo\n", String::from_utf8_lossy(&output.stdout));
+
+    // The PID 1 is the minimal init of distrod itself, not the init system of the distro.
+    let mut cat = distrod_setup.new_command();
+    cat.args(&["exec", "--", "/bin/busybox", "cat", "/proc/1/comm"]);
+    let output = cat.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!("distrod\n", String::from_utf8_lossy(&output.stdout));
+
+    let mut stop = distrod_setup.new_command();
+    stop.arg("stop");
+    assert!(stop.status().unwrap().success());
+}
+
+/// This test restarts the distro shared by the other tests, so run it separately from them by
+/// `cargo test -- --ignored test_restart`.
+#[test]
+#[ignore]
+fn test_restart() {
+    let get_init_pid = || {
+        let output = Command::new("sudo")
+            .args(&["cat", "/run/distrod/distrod_run_info.json"])
+            .output()
+            .unwrap();
+        let run_info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        run_info["init_pid"].as_u64().unwrap()
+    };
+    let old_init_pid = {
+        // Make sure that the distro is running.
+        Lazy::force(&DISTROD_SETUP);
+        get_init_pid()
+    };
+
+    let mut restart = DISTROD_SETUP.new_command();
+    restart.arg("restart");
+    assert!(restart.status().unwrap().success());
+
+    let new_init_pid = get_init_pid();
+    assert_ne!(old_init_pid, new_init_pid);
+    let mut echo = DISTROD_SETUP.new_command();
+    echo.args(&["exec", "echo", "foo"]);
+    assert_eq!(
+        "foo\n",
+        String::from_utf8_lossy(&echo.output().unwrap().stdout)
+    );
+}
+
+/// This test launches its own distro, so run it separately from the others by
+/// `cargo test -- --ignored test_first_boot_scripts`.
+#[test]
+#[ignore]
+fn test_first_boot_scripts() {
+    let rootfs = TestEnvironment::install_dir().with_file_name("distrod_first_boot_rootfs");
+    std::fs::create_dir_all(&rootfs).unwrap();
+    let image = setup_distro_image("alpine");
+    let status = Command::new("sudo")
+        .args(&["tar", "-xJf", image.to_str().unwrap(), "-C"])
+        .arg(&rootfs)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let scripts_dir = rootfs.join("etc/distrod/first-boot.d");
+    let status = Command::new("sudo")
+        .args(&["sh", "-c"])
+        .arg(format!(
+            "mkdir -p {0} && printf '#!/bin/sh\necho ran >> /var/tmp/first-boot\n' > {0}/10-touch && chmod +x {0}/10-touch",
+            scripts_dir.to_str().unwrap()
+        ))
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let distrod_setup = DistrodSetup::new("alpine");
+    for _ in 0..2 {
+        let mut start = distrod_setup.new_command();
+        start.args(&["start", "--no-init", "--rootfs", rootfs.to_str().unwrap()]);
+        assert!(start.status().unwrap().success());
+        let mut stop = distrod_setup.new_command();
+        stop.arg("stop");
+        assert!(stop.status().unwrap().success());
+    }
+
+    // The script has run only on the first launch.
+    let output = Command::new("sudo")
+        .arg("cat")
+        .arg(rootfs.join("var/tmp/first-boot"))
+        .output()
+        .unwrap();
+    assert_eq!("ran\n", String::from_utf8_lossy(&output.stdout));
+}
+
 #[tokio::test]
 async fn test_distro_download_url_is_live() {
     let distro_image =
@@ -464,6 +879,8 @@ impl DistrodSetup {
             "start",
             "--rootfs",
             self.install_dir.as_path().to_str().unwrap(),
+            "--wait",
+            "120",
         ]);
         let exit_status = distrod.status().unwrap();
         assert!(exit_status.success());
@@ -0,0 +1,2 @@
+// The launcher shows the same version string as distrod.
+include!("../distrod/build.rs");
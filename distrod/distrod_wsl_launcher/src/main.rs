@@ -11,7 +11,7 @@ use libs::distrod_config;
 use libs::local_image::LocalDistroImage;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Cursor, Read};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use structopt::StructOpt;
@@ -20,16 +20,24 @@ use tempfile::TempDir;
 use xz2::read::XzDecoder;
 
 mod tar_helper;
+mod threaded_io;
 mod wsl;
 
 static DISTRO_NAME: &str = "Distrod";
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "distrod-install", rename_all = "kebab")]
+#[structopt(
+    name = "distrod-install",
+    version = env!("DISTROD_VERSION"),
+    rename_all = "kebab"
+)]
 pub struct Opts {
     /// Log level in the env_logger format. Simple levels: trace, debug, info(default), warn, error.
     #[structopt(short, long)]
     pub log_level: Option<String>,
+    /// Don't colorize the output. Setting the NO_COLOR environment variable does the same.
+    #[structopt(long)]
+    pub no_color: bool,
     #[structopt(short, long)]
     pub distro_name: Option<String>,
     #[structopt(subcommand)]
@@ -41,6 +49,9 @@ pub enum Subcommand {
     Install(InstallOpts),
     Run(RunOpts),
     Config(ConfigOpts),
+    Stop(StopOpts),
+    Shutdown(ShutdownOpts),
+    Uninstall(UninstallOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -48,6 +59,9 @@ pub enum Subcommand {
 pub struct InstallOpts {
     #[structopt(long)]
     root: bool,
+    /// Make the new distro the default distro of wsl.exe.
+    #[structopt(long)]
+    set_default: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -61,11 +75,36 @@ pub struct RunOpts {
 pub struct ConfigOpts {
     #[structopt(long)]
     default_user: Option<String>,
+    /// Make the distro the default distro of wsl.exe.
+    #[structopt(long)]
+    set_default: bool,
+}
+
+/// Terminate the distro by `wsl --terminate`.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct StopOpts {}
+
+/// Terminate all the WSL distros by `wsl --shutdown`, not only the Distrod distro.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ShutdownOpts {}
+
+/// Unregister the distro and remove its install directory.
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct UninstallOpts {
+    /// Don't ask for confirmation.
+    #[structopt(short, long)]
+    yes: bool,
+    /// Uninstall the distro even if it doesn't look like a Distrod distro.
+    #[structopt(long)]
+    force: bool,
 }
 
 fn main() {
     let opts = Opts::from_args();
-    init_logger("Distrod".to_owned(), opts.log_level.clone());
+    init_logger("Distrod".to_owned(), opts.log_level.clone(), opts.no_color);
 
     if let Err(err) = run(opts) {
         log::error!("{:?}", err);
@@ -89,15 +128,31 @@ fn run(opts: Opts) -> Result<()> {
         Some(Subcommand::Config(config_opts)) => {
             config_distro(&distro_name, config_opts)?;
         }
+        Some(Subcommand::Stop(_stop_opts)) => {
+            wsl::terminate_distribution(&distro_name)
+                .with_context(|| format!("Failed to stop {}.", &distro_name))?;
+            log::info!("{} has been stopped.", &distro_name);
+        }
+        Some(Subcommand::Shutdown(_shutdown_opts)) => {
+            wsl::shutdown().with_context(|| "Failed to shut down WSL.")?;
+            log::info!("WSL has been shut down.");
+        }
+        Some(Subcommand::Uninstall(uninstall_opts)) => {
+            uninstall_distro(&distro_name, uninstall_opts)?;
+        }
     }
     Ok(())
 }
 
 fn run_distro(distro_name: &str, opts: RunOpts) -> Result<()> {
     if !unsafe { wsl::is_distribution_registered(distro_name) } {
-        let install_opts = InstallOpts { root: false };
+        let install_opts = InstallOpts {
+            root: false,
+            set_default: false,
+        };
         return install_distro(distro_name, install_opts);
     }
+    ensure_wsl2_distro(distro_name)?;
 
     let mut command = wsl::WslCommand::new(opts.cmd.get(0), distro_name);
     if opts.cmd.len() > 1 {
@@ -121,13 +176,155 @@ fn config_distro(distro_name: &str, opts: ConfigOpts) -> Result<()> {
                 .with_context(|| "Failed to set the default user")?;
         }
     }
+    if opts.set_default {
+        wsl::set_default_distribution(distro_name)
+            .with_context(|| format!("Failed to make {} the default distro.", distro_name))?;
+    }
     log::info!("Configuration done.");
 
     Ok(())
 }
 
+fn uninstall_distro(distro_name: &str, opts: UninstallOpts) -> Result<()> {
+    if !unsafe { wsl::is_distribution_registered(distro_name) } {
+        bail!("{} is not registered.", distro_name);
+    }
+    if !opts.force {
+        match is_distrod_distro(distro_name) {
+            Ok(true) => {}
+            Ok(false) => bail!(
+                "{} doesn't look like a Distrod distro. Pass --force to uninstall it anyway.",
+                distro_name
+            ),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to check if {} is a Distrod distro. Pass --force to uninstall it anyway.",
+                        distro_name
+                    )
+                })
+            }
+        }
+    }
+    // Only the directory WSL has registered is removed, which is not always the one the
+    // installer created, for example when the distro has been moved.
+    let install_dir = wsl::get_distribution_base_path(distro_name)
+        .with_context(|| format!("Failed to get the directory of {}.", distro_name))?;
+    if !opts.yes {
+        let answer = prompt_string(
+            &format!(
+                "{} will be unregistered and all of its files will be deleted. Are you sure?",
+                distro_name
+            ),
+            "y/N",
+            Some("N"),
+        )?;
+        if !matches!(answer.to_lowercase().as_str(), "y" | "yes") {
+            log::info!("Canceled.");
+            return Ok(());
+        }
+    }
+
+    log::info!("Unregistering {}...", distro_name);
+    let status = Command::new("wsl")
+        .arg("--unregister")
+        .arg(distro_name)
+        .status()
+        .with_context(|| "Failed to launch wsl.exe command.")?;
+    if !status.success() {
+        bail!("Failed: wsl --unregister {}", distro_name);
+    }
+    match install_dir {
+        Some(install_dir) if install_dir.exists() => {
+            log::info!("Removing {:?}...", &install_dir);
+            std::fs::remove_dir_all(&install_dir)
+                .with_context(|| format!("Failed to remove {:?}.", &install_dir))?;
+        }
+        Some(_) => {}
+        None => log::warn!(
+            "The directory of {} is not registered in WSL. No files are removed.",
+            distro_name
+        ),
+    }
+    log::info!("{} has been uninstalled.", distro_name);
+    Ok(())
+}
+
+/// Warn if the distro is a WSL1 distro, on which Distrod doesn't work, and offer to convert it
+/// to WSL2.
+fn ensure_wsl2_distro(distro_name: &str) -> Result<()> {
+    let version = unsafe { wsl::get_distribution_version(distro_name) }
+        .with_context(|| format!("Failed to get the WSL version of {}.", distro_name))?;
+    if version == 2 {
+        return Ok(());
+    }
+    log::warn!(
+        "{} is a WSL1 distro, but Distrod requires WSL2 for namespaces and systemd.",
+        distro_name
+    );
+    let answer = prompt_string(
+        &format!(
+            "Convert {} to WSL2 by `wsl --set-version {} 2`? This may take a while.",
+            distro_name, distro_name
+        ),
+        "Y/n",
+        Some("Y"),
+    )?;
+    if !matches!(answer.to_lowercase().as_str(), "y" | "yes") {
+        log::warn!("Distrod won't work until the distro is converted to WSL2.");
+        return Ok(());
+    }
+    wsl::set_distribution_version(distro_name, 2)
+        .with_context(|| format!("Failed to convert {} to WSL2.", distro_name))?;
+    log::info!("{} has been converted to WSL2.", distro_name);
+    Ok(())
+}
+
+fn is_distrod_distro(distro_name: &str) -> Result<bool> {
+    let mut test = wsl::WslCommand::new(Some("test"), distro_name);
+    test.args(["-x", distrod_config::get_distrod_bin_path()]);
+    Ok(test
+        .status()
+        .with_context(|| "Failed to look for the distrod binary.")?
+        == 0)
+}
+
+/// Get %LocalAppData%\<distro_name>, where `register_distribution` installs the distro by `wsl --import`.
+fn get_install_dir(distro_name: &str) -> Option<PathBuf> {
+    std::env::var_os("LOCALAPPDATA").map(|dir| get_install_dir_in(Path::new(&dir), distro_name))
+}
+
+fn get_install_dir_in(local_app_data: &Path, distro_name: &str) -> PathBuf {
+    local_app_data.join(distro_name)
+}
+
+/// Reject the distro names which can't be the name of the install directory. Spaces are allowed.
+fn validate_distro_name(distro_name: &str) -> Result<()> {
+    if distro_name.trim().is_empty() {
+        bail!("The distro name must not be empty.");
+    }
+    if distro_name.trim() != distro_name || distro_name.ends_with('.') {
+        bail!(
+            "The distro name '{}' must not start or end with spaces, or end with a dot.",
+            distro_name
+        );
+    }
+    if let Some(c) = distro_name
+        .chars()
+        .find(|c| c.is_control() || "<>:\"/\\|?*".contains(*c))
+    {
+        bail!(
+            "The distro name '{}' has an unsupported character {:?}.",
+            distro_name,
+            c
+        );
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn install_distro(distro_name: &str, opts: InstallOpts) -> Result<()> {
+    validate_distro_name(distro_name)?;
     println!(
         r"
         ██████╗ ██╗███████╗████████╗██████╗  ██████╗ ██████╗ 
@@ -148,7 +345,10 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
     let container_org_root_tarxz = fetch_distro_image()
         .await
         .with_context(|| "Failed to fetch a distro image.")?;
-    let container_org_tar = tar::Archive::new(XzDecoder::new(container_org_root_tarxz));
+    // Decompress the image on another thread so that it runs in parallel with merging.
+    let container_org_tar = tar::Archive::new(threaded_io::ThreadedReader::new(XzDecoder::new(
+        container_org_root_tarxz,
+    )));
 
     log::info!(
         "Unpacking and merging the given rootfs to the distrod rootfs. This may take a while..."
@@ -171,6 +371,8 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
     log::info!("Now Windows is installing the new distribution. This may take a while...");
     register_distribution(distro_name, &install_targz_path)
         .with_context(|| "Failed to register the distribution.")?;
+    // The distro is registered as the default version of WSL, which may be WSL1.
+    ensure_wsl2_distro(distro_name)?;
     log::info!("Done!");
 
     let uid = if !opts.root {
@@ -214,6 +416,16 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
         }
     }
 
+    if opts.set_default {
+        log::info!("Making {} the default distro of WSL.", distro_name);
+        if let Err(e) = wsl::set_default_distribution(distro_name) {
+            log::warn!("{:?}", e);
+            log::info!(
+                "You can make it the default later by `distrod_wsl_launcher config --set-default`"
+            );
+        }
+    }
+
     log::info!("Installation of Distrod is now complete.");
     let _ = wsl::WslCommand::new::<String, _>(None, distro_name)
         .status()
@@ -226,7 +438,7 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
     Ok(())
 }
 
-async fn fetch_distro_image() -> Result<Box<dyn Read>> {
+async fn fetch_distro_image() -> Result<Box<dyn Read + Send>> {
     let local_image_fetcher =
         || Ok(Box::new(LocalDistroImage::new(&cli_ui::prompt_path)) as Box<dyn DistroImageFetcher>);
     let container_org_image_fetcher =
@@ -242,14 +454,14 @@ async fn fetch_distro_image() -> Result<Box<dyn Read>> {
         DistroImageFile::Local(path) => {
             let file =
                 File::open(&path).with_context(|| format!("Failed to open '{:?}'.", &path))?;
-            Ok(Box::new(BufReader::new(file)) as Box<dyn Read>)
+            Ok(Box::new(BufReader::new(file)) as Box<dyn Read + Send>)
         }
         DistroImageFile::Url(url) => {
             log::info!("Downloading '{}'...", url);
             let mut bytes = vec![];
             download_file_with_progress(&url, build_progress_bar, &mut bytes).await?;
             log::info!("Download done.");
-            Ok(Box::new(Cursor::new(bytes)) as Box<dyn Read>)
+            Ok(Box::new(Cursor::new(bytes)) as Box<dyn Read + Send>)
         }
     }
 }
@@ -263,7 +475,11 @@ fn merge_tar_archive<R: Read>(work_dir: &TempDir, mut rootfs: tar::Archive<R>) -
         BufWriter::new(File::create(&install_targz_path).with_context(|| {
             format!("Failed to create a new file at '{:?}'.", install_targz_path)
         })?);
-    let encoder = GzEncoder::new(install_targz, flate2::Compression::default());
+    // Compress the merged archive on another thread so that it runs in parallel with merging.
+    let encoder = threaded_io::ThreadedWriter::new(GzEncoder::new(
+        install_targz,
+        flate2::Compression::default(),
+    ));
 
     let mut builder = tar::Builder::new(encoder);
     tar_helper::append_tar_archive::<_, _, _, &str>(
@@ -274,8 +490,8 @@ fn merge_tar_archive<R: Read>(work_dir: &TempDir, mut rootfs: tar::Archive<R>) -
     .with_context(|| "Failed to merge the given image.")?;
     tar_helper::append_tar_archive::<_, _, _, &str>(&mut builder, &mut distrod_tar, vec![])
         .with_context(|| "Failed to merge the given image.")?;
-    builder.finish()?;
-    drop(builder); // So that we can close the install_targz file.
+    let encoder = builder.into_inner()?.finish()?;
+    encoder.finish()?.flush()?;
     Ok(install_targz_path)
 }
 
@@ -288,12 +504,14 @@ fn register_distribution<P: AsRef<Path>>(distro_name: &str, tar_gz_filename: P)
         }
     } else {
         // Otherwise, use wsl.exe --import to install the distro for flexibility.
-        let mut cmd = Command::new("cmd.exe");
-        cmd.arg("/C")
-            .arg("wsl")
-            .arg("--import")
+        // wsl.exe is run directly, not through cmd.exe, so that the arguments are quoted
+        // properly even if the distro name has spaces.
+        let install_dir =
+            get_install_dir(distro_name).ok_or_else(|| anyhow!("%LocalAppData% is not set."))?;
+        let mut cmd = Command::new("wsl");
+        cmd.arg("--import")
             .arg(distro_name)
-            .arg(format!("%LocalAppData%\\{}", distro_name))
+            .arg(&install_dir)
             .arg(tar_gz_filename.as_ref());
         let mut child = cmd
             .spawn()
@@ -303,17 +521,13 @@ fn register_distribution<P: AsRef<Path>>(distro_name: &str, tar_gz_filename: P)
             .with_context(|| "Failed to wait for wsl.exe command.")?;
         if !status.success() {
             bail!(
-                "Failed: cmd.exe /C wsl --import {} {} {:#?}",
+                "Failed: wsl --import \"{}\" {:?} {:?}",
                 distro_name,
-                format!("%LocalAppData%\\{}", distro_name),
+                &install_dir,
                 tar_gz_filename.as_ref()
             );
         }
-        log::info!(
-            "{} is installed in %LocalAppData%\\{}",
-            distro_name,
-            distro_name
-        );
+        log::info!("{} is installed in {:?}", distro_name, &install_dir);
         Ok(())
     }
 }
@@ -441,3 +655,38 @@ fn get_windows10_build_number() -> Result<u32> {
         .parse::<u32>()
         .with_context(|| "Failed to parse the version string as u32")
 }
+
+#[cfg(test)]
+mod test_distro_name {
+    use super::*;
+
+    #[test]
+    fn test_validate_distro_name() {
+        for valid in &["Distrod", "Ubuntu-20.04", "My Distro", "my_distro (2)"] {
+            assert!(validate_distro_name(valid).is_ok(), "{}", valid);
+        }
+        for invalid in &[
+            "",
+            " ",
+            " My Distro",
+            "My Distro ",
+            "My Distro.",
+            "My\tDistro",
+            "My/Distro",
+            "My\\Distro",
+            "My:Distro",
+            "My\"Distro\"",
+            "My*Distro",
+        ] {
+            assert!(validate_distro_name(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_install_dir_with_spaces() {
+        assert_eq!(
+            PathBuf::from("C:\\Users\\user\\AppData\\Local\\My Distro"),
+            get_install_dir_in(Path::new("C:\\Users\\user\\AppData\\Local"), "My Distro")
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use anyhow::{Context, Result};
 use std::collections::HashSet;
 use std::ffi::OsString;
-use std::io::{Cursor, Read};
 use std::iter::FromIterator;
 use std::path::Path;
 
+/// The pax extensions which the builder encodes into the header by itself.
+const REENCODED_PAX_KEYS: &[&[u8]] = &[b"path", b"linkpath", b"size"];
+
+/// Append the entries of the archive to the builder except the excluded paths.
+/// The data of the entries are streamed into the builder without buffering them, and the pax
+/// extensions such as the extended attributes are carried over.
 pub fn append_tar_archive<W, R, I, P>(
     builder: &mut tar::Builder<W>,
     archive: &mut tar::Archive<R>,
@@ -39,29 +44,92 @@ where
             continue;
         }
 
+        let pax_extensions = get_carried_pax_extensions(&mut entry)
+            .with_context(|| format!("Failed to read the pax extensions of {:?}.", &path))?;
+        if !pax_extensions.is_empty() {
+            append_pax_extensions(builder, &pax_extensions)
+                .with_context(|| format!("Failed to add the pax extensions of {:?}.", &path))?;
+        }
+
         let mut gnu_header =
             to_gnu_header(entry.header()).unwrap_or_else(|| entry.header().clone());
 
-        if let Some(link_name) = entry
+        let link_name = entry
             .link_name()
             .with_context(|| format!("Failed to get the link_name {:?}", &path))?
-        {
+            .map(|link_name| link_name.into_owned());
+        if let Some(link_name) = link_name {
             builder
                 .append_link(&mut gnu_header, &path, link_name.as_os_str())
                 .with_context(|| format!("Failed to append_link {:?}", &path))?;
         } else {
-            let mut data = vec![];
-            entry
-                .read_to_end(&mut data)
-                .with_context(|| format!("Failed to read the data of an entry: {:?}.", &path))?;
+            // The entry reads exactly the size in the header, so it can be passed as is.
             builder
-                .append_data(&mut gnu_header, &path, Cursor::new(data))
+                .append_data(&mut gnu_header, &path, &mut entry)
                 .with_context(|| format!("Failed to add an entry to an archive. {:?}", &path))?;
         }
     }
     Ok(())
 }
 
+type PaxExtension = (Vec<u8>, Vec<u8>);
+
+fn get_carried_pax_extensions<R: std::io::Read>(
+    entry: &mut tar::Entry<R>,
+) -> Result<Vec<PaxExtension>> {
+    let extensions = match entry.pax_extensions()? {
+        Some(extensions) => extensions,
+        None => return Ok(vec![]),
+    };
+    let mut carried = vec![];
+    for extension in extensions {
+        let extension = extension?;
+        if REENCODED_PAX_KEYS.contains(&extension.key_bytes()) {
+            continue;
+        }
+        carried.push((
+            extension.key_bytes().to_owned(),
+            extension.value_bytes().to_owned(),
+        ));
+    }
+    Ok(carried)
+}
+
+/// Append a pax extended header, which applies to the next entry.
+fn append_pax_extensions<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    extensions: &[PaxExtension],
+) -> Result<()> {
+    let data: Vec<u8> = extensions
+        .iter()
+        .flat_map(|(key, value)| encode_pax_record(key, value))
+        .collect();
+    let mut header = tar::Header::new_ustar();
+    header.set_path("././@PaxHeader")?;
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_mode(0o644);
+    header.set_size(data.len() as u64);
+    header.set_cksum();
+    builder.append(&header, data.as_slice())?;
+    Ok(())
+}
+
+/// Encode a record as "<length> <key>=<value>\n", where the length includes its own digits.
+fn encode_pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    // ' ', '=', and '\n'
+    let rest_len = key.len() + value.len() + 3;
+    let mut len = rest_len;
+    while len != rest_len + len.to_string().len() {
+        len = rest_len + len.to_string().len();
+    }
+    let mut record = format!("{} ", len).into_bytes();
+    record.extend_from_slice(key);
+    record.push(b'=');
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
 fn to_gnu_header(header: &tar::Header) -> Option<tar::Header> {
     if header.as_gnu().is_some() {
         return None;
@@ -114,3 +182,136 @@ fn gen_name_candidates_for_path<P: AsRef<Path>>(path: P) -> Vec<OsString> {
     }
     candidates
 }
+
+#[cfg(test)]
+mod test_tar_helper {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct EntrySummary {
+        path: String,
+        entry_type: tar::EntryType,
+        mode: u32,
+        uid: u64,
+        gid: u64,
+        mtime: u64,
+        link_name: Option<String>,
+        data: Vec<u8>,
+        pax_extensions: Vec<PaxExtension>,
+    }
+
+    fn summarize(archive: &[u8]) -> Vec<EntrySummary> {
+        let mut archive = tar::Archive::new(archive);
+        archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let pax_extensions = get_carried_pax_extensions(&mut entry).unwrap();
+                let header = entry.header().clone();
+                let link_name = entry
+                    .link_name()
+                    .unwrap()
+                    .map(|link_name| link_name.to_string_lossy().into_owned());
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = vec![];
+                std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+                EntrySummary {
+                    path,
+                    entry_type: header.entry_type(),
+                    mode: header.mode().unwrap(),
+                    uid: header.uid().unwrap(),
+                    gid: header.gid().unwrap(),
+                    mtime: header.mtime().unwrap(),
+                    link_name,
+                    data,
+                    pax_extensions,
+                }
+            })
+            .collect()
+    }
+
+    fn new_header(entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_mtime(1_600_000_000);
+        header.set_size(size);
+        header
+    }
+
+    fn build_rootfs() -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut dir = new_header(tar::EntryType::Directory, 0o755, 0);
+        builder
+            .append_data(&mut dir, "./etc/", std::io::empty())
+            .unwrap();
+        let mut file = new_header(tar::EntryType::Regular, 0o640, 9);
+        builder
+            .append_data(&mut file, "./etc/hosts", &b"127.0.0.1"[..])
+            .unwrap();
+        let mut resolv_conf = new_header(tar::EntryType::Regular, 0o644, 7);
+        builder
+            .append_data(&mut resolv_conf, "./etc/resolv.conf", &b"nameser"[..])
+            .unwrap();
+        let mut symlink = new_header(tar::EntryType::Symlink, 0o777, 0);
+        symlink.set_link_name("/usr/share/zoneinfo/UTC").unwrap();
+        builder
+            .append_data(&mut symlink, "./etc/localtime", std::io::empty())
+            .unwrap();
+        let mut hard_link = new_header(tar::EntryType::Link, 0o640, 0);
+        hard_link.set_link_name("./etc/hosts").unwrap();
+        builder
+            .append_data(&mut hard_link, "./etc/hosts2", std::io::empty())
+            .unwrap();
+        let xattrs = vec![(
+            b"SCHILY.xattr.security.capability".to_vec(),
+            b"\x01\x00\x00\x02\x00\x20\x00\x00".to_vec(),
+        )];
+        append_pax_extensions(&mut builder, &xattrs).unwrap();
+        let mut ping = new_header(tar::EntryType::Regular, 0o4755, 4);
+        builder
+            .append_data(&mut ping, "./usr/bin/ping", &b"\x7fELF"[..])
+            .unwrap();
+        let long_path = format!("./usr/share/{}/file", "long".repeat(40));
+        let mut long = new_header(tar::EntryType::Regular, 0o600, 3);
+        builder
+            .append_data(&mut long, &long_path, &b"abc"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_append_tar_archive() {
+        let rootfs = build_rootfs();
+        let mut builder = tar::Builder::new(vec![]);
+        append_tar_archive(
+            &mut builder,
+            &mut tar::Archive::new(rootfs.as_slice()),
+            vec!["/etc/resolv.conf"],
+        )
+        .unwrap();
+        let merged = builder.into_inner().unwrap();
+
+        let expected: Vec<_> = summarize(&rootfs)
+            .into_iter()
+            .filter(|entry| entry.path != "etc/resolv.conf")
+            .collect();
+        assert_eq!(6, expected.len());
+        assert_eq!(1, expected[4].pax_extensions.len());
+        assert_eq!(expected, summarize(&merged));
+    }
+
+    #[test]
+    fn test_encode_pax_record() {
+        assert_eq!(b"6 a=b\n".to_vec(), encode_pax_record(b"a", b"b"));
+        // The length grows to 11 since 10 has two digits.
+        assert_eq!(b"11 ab=cdef\n".to_vec(), encode_pax_record(b"ab", b"cdef"));
+        assert_eq!(
+            format!("101 k={}\n", "v".repeat(94)).into_bytes(),
+            encode_pax_record(b"k", "v".repeat(94).as_bytes())
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! A reader and a writer which run the inner ones on separate threads, so that decompressing the
+//! given rootfs, merging it, and compressing the result run in a pipeline.
+//! Merging a 390 MB rootfs took 28 seconds on a single core, of which the xz decompression took
+//! 5 seconds and the gzip compression took 16 seconds, so the pipeline is bounded by the latter.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::JoinHandle;
+
+const CHUNK_SIZE: usize = 1 << 20;
+/// The number of the chunks which can be in flight between the threads.
+const MAX_QUEUED_CHUNKS: usize = 8;
+
+/// A reader which reads the inner reader ahead on a separate thread.
+pub struct ThreadedReader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ThreadedReader {
+    pub fn new<R: Read + Send + 'static>(mut inner: R) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_CHUNKS);
+        std::thread::spawn(move || loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            let chunk = match (&mut inner).take(CHUNK_SIZE as u64).read_to_end(&mut chunk) {
+                // Dropping the sender tells EOF to the receiver.
+                Ok(0) => break,
+                Ok(_) => Ok(chunk),
+                Err(e) => Err(e),
+            };
+            let is_err = chunk.is_err();
+            if sender.send(chunk).is_err() || is_err {
+                break;
+            }
+        });
+        ThreadedReader {
+            receiver,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// A writer which writes to the inner writer on a separate thread.
+/// `finish` must be called to get the errors of the last writes and the inner writer.
+pub struct ThreadedWriter<W> {
+    sender: Option<SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<io::Result<W>>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write + Send + 'static> ThreadedWriter<W> {
+    pub fn new(mut inner: W) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(MAX_QUEUED_CHUNKS);
+        let handle = std::thread::spawn(move || {
+            for chunk in receiver {
+                inner.write_all(&chunk)?;
+            }
+            inner.flush()?;
+            Ok(inner)
+        });
+        ThreadedWriter {
+            sender: Some(sender),
+            handle: Some(handle),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Write the buffered data, wait for the thread to write everything, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.send_buf()?;
+        self.join()
+    }
+
+    fn send_buf(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let sender = self.sender.as_ref().ok_or_else(already_finished)?;
+        if sender.send(chunk).is_err() {
+            // The thread has exited due to an error, which join returns.
+            self.join()?;
+            return Err(already_finished());
+        }
+        Ok(())
+    }
+
+    fn join(&mut self) -> io::Result<W> {
+        drop(self.sender.take());
+        let handle = self.handle.take().ok_or_else(already_finished)?;
+        handle
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "The writer thread panicked."))?
+    }
+}
+
+fn already_finished() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The writer thread has finished.")
+}
+
+impl<W: Write + Send + 'static> Write for ThreadedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send_buf()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buf()
+    }
+}
+
+#[cfg(test)]
+mod test_threaded_io {
+    use super::*;
+
+    #[test]
+    fn test_threaded_reader_and_writer() {
+        let data: Vec<u8> = (0..(CHUNK_SIZE * 3 + 7)).map(|i| (i % 251) as u8).collect();
+        let mut reader = ThreadedReader::new(io::Cursor::new(data.clone()));
+        let mut writer = ThreadedWriter::new(vec![]);
+        io::copy(&mut reader, &mut writer).unwrap();
+        assert_eq!(data, writer.finish().unwrap());
+    }
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_threaded_writer_error() {
+        let mut writer = ThreadedWriter::new(FailingWriter);
+        writer.write_all(b"data").unwrap();
+        assert_eq!("disk full", writer.finish().err().unwrap().to_string());
+    }
+}
@@ -1,12 +1,16 @@
 use std::{
     ffi::{OsStr, OsString},
-    path::Path,
+    path::{Path, PathBuf},
+    process::Command,
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use windows::{
     runtime::IntoParam,
-    Win32::{Foundation::PWSTR, System::SubsystemForLinux::*},
+    Win32::{
+        Foundation::{PSTR, PWSTR},
+        System::{Com::CoTaskMemFree, SubsystemForLinux::*},
+    },
 };
 
 pub unsafe fn is_distribution_registered<'a, Param0: IntoParam<'a, PWSTR>>(
@@ -46,6 +50,130 @@ pub unsafe fn set_distribution_default_user<'a, Param0: IntoParam<'a, PWSTR> + s
     WslConfigureDistribution(distributionname, defaultuid, default_distro_flag).with_context(|| err)
 }
 
+/// Get the WSL version of the distribution, which is 1 or 2.
+pub unsafe fn get_distribution_version<'a, Param0: IntoParam<'a, PWSTR> + std::fmt::Debug>(
+    distributionname: Param0,
+) -> Result<u32> {
+    let err = format!(
+        "WslGetDistributionConfiguration failed. distribution_name: {:?}",
+        distributionname
+    );
+    let mut version = 0;
+    let mut default_uid = 0;
+    let mut flags = WSL_DISTRIBUTION_FLAGS(0);
+    let mut env_vars: *mut PSTR = std::ptr::null_mut();
+    let mut env_var_count = 0;
+    WslGetDistributionConfiguration(
+        distributionname,
+        &mut version,
+        &mut default_uid,
+        &mut flags,
+        &mut env_vars,
+        &mut env_var_count,
+    )
+    .with_context(|| err)?;
+    // The caller owns the returned environment variables.
+    for i in 0..env_var_count as usize {
+        CoTaskMemFree((*env_vars.add(i)).0 as *const _);
+    }
+    CoTaskMemFree(env_vars as *const _);
+    // `version` is the version of the distro configuration, not of WSL. The VM mode flag tells it.
+    if flags.0 & WSL_DISTRIBUTION_FLAGS_VM_MODE.0 != 0 {
+        Ok(2)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Convert the distribution to the given WSL version by `wsl --set-version`.
+pub fn set_distribution_version(distribution_name: &str, version: u32) -> Result<()> {
+    run_wsl_exe(&["--set-version", distribution_name, &version.to_string()])
+}
+
+/// Make the distribution the default one of wsl.exe. This uses `wsl --set-default`, since the
+/// WSL API has no function to do it.
+pub fn set_default_distribution(distribution_name: &str) -> Result<()> {
+    if !unsafe { is_distribution_registered(distribution_name) } {
+        bail!(
+            "{} is not registered. Install it before making it the default.",
+            distribution_name
+        );
+    }
+    run_wsl_exe(&["--set-default", distribution_name])
+}
+
+/// Terminate the running distribution by `wsl --terminate`.
+pub fn terminate_distribution(distribution_name: &str) -> Result<()> {
+    if !unsafe { is_distribution_registered(distribution_name) } {
+        bail!("{} is not registered.", distribution_name);
+    }
+    run_wsl_exe(&["--terminate", distribution_name])
+}
+
+/// Terminate all the running distributions and the WSL 2 VM by `wsl --shutdown`.
+pub fn shutdown() -> Result<()> {
+    run_wsl_exe(&["--shutdown"])
+}
+
+const LXSS_REGISTRY_KEY: &str = "HKCU\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss";
+
+/// Get the directory of the distribution registered in the Lxss registry key, which holds its
+/// disk image. None if the distribution is not found there.
+pub fn get_distribution_base_path(distribution_name: &str) -> Result<Option<PathBuf>> {
+    let output = Command::new("reg")
+        .args(["query", LXSS_REGISTRY_KEY, "/s"])
+        .output()
+        .with_context(|| "Failed to launch reg.exe command.")?;
+    if !output.status.success() {
+        bail!("Failed: reg query {} /s", LXSS_REGISTRY_KEY);
+    }
+    Ok(parse_lxss_base_path(
+        &String::from_utf8_lossy(&output.stdout),
+        distribution_name,
+    ))
+}
+
+/// Find the BasePath of the distribution in the output of `reg query <Lxss key> /s`, which lists
+/// a key per distribution followed by its values such as "    BasePath    REG_SZ    C:\...".
+fn parse_lxss_base_path(reg_output: &str, distribution_name: &str) -> Option<PathBuf> {
+    let mut name: Option<&str> = None;
+    let mut base_path: Option<&str> = None;
+    for line in reg_output.lines().chain(std::iter::once("")) {
+        let line = line.trim_end_matches('\r');
+        if !line.starts_with(' ') {
+            // The end of the values of a key.
+            if let (Some(name), Some(base_path)) = (name.take(), base_path.take()) {
+                // The distribution names are case-insensitive in WSL.
+                if name.eq_ignore_ascii_case(distribution_name) {
+                    return Some(PathBuf::from(base_path));
+                }
+            }
+            continue;
+        }
+        let mut fields = line.trim_start().splitn(3, "    ");
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("DistributionName"), Some("REG_SZ"), Some(value)) => name = Some(value),
+            (Some("BasePath"), Some("REG_SZ"), Some(value)) => base_path = Some(value),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn run_wsl_exe(args: &[&str]) -> Result<()> {
+    let status = match Command::new("wsl").args(args).status() {
+        Ok(status) => status,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("wsl.exe is not found. Please make sure that WSL is installed.")
+        }
+        Err(e) => return Err(e).with_context(|| "Failed to launch wsl.exe command."),
+    };
+    if !status.success() {
+        bail!("Failed: wsl {}", args.join(" "));
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct WslCommand {
     distribution_name: OsString,
@@ -130,3 +258,33 @@ impl WslCommand {
         command
     }
 }
+
+#[cfg(test)]
+mod test_wsl {
+    use super::*;
+
+    #[test]
+    fn test_parse_lxss_base_path() {
+        let output = "\r\n\
+            HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\r\n    \
+            DefaultDistribution    REG_SZ    {1}\r\n\
+            \r\n\
+            HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\\{1}\r\n    \
+            State    REG_DWORD    0x1\r\n    \
+            DistributionName    REG_SZ    Ubuntu\r\n    \
+            BasePath    REG_SZ    C:\\Users\\user\\AppData\\Local\\Ubuntu\r\n\
+            \r\n\
+            HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\\{2}\r\n    \
+            BasePath    REG_SZ    D:\\WSL\\My Distro\r\n    \
+            DistributionName    REG_SZ    My Distro\r\n";
+        assert_eq!(
+            Some(PathBuf::from("C:\\Users\\user\\AppData\\Local\\Ubuntu")),
+            parse_lxss_base_path(output, "ubuntu")
+        );
+        assert_eq!(
+            Some(PathBuf::from("D:\\WSL\\My Distro")),
+            parse_lxss_base_path(output, "My Distro")
+        );
+        assert_eq!(None, parse_lxss_base_path(output, "Distrod"));
+    }
+}
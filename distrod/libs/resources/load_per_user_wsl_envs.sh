@@ -1,4 +1,5 @@
 #!/bin/sh
+# This file is generated by Distrod. Remove this line to keep the file on uninstallation.
 
 # Load additional WSL session environment variables at runtime by sourcing
 # a script Distrod creates at runtime. A Linux user who launches Distrod first
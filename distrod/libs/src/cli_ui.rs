@@ -1,8 +1,13 @@
 use crate::distro_image::{DefaultImageFetcher, DistroImageFetcher, DistroImageList};
+use crate::log_file::{RotatingLogFile, DEFAULT_LOG_FILE_MAX_SIZE, DEFAULT_LOG_FILE_ROTATIONS};
 use anyhow::{bail, Context, Result};
 use colored::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::{ffi::OsString, fmt::Debug, io::Write};
 use tracing::metadata::LevelFilter;
+use tracing_subscriber::filter::{Filtered, Targets};
+use tracing_subscriber::fmt::format::DefaultFields;
 use tracing_subscriber::{fmt::FormatEvent, prelude::*};
 
 #[derive(Default, Debug)]
@@ -10,6 +15,8 @@ pub struct LoggerInitializer {
     logs_kmsg: bool,
     log_level: Option<String>,
     kmsg_log_level: Option<String>,
+    no_color: bool,
+    log_file: Option<PathBuf>,
 }
 
 impl LoggerInitializer {
@@ -28,9 +35,26 @@ impl LoggerInitializer {
         self
     }
 
+    /// Disable the colors of the terminal output even if it's a terminal.
+    pub fn with_no_color(&mut self, no_color: bool) -> &mut Self {
+        self.no_color = no_color;
+        self
+    }
+
+    /// Also append the logs to the file, which is rotated when it gets large.
+    pub fn with_log_file(&mut self, log_file: PathBuf) -> &mut Self {
+        self.log_file = Some(log_file);
+        self
+    }
+
     pub fn init(self, app_name: String) {
         let inner = || -> Result<()> {
-            let terminal_formatter = TerminalLogFormatter::new(app_name.clone());
+            let uses_color = !self.no_color && should_use_color();
+            if !uses_color {
+                // This also disables the colors of the other outputs such as the prompts.
+                colored::control::set_override(false);
+            }
+            let terminal_formatter = TerminalLogFormatter::new(app_name.clone(), uses_color);
             let mut terminal_filter =
                 tracing_subscriber::filter::Targets::new().with_default(LevelFilter::INFO);
             if let Some(target) = self
@@ -48,6 +72,7 @@ impl LoggerInitializer {
             {
                 terminal_filter = target;
             };
+            let file_filter = terminal_filter.clone();
             let terminal_fmt_layer = tracing_subscriber::fmt::layer()
                 .with_target(false)
                 .event_format(terminal_formatter)
@@ -55,8 +80,12 @@ impl LoggerInitializer {
                 .with_filter(terminal_filter);
 
             if !self.logs_kmsg {
+                let file_fmt_layer =
+                    build_file_fmt_layer(self.log_file.as_deref(), app_name, file_filter);
                 tracing::subscriber::set_global_default(
-                    tracing_subscriber::registry().with(terminal_fmt_layer),
+                    tracing_subscriber::registry()
+                        .with(terminal_fmt_layer)
+                        .with(file_fmt_layer),
                 )
                 .with_context(|| "set_global_default failed.")?;
                 tracing_log::LogTracer::init()
@@ -64,6 +93,8 @@ impl LoggerInitializer {
                 return Ok(());
             }
 
+            let file_fmt_layer =
+                build_file_fmt_layer(self.log_file.as_deref(), app_name.clone(), file_filter);
             let kmsg_formatter = KmsgLogFormatter::new(app_name);
             let mut kmsg_filter =
                 tracing_subscriber::filter::Targets::new().with_default(LevelFilter::ERROR);
@@ -90,7 +121,8 @@ impl LoggerInitializer {
             tracing::subscriber::set_global_default(
                 tracing_subscriber::registry()
                     .with(terminal_fmt_layer)
-                    .with(kmsg_fmt_layer),
+                    .with(kmsg_fmt_layer)
+                    .with(file_fmt_layer),
             )
             .with_context(|| "set_global_default for kmsg failed.")?;
             tracing_log::LogTracer::init().with_context(|| {
@@ -105,28 +137,59 @@ impl LoggerInitializer {
     }
 }
 
-pub fn init_logger(app_name: String, log_level: Option<String>) {
+pub fn init_logger(app_name: String, log_level: Option<String>, no_color: bool) {
     let mut logger_initializer = LoggerInitializer::default();
     if let Some(log_level) = log_level {
         logger_initializer.with_log_level(log_level);
     }
+    logger_initializer.with_no_color(no_color);
     logger_initializer.init(app_name);
 }
 
+/// Colors are used only if stderr is a terminal and NO_COLOR is not set.
+/// See https://no-color.org/.
+fn should_use_color() -> bool {
+    !matches!(std::env::var_os("NO_COLOR"), Some(val) if !val.is_empty())
+        && atty::is(atty::Stream::Stderr)
+}
+
 #[derive(Clone, Debug)]
 struct TerminalLogFormatter {
     app_name: String,
+    uses_color: bool,
 }
 
 impl TerminalLogFormatter {
-    fn new(app_name: String) -> TerminalLogFormatter {
+    fn new(app_name: String, uses_color: bool) -> TerminalLogFormatter {
         #[cfg(target_os = "windows")]
         {
-            if let Err(e) = ansi_term::enable_ansi_support() {
-                eprintln!("Warn: ansi_term::enable_ansi_support failed. {:?}", e);
+            if uses_color {
+                if let Err(e) = ansi_term::enable_ansi_support() {
+                    eprintln!("Warn: ansi_term::enable_ansi_support failed. {:?}", e);
+                }
             }
         }
-        TerminalLogFormatter { app_name }
+        TerminalLogFormatter {
+            app_name,
+            uses_color,
+        }
+    }
+
+    fn format_prefix(&self, level: tracing::Level) -> String {
+        let app_name = format!("[{}]", &self.app_name);
+        let level_name = match level {
+            tracing::Level::INFO => String::new(),
+            _ => format!("[{}]", level),
+        };
+        if !self.uses_color {
+            return format!("{}{} ", app_name, level_name);
+        }
+        let level_name = match level {
+            tracing::Level::INFO => level_name,
+            tracing::Level::ERROR | tracing::Level::WARN => level_name.red().to_string(),
+            _ => level_name.bright_green().to_string(),
+        };
+        format!("{}{} ", app_name.bright_green(), level_name)
     }
 }
 
@@ -141,20 +204,74 @@ where
         mut writer: tracing_subscriber::fmt::format::Writer<'_>,
         event: &tracing::Event<'_>,
     ) -> std::fmt::Result {
-        let level = *event.metadata().level();
+        write!(writer, "{}", self.format_prefix(*event.metadata().level()))?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+type LogFileWriter = Mutex<Box<dyn Write + Send>>;
+
+/// Build the layer to write the logs to the file. If the log file is not given or can't be opened,
+/// the layer discards all the logs so that the logger works only with the other layers.
+fn build_file_fmt_layer<S>(
+    log_file: Option<&Path>,
+    app_name: String,
+    filter: Targets,
+) -> Filtered<
+    tracing_subscriber::fmt::Layer<S, DefaultFields, FileLogFormatter, LogFileWriter>,
+    Targets,
+    S,
+>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let log_file = log_file.and_then(|path| {
+        RotatingLogFile::open(path, DEFAULT_LOG_FILE_MAX_SIZE, DEFAULT_LOG_FILE_ROTATIONS)
+            .map_err(|e| eprintln!("Warn: Failed to open the log file. {:?}", e))
+            .ok()
+    });
+    let (writer, filter) = match log_file {
+        Some(log_file) => (Box::new(log_file) as Box<dyn Write + Send>, filter),
+        None => (
+            Box::new(std::io::sink()) as Box<dyn Write + Send>,
+            Targets::new().with_default(LevelFilter::OFF),
+        ),
+    };
+    tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .event_format(FileLogFormatter { app_name })
+        .with_writer(Mutex::new(writer))
+        .with_filter(filter)
+}
+
+#[derive(Debug)]
+struct FileLogFormatter {
+    app_name: String,
+}
+
+impl<S, N> FormatEvent<S, N> for FileLogFormatter
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
         write!(
-            writer,
-            "{}{} ",
-            format!("[{}]", &self.app_name).bright_green(),
-            match level {
-                tracing::Level::INFO => "".to_string(),
-                tracing::Level::ERROR | tracing::Level::WARN =>
-                    format!("[{}]", level).red().to_string(),
-                _ => format!("[{}]", level).bright_green().to_string(),
-            }
+            writer.by_ref(),
+            "{} {}[{}]: [{}] ",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            self.app_name,
+            std::process::id(),
+            event.metadata().level()
         )?;
         ctx.field_format().format_fields(writer.by_ref(), event)?;
-        writeln!(writer)?;
+        writeln!(writer.by_ref())?;
         Ok(())
     }
 }
@@ -220,12 +337,14 @@ pub fn choose_from_list(list: DistroImageList) -> Result<Box<dyn DistroImageFetc
                 DefaultImageFetcher::Index(index) => fetchers[index].get_name().to_owned(),
                 DefaultImageFetcher::Name(name) => name,
             };
-            for (i, fetcher) in fetchers.iter().enumerate() {
-                println!("{} {}", format!("[{}]", i + 1).cyan(), fetcher.get_name());
-            }
+            let names: Vec<&str> = fetchers.iter().map(|fetcher| fetcher.get_name()).collect();
+            let mut candidates: Vec<usize> = (0..names.len()).collect();
+            print_list_items(&names, &candidates);
             log::info!("Choose {} from the list above.", &list_item_kind);
-            loop {
-                log::info!("Type the name or the index of your choice.");
+            let index = loop {
+                log::info!(
+                    "Type the name or the index of your choice, or a part of the name to narrow down the list."
+                );
                 print!("[Default: {}]: ", &default);
                 let _ = std::io::stdout().flush();
                 let mut choice = String::new();
@@ -236,24 +355,81 @@ pub fn choose_from_list(list: DistroImageList) -> Result<Box<dyn DistroImageFetc
                 if choice.is_empty() {
                     choice = default.to_owned();
                 }
-                let index = fetchers
-                    .iter()
-                    .position(|fetcher| fetcher.get_name() == choice.as_str());
-                if let Some(index) = index {
-                    return Ok(fetchers.into_iter().nth(index).unwrap());
-                }
-                if let Ok(index) = choice.parse::<usize>() {
-                    if index <= fetchers.len() && index >= 1 {
-                        return Ok(fetchers.into_iter().nth(index - 1).unwrap());
+                match match_list_choice(&names, &candidates, &choice) {
+                    ListChoice::Chosen(index) => break index,
+                    ListChoice::Narrowed(narrowed) => {
+                        candidates = narrowed;
+                        print_list_items(&names, &candidates);
+                        log::info!("{} items match {}.", candidates.len(), choice);
                     }
+                    ListChoice::NoMatch => log::info!("{} is off the list.", choice),
                 }
-                log::info!("{} is off the list.", choice);
-            }
+            };
+            Ok(fetchers.into_iter().nth(index).unwrap())
         }
         DistroImageList::Image(_) => bail!("Image should not be passed to choose_from_list."),
     }
 }
 
+fn print_list_items(names: &[&str], indices: &[usize]) {
+    for &i in indices {
+        println!("{} {}", format!("[{}]", i + 1).cyan(), names[i]);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ListChoice {
+    Chosen(usize),
+    Narrowed(Vec<usize>),
+    NoMatch,
+}
+
+/// Match the query against the names. An exact name or an index in the whole list is chosen as
+/// is. Otherwise, the candidates which contain the query, or which contain its characters in order
+/// if none contains it, are chosen if there is only one of them, or narrowed down to otherwise.
+/// The whole list is searched if no candidate matches.
+fn match_list_choice(names: &[&str], candidates: &[usize], query: &str) -> ListChoice {
+    if let Some(index) = names.iter().position(|name| *name == query) {
+        return ListChoice::Chosen(index);
+    }
+    if let Ok(index) = query.parse::<usize>() {
+        if index <= names.len() && index >= 1 {
+            return ListChoice::Chosen(index - 1);
+        }
+    }
+    let query = query.to_lowercase();
+    let all: Vec<usize> = (0..names.len()).collect();
+    for indices in [candidates, all.as_slice()] {
+        let lowercase_name = |i: &usize| names[*i].to_lowercase();
+        if let Some(&index) = indices.iter().find(|i| lowercase_name(i) == query) {
+            return ListChoice::Chosen(index);
+        }
+        let mut matched: Vec<usize> = indices
+            .iter()
+            .filter(|i| lowercase_name(i).contains(&query))
+            .copied()
+            .collect();
+        if matched.is_empty() {
+            matched = indices
+                .iter()
+                .filter(|i| is_subsequence(&query, &lowercase_name(i)))
+                .copied()
+                .collect();
+        }
+        match matched.len() {
+            0 => continue,
+            1 => return ListChoice::Chosen(matched[0]),
+            _ => return ListChoice::Narrowed(matched),
+        }
+    }
+    ListChoice::NoMatch
+}
+
+fn is_subsequence(query: &str, name: &str) -> bool {
+    let mut name_chars = name.chars();
+    query.chars().all(|q| name_chars.any(|c| c == q))
+}
+
 pub fn prompt_path(message: &str, default: Option<&str>) -> Result<OsString> {
     log::info!("{}", message);
     print!(
@@ -296,3 +472,106 @@ pub fn build_progress_bar(total_size: u64) -> indicatif::ProgressBar {
                     .progress_chars("#>-"));
     bar
 }
+
+#[cfg(test)]
+mod test_cli_ui {
+    use super::*;
+
+    #[test]
+    fn test_terminal_log_formatter_without_color() {
+        let formatter = TerminalLogFormatter::new("Distrod".to_owned(), false);
+        assert_eq!("[Distrod] ", formatter.format_prefix(tracing::Level::INFO));
+        assert_eq!(
+            "[Distrod][ERROR] ",
+            formatter.format_prefix(tracing::Level::ERROR)
+        );
+        assert_eq!(
+            "[Distrod][DEBUG] ",
+            formatter.format_prefix(tracing::Level::DEBUG)
+        );
+    }
+
+    const NAMES: &[&str] = &[
+        "almalinux",
+        "alpine",
+        "archlinux",
+        "debian",
+        "fedora",
+        "ubuntu",
+        "ubuntu-core",
+    ];
+
+    fn all() -> Vec<usize> {
+        (0..NAMES.len()).collect()
+    }
+
+    #[test]
+    fn test_match_list_choice_exact_name_and_index() {
+        assert_eq!(
+            ListChoice::Chosen(5),
+            match_list_choice(NAMES, &all(), "ubuntu")
+        );
+        assert_eq!(
+            ListChoice::Chosen(3),
+            match_list_choice(NAMES, &all(), "Debian")
+        );
+        assert_eq!(ListChoice::Chosen(0), match_list_choice(NAMES, &all(), "1"));
+        assert_eq!(ListChoice::Chosen(6), match_list_choice(NAMES, &all(), "7"));
+        // The index refers to the whole list even after it's narrowed down.
+        assert_eq!(
+            ListChoice::Chosen(3),
+            match_list_choice(NAMES, &[5, 6], "4")
+        );
+        assert_eq!(ListChoice::NoMatch, match_list_choice(NAMES, &all(), "8"));
+    }
+
+    #[test]
+    fn test_match_list_choice_substring() {
+        assert_eq!(
+            ListChoice::Chosen(4),
+            match_list_choice(NAMES, &all(), "fed")
+        );
+        assert_eq!(
+            ListChoice::Chosen(6),
+            match_list_choice(NAMES, &all(), "CORE")
+        );
+        assert_eq!(
+            ListChoice::Narrowed(vec![0, 1]),
+            match_list_choice(NAMES, &all(), "al")
+        );
+        assert_eq!(
+            ListChoice::Narrowed(vec![5, 6]),
+            match_list_choice(NAMES, &all(), "ubu")
+        );
+    }
+
+    #[test]
+    fn test_match_list_choice_fuzzy() {
+        assert_eq!(
+            ListChoice::Chosen(2),
+            match_list_choice(NAMES, &all(), "arx")
+        );
+        assert_eq!(
+            ListChoice::Narrowed(vec![0, 1, 2]),
+            match_list_choice(NAMES, &all(), "ai")
+        );
+        assert_eq!(
+            ListChoice::NoMatch,
+            match_list_choice(NAMES, &all(), "gentoo")
+        );
+    }
+
+    #[test]
+    fn test_match_list_choice_in_narrowed_list() {
+        // "l" matches only alpine in the narrowed list.
+        assert_eq!(
+            ListChoice::Chosen(1),
+            match_list_choice(NAMES, &[1, 4], "l")
+        );
+        // The whole list is searched if nothing in the narrowed list matches.
+        assert_eq!(
+            ListChoice::Chosen(3),
+            match_list_choice(NAMES, &[0, 1], "deb")
+        );
+    }
+}
@@ -1,7 +1,8 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
+use crate::container::{ContainerPath, HostPath};
 use crate::distrod_config;
 
 pub struct CommandAlias {
@@ -69,6 +70,88 @@ impl CommandAlias {
         })
     }
 
+    /// Create an alias named `name` which runs `source` in the Distrod's container.
+    /// The alias is a symlink in the named alias directory pointing to the link of `source`,
+    /// so that the alias is resolved to `source` by `open_from_link` when it's executed.
+    /// `source` must be a file in `rootfs`, the rootfs of the distro the alias runs in.
+    pub fn create_named<P: AsRef<Path>>(
+        name: &str,
+        source: P,
+        rootfs: &HostPath,
+    ) -> Result<CommandAlias> {
+        validate_alias_name(name)?;
+        let source = source.as_ref();
+        let is_file = matches!(
+            ContainerPath::new(source)?.resolve_in_rootfs(rootfs),
+            Some(path) if path.as_path().is_file()
+        );
+        if !is_file {
+            bail!(
+                "The alias target does not exist in the rootfs: {:?}",
+                source
+            );
+        }
+        let named_link_path = get_named_alias_dir().join(name);
+        if named_link_path.symlink_metadata().is_ok() {
+            bail!("The alias '{}' already exists.", name);
+        }
+        let alias =
+            CommandAlias::open_from_source(source, true)?.expect("an alias should be created.");
+        let named_alias_dir = get_named_alias_dir();
+        if !named_alias_dir.exists() {
+            std::fs::create_dir_all(&named_alias_dir).with_context(|| {
+                format!("Failed to create the directory {:?}", &named_alias_dir)
+            })?;
+        }
+        std::os::unix::fs::symlink(alias.get_link_path(), &named_link_path)
+            .with_context(|| format!("Failed to create a symlink at {:?}", &named_link_path))?;
+        Ok(alias)
+    }
+
+    /// Open the alias named `name` if it exists.
+    pub fn open_named(name: &str) -> Result<Option<CommandAlias>> {
+        validate_alias_name(name)?;
+        let named_link_path = get_named_alias_dir().join(name);
+        if named_link_path.symlink_metadata().is_err() {
+            return Ok(None);
+        }
+        let link_path = std::fs::read_link(&named_link_path)
+            .with_context(|| format!("Failed to read the link {:?}", &named_link_path))?;
+        Ok(Some(CommandAlias::open_from_link(link_path)?))
+    }
+
+    /// Remove the alias named `name`. The link to the source is kept, because other
+    /// aliases or the default shell hook may still use it.
+    pub fn remove_named(name: &str) -> Result<()> {
+        if CommandAlias::open_named(name)?.is_none() {
+            bail!("The alias '{}' does not exist.", name);
+        }
+        let named_link_path = get_named_alias_dir().join(name);
+        std::fs::remove_file(&named_link_path)
+            .with_context(|| format!("Failed to remove {:?}", &named_link_path))
+    }
+
+    /// List the named aliases sorted by their names.
+    pub fn list_named() -> Result<Vec<(String, CommandAlias)>> {
+        let named_alias_dir = get_named_alias_dir();
+        if !named_alias_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut aliases = vec![];
+        for entry in std::fs::read_dir(&named_alias_dir)
+            .with_context(|| format!("Failed to read {:?}", &named_alias_dir))?
+        {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            match CommandAlias::open_named(&name) {
+                Ok(Some(alias)) => aliases.push((name, alias)),
+                Ok(None) => {}
+                Err(e) => log::warn!("Ignoring the broken alias '{}'. {:?}", name, e),
+            }
+        }
+        aliases.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(aliases)
+    }
+
     pub fn get_source_path(&self) -> &Path {
         &self.source_path
     }
@@ -77,3 +160,30 @@ impl CommandAlias {
         &self.link_path
     }
 }
+
+/// The directory where the symlinks of the named aliases are stored.
+/// It's a hidden directory so that it doesn't collide with the links mirroring the rootfs.
+pub fn get_named_alias_dir() -> PathBuf {
+    Path::new(distrod_config::get_alias_dir()).join(".names")
+}
+
+fn validate_alias_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.contains('\0') {
+        bail!("Invalid alias name: '{}'", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_command_alias {
+    use super::*;
+
+    #[test]
+    fn test_validate_alias_name() {
+        assert!(validate_alias_name("code").is_ok());
+        assert!(validate_alias_name("docker-compose.v2").is_ok());
+        assert!(validate_alias_name("").is_err());
+        assert!(validate_alias_name("..").is_err());
+        assert!(validate_alias_name("usr/bin/code").is_err());
+    }
+}
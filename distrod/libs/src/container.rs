@@ -1,16 +1,26 @@
 use anyhow::{anyhow, bail, Context, Result};
+use nix::mount::MsFlags;
 use nix::sched::CloneFlags;
+use nix::unistd::{Gid, Uid};
 use nix::NixPath;
 use passfd::FdPassingExt;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 
-use crate::mount_info::{get_mount_entries, MountEntry};
+use crate::mount_info::{
+    detect_cgroup_version, get_mount_entries, get_mountinfo_entries, is_identically_mounted,
+    is_mountpoint, CgroupVersion, MountEntry,
+};
 use crate::multifork::{CommandByMultiFork, Waiter};
 use crate::passwd::Credential;
 use crate::procfile::ProcFile;
@@ -21,6 +31,7 @@ pub struct ContainerLauncher {
     init_envs: Vec<(OsString, OsString)>,
     init_args: Vec<OsString>,
     pre_exec_closures: Vec<Box<dyn FnMut() -> Result<()> + Send + Sync + 'static>>,
+    user_namespace: Option<UserNamespace>,
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +44,109 @@ pub struct ContainerMount {
     pub is_file: bool,
 }
 
+/// A line of /proc/<pid>/uid_map or gid_map, which maps the IDs inside the user namespace
+/// to the ones outside of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMap {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+/// The ID mappings of the user namespace of the container.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserNamespace {
+    pub uid_maps: Vec<IdMap>,
+    pub gid_maps: Vec<IdMap>,
+}
+
+impl UserNamespace {
+    /// Map all the IDs to themselves. The root in the container is still the real root for the
+    /// files, but it loses the capabilities over the resources owned by the host's namespaces.
+    pub fn identity() -> Self {
+        let map = IdMap {
+            inside: 0,
+            outside: 0,
+            count: u32::MAX,
+        };
+        UserNamespace {
+            uid_maps: vec![map],
+            gid_maps: vec![map],
+        }
+    }
+
+    /// Map the root in the container to the given user and group. The other users in the
+    /// container are not mapped, so they can't be used.
+    pub fn root_to(uid: Uid, gid: Gid) -> Self {
+        let map = |outside| IdMap {
+            inside: 0,
+            outside,
+            count: 1,
+        };
+        UserNamespace {
+            uid_maps: vec![map(uid.as_raw())],
+            gid_maps: vec![map(gid.as_raw())],
+        }
+    }
+
+    /// Write the ID maps of the process which has unshared the user namespace by
+    /// `wait_for_id_maps`. Only a process in the parent namespace can map the arbitrary IDs.
+    fn write_id_maps(&self, channel: &UnixStream) -> Result<()> {
+        let mut channel = channel;
+        let mut pid = [0u8; 4];
+        channel
+            .read_exact(&mut pid)
+            .with_context(|| "Failed to receive the pid in the new user namespace.")?;
+        let pid = u32::from_ne_bytes(pid);
+        for (name, maps) in [("uid_map", &self.uid_maps), ("gid_map", &self.gid_maps)] {
+            let path = format!("/proc/{}/{}", pid, name);
+            let cont: String = maps
+                .iter()
+                .map(|map| format!("{} {} {}\n", map.inside, map.outside, map.count))
+                .collect();
+            // The map must be written by a single write(2).
+            fs::write(&path, cont.as_bytes())
+                .with_context(|| format!("Failed to write {:?}.", &path))?;
+        }
+        channel
+            .write_all(&[1])
+            .with_context(|| "Failed to notify that the ID maps are written.")?;
+        Ok(())
+    }
+}
+
+impl FromStr for UserNamespace {
+    type Err = anyhow::Error;
+
+    /// Parse "identity", or "<uid>:<gid>" to map the root to them.
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "identity" {
+            return Ok(UserNamespace::identity());
+        }
+        let (uid, gid) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("The user namespace should be 'identity' or '<uid>:<gid>'."))?;
+        let uid = uid
+            .parse()
+            .with_context(|| format!("Invalid uid: {:?}.", uid))?;
+        let gid = gid
+            .parse()
+            .with_context(|| format!("Invalid gid: {:?}.", gid))?;
+        Ok(UserNamespace::root_to(
+            Uid::from_raw(uid),
+            Gid::from_raw(gid),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BindMountOptions {
+    /// Make the bind mount read-only. Note that the submounts of a recursive bind stay writable.
+    pub read_only: bool,
+    /// Bind the whole subtree of the source including its submounts by MS_REC.
+    pub recursive: bool,
+}
+
 impl ContainerLauncher {
     pub fn new() -> Self {
         ContainerLauncher::default()
@@ -76,6 +190,23 @@ impl ContainerLauncher {
         self
     }
 
+    pub fn with_bind_mount(
+        &mut self,
+        source: HostPath,
+        target: ContainerPath,
+        options: BindMountOptions,
+        is_file: bool,
+    ) -> &mut Self {
+        let mut flags = MsFlags::MS_BIND;
+        if options.read_only {
+            flags |= MsFlags::MS_RDONLY;
+        }
+        if options.recursive {
+            flags |= MsFlags::MS_REC;
+        }
+        self.with_mount(Some(source), target, None, flags, None, is_file)
+    }
+
     pub fn with_init_arg<O: AsRef<OsStr>>(&mut self, arg: O) -> &mut Self {
         self.init_args.push(arg.as_ref().to_owned());
         self
@@ -91,6 +222,13 @@ impl ContainerLauncher {
         self
     }
 
+    /// Run the container in a new user namespace with the ID maps. Note that Systemd may fail to
+    /// start some services in it since they lose the capabilities over the host's resources.
+    pub fn with_user_namespace(&mut self, user_namespace: UserNamespace) -> &mut Self {
+        self.user_namespace = Some(user_namespace);
+        self
+    }
+
     /// # Safety
     /// See the notes and safety of https://doc.rust-lang.org/std/os/unix/process/trait.CommandExt.html#tymethod.pre_exec
     /// In addition, note that registered pre_exec closures will run after the rootfs is set up including tmpfs such as /run.
@@ -103,58 +241,106 @@ impl ContainerLauncher {
     }
 
     pub fn launch<S: AsRef<OsStr>>(
-        mut self,
+        self,
         init: S,
         rootfs: HostPath,
         old_root: ContainerPath,
+    ) -> Result<Container> {
+        self.launch_with_optional_init(Some(init.as_ref()), rootfs, old_root)
+    }
+
+    /// Launch the container whose PID 1 is not an init system but a process which only reaps
+    /// the orphaned processes. The container exits when it receives SIGINT or SIGTERM.
+    pub fn launch_without_init(
+        self,
+        rootfs: HostPath,
+        old_root: ContainerPath,
+    ) -> Result<Container> {
+        self.launch_with_optional_init(None, rootfs, old_root)
+    }
+
+    fn launch_with_optional_init(
+        mut self,
+        init: Option<&OsStr>,
+        rootfs: HostPath,
+        old_root: ContainerPath,
     ) -> Result<Container> {
         let (fd_channel_host, fd_channel_child) = UnixStream::pair()?;
+        let (userns_channel_host, userns_channel_child) = UnixStream::pair()?;
+        let user_namespace = self.user_namespace.take();
+        let uses_user_namespace = user_namespace.is_some();
+        let fd_channel_child_fd = fd_channel_child.as_raw_fd();
         {
-            let mut command = Command::new(&init);
-            // The init must not inherit environment variables from the parent process which may
-            // be launcehd by a non-root user.
-            command.env_clear();
-            command.args(&self.init_args);
-            command.envs(self.init_envs.iter().map(|(k, v)| (k, v)));
-            let mut command = CommandByMultiFork::new(command);
-            let fds_to_keep = vec![fd_channel_child.as_raw_fd()];
+            // The command of the init must be built before self is moved into prepare_init.
+            let init_command = init.map(|init| {
+                let mut command = Command::new(init);
+                // The init must not inherit environment variables from the parent process which
+                // may be launcehd by a non-root user.
+                command.env_clear();
+                command.args(&self.init_args);
+                command.envs(self.init_envs.iter().map(|(k, v)| (k, v)));
+                command
+            });
+            let mut prepare_init = move || {
+                let mut inner = || -> Result<()> {
+                    let procfile =
+                        ProcFile::current_proc().with_context(|| "Failed to make a ProcFile.")?;
+                    fd_channel_child
+                        .send_fd(procfile.as_raw_fd())
+                        .with_context(|| "Failed to do send_fd.")?;
+                    drop(procfile);
+
+                    self.prepare_filesystem(&rootfs, &old_root)
+                        .with_context(|| "Failed to initialize the container's filesystem.")?;
+
+                    for pre_exec_closure in &mut self.pre_exec_closures {
+                        pre_exec_closure().with_context(|| {
+                            "a registered pre_exec closure of the init process failed."
+                        })?;
+                    }
+                    Ok(())
+                };
+                if let Err(err) = inner().with_context(|| "Failed to send pidfd.") {
+                    log::error!("{:?}", err);
+                    std::process::exit(1);
+                }
+                Ok::<(), std::io::Error>(())
+            };
+            let mut command = match init_command {
+                Some(init_command) => {
+                    let mut command = CommandByMultiFork::new(init_command);
+                    unsafe {
+                        command.pre_exec(prepare_init);
+                    }
+                    command
+                }
+                None => CommandByMultiFork::new_with_function(move || {
+                    prepare_init()?;
+                    close_cloexec_fds();
+                    run_minimal_init()
+                }),
+            };
+            let fds_to_keep = vec![fd_channel_child_fd, userns_channel_child.as_raw_fd()];
             command.pre_second_fork(move || {
                 daemonize(&fds_to_keep)
                     .with_context(|| "The container failed to be daemonized.")?;
-                enter_new_namespace().with_context(|| "Failed to initialize Linux namespaces.")?;
+                enter_new_namespace(uses_user_namespace)
+                    .with_context(|| "Failed to initialize Linux namespaces.")?;
+                if uses_user_namespace {
+                    wait_for_id_maps(&userns_channel_child)
+                        .with_context(|| "Failed to set up the user namespace.")?;
+                }
                 Ok(())
             });
-            unsafe {
-                command.pre_exec(move || {
-                    let mut inner = || -> Result<()> {
-                        let procfile = ProcFile::current_proc()
-                            .with_context(|| "Failed to make a ProcFile.")?;
-                        fd_channel_child
-                            .send_fd(procfile.as_raw_fd())
-                            .with_context(|| "Failed to do send_fd.")?;
-                        drop(procfile);
-
-                        self.prepare_filesystem(&rootfs, &old_root)
-                            .with_context(|| "Failed to initialize the container's filesystem.")?;
-
-                        for pre_exec_closure in &mut self.pre_exec_closures {
-                            pre_exec_closure().with_context(|| {
-                                "a registered pre_exec closure of the init process failed."
-                            })?;
-                        }
-                        Ok(())
-                    };
-                    if let Err(err) = inner().with_context(|| "Failed to send pidfd.") {
-                        log::error!("{:?}", err);
-                        std::process::exit(1);
-                    }
-                    Ok(())
-                });
-            }
             command
                 .spawn()
                 .with_context(|| "Failed to spawn the init process.")?;
         };
+        if let Some(user_namespace) = user_namespace {
+            user_namespace
+                .write_id_maps(&userns_channel_host)
+                .with_context(|| "Failed to write the ID maps of the user namespace.")?;
+        }
 
         let procfile_fd = fd_channel_host
             .recv_fd()
@@ -177,6 +363,12 @@ impl ContainerLauncher {
         } else {
             prepare_minimum_root(new_root, old_root)?;
             self.process_mounts(old_root)?;
+            let mount_entries =
+                get_mount_entries().with_context(|| "Failed to retrieve mount entries")?;
+            // This has to be done after the mounts because /sys may be bind-mounted by them.
+            // On the host base root, the host's /sys/fs/cgroup is visible as it is.
+            mount_cgroup_hierarchy(old_root, &mount_entries)
+                .with_context(|| "Failed to mount the cgroup hierarchy.")?;
             let mount_entries =
                 get_mount_entries().with_context(|| "Failed to retrieve mount entries")?;
             umount_host_mountpoints(old_root, &mount_entries)?;
@@ -193,20 +385,76 @@ impl ContainerLauncher {
                 log::trace!("skipping an identical mount: {:#?}, {:#?}", source, mount);
                 continue;
             }
+            if let (true, Some(source)) = (mount.flags.contains(MsFlags::MS_BIND), source.as_ref())
+            {
+                let mountinfo_entries = get_mountinfo_entries()
+                    .with_context(|| "Failed to retrieve mountinfo entries")?;
+                if is_identically_mounted(&mountinfo_entries, source, &mount.target) {
+                    log::trace!("skipping an already mounted target: {:#?}", mount);
+                    continue;
+                }
+            }
             log::trace!("mounting source: {:#?}, mount: {:?}", &source, &mount);
-            nix::mount::mount(
-                source.as_ref().map(|p| p.as_path()),
-                mount.target.as_path(),
-                mount.fstype.as_deref(),
-                mount.flags,
-                mount.data.as_deref(),
-            )
-            .with_context(|| format!("Failed to mount {:?}", &mount))?;
+            for op in mount.to_mount_operations(source) {
+                nix::mount::mount(
+                    op.source.as_ref().map(|p| p.as_path()),
+                    op.target.as_path(),
+                    op.fstype.as_deref(),
+                    op.flags,
+                    op.data.as_deref(),
+                )
+                .with_context(|| format!("Failed to mount {:?} by {:?}", &mount, &op))?;
+            }
         }
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+struct MountOperation {
+    source: Option<ContainerPath>,
+    target: ContainerPath,
+    fstype: Option<OsString>,
+    flags: MsFlags,
+    data: Option<OsString>,
+}
+
+impl ContainerMount {
+    /// The sequence of mount(2) calls to set up this mount.
+    /// MS_RDONLY is ignored when a bind mount is created, so a read-only bind
+    /// needs to be remounted after the bind.
+    fn to_mount_operations(&self, source: Option<ContainerPath>) -> Vec<MountOperation> {
+        let is_read_only_bind = self.flags.contains(MsFlags::MS_BIND | MsFlags::MS_RDONLY)
+            && !self.flags.contains(MsFlags::MS_REMOUNT);
+        if !is_read_only_bind {
+            return vec![MountOperation {
+                source,
+                target: self.target.clone(),
+                fstype: self.fstype.clone(),
+                flags: self.flags,
+                data: self.data.clone(),
+            }];
+        }
+        let remount_flags = (self.flags - MsFlags::MS_REC) | MsFlags::MS_REMOUNT;
+        vec![
+            MountOperation {
+                source,
+                target: self.target.clone(),
+                fstype: self.fstype.clone(),
+                flags: self.flags - MsFlags::MS_RDONLY,
+                data: self.data.clone(),
+            },
+            MountOperation {
+                source: None,
+                target: self.target.clone(),
+                fstype: None,
+                flags: remount_flags,
+                data: None,
+            },
+        ]
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MountSource {
     Host(HostPath),
@@ -258,6 +506,58 @@ impl Container {
     }
 }
 
+/// Close the file descriptors as exec does, for the init process which doesn't exec.
+fn close_cloexec_fds() {
+    for fd in 0..=255 {
+        let flags = nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD);
+        if let Ok(flags) = flags {
+            if nix::fcntl::FdFlag::from_bits_truncate(flags)
+                .contains(nix::fcntl::FdFlag::FD_CLOEXEC)
+            {
+                let _ = nix::unistd::close(fd);
+            }
+        }
+    }
+}
+
+/// The main loop of the PID 1 of a container without an init system.
+/// It reaps the orphaned processes until it receives SIGINT or SIGTERM.
+fn run_minimal_init() -> Result<()> {
+    use nix::sys::signal::{self, SigHandler, SigSet, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+
+    let handled_signals = [Signal::SIGCHLD, Signal::SIGINT, Signal::SIGTERM];
+    let mut signals = SigSet::empty();
+    for sig in &handled_signals {
+        signals.add(*sig);
+    }
+    signals
+        .thread_block()
+        .with_context(|| "Failed to block the signals.")?;
+    for &sig in &handled_signals {
+        // PID 1 of a PID namespace receives only the signals it has handlers for.
+        unsafe { signal::signal(sig, SigHandler::Handler(do_nothing_on_signal)) }
+            .with_context(|| format!("Failed to set the handler for {:?}.", sig))?;
+    }
+    loop {
+        match signals
+            .wait()
+            .with_context(|| "Failed to wait for signals.")?
+        {
+            Signal::SIGCHLD => {
+                while let Ok(status) = waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    if status == WaitStatus::StillAlive {
+                        break;
+                    }
+                }
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+extern "C" fn do_nothing_on_signal(_sig: nix::libc::c_int) {}
+
 fn daemonize(fds_to_keep: &[i32]) -> Result<()> {
     nix::unistd::setsid().with_context(|| "Failed to setsid().")?;
     for i in 1..=255 {
@@ -273,6 +573,16 @@ fn daemonize(fds_to_keep: &[i32]) -> Result<()> {
 }
 
 fn enter_namespace(proc: &ProcFile) -> Result<()> {
+    // The other namespaces are owned by the user namespace of the container if it has its own.
+    // So enter it first to have the capabilities over them.
+    let user_ns_file = proc.open_file_at("ns/user")?;
+    let current_user_ns = fs::metadata("/proc/self/ns/user")
+        .with_context(|| "Failed to get the current user namespace.")?;
+    if user_ns_file.metadata()?.ino() != current_user_ns.ino() {
+        nix::sched::setns(user_ns_file.as_raw_fd(), CloneFlags::CLONE_NEWUSER)
+            .with_context(|| "Setns(ns/user) failed.")?;
+        become_root_in_user_namespace()?;
+    }
     for ns in &["ns/uts", "ns/pid", "ns/mnt"] {
         let ns_file = proc.open_file_at(ns)?;
         nix::sched::setns(ns_file.as_raw_fd(), CloneFlags::empty())
@@ -281,10 +591,37 @@ fn enter_namespace(proc: &ProcFile) -> Result<()> {
     Ok(())
 }
 
-fn enter_new_namespace() -> Result<()> {
-    nix::sched::unshare(
-        CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWUTS,
-    )?;
+fn enter_new_namespace(uses_user_namespace: bool) -> Result<()> {
+    let mut flags = CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID | CloneFlags::CLONE_NEWUTS;
+    if uses_user_namespace {
+        // The user namespace is created first, and it owns the other new namespaces.
+        flags |= CloneFlags::CLONE_NEWUSER;
+    }
+    nix::sched::unshare(flags)?;
+    Ok(())
+}
+
+/// Let the parent process write the ID maps of the new user namespace, and become the root in it.
+fn wait_for_id_maps(channel: &UnixStream) -> Result<()> {
+    let mut channel = channel;
+    channel
+        .write_all(&std::process::id().to_ne_bytes())
+        .with_context(|| "Failed to send the pid.")?;
+    let mut done = [0u8; 1];
+    channel
+        .read_exact(&mut done)
+        .with_context(|| "The ID maps were not written.")?;
+    become_root_in_user_namespace()
+}
+
+fn become_root_in_user_namespace() -> Result<()> {
+    let root_uid = Uid::from_raw(0);
+    let root_gid = Gid::from_raw(0);
+    nix::unistd::setgroups(&[]).with_context(|| "Failed to clear the groups.")?;
+    nix::unistd::setresgid(root_gid, root_gid, root_gid)
+        .with_context(|| "Failed to be the root group in the user namespace.")?;
+    nix::unistd::setresuid(root_uid, root_uid, root_uid)
+        .with_context(|| "Failed to be the root in the user namespace.")?;
     Ok(())
 }
 
@@ -346,6 +683,63 @@ fn prepare_minimum_root(new_root: &HostPath, old_root: &ContainerPath) -> Result
     Ok(())
 }
 
+/// Mount the cgroup hierarchy at /sys/fs/cgroup of the container so that systemd in the container
+/// doesn't have to set it up by itself under the host's cgroup setup.
+fn mount_cgroup_hierarchy(old_root: &ContainerPath, mount_entries: &[MountEntry]) -> Result<()> {
+    let cgroup_root = Path::new("/sys/fs/cgroup");
+    if !cgroup_root.exists() {
+        log::debug!("/sys is not mounted in the container. Not mounting the cgroup hierarchy.");
+        return Ok(());
+    }
+    if is_mountpoint(mount_entries, cgroup_root) {
+        log::debug!("/sys/fs/cgroup is already mounted in the container.");
+        return Ok(());
+    }
+    let host_cgroup_root = old_root.join("sys/fs/cgroup");
+    match detect_cgroup_version(mount_entries, &host_cgroup_root) {
+        Some(CgroupVersion::V2) => {
+            // A new cgroup2 mount fails without a cgroup namespace in a user namespace, so fall back
+            // to the host's hierarchy.
+            let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+            let result = nix::mount::mount::<Path, Path, Path, Path>(
+                None,
+                cgroup_root,
+                Some("cgroup2".as_ref()),
+                flags,
+                None,
+            );
+            if let Err(err) = result {
+                log::debug!("Failed to mount a new cgroup2 filesystem. {}", err);
+                bind_mount_recursively(&host_cgroup_root, cgroup_root)?;
+            }
+        }
+        Some(CgroupVersion::V1) => {
+            bind_mount_recursively(&host_cgroup_root, cgroup_root)?;
+        }
+        None => {
+            log::debug!("The host has no cgroup hierarchy at /sys/fs/cgroup.");
+        }
+    }
+    Ok(())
+}
+
+fn bind_mount_recursively<P1: AsRef<Path>, P2: AsRef<Path>>(source: P1, target: P2) -> Result<()> {
+    nix::mount::mount::<Path, Path, Path, Path>(
+        Some(source.as_ref()),
+        target.as_ref(),
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to bind mount {:?} on {:?}.",
+            source.as_ref(),
+            target.as_ref()
+        )
+    })
+}
+
 fn mount_nosource_fs<P: AsRef<Path>>(path: P, fstype: &str) -> Result<()> {
     create_mountpoint_unless_exist(path.as_ref(), false)?;
     nix::mount::mount::<Path, Path, Path, Path>(
@@ -404,12 +798,7 @@ fn create_mountpoint_unless_exist<P: AsRef<Path>>(path: P, is_file: bool) -> Res
 
 #[allow(clippy::unnecessary_wraps)]
 fn umount_host_mountpoints(old_root: &ContainerPath, mount_entries: &[MountEntry]) -> Result<()> {
-    let mut mount_paths: Vec<&PathBuf> = mount_entries.iter().map(|e| &e.path).collect();
-    mount_paths.sort_by_key(|b| std::cmp::Reverse(b.len())); // reverse sort
-    for mount_path in mount_paths {
-        if !mount_path.starts_with(&old_root) || mount_path.as_path() == old_root.as_path() {
-            continue;
-        }
+    for mount_path in get_host_mountpoints_under_old_root(old_root, mount_entries) {
         let err = nix::mount::umount(mount_path.as_path());
         if err.is_err() {
             log::warn!(
@@ -422,6 +811,20 @@ fn umount_host_mountpoints(old_root: &ContainerPath, mount_entries: &[MountEntry
     Ok(())
 }
 
+/// Get the mount points under the old root except the old root itself, the deepest ones first.
+fn get_host_mountpoints_under_old_root<'a>(
+    old_root: &ContainerPath,
+    mount_entries: &'a [MountEntry],
+) -> Vec<&'a PathBuf> {
+    let mut mount_paths: Vec<&PathBuf> = mount_entries
+        .iter()
+        .map(|e| &e.path)
+        .filter(|path| path.starts_with(&old_root) && path.as_path() != old_root.as_path())
+        .collect();
+    mount_paths.sort_by_key(|b| std::cmp::Reverse(b.len())); // reverse sort
+    mount_paths
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ContainerPath(PathBuf);
 
@@ -444,6 +847,51 @@ impl ContainerPath {
         );
         HostPath(host_path)
     }
+
+    /// Get the host path following the symlinks in all the components as if the rootfs is the
+    /// root directory, so that the result never goes out of the rootfs. The components which
+    /// don't exist are left as they are. None if there are too many levels of symlinks.
+    pub fn resolve_in_rootfs(&self, container_rootfs: &HostPath) -> Option<HostPath> {
+        // The same limit as Linux's MAXSYMLINKS
+        const MAX_SYMLINKS: usize = 40;
+        let parent_dir = OsString::from("..");
+        let names_of = |path: &Path| -> Vec<OsString> {
+            path.components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_owned()),
+                    Component::ParentDir => Some(parent_dir.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut pending: VecDeque<OsString> = names_of(&self.0).into();
+        let mut resolved = PathBuf::new();
+        let mut n_symlinks = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == parent_dir {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&name);
+            match fs::read_link(container_rootfs.join(&candidate)) {
+                Ok(target) => {
+                    n_symlinks += 1;
+                    if n_symlinks > MAX_SYMLINKS {
+                        return None;
+                    }
+                    if target.has_root() {
+                        resolved = PathBuf::new();
+                    }
+                    for name in names_of(&target).into_iter().rev() {
+                        pending.push_front(name);
+                    }
+                }
+                // Not a symlink, or doesn't exist.
+                Err(_) => resolved = candidate,
+            }
+        }
+        Some(HostPath(container_rootfs.join(resolved)))
+    }
 }
 
 impl AsRef<Path> for ContainerPath {
@@ -519,3 +967,227 @@ impl DerefMut for HostPath {
         &mut self.0
     }
 }
+
+#[cfg(test)]
+mod test_container_mount {
+    use super::*;
+
+    fn bind_mount(flags: MsFlags) -> ContainerMount {
+        ContainerMount {
+            source: Some(HostPath::new("/src").unwrap()),
+            target: ContainerPath::new("/dst").unwrap(),
+            fstype: None,
+            flags,
+            data: None,
+            is_file: false,
+        }
+    }
+
+    #[test]
+    fn test_bind_mount_operations() {
+        let source = Some(ContainerPath::new("/mnt/distrod_root/src").unwrap());
+        let ops =
+            bind_mount(MsFlags::MS_BIND | MsFlags::MS_REC).to_mount_operations(source.clone());
+        assert_eq!(1, ops.len());
+        assert_eq!(source, ops[0].source);
+        assert_eq!(MsFlags::MS_BIND | MsFlags::MS_REC, ops[0].flags);
+    }
+
+    #[test]
+    fn test_read_only_bind_mount_operations() {
+        let source = Some(ContainerPath::new("/mnt/distrod_root/src").unwrap());
+        let ops = bind_mount(MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_REC)
+            .to_mount_operations(source.clone());
+        assert_eq!(
+            vec![
+                MountOperation {
+                    source,
+                    target: ContainerPath::new("/dst").unwrap(),
+                    fstype: None,
+                    flags: MsFlags::MS_BIND | MsFlags::MS_REC,
+                    data: None,
+                },
+                MountOperation {
+                    source: None,
+                    target: ContainerPath::new("/dst").unwrap(),
+                    fstype: None,
+                    flags: MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                    data: None,
+                }
+            ],
+            ops
+        );
+    }
+
+    #[test]
+    fn test_with_bind_mount() {
+        let mut launcher = ContainerLauncher::new();
+        launcher.with_bind_mount(
+            HostPath::new("/src").unwrap(),
+            ContainerPath::new("/dst").unwrap(),
+            BindMountOptions {
+                read_only: true,
+                recursive: true,
+            },
+            false,
+        );
+        assert_eq!(
+            MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_REC,
+            launcher.mounts[0].flags
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_container_path {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_resolve_in_rootfs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        fs::write(root.join("usr/lib/os-release"), "ID=debian\n").unwrap();
+        fs::create_dir(root.join("etc")).unwrap();
+        symlink("../usr/lib/os-release", root.join("etc/os-release")).unwrap();
+        symlink("/usr/lib", root.join("lib")).unwrap();
+        symlink("../../../../..", root.join("etc/up")).unwrap();
+        symlink("loop", root.join("loop")).unwrap();
+        let rootfs = HostPath::new(root).unwrap();
+        let resolve = |path: &str| {
+            ContainerPath::new(path)
+                .unwrap()
+                .resolve_in_rootfs(&rootfs)
+                .map(|host_path| host_path.as_path().to_owned())
+        };
+
+        assert_eq!(
+            Some(root.join("usr/lib/os-release")),
+            resolve("/etc/os-release")
+        );
+        assert_eq!(
+            Some(root.join("usr/lib/os-release")),
+            resolve("/lib/os-release")
+        );
+        // The parent of the root is the root.
+        assert_eq!(Some(root.join("usr/lib")), resolve("/etc/up/lib"));
+        assert_eq!(Some(root.join("usr/lib/none/x")), resolve("/lib/none/x"));
+        assert_eq!(None, resolve("/loop"));
+    }
+}
+
+#[cfg(test)]
+mod test_old_root {
+    use super::*;
+    use crate::distrod_config::{
+        resolve_distro_old_root_path, test_distrod_config::config_with_old_root_path,
+    };
+    use crate::mount_info::test_mount_info::mount_entry;
+
+    #[test]
+    fn test_host_mountpoints_under_configured_old_root() {
+        let config = config_with_old_root_path("/mnt/custom_root");
+        let old_root =
+            ContainerPath::new(resolve_distro_old_root_path(Some(&config)).unwrap()).unwrap();
+        let entries = vec![
+            mount_entry("none", "/mnt/custom_root", "tmpfs"),
+            mount_entry("none", "/mnt/custom_root/mnt/c", "tmpfs"),
+            mount_entry("none", "/mnt/custom_root/mnt/c/sub", "tmpfs"),
+            mount_entry("none", "/mnt/distrod_root/proc", "tmpfs"),
+            mount_entry("none", "/mnt/custom_rootfs", "tmpfs"),
+            mount_entry("none", "/proc", "tmpfs"),
+        ];
+        assert_eq!(
+            vec![
+                &PathBuf::from("/mnt/custom_root/mnt/c/sub"),
+                &PathBuf::from("/mnt/custom_root/mnt/c"),
+            ],
+            get_host_mountpoints_under_old_root(&old_root, &entries)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_user_namespace {
+    use super::*;
+    use std::os::unix::process::CommandExt;
+    use std::process::Stdio;
+
+    #[test]
+    fn test_parse_user_namespace() {
+        assert_eq!(
+            UserNamespace::identity(),
+            "identity".parse::<UserNamespace>().unwrap()
+        );
+        assert_eq!(
+            UserNamespace::root_to(Uid::from_raw(1000), Gid::from_raw(100)),
+            "1000:100".parse::<UserNamespace>().unwrap()
+        );
+        assert!("1000".parse::<UserNamespace>().is_err());
+        assert!("root:root".parse::<UserNamespace>().is_err());
+    }
+
+    /// Writing the id maps of a user namespace requires CAP_SETUID and CAP_SETGID.
+    fn can_write_id_maps() -> bool {
+        if nix::unistd::geteuid().is_root() {
+            return true;
+        }
+        eprintln!("Skipping the test, which requires the root privilege.");
+        false
+    }
+
+    fn read_id_maps_in_user_namespace(user_namespace: &UserNamespace) -> (String, String) {
+        let (channel_host, channel_child) = UnixStream::pair().unwrap();
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "cat /proc/self/uid_map; echo; cat /proc/self/gid_map"])
+            .stdout(Stdio::piped());
+        unsafe {
+            command.pre_exec(move || {
+                let inner = || -> Result<()> {
+                    nix::sched::unshare(CloneFlags::CLONE_NEWUSER)?;
+                    wait_for_id_maps(&channel_child)
+                };
+                if let Err(err) = inner() {
+                    eprintln!("{:?}", err);
+                    std::process::exit(1);
+                }
+                Ok(())
+            });
+        }
+        // spawn() doesn't return until the child execs, so write the maps from another thread.
+        let user_namespace = user_namespace.clone();
+        let writer = std::thread::spawn(move || user_namespace.write_id_maps(&channel_host));
+        let output = command.output().unwrap();
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+        let normalize = |map: &str| map.split_whitespace().collect::<Vec<_>>().join(" ");
+        let (uid_map, gid_map) = output.split_once("\n\n").unwrap();
+        (normalize(uid_map), normalize(gid_map))
+    }
+
+    #[test]
+    fn test_id_maps_of_root_to_user() {
+        if !can_write_id_maps() {
+            return;
+        }
+        let user_namespace = UserNamespace::root_to(Uid::from_raw(1000), Gid::from_raw(100));
+        assert_eq!(
+            ("0 1000 1".to_owned(), "0 100 1".to_owned()),
+            read_id_maps_in_user_namespace(&user_namespace)
+        );
+    }
+
+    #[test]
+    fn test_id_maps_of_identity() {
+        if !can_write_id_maps() {
+            return;
+        }
+        assert_eq!(
+            ("0 0 4294967295".to_owned(), "0 0 4294967295".to_owned()),
+            read_id_maps_in_user_namespace(&UserNamespace::identity())
+        );
+    }
+}
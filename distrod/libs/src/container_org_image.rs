@@ -2,14 +2,38 @@ use crate::distro_image::{
     DefaultImageFetcher, DistroImage, DistroImageFetcher, DistroImageFile, DistroImageList,
     ListChooseFn,
 };
+use crate::distrod_config;
+use crate::http_client;
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 static LINUX_CONTAINERS_ORG_BASE: &str = "https://images.linuxcontainers.org/";
+/// Known mirrors of the linuxcontainers.org image server, which can be selected by the name.
+pub static LINUX_CONTAINERS_ORG_MIRRORS: &[(&str, &str)] = &[
+    ("default", "https://images.linuxcontainers.org/"),
+    ("uk", "https://uk.images.linuxcontainers.org/"),
+    ("us", "https://us.images.linuxcontainers.org/"),
+];
+/// The architectures of the images which Distrod supports.
+pub static SUPPORTED_ARCHITECTURES: &[&str] = &["amd64", "arm64", "armhf"];
+const FILE_LIST_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+/// The maximum length of the cache file names without the extension, which leaves room for
+/// ".html" and ".tmp" in the 255 bytes of a file name.
+const MAX_CACHE_FILE_STEM_LEN: usize = 240;
+/// The machine-readable index of all the images on the server, whose lines are
+/// "distro;release;arch;variant;build date;path".
+const INDEX_SYSTEM_URL: &str = "meta/1.0/index-system";
 
 pub async fn fetch_container_org_image(choose_from_list: ListChooseFn<'_>) -> Result<DistroImage> {
-    let mut distro_image_list = Box::new(ContainerOrgImageList {}) as Box<dyn DistroImageFetcher>;
+    let mut distro_image_list =
+        Box::new(ContainerOrgImageList::default()) as Box<dyn DistroImageFetcher>;
     loop {
         let fetched_image_list = distro_image_list.fetch().await?;
         match fetched_image_list {
@@ -23,8 +47,76 @@ pub async fn fetch_container_org_image(choose_from_list: ListChooseFn<'_>) -> Re
     }
 }
 
-#[derive(Default)]
-pub struct ContainerOrgImageList;
+#[derive(Default, Clone, Debug)]
+pub struct ContainerOrgImageList {
+    config: FetchConfig,
+}
+
+impl ContainerOrgImageList {
+    /// Ignore the cached listings and fetch them again.
+    pub fn with_cache_refreshed(&mut self, refreshes_cache: bool) -> &mut Self {
+        self.config.refreshes_cache = refreshes_cache;
+        self
+    }
+
+    /// Fetch the images for the architecture, which is one of `SUPPORTED_ARCHITECTURES`.
+    pub fn with_arch(&mut self, arch: &str) -> Result<&mut Self> {
+        if !SUPPORTED_ARCHITECTURES.contains(&arch) {
+            bail!(
+                "Unsupported architecture '{}'. Supported architectures: {}.",
+                arch,
+                SUPPORTED_ARCHITECTURES.join(", ")
+            );
+        }
+        self.config.arch = arch.to_owned();
+        Ok(self)
+    }
+
+    /// Fetch the images from the mirror, which is either a name in `LINUX_CONTAINERS_ORG_MIRRORS`
+    /// or the URL of the mirror.
+    pub fn with_mirror(&mut self, mirror: &str) -> Result<&mut Self> {
+        if let Some((_, url)) = LINUX_CONTAINERS_ORG_MIRRORS
+            .iter()
+            .find(|(name, _)| *name == mirror)
+        {
+            self.config.base_url = url.to_string();
+            return Ok(self);
+        }
+        if !mirror.starts_with("https://") && !mirror.starts_with("http://") {
+            bail!(
+                "'{}' is neither a known mirror nor a URL. Known mirrors: {}.",
+                mirror,
+                LINUX_CONTAINERS_ORG_MIRRORS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if !mirror.ends_with('/') {
+            bail!("The mirror URL '{}' should end with '/'.", mirror);
+        }
+        self.config.base_url = mirror.to_owned();
+        Ok(self)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FetchConfig {
+    base_url: String,
+    arch: String,
+    refreshes_cache: bool,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        FetchConfig {
+            base_url: LINUX_CONTAINERS_ORG_BASE.to_owned(),
+            arch: "amd64".to_owned(),
+            refreshes_cache: false,
+        }
+    }
+}
 
 #[async_trait]
 impl DistroImageFetcher for ContainerOrgImageList {
@@ -33,7 +125,7 @@ impl DistroImageFetcher for ContainerOrgImageList {
     }
 
     async fn fetch(&self) -> Result<DistroImageList> {
-        let distros: Vec<_> = fetch_apache_file_list("images/")
+        let distros: Vec<_> = fetch_file_list(&self.config, "images/")
             .await
             .map(|links| {
                 links
@@ -42,6 +134,7 @@ impl DistroImageFetcher for ContainerOrgImageList {
                         Box::new(ContainerOrgDistroVersionList {
                             name: link.name,
                             version_list_url: format!("images/{}", link.url),
+                            config: self.config.clone(),
                         }) as Box<dyn DistroImageFetcher>
                     })
                     .collect()
@@ -62,6 +155,7 @@ impl DistroImageFetcher for ContainerOrgImageList {
 pub struct ContainerOrgDistroVersionList {
     name: String,
     version_list_url: String,
+    config: FetchConfig,
 }
 
 #[async_trait]
@@ -71,7 +165,7 @@ impl DistroImageFetcher for ContainerOrgDistroVersionList {
     }
 
     async fn fetch(&self) -> Result<DistroImageList> {
-        let mut links = fetch_apache_file_list(&self.version_list_url)
+        let mut links = fetch_file_list(&self.config, &self.version_list_url)
             .await
             .with_context(|| "Failed to parse the version list.")?;
         links.sort_by(|a, b| a.last_modified.cmp(&b.last_modified));
@@ -82,6 +176,7 @@ impl DistroImageFetcher for ContainerOrgDistroVersionList {
                     distro_name: self.name.clone(),
                     version_name: link.name,
                     platform_list_url: format!("{}{}", self.version_list_url, link.url),
+                    config: self.config.clone(),
                 }) as Box<dyn DistroImageFetcher>
             })
             .collect();
@@ -103,6 +198,7 @@ pub struct ContainerOrgDistroVersion {
     distro_name: String,
     version_name: String,
     platform_list_url: String,
+    config: FetchConfig,
 }
 
 #[async_trait]
@@ -112,23 +208,38 @@ impl DistroImageFetcher for ContainerOrgDistroVersion {
     }
 
     async fn fetch(&self) -> Result<DistroImageList> {
-        let variant = match self.distro_name.as_str() {
-            "gentoo" => "amd64/systemd",
-            _ => "amd64/default",
+        let variant = get_variant(&self.distro_name, &self.config.arch);
+        let dates = fetch_file_list(
+            &self.config,
+            &format!("{}{}", &self.platform_list_url, variant),
+        )
+        .await;
+        let mut dates = match dates {
+            Ok(dates) => dates,
+            Err(e) => {
+                let available_arches = fetch_file_list(&self.config, &self.platform_list_url)
+                    .await
+                    .map(|arches| {
+                        arches
+                            .into_iter()
+                            .map(|arch| arch.name)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .unwrap_or_else(|_| "unknown".to_owned());
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to get the image for {}. Perhaps '{}{}' is not found? Available architectures: {}.",
+                        variant, &self.platform_list_url, variant, available_arches
+                    )
+                });
+            }
         };
-        let mut dates = fetch_apache_file_list(&format!("{}{}", &self.platform_list_url, variant))
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to get the image for {}. Perhaps '{}{}' is not found?",
-                    variant, &self.platform_list_url, variant
-                )
-            })?;
         dates.sort_by(|a, b| b.last_modified.cmp(&a.last_modified));
         let latest = &dates[0];
         let rootfs_url = format!(
             "{}{}{}/{}rootfs.tar.xz",
-            &LINUX_CONTAINERS_ORG_BASE, &self.platform_list_url, variant, latest.url
+            &self.config.base_url, &self.platform_list_url, variant, latest.url
         );
         Ok(DistroImageList::Image(DistroImage {
             name: format!("{}-{}", &self.distro_name, &self.version_name),
@@ -137,20 +248,169 @@ impl DistroImageFetcher for ContainerOrgDistroVersion {
     }
 }
 
-async fn fetch_apache_file_list(relative_url: &str) -> Result<Vec<FileOnApache>> {
-    let url = LINUX_CONTAINERS_ORG_BASE.to_owned() + relative_url;
+fn get_variant(distro_name: &str, arch: &str) -> String {
+    match distro_name {
+        "gentoo" => format!("{}/systemd", arch),
+        _ => format!("{}/default", arch),
+    }
+}
+
+/// List the files in the directory of the server from the index of the images, or from the
+/// Apache directory listing if the index is unavailable.
+async fn fetch_file_list(config: &FetchConfig, relative_url: &str) -> Result<Vec<FileOnApache>> {
+    match fetch_cached(config, INDEX_SYSTEM_URL, parse_index_system).await {
+        Ok(index) => list_index_dir(&index, relative_url),
+        Err(e) => {
+            log::debug!(
+                "The image index is unavailable. Falling back to the directory listings. {:?}",
+                e
+            );
+            fetch_cached(config, relative_url, |body| {
+                parse_apache_file_list(relative_url, body)
+            })
+            .await
+        }
+    }
+}
+
+/// Fetch the page and parse it, using the cached page if it's fresh and parsable.
+async fn fetch_cached<T, F>(config: &FetchConfig, relative_url: &str, parse: F) -> Result<T>
+where
+    F: Fn(&str) -> Result<T>,
+{
+    let cache_dirs = get_file_list_cache_dirs();
+    let url = config.base_url.clone() + relative_url;
+    if !config.refreshes_cache {
+        let cached_body = cache_dirs
+            .iter()
+            .find_map(|cache_dir| read_file_list_cache(cache_dir, &url, FILE_LIST_CACHE_TTL));
+        if let Some(cached_body) = cached_body {
+            match parse(&cached_body) {
+                Ok(parsed) => return Ok(parsed),
+                Err(e) => log::debug!("Ignoring the broken cache of {}. {:?}", relative_url, e),
+            }
+        }
+    }
+
+    log::info!("Fetching from {}...", &config.base_url);
+    let client = http_client::build_http_client()?;
+    let body = http_client::get_with_retry(&client, &url)
+        .await?
+        .text()
+        .await
+        .with_context(|| format!("Failed to get the text of {}", &url))?;
+    let parsed = parse(&body)?;
+    let is_cached = cache_dirs.iter().any(|cache_dir| {
+        write_file_list_cache(cache_dir, &url, &body)
+            .map_err(|e| log::debug!("Failed to cache the file list of {}. {:?}", relative_url, e))
+            .is_ok()
+    });
+    if !is_cached {
+        log::debug!("Skipped caching the file list of {}.", relative_url);
+    }
+    Ok(parsed)
+}
+
+/// The directories to cache the file lists in, in the order of preference. The Distrod cache
+/// directory is not writable when the images are listed by a non-root user, so the user's cache
+/// directory follows it.
+fn get_file_list_cache_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(distrod_config::get_distrod_cache_dir())];
+    dirs.extend(get_user_cache_dir(
+        std::env::var_os("XDG_CACHE_HOME"),
+        std::env::var_os("HOME"),
+    ));
+    dirs
+}
+
+/// `$XDG_CACHE_HOME/distrod`, or `$HOME/.cache/distrod` if XDG_CACHE_HOME is not an absolute
+/// path as the XDG Base Directory Specification says.
+fn get_user_cache_dir(xdg_cache_home: Option<OsString>, home: Option<OsString>) -> Option<PathBuf> {
+    let xdg_cache_home = xdg_cache_home
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute());
+    let home_cache = || {
+        home.map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+            .map(|home| home.join(".cache"))
+    };
+    xdg_cache_home
+        .or_else(home_cache)
+        .map(|cache_home| cache_home.join("distrod"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct IndexEntry {
+    /// The path of the directory of the image, such as "/images/ubuntu/focal/amd64/default/20211101_07:42/".
+    path: String,
+    build_date: NaiveDateTime,
+}
+
+fn parse_index_system(body: &str) -> Result<Vec<IndexEntry>> {
+    let entries = body
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let fields: Vec<_> = line.trim().split(';').collect();
+            if fields.len() != 6 {
+                bail!("Malformed line in the image index: '{}'", line);
+            }
+            let build_date = NaiveDateTime::parse_from_str(fields[4], "%Y%m%d_%H:%M")
+                .with_context(|| format!("Invalid build date in the image index: '{}'", line))?;
+            Ok(IndexEntry {
+                path: fields[5].to_owned(),
+                build_date,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if entries.is_empty() {
+        bail!("The image index is empty.");
+    }
+    Ok(entries)
+}
+
+/// List the files in the directory from the paths in the index, as the Apache directory listing does.
+/// The last modified time of a directory is the latest build date of the images in it.
+fn list_index_dir(index: &[IndexEntry], relative_url: &str) -> Result<Vec<FileOnApache>> {
+    let mut dir = relative_url.trim_start_matches('/').to_owned();
+    if !dir.ends_with('/') {
+        dir.push('/');
+    }
+    let mut files = BTreeMap::new();
+    for entry in index {
+        let rest = match entry.path.trim_start_matches('/').strip_prefix(&dir) {
+            Some(rest) => rest,
+            None => continue,
+        };
+        let name = match rest.split('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        let last_modified = files.entry(name).or_insert(entry.build_date);
+        *last_modified = std::cmp::max(*last_modified, entry.build_date);
+    }
+    if files.is_empty() {
+        bail!("{:?} is not available", &relative_url);
+    }
+    Ok(files
+        .into_iter()
+        .map(|(name, last_modified)| FileOnApache {
+            name: name.to_owned(),
+            url: format!("{}/", name),
+            last_modified,
+        })
+        .collect())
+}
+
+fn parse_apache_file_list(
+    relative_url: &str,
+    apache_file_list_body: &str,
+) -> Result<Vec<FileOnApache>> {
     let date_selector =
         scraper::Selector::parse("body > table > tbody > tr > td:nth-child(3)").unwrap();
     let a_link_selector =
         scraper::Selector::parse("body > table > tbody > tr > td:nth-child(2) > a").unwrap();
-    log::info!("Fetching from linuxcontainers.org...");
-    let apache_file_list_body = reqwest::get(&url)
-        .await
-        .with_context(|| format!("Failed to fetch {}", &url))?
-        .text()
-        .await
-        .with_context(|| format!("Failed to get the text of {}", &url))?;
-    let doc = scraper::Html::parse_document(&apache_file_list_body);
+    let doc = scraper::Html::parse_document(apache_file_list_body);
     let dates: Vec<_> = doc.select(&date_selector).collect();
     let a_links: Vec<_> = doc.select(&a_link_selector).collect();
     let links = a_links
@@ -198,9 +458,282 @@ async fn fetch_apache_file_list(relative_url: &str) -> Result<Vec<FileOnApache>>
     Ok(links)
 }
 
+fn get_file_list_cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    let escaped_url: String = url
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // The URL of a custom mirror can be longer than the maximum length of a file name, 255 bytes.
+    // Keep a prefix readable and tell the URLs apart by the hash.
+    if escaped_url.len() > MAX_CACHE_FILE_STEM_LEN {
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        return cache_dir.join(format!(
+            "{}_{:016x}.html",
+            &escaped_url[..MAX_CACHE_FILE_STEM_LEN - 17],
+            hasher.finish()
+        ));
+    }
+    cache_dir.join(format!("{}.html", escaped_url))
+}
+
+fn read_file_list_cache(cache_dir: &Path, url: &str, ttl: Duration) -> Option<String> {
+    let cache_path = get_file_list_cache_path(cache_dir, url);
+    let age = std::fs::metadata(&cache_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?
+        .elapsed()
+        .ok()?;
+    if age > ttl {
+        return None;
+    }
+    std::fs::read_to_string(&cache_path).ok()
+}
+
+fn write_file_list_cache(cache_dir: &Path, url: &str, body: &str) -> Result<()> {
+    std::fs::create_dir_all(cache_dir)
+        .with_context(|| format!("Failed to create {:?}.", cache_dir))?;
+    let cache_path = get_file_list_cache_path(cache_dir, url);
+    // Write to a temporary file first so that a half-written cache is never read.
+    let tmp_path = cache_path.with_extension("tmp");
+    std::fs::write(&tmp_path, body).with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+    std::fs::rename(&tmp_path, &cache_path)
+        .with_context(|| format!("Failed to rename {:?}.", &tmp_path))?;
+    Ok(())
+}
+
 #[derive(Debug)]
 struct FileOnApache {
     name: String,
     url: String,
     last_modified: NaiveDateTime,
 }
+
+#[cfg(test)]
+mod test_file_list_cache {
+    use super::*;
+
+    #[test]
+    fn test_read_and_write_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().join("cache");
+        let ttl = Duration::from_secs(60);
+        assert_eq!(
+            None,
+            read_file_list_cache(
+                &cache_dir,
+                "https://images.linuxcontainers.org/images/ubuntu/",
+                ttl
+            )
+        );
+
+        write_file_list_cache(
+            &cache_dir,
+            "https://images.linuxcontainers.org/images/ubuntu/",
+            "<html>ubuntu</html>",
+        )
+        .unwrap();
+        write_file_list_cache(
+            &cache_dir,
+            "https://images.linuxcontainers.org/images/debian/",
+            "<html>debian</html>",
+        )
+        .unwrap();
+        assert_eq!(
+            Some("<html>ubuntu</html>".to_owned()),
+            read_file_list_cache(
+                &cache_dir,
+                "https://images.linuxcontainers.org/images/ubuntu/",
+                ttl
+            )
+        );
+        assert_eq!(
+            Some("<html>debian</html>".to_owned()),
+            read_file_list_cache(
+                &cache_dir,
+                "https://images.linuxcontainers.org/images/debian/",
+                ttl
+            )
+        );
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(
+            None,
+            read_file_list_cache(
+                &cache_dir,
+                "https://images.linuxcontainers.org/images/ubuntu/",
+                Duration::from_millis(1)
+            )
+        );
+    }
+
+    #[test]
+    fn test_cache_is_separated_by_mirror() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(60);
+        write_file_list_cache(
+            cache_dir.path(),
+            "https://images.linuxcontainers.org/images/",
+            "default",
+        )
+        .unwrap();
+        assert_eq!(
+            None,
+            read_file_list_cache(
+                cache_dir.path(),
+                "https://uk.images.linuxcontainers.org/images/",
+                ttl
+            )
+        );
+    }
+
+    #[test]
+    fn test_cache_of_long_mirror_url() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(60);
+        let mirror = format!("https://mirror.example.com/{}/", "lxc".repeat(100));
+        let urls = [mirror.clone() + "images/ubuntu/", mirror + "images/debian/"];
+        for url in &urls {
+            write_file_list_cache(cache_dir.path(), url, url).unwrap();
+        }
+        for url in &urls {
+            assert_eq!(
+                Some(url.clone()),
+                read_file_list_cache(cache_dir.path(), url, ttl)
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_user_cache_dir() {
+        let os = |s: &str| Some(OsString::from(s));
+        assert_eq!(
+            Some(PathBuf::from("/home/user/.xdg-cache/distrod")),
+            get_user_cache_dir(os("/home/user/.xdg-cache"), os("/home/user"))
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/user/.cache/distrod")),
+            get_user_cache_dir(None, os("/home/user"))
+        );
+        // A relative XDG_CACHE_HOME is invalid and ignored.
+        assert_eq!(
+            Some(PathBuf::from("/home/user/.cache/distrod")),
+            get_user_cache_dir(os("relative"), os("/home/user"))
+        );
+        assert_eq!(None, get_user_cache_dir(None, None));
+    }
+
+    #[test]
+    fn test_broken_cache_is_not_parsed() {
+        assert!(parse_apache_file_list("images/", "<html>broken</html>").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_container_org_image_list {
+    use super::*;
+
+    #[test]
+    fn test_with_mirror() {
+        let mut list = ContainerOrgImageList::default();
+        assert_eq!(LINUX_CONTAINERS_ORG_BASE, list.config.base_url);
+        list.with_mirror("uk").unwrap();
+        assert_eq!(
+            "https://uk.images.linuxcontainers.org/",
+            list.config.base_url
+        );
+        list.with_mirror("https://mirror.example.com/lxc/").unwrap();
+        assert_eq!("https://mirror.example.com/lxc/", list.config.base_url);
+        assert!(list.with_mirror("https://mirror.example.com/lxc").is_err());
+        assert!(list.with_mirror("unknown").is_err());
+    }
+
+    #[test]
+    fn test_with_arch() {
+        let mut list = ContainerOrgImageList::default();
+        assert_eq!("amd64", list.config.arch);
+        list.with_arch("arm64").unwrap();
+        assert_eq!("arm64", list.config.arch);
+        assert!(list.with_arch("mips").is_err());
+    }
+
+    #[test]
+    fn test_variant_uses_arch() {
+        assert_eq!("arm64/default", get_variant("ubuntu", "arm64"));
+        assert_eq!("armhf/systemd", get_variant("gentoo", "armhf"));
+    }
+}
+
+#[cfg(test)]
+mod test_index_system {
+    use super::*;
+
+    /// Captured from https://images.linuxcontainers.org/meta/1.0/index-system
+    static INDEX_SAMPLE: &str = "\
+almalinux;8;amd64;default;20211213_23:08;/images/almalinux/8/amd64/default/20211213_23:08/
+debian;bullseye;amd64;default;20211212_05:24;/images/debian/bullseye/amd64/default/20211212_05:24/
+debian;bullseye;amd64;default;20211213_05:24;/images/debian/bullseye/amd64/default/20211213_05:24/
+debian;bullseye;arm64;default;20211213_05:24;/images/debian/bullseye/arm64/default/20211213_05:24/
+gentoo;current;amd64;systemd;20211212_16:07;/images/gentoo/current/amd64/systemd/20211212_16:07/
+ubuntu;focal;amd64;default;20211213_07:42;/images/ubuntu/focal/amd64/default/20211213_07:42/
+ubuntu;impish;amd64;default;20211214_07:42;/images/ubuntu/impish/amd64/default/20211214_07:42/
+";
+
+    fn date(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y%m%d_%H:%M").unwrap()
+    }
+
+    fn names(files: &[FileOnApache]) -> Vec<&str> {
+        files.iter().map(|file| file.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_index_system() {
+        let index = parse_index_system(INDEX_SAMPLE).unwrap();
+        assert_eq!(7, index.len());
+        assert_eq!(
+            IndexEntry {
+                path: "/images/almalinux/8/amd64/default/20211213_23:08/".to_owned(),
+                build_date: date("20211213_23:08"),
+            },
+            index[0]
+        );
+        assert!(parse_index_system("").is_err());
+        assert!(parse_index_system("<html><body>Not Found</body></html>").is_err());
+        assert!(parse_index_system("ubuntu;focal;amd64;default;invalid;/images/ubuntu/").is_err());
+    }
+
+    #[test]
+    fn test_list_index_dir() {
+        let index = parse_index_system(INDEX_SAMPLE).unwrap();
+
+        let distros = list_index_dir(&index, "images/").unwrap();
+        assert_eq!(
+            vec!["almalinux", "debian", "gentoo", "ubuntu"],
+            names(&distros)
+        );
+        assert_eq!("debian/", distros[1].url);
+        assert_eq!(date("20211213_05:24"), distros[1].last_modified);
+
+        let versions = list_index_dir(&index, "images/ubuntu/").unwrap();
+        assert_eq!(vec!["focal", "impish"], names(&versions));
+        assert_eq!(date("20211214_07:42"), versions[1].last_modified);
+
+        let arches = list_index_dir(&index, "images/debian/bullseye/").unwrap();
+        assert_eq!(vec!["amd64", "arm64"], names(&arches));
+
+        // The URL of the dates doesn't end with '/'.
+        let dates = list_index_dir(&index, "images/debian/bullseye/amd64/default").unwrap();
+        assert_eq!(vec!["20211212_05:24", "20211213_05:24"], names(&dates));
+        assert_eq!("20211213_05:24/", dates[1].url);
+
+        assert!(list_index_dir(&index, "images/ubuntu/focal/arm64/default").is_err());
+        assert!(list_index_dir(&index, "images/ubun").is_err());
+    }
+}
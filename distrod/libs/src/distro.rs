@@ -1,27 +1,34 @@
 use anyhow::{anyhow, bail, Context, Result};
+use nix::sys::stat::Mode;
 use nix::unistd::{Gid, Uid};
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::os::linux::fs::MetadataExt;
-use std::os::unix::prelude::{CommandExt, OsStrExt};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::os::unix::prelude::{AsRawFd, CommandExt, FromRawFd, OsStrExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
 
-use crate::container::{Container, ContainerLauncher, ContainerPath, HostPath};
-use crate::distrod_config::{self, DistrodConfig};
+use crate::container::{Container, ContainerLauncher, ContainerPath, HostPath, UserNamespace};
+use crate::distrod_config::{self, DistrodConfig, ExtraMountEntry};
 use crate::envfile::{EnvFile, EnvShellScript};
-use crate::mount_info::get_mount_entries;
-pub use crate::multifork::Waiter;
+use crate::first_boot;
+use crate::mount_info::{
+    find_mount_entry, get_mount_entries, get_mount_entries_of_process, is_mountpoint, MountEntry,
+};
+pub use crate::multifork::{WaitStatus, Waiter};
 use crate::passwd::{get_real_credential, Credential};
 use crate::procfile::ProcFile;
+use crate::rootfs_image;
 use crate::systemdunit::{get_existing_systemd_unit, SystemdUnitDisabler, SystemdUnitOverride};
 use crate::template::Template;
-use crate::wsl_interop::{collect_wsl_env_vars, collect_wsl_paths};
+use crate::wsl_interop::{self, collect_wsl_env_vars, collect_wsl_paths};
 use serde::{Deserialize, Serialize};
 
-const DISTRO_OLD_ROOT_PATH: &str = "/mnt/distrod_root";
+pub const DEFAULT_INIT_TARGET: &str = "multi-user.target";
+const CREATABLE_WORKING_DIRECTORY_ROOTS: &[&str] = &["/home", "/root", "/tmp", "/var/tmp", "/srv"];
 
 pub struct DistroLauncher {
     rootfs: Option<PathBuf>,
@@ -30,6 +37,10 @@ pub struct DistroLauncher {
     per_user_envs: HashMap<String, String>,
     per_user_paths: HashSet<(String, bool)>,
     container_launcher: ContainerLauncher,
+    user_namespace: Option<UserNamespace>,
+    launches_init: bool,
+    init_target: String,
+    resource_limits: ResourceLimits,
 }
 
 impl DistroLauncher {
@@ -41,6 +52,10 @@ impl DistroLauncher {
             per_user_envs: HashMap::new(),
             per_user_paths: HashSet::new(),
             container_launcher: ContainerLauncher::new(),
+            user_namespace: None,
+            launches_init: true,
+            init_target: DEFAULT_INIT_TARGET.to_owned(),
+            resource_limits: ResourceLimits::default(),
         };
         set_wsl_interop_envs_in_system_envs(&mut distro_launcher)
             .with_context(|| "failed to set up WSL interop env vars")?;
@@ -57,6 +72,7 @@ impl DistroLauncher {
             mount_dotx11_unix_for_wslg(&mut distro_launcher)
                 .with_context(|| "Failed to mount /tmp/.X11-unix for wslg")?;
         }
+        apply_launch_config(&mut distro_launcher);
 
         Ok(distro_launcher)
     }
@@ -69,12 +85,18 @@ impl DistroLauncher {
         }
         let run_info = BufReader::new(run_info_file.unwrap());
         let run_info: DistroRunInfo = serde_json::from_reader(run_info)?;
-        if ProcFile::from_pid(run_info.init_pid)?.is_none() {
+        if !run_info.is_init_alive()? {
+            log::debug!(
+                "The init process in the run info is not running. {:?}",
+                run_info
+            );
             return Ok(None);
         }
         Ok(Some(Distro {
             rootfs: run_info.rootfs,
             container: ContainerLauncher::from_pid(run_info.init_pid)?,
+            launches_init: !run_info.no_init,
+            user_namespace: run_info.user_namespace,
         }))
     }
 
@@ -94,6 +116,10 @@ impl DistroLauncher {
         Ok(self)
     }
 
+    pub fn get_rootfs(&self) -> Option<&Path> {
+        self.rootfs.as_deref()
+    }
+
     pub fn with_system_env(&mut self, key: String, val: String) -> &mut Self {
         self.system_envs.insert(key, val);
         self
@@ -142,6 +168,49 @@ impl DistroLauncher {
         self
     }
 
+    pub fn with_user_namespace(&mut self, user_namespace: UserNamespace) -> &mut Self {
+        self.user_namespace = Some(user_namespace.clone());
+        self.container_launcher.with_user_namespace(user_namespace);
+        self
+    }
+
+    /// Launch the distro without its init system. Only the namespaces and the mounts are set up
+    /// so that commands can be run in the distro by exec.
+    pub fn without_init(&mut self) -> &mut Self {
+        self.launches_init = false;
+        self
+    }
+
+    /// Set a system-wide environment variable, which is passed to systemd by the systemd.setenv
+    /// argument. Systemd gives it to all the services and the sessions started by them.
+    pub fn with_systemd_setenv(&mut self, key: &str, value: &str) -> Result<&mut Self> {
+        validate_systemd_setenv(key, value)?;
+        self.container_launcher
+            .with_init_arg(&env_to_systemd_setenv_arg(key, value));
+        Ok(self)
+    }
+
+    /// Boot the given systemd target instead of multi-user.target.
+    pub fn with_init_target(&mut self, target: &str) -> Result<&mut Self> {
+        validate_target_name(target)?;
+        self.init_target = target.to_owned();
+        Ok(self)
+    }
+
+    /// Limit the memory usage of the services by MemoryMax of the system and the user slices.
+    pub fn with_memory_max(&mut self, memory_max: &str) -> Result<&mut Self> {
+        validate_memory_max(memory_max)?;
+        self.resource_limits.memory_max = Some(memory_max.to_owned());
+        Ok(self)
+    }
+
+    /// Limit the CPU usage of the services by CPUQuota of the system and the user slices.
+    pub fn with_cpu_quota(&mut self, cpu_quota: &str) -> Result<&mut Self> {
+        validate_cpu_quota(cpu_quota)?;
+        self.resource_limits.cpu_quota = Some(cpu_quota.to_owned());
+        Ok(self)
+    }
+
     pub fn launch(mut self) -> Result<Distro> {
         log::debug!("DistroLauncher::launch");
         let rootfs = self
@@ -150,11 +219,16 @@ impl DistroLauncher {
             .ok_or_else(|| anyhow!("rootfs is not initialized."))?
             .clone();
 
+        rootfs_image::mount_registered_rootfs_image(&rootfs)
+            .with_context(|| format!("Failed to mount the image of the rootfs {:?}.", &rootfs))?;
         if rootfs == Path::new("/") {
             make_host_mountpoints_shared().with_context(|| "Failed to make mountpoint shared.")?;
         } else {
             mount_wsl_mountpoints(&mut self).with_context(|| "Failed to mount WSL mountpoints.")?;
         }
+        mount_extra_mounts(&mut self).with_context(|| "Failed to mount the extra mounts.")?;
+        mount_custom_resolv_conf(&mut self)
+            .with_context(|| "Failed to mount the resolv.conf for the custom DNS servers.")?;
 
         self.mount_per_user_envs_script()
             .with_context(|| "Failed to mount per-user envs script.")?;
@@ -165,9 +239,58 @@ impl DistroLauncher {
         )
         .with_context(|| "Failed to write system env file.")?;
 
+        let old_root = ContainerPath::new(
+            distrod_config::get_distro_old_root_path()
+                .with_context(|| "Failed to get the old root path.")?,
+        )?;
+        if !self.launches_init {
+            log::info!("Launching the distro without init.");
+            let container = self
+                .container_launcher
+                .launch_without_init(HostPath::new(&rootfs)?, old_root)
+                .with_context(|| "Failed to launch a container.")?;
+            let distro = Distro {
+                rootfs,
+                container,
+                launches_init: false,
+                user_namespace: self.user_namespace,
+            };
+            export_distro_run_info(&distro)
+                .with_context(|| "Failed to export the Distro running information.")?;
+            run_first_boot_scripts(&distro);
+            return Ok(distro);
+        }
+
+        let (init_system, init_path) = detect_init_system(&HostPath::new(&rootfs)?);
         self.container_launcher
-            .with_init_env("container", "distrod") // See https://systemd.io/CONTAINER_INTERFACE/
-            .with_init_arg("--unit=multi-user.target");
+            .with_init_env("container", "distrod"); // See https://systemd.io/CONTAINER_INTERFACE/
+        if init_system == InitSystem::Systemd {
+            log::debug!(
+                "Launching systemd at {:?} with {}.",
+                &init_path,
+                &self.init_target
+            );
+            if !is_systemd_unit_installed(&HostPath::new(&rootfs)?, &self.init_target) {
+                log::warn!(
+                    "{} is not found in the rootfs. Systemd may fail to boot the distro.",
+                    &self.init_target
+                );
+            }
+            for arg in get_systemd_init_args(&self.init_target) {
+                self.container_launcher.with_init_arg(arg);
+            }
+            if !self.resource_limits.is_empty() {
+                write_resource_limits(&HostPath::new(&rootfs)?, &self.resource_limits)
+                    .with_context(|| "Failed to write the resource limits of the distro.")?;
+            }
+        } else {
+            log::warn!(
+                "The init of the distro {:?} is not systemd but {:?}. \
+                 Launching it without the arguments for systemd.",
+                &init_path,
+                init_system
+            );
+        }
         unsafe {
             self.container_launcher.with_init_pre_exec(|| {
                 // Systemd requires the real uid / gid to be the root.
@@ -178,17 +301,18 @@ impl DistroLauncher {
         };
         let container = self
             .container_launcher
-            .launch(
-                "/sbin/init",
-                HostPath::new(&rootfs)?,
-                ContainerPath::new(DISTRO_OLD_ROOT_PATH)?,
-            )
+            .launch(&init_path, HostPath::new(&rootfs)?, old_root)
             .with_context(|| "Failed to launch a container.")?;
 
-        export_distro_run_info(&rootfs, container.init_pid)
+        let distro = Distro {
+            rootfs,
+            container,
+            launches_init: true,
+            user_namespace: self.user_namespace,
+        };
+        export_distro_run_info(&distro)
             .with_context(|| "Failed to export the Distro running information.")?;
-
-        let distro = Distro { rootfs, container };
+        run_first_boot_scripts(&distro);
         Ok(distro)
     }
 
@@ -222,15 +346,57 @@ impl DistroLauncher {
     }
 }
 
+/// Apply the launch settings in the config, so that the distro launched by distrod-exec gets
+/// them as well as the one launched by `distrod start`. An invalid setting is skipped with a
+/// warning so that the distro can still be launched.
+fn apply_launch_config(distro_launcher: &mut DistroLauncher) {
+    let config = match DistrodConfig::get() {
+        Ok(config) => config,
+        Err(e) => {
+            log::warn!(
+                "Failed to get the Distrod config. The launch settings in it are ignored. {:?}",
+                e
+            );
+            return;
+        }
+    };
+    if let Some(ref target) = config.distrod.default_init_target {
+        if let Err(e) = distro_launcher.with_init_target(target) {
+            log::warn!("Ignoring default_init_target in the config. {:?}", e);
+        }
+    }
+    for assignment in &config.distrod.system_envs {
+        let result = parse_env_assignment(assignment).and_then(|(key, value)| {
+            distro_launcher
+                .with_systemd_setenv(&key, &value)
+                .map(|_| ())
+        });
+        if let Err(e) = result {
+            log::warn!(
+                "Ignoring '{}' in system_envs in the config. {:?}",
+                assignment,
+                e
+            );
+        }
+    }
+}
+
+fn run_first_boot_scripts(distro: &Distro) {
+    // The distro is already running, so the failure should not be fatal.
+    if let Err(e) = first_boot::run_first_boot_scripts(distro) {
+        log::error!("Failed to run the first-boot scripts. {:?}", e);
+    }
+}
+
 fn set_wsl_interop_envs_in_system_envs(distro_launcher: &mut DistroLauncher) -> Result<()> {
     for (key, value) in collect_wsl_interop_envs_for_system_envs()
         .with_context(|| "Failed to collect safe WSL interop envs")?
     {
         log::debug!("WSL envs: {:?} = {:?}", &key, &value);
-        distro_launcher.with_system_env(
-            key.to_string_lossy().to_string(),
-            value.to_string_lossy().to_string(),
-        );
+        // The argument for systemd keeps the bytes as they are, even if they aren't UTF-8.
+        if let Some((key, value)) = to_utf8_env(&key, &value) {
+            distro_launcher.with_system_env(key, value);
+        }
         distro_launcher
             .container_launcher
             .with_init_arg(&env_to_systemd_setenv_arg(key, value));
@@ -263,6 +429,7 @@ fn mount_kernelcmdline_with_wsl_interop_envs_for_systemd(
 
 const X11_TARGET_PATH: &str = "/tmp/.X11-unix";
 const WSLG_PATH: &str = "/mnt/wslg/.X11-unix";
+const WSLG_DIR: &str = "/mnt/wslg";
 
 fn is_wsl_bind_mount_dotx11_unix() -> Result<bool> {
     // if it's an existing dir, then it's WSL>=0.60.0, we should bind-mount it
@@ -292,17 +459,68 @@ fn get_cmdline_with_wsl_interop_envs_for_systemd<P: AsRef<Path>>(
     }
 
     // Set default environment vairables for the systemd services.
-    for (key, value) in
-        collect_wsl_interop_envs_for_system_envs().with_context(|| "Failed to collect WSL envs.")?
-    {
-        cmdline.extend(" ".as_bytes());
-        cmdline.extend(env_to_systemd_setenv_arg(&key, &value).as_bytes());
-    }
+    append_systemd_setenv_args(
+        &mut cmdline,
+        collect_wsl_interop_envs_for_system_envs()
+            .with_context(|| "Failed to collect WSL envs.")?,
+    );
     cmdline.extend("\n".as_bytes());
 
     Ok(cmdline)
 }
 
+fn append_systemd_setenv_args<K, V, I>(cmdline: &mut Vec<u8>, envs: I)
+where
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+    I: IntoIterator<Item = (K, V)>,
+{
+    for (key, value) in envs {
+        cmdline.extend(" ".as_bytes());
+        cmdline.extend(env_to_systemd_setenv_arg(key, value).as_bytes());
+    }
+}
+
+/// Parse "KEY=VALUE" given by --setenv or the config.
+pub fn parse_env_assignment(assignment: &str) -> Result<(String, String)> {
+    match assignment.split_once('=') {
+        Some((key, value)) => Ok((key.to_owned(), value.to_owned())),
+        None => bail!("'{}' is not in the form of KEY=VALUE.", assignment),
+    }
+}
+
+/// Make sure that a variable given by the user can't inject other arguments for systemd.
+fn validate_systemd_setenv(key: &str, value: &str) -> Result<()> {
+    let is_valid_key = key
+        .chars()
+        .next()
+        .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_valid_key {
+        bail!(
+            "'{}' is not a valid variable name. Use alphanumerics and underscores.",
+            key
+        );
+    }
+    if breaks_systemd_setenv_arg(value) {
+        bail!(
+            "The value of {} must not contain whitespaces, quotes, backslashes, or control \
+             characters, or start with '='.",
+            key
+        );
+    }
+    Ok(())
+}
+
+/// Whether the value can split or extend the systemd.setenv= argument, which systemd parses
+/// with the quotes, or inject other lines to /etc/environment.
+fn breaks_systemd_setenv_arg(value: &str) -> bool {
+    value
+        .chars()
+        .any(|c| c.is_control() || c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+        || value.starts_with('=')
+}
+
 fn collect_wsl_interop_envs_for_system_envs() -> Result<Vec<(OsString, OsString)>> {
     // Collect only harmless environment variables.
     // Distrod can be running as setuid program. So, non-root user can set arbitrary environment variables.
@@ -311,7 +529,9 @@ fn collect_wsl_interop_envs_for_system_envs() -> Result<Vec<(OsString, OsString)
     // but be prepared for this WSL spec to be improved in a safer direction someday.
     let mut envs = vec![];
     let wsl_interop_env_names_for_system_envs = get_names_of_wsl_interop_envs_for_system_envs();
-    for (key, value) in collect_wsl_env_vars().with_context(|| "Failed to collect WSL envs.")? {
+    let mut wsl_envs = collect_wsl_env_vars().with_context(|| "Failed to collect WSL envs.")?;
+    resolve_wslg_envs(Path::new(WSLG_DIR), &mut wsl_envs);
+    for (key, value) in wsl_envs {
         if !wsl_interop_env_names_for_system_envs.contains(&key) {
             continue;
         }
@@ -325,9 +545,9 @@ fn collect_wsl_interop_envs_for_system_envs() -> Result<Vec<(OsString, OsString)
     Ok(envs)
 }
 
-/// Make sure that the values of WSL_INTEROP, WSLENV, and WSL_DISTRO_NAME are harmless values that can be
-/// written to /etc/environment and passed to Systemd via /proc/cmdline. These values may be polluted
-/// because distrod-exec can be launched by any user.
+/// Make sure that the values of WSL_INTEROP, WSLENV, WSL_DISTRO_NAME, and the other forwarded WSL
+/// variables are harmless values that can be written to /etc/environment and passed to Systemd via
+/// /proc/cmdline. These values may be polluted because distrod-exec can be launched by any user.
 fn sanity_check_wsl_env(key: &OsStr, value: &OsStr) -> bool {
     if key == OsStr::new("WSL_INTEROP") {
         sanity_check_wsl_interop(value)
@@ -347,14 +567,34 @@ fn sanity_check_wsl_interop(value: &OsStr) -> bool {
     inner().unwrap_or(false)
 }
 
+/// The characters allowed in WSLENV and WSL_DISTRO_NAME by default. The whitespaces, the quotes,
+/// and the shell metacharacters are not allowed since the values are written to /etc/environment
+/// and /proc/cmdline.
+const DEFAULT_WSL_ENV_HARMLESS_PATTERN: &str = r#"^[\p{L}\p{N}_./:,+=@%~-]*$"#;
+
 fn sanity_check_general_wsl_envs(value: &OsStr) -> bool {
-    // sanity check for WSLENV and WSL_INTEROP
+    // sanity check for WSLENV, WSL_DISTRO_NAME, and the other forwarded WSL variables
+    let config = DistrodConfig::get().ok();
+    let pattern = config
+        .as_ref()
+        .and_then(|config| config.distrod.wsl_env_harmless_pattern.as_deref())
+        .unwrap_or(DEFAULT_WSL_ENV_HARMLESS_PATTERN);
+    sanity_check_general_wsl_envs_with_pattern(value, pattern)
+}
+
+fn sanity_check_general_wsl_envs_with_pattern(value: &OsStr, harmless_pattern: &str) -> bool {
     let inner = || -> Result<bool> {
-        let harmless_pattern = regex::Regex::new(r#"^([a-zA-Z0-9_./:]|-)*$"#)?;
+        let harmless_pattern = regex::Regex::new(harmless_pattern)
+            .with_context(|| format!("Invalid pattern: {:?}", harmless_pattern))?;
         let str = value.to_str().ok_or_else(|| anyhow!("non-UTF8 value."))?;
+        // Never allow the characters which breaks the kernel command line or /etc/environment,
+        // such as newlines and whitespaces, even if the pattern allows them.
+        if breaks_systemd_setenv_arg(str) {
+            return Ok(false);
+        }
         Ok(harmless_pattern.is_match(str))
     };
-    inner().unwrap_or(false)
+    inner().map_err(|e| log::warn!("{:?}", e)).unwrap_or(false)
 }
 
 fn get_cmdline_overwrite_path() -> Result<HostPath> {
@@ -376,12 +616,35 @@ where
     arg
 }
 
+/// Make the WSLg variables point at the sockets under /mnt/wslg, which is bind-mounted into the
+/// distro. WAYLAND_DISPLAY is relative to XDG_RUNTIME_DIR, which is /run/user/<uid> in the Systemd
+/// user sessions rather than WSLg's runtime-dir, so it's made absolute. PULSE_SERVER is set if WSL
+/// doesn't set it. Nothing is done if WSLg is not available.
+fn resolve_wslg_envs(wslg_dir: &Path, envs: &mut HashMap<OsString, OsString>) {
+    if !wslg_dir.exists() {
+        return;
+    }
+    if let Some(wayland_display) = envs.get_mut(OsStr::new("WAYLAND_DISPLAY")) {
+        let socket = wslg_dir.join("runtime-dir").join(&wayland_display);
+        if !Path::new(wayland_display).is_absolute() && socket.exists() {
+            *wayland_display = socket.into_os_string();
+        }
+    }
+    let pulse_server = wslg_dir.join("PulseServer");
+    if !envs.contains_key(OsStr::new("PULSE_SERVER")) && pulse_server.exists() {
+        let mut value = OsString::from("unix:");
+        value.push(pulse_server);
+        envs.insert(OsString::from("PULSE_SERVER"), value);
+    }
+}
+
 fn set_per_user_wsl_envs(distro_launcher: &mut DistroLauncher) -> Result<()> {
-    for (key, value) in collect_wsl_env_vars().with_context(|| "Failed to collect WSL envs.")? {
-        distro_launcher.with_per_user_env(
-            key.to_string_lossy().to_string(),
-            value.to_string_lossy().to_string(),
-        );
+    let mut wsl_envs = collect_wsl_env_vars().with_context(|| "Failed to collect WSL envs.")?;
+    resolve_wslg_envs(Path::new(WSLG_DIR), &mut wsl_envs);
+    for (key, value) in wsl_envs {
+        if let Some((key, value)) = to_utf8_env(&key, &value) {
+            distro_launcher.with_per_user_env(key, value);
+        }
     }
     for path in collect_wsl_paths().with_context(|| "Failed to collect WSL paths.")? {
         distro_launcher.with_per_user_path(path, false);
@@ -389,6 +652,22 @@ fn set_per_user_wsl_envs(distro_launcher: &mut DistroLauncher) -> Result<()> {
     Ok(())
 }
 
+/// Convert the environment variable to UTF-8 so that it can be written to the env files.
+/// It's skipped with a warning if it isn't valid UTF-8, rather than written as a corrupted value.
+fn to_utf8_env(key: &OsStr, value: &OsStr) -> Option<(String, String)> {
+    match (key.to_str(), value.to_str()) {
+        (Some(key), Some(value)) => Some((key.to_owned(), value.to_owned())),
+        _ => {
+            log::warn!(
+                "Skipping the environment variable which is not valid UTF-8: {:?}={:?}",
+                key,
+                value
+            );
+            None
+        }
+    }
+}
+
 fn mount_slash_run_static_files(distro_launcher: &mut DistroLauncher) -> Result<()> {
     for path in glob::glob(&format!(
         "{}/**/*",
@@ -449,12 +728,28 @@ fn mount_wsl_mountpoints(distro_launcher: &mut DistroLauncher) -> Result<()> {
         ("/sys", false),
         ("/dev", false),
         ("/mnt/wsl", false),
+        // The sockets of WSLg, such as PulseServer and runtime-dir/wayland-0.
+        (WSLG_DIR, false),
         ("/run/WSL", false),
         ("/etc/wsl.conf", true),
         ("/etc/resolv.conf", true),
         ("/proc/sys/fs/binfmt_misc", false),
     ];
+    let keeps_systemd_resolved = DistrodConfig::get()
+        .map(|config| config.distrod.keep_systemd_resolved)
+        .unwrap_or_default();
+    // An invalid DNS config is reported by mount_custom_resolv_conf, and WSL's one is used then.
+    let has_custom_dns = matches!(get_custom_resolv_conf(), Ok(Some(_)));
     for (bind_file, is_file) in binds {
+        if bind_file == "/etc/resolv.conf" && keeps_systemd_resolved {
+            // /etc/resolv.conf of the distro links to systemd-resolved's one in that case.
+            log::debug!("Not mounting /etc/resolv.conf since systemd-resolved is kept.");
+            continue;
+        }
+        if bind_file == "/etc/resolv.conf" && has_custom_dns {
+            // The resolv.conf generated from dns_servers is mounted instead.
+            continue;
+        }
         if !Path::new(bind_file).exists() {
             log::debug!("WSL path {:?} does not exist.", bind_file);
             continue;
@@ -492,6 +787,190 @@ fn mount_wsl_mountpoints(distro_launcher: &mut DistroLauncher) -> Result<()> {
     Ok(())
 }
 
+fn mount_extra_mounts(distro_launcher: &mut DistroLauncher) -> Result<()> {
+    let config = DistrodConfig::get()?;
+    for entry in &config.distrod.extra_mounts {
+        let (source, target, flags) = validate_extra_mount(entry)
+            .with_context(|| format!("Invalid extra_mounts entry: {:?}.", entry))?;
+        distro_launcher.with_mount(Some(source), target, None, flags, None, entry.is_file);
+    }
+    Ok(())
+}
+
+fn validate_extra_mount(
+    entry: &ExtraMountEntry,
+) -> Result<(HostPath, ContainerPath, nix::mount::MsFlags)> {
+    if !entry.target.is_absolute() {
+        bail!("The target {:?} is not an absolute path.", &entry.target);
+    }
+    if !entry.source.exists() {
+        bail!("The source {:?} doesn't exist.", &entry.source);
+    }
+    let source = HostPath::new(&entry.source)?;
+    let target = ContainerPath::new(&entry.target)?;
+    let mut flags = nix::mount::MsFlags::MS_BIND;
+    for flag in &entry.flags {
+        flags |= parse_mount_flag(flag)?;
+    }
+    Ok((source, target, flags))
+}
+
+fn parse_mount_flag(flag: &str) -> Result<nix::mount::MsFlags> {
+    use nix::mount::MsFlags;
+    Ok(match flag {
+        "bind" => MsFlags::MS_BIND,
+        "rdonly" | "ro" => MsFlags::MS_RDONLY,
+        "rec" => MsFlags::MS_REC,
+        "nosuid" => MsFlags::MS_NOSUID,
+        "nodev" => MsFlags::MS_NODEV,
+        "noexec" => MsFlags::MS_NOEXEC,
+        _ => bail!(
+            "Unknown mount flag {:?}. Use rdonly, rec, nosuid, nodev, or noexec.",
+            flag
+        ),
+    })
+}
+
+/// Mount the resolv.conf generated from dns_servers in the config on /etc/resolv.conf so that
+/// the one generated by WSL doesn't take effect.
+fn mount_custom_resolv_conf(distro_launcher: &mut DistroLauncher) -> Result<()> {
+    let resolv_conf = match get_custom_resolv_conf() {
+        Ok(Some(resolv_conf)) => resolv_conf,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            // Don't make the distro unable to launch only because of the DNS config.
+            log::warn!("Using the resolv.conf of WSL. {:?}", e);
+            return Ok(());
+        }
+    };
+    let mut host_resolv_conf_path = get_distrod_runtime_files_dir_path()?;
+    host_resolv_conf_path.push("resolv.conf");
+    fs::write(host_resolv_conf_path.as_path(), resolv_conf)
+        .with_context(|| format!("Failed to write {:?}.", &host_resolv_conf_path))?;
+    distro_launcher.with_mount(
+        Some(host_resolv_conf_path),
+        ContainerPath::new("/etc/resolv.conf")?,
+        None,
+        nix::mount::MsFlags::MS_BIND,
+        None,
+        true,
+    );
+    Ok(())
+}
+
+/// The contents of resolv.conf generated from dns_servers and dns_search in the config,
+/// or None if dns_servers is not set, or if keep_systemd_resolved is set.
+fn get_custom_resolv_conf() -> Result<Option<String>> {
+    let config = DistrodConfig::get()?;
+    if config.distrod.keep_systemd_resolved {
+        // /etc/resolv.conf is systemd-resolved's one, which has its own DNS settings.
+        if config.distrod.dns_servers.is_some() {
+            log::debug!("dns_servers is ignored since keep_systemd_resolved is set.");
+        }
+        return Ok(None);
+    }
+    match config.distrod.dns_servers {
+        Some(ref servers) if !servers.is_empty() => {
+            let search = config.distrod.dns_search.as_deref().unwrap_or(&[]);
+            generate_resolv_conf(servers, search)
+                .map(Some)
+                .with_context(|| "Invalid dns_servers or dns_search in the Distrod config.")
+        }
+        _ => Ok(None),
+    }
+}
+
+fn generate_resolv_conf(servers: &[String], search: &[String]) -> Result<String> {
+    let mut resolv_conf = String::from(
+        "# This file is generated by Distrod from dns_servers and dns_search in\n\
+         # /opt/distrod/conf/distrod.toml. Edit them instead of this file.\n",
+    );
+    for server in servers {
+        server
+            .parse::<std::net::IpAddr>()
+            .with_context(|| format!("{:?} is not an IP address.", server))?;
+        resolv_conf.push_str(&format!("nameserver {}\n", server));
+    }
+    for domain in search {
+        validate_hostname(domain)
+            .with_context(|| format!("{:?} is not a valid search domain.", domain))?;
+    }
+    if !search.is_empty() {
+        resolv_conf.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    Ok(resolv_conf)
+}
+
+/// Write the resolv.conf generated from the config into the rootfs, replacing the link to
+/// systemd's one if any, so that it's effective even before Distrod mounts it.
+fn write_custom_resolv_conf(rootfs: &HostPath, dry_run: bool) -> Result<()> {
+    let resolv_conf = match get_custom_resolv_conf()? {
+        Some(resolv_conf) => resolv_conf,
+        None => return Ok(()),
+    };
+    let resolv_conf_path = ContainerPath::new("/etc/resolv.conf")?.to_host_path(rootfs);
+    if is_mounted_file(&resolv_conf_path) {
+        // It can't be replaced while it's mounted, and the file is mounted on it at launch anyway.
+        log::info!(
+            "Not writing the resolv.conf for the custom DNS servers to {:?} since a file is \
+             mounted on it. It takes effect on the next launch.",
+            &resolv_conf_path
+        );
+        return Ok(());
+    }
+    if dry_run {
+        log::info!(
+            "Would write {:?} for the custom DNS servers:\n{}",
+            &resolv_conf_path,
+            resolv_conf
+        );
+        return Ok(());
+    }
+    if let Ok(metadata) = fs::symlink_metadata(resolv_conf_path.as_path()) {
+        if metadata.file_type().is_symlink() {
+            fs::remove_file(resolv_conf_path.as_path())
+                .with_context(|| format!("Failed to remove {:?}.", &resolv_conf_path))?;
+        }
+    }
+    overwrite_file_preserving_metadata(
+        rootfs,
+        &ContainerPath::new("/etc/resolv.conf")?,
+        resolv_conf.as_bytes(),
+    )
+    .with_context(|| format!("Failed to write {:?}.", &resolv_conf_path))
+}
+
+/// Translate the working directory of the host into the path in the container, or None if the
+/// container doesn't see the filesystem of it.
+fn translate_working_directory(
+    host_wd: &Path,
+    rootfs: &Path,
+    host_mounts: &[MountEntry],
+    container_mounts: &[MountEntry],
+) -> Option<PathBuf> {
+    if rootfs != Path::new("/") {
+        if let Ok(relative) = host_wd.strip_prefix(rootfs) {
+            return Some(Path::new("/").join(relative));
+        }
+    }
+    // Paths such as /mnt/c/... are accessible only if the same filesystem is mounted on the
+    // same mountpoint in the container.
+    match find_mount_entry(host_mounts, host_wd) {
+        Some(host_mount) if host_mount.path != Path::new("/") => {
+            let container_mount = find_mount_entry(container_mounts, host_wd)?;
+            if container_mount.path == host_mount.path
+                && container_mount.source == host_mount.source
+                && container_mount.fstype == host_mount.fstype
+            {
+                Some(host_wd.to_owned())
+            } else {
+                None
+            }
+        }
+        _ => Some(host_wd.to_owned()),
+    }
+}
+
 fn make_host_mountpoints_shared() -> Result<()> {
     // Share the mount modification the distro may make with the host mount namespace
     // by MS_SHARED so that WSL's file sharing feature can see them.
@@ -517,12 +996,129 @@ fn make_host_mountpoints_shared() -> Result<()> {
 pub struct Distro {
     rootfs: PathBuf,
     container: Container,
+    launches_init: bool,
+    user_namespace: Option<UserNamespace>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DistroRunInfo {
     rootfs: PathBuf,
     init_pid: u32,
+    /// The start time of the init process, which tells if init_pid has been reused by another
+    /// process. The run info exported by older versions doesn't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    init_start_time: Option<u64>,
+    /// Whether the distro is launched without init, which is kept on restart.
+    #[serde(default)]
+    no_init: bool,
+    /// The user namespace the distro runs in, which is kept on restart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user_namespace: Option<UserNamespace>,
+}
+
+/// The state of systemd which `wait_until_system_running` ended with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemRunningState {
+    /// All the units have started.
+    Running,
+    /// The system has started, but some units failed.
+    Degraded,
+    /// The timeout expired while systemd was in the given state, such as "starting".
+    TimedOut(String),
+}
+
+const SYSTEM_STATE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Poll `systemctl is-system-running` in the distro until the system is running or degraded.
+pub fn wait_until_system_running(distro: &Distro, timeout: Duration) -> Result<SystemRunningState> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        // systemctl fails until systemd gets ready to answer, which is just "not yet".
+        let state = distro
+            .get_system_state()
+            .unwrap_or_else(|_| "unknown".to_owned());
+        if let Some(state) = to_system_running_state(&state)? {
+            return Ok(state);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(SystemRunningState::TimedOut(state));
+        }
+        std::thread::sleep(SYSTEM_STATE_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Map the state by `systemctl is-system-running` to the final one, or None if systemd may
+/// still become running.
+fn to_system_running_state(state: &str) -> Result<Option<SystemRunningState>> {
+    match state {
+        "running" => Ok(Some(SystemRunningState::Running)),
+        "degraded" => Ok(Some(SystemRunningState::Degraded)),
+        "maintenance" | "stopping" => bail!("The system is {}. It won't get running.", state),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedSystemdUnit {
+    pub name: String,
+    /// The LOAD column, such as "loaded" or "not-found".
+    pub load: String,
+    /// The SUB column, such as "failed" or "auto-restart".
+    pub sub: String,
+    pub description: String,
+}
+
+/// Parse the output of `systemctl --failed --no-legend`. Each line is
+/// "UNIT LOAD ACTIVE SUB DESCRIPTION", which newer systemd prefixes by a bullet
+/// unless --plain is given.
+fn parse_failed_systemd_units(output: &str) -> Vec<FailedSystemdUnit> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_start();
+            let line = line
+                .strip_prefix('\u{25cf}')
+                .or_else(|| line.strip_prefix('*'))
+                .unwrap_or(line);
+            let mut fields = line.split_whitespace();
+            let (name, load, _active, sub) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            // Unit names always have a type suffix, unlike a summary such as "0 loaded units listed."
+            if !name.contains('.') {
+                return None;
+            }
+            Some(FailedSystemdUnit {
+                name: name.to_owned(),
+                load: load.to_owned(),
+                sub: sub.to_owned(),
+                description: fields.collect::<Vec<_>>().join(" "),
+            })
+        })
+        .collect()
+}
+
+impl DistroRunInfo {
+    fn is_init_alive(&self) -> Result<bool> {
+        let mut init = match ProcFile::from_pid(self.init_pid)? {
+            Some(init) => init,
+            None => return Ok(false),
+        };
+        let start_time = match init.start_time() {
+            Ok(start_time) => start_time,
+            // The process has exited after the PID directory was opened.
+            Err(_) if !init.is_live() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        match self.init_start_time {
+            Some(init_start_time) => Ok(init_start_time == start_time),
+            None => Ok(true),
+        }
+    }
 }
 
 impl Distro {
@@ -530,6 +1126,19 @@ impl Distro {
         self.rootfs.as_path()
     }
 
+    pub fn get_init_pid(&self) -> u32 {
+        self.container.init_pid
+    }
+
+    /// Whether the distro is launched with its init system, that is, without `without_init`.
+    pub fn launches_init(&self) -> bool {
+        self.launches_init
+    }
+
+    pub fn get_user_namespace(&self) -> Option<&UserNamespace> {
+        self.user_namespace.as_ref()
+    }
+
     pub fn exec_command<I, S, T1, T2, P>(
         &self,
         command: S,
@@ -537,6 +1146,7 @@ impl Distro {
         wd: Option<P>,
         arg0: Option<T2>,
         cred: Option<&Credential>,
+        umask: Option<Mode>,
     ) -> Result<Waiter>
     where
         I: IntoIterator<Item = T1>,
@@ -554,117 +1164,793 @@ impl Distro {
         if let Some(arg0) = arg0 {
             command.arg0(arg0.as_ref());
         }
+        if let Some(umask) = umask {
+            set_umask_on_exec(&mut command, umask);
+        }
+        self.exec_std_command(command, cred)
+    }
+
+    /// Exec the prepared command in the container, which lets the caller set up its stdio.
+    pub fn exec_std_command(&self, command: Command, cred: Option<&Credential>) -> Result<Waiter> {
         self.container
             .exec_command(command, cred)
             .with_context(|| "Failed to exec command in the container")
     }
 
+    /// Get the units which systemd in the distro reports as failed.
+    pub fn get_failed_systemd_units(&self) -> Result<Vec<FailedSystemdUnit>> {
+        let (status, output) = self.read_systemctl_output(&[
+            "--failed",
+            "--no-legend",
+            "--plain",
+            "--full",
+            "--no-pager",
+        ])?;
+        if status.to_exit_code() != 0 {
+            bail!("systemctl --failed exited with {:?}.", status);
+        }
+        Ok(parse_failed_systemd_units(&output))
+    }
+
+    /// Get the state reported by `systemctl is-system-running`, such as "starting" or "running".
+    pub fn get_system_state(&self) -> Result<String> {
+        // is-system-running exits with non-zero unless the state is "running".
+        let (_, output) = self.read_systemctl_output(&["is-system-running"])?;
+        let state = output.trim();
+        Ok(if state.is_empty() { "unknown" } else { state }.to_owned())
+    }
+
+    fn read_systemctl_output(&self, args: &[&str]) -> Result<(WaitStatus, String)> {
+        let mut command = Command::new("systemctl");
+        command.args(args).env("LANG", "C").stdin(Stdio::null());
+        let (read_end, write_end) = nix::unistd::pipe().with_context(|| "Failed to pipe.")?;
+        let mut read_end = unsafe { File::from_raw_fd(read_end) };
+        command.stdout(unsafe { Stdio::from_raw_fd(write_end) });
+        // The command, which owns the write end, is dropped here so that the read end gets EOF
+        // when systemctl exits.
+        let mut waiter = self
+            .exec_std_command(command, None)
+            .with_context(|| "Failed to run systemctl in the distro.")?;
+        let mut output = String::new();
+        let read_result = read_end.read_to_string(&mut output);
+        let status = waiter.wait();
+        read_result.with_context(|| "Failed to read the output of systemctl.")?;
+        Ok((status, output))
+    }
+
+    /// Translate the working directory of the host into the one in the container.
+    /// If it's not accessible in the container, for example because it's on a Windows drive
+    /// which is not mounted in the container yet, fall back to `/` with a warning.
+    pub fn translate_host_working_directory<P: AsRef<Path>>(&self, host_wd: P) -> PathBuf {
+        let host_wd = host_wd.as_ref();
+        let translated = (|| -> Result<Option<PathBuf>> {
+            let host_mounts = get_mount_entries()?;
+            let container_mounts = get_mount_entries_of_process(self.container.init_pid)?;
+            let wd = match translate_working_directory(
+                host_wd,
+                self.rootfs.as_path(),
+                &host_mounts,
+                &container_mounts,
+            ) {
+                Some(wd) => wd,
+                None => return Ok(None),
+            };
+            let container_root = HostPath::new(format!("/proc/{}/root", self.container.init_pid))?;
+            let is_dir = ContainerPath::new(&wd)?
+                .to_host_path(&container_root)
+                .as_path()
+                .is_dir();
+            Ok(Some(wd).filter(|_| is_dir))
+        })();
+        match translated {
+            Ok(Some(wd)) => wd,
+            Ok(None) => {
+                log::warn!(
+                    "{:?} is not accessible in the distro. Using / as the working directory.",
+                    host_wd
+                );
+                PathBuf::from("/")
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to translate the working directory {:?}. Using / instead. {:?}",
+                    host_wd,
+                    e
+                );
+                PathBuf::from("/")
+            }
+        }
+    }
+
+    /// Create the given working directory inside the running container if it doesn't exist.
+    /// Newly created directories are owned by the given credential, or root if it's None.
+    /// Only directories under CREATABLE_WORKING_DIRECTORY_ROOTS can be created so that
+    /// system directories are not created by mistake.
+    pub fn create_working_directory<P: AsRef<Path>>(
+        &self,
+        wd: P,
+        cred: Option<&Credential>,
+    ) -> Result<()> {
+        let wd = ContainerPath::new(wd.as_ref())?;
+        if wd
+            .components()
+            .any(|c| !matches!(c, Component::RootDir | Component::Normal(_)))
+        {
+            bail!(
+                "The working directory {:?} should not contain '.' or '..'.",
+                wd.as_path()
+            );
+        }
+        if !CREATABLE_WORKING_DIRECTORY_ROOTS
+            .iter()
+            .any(|root| wd.starts_with(root) && wd.as_path() != Path::new(root))
+        {
+            bail!(
+                "Distrod creates a working directory only under {}, but {:?} was given.",
+                CREATABLE_WORKING_DIRECTORY_ROOTS.join(", "),
+                wd.as_path()
+            );
+        }
+
+        // Resolve the path through /proc/PID/root so that the container's own mounts such as
+        // its /tmp are seen.
+        let container_root = HostPath::new(format!("/proc/{}/root", self.container.init_pid))?;
+        let (uid, gid) = cred
+            .map(|cred| (cred.uid, cred.gid))
+            .unwrap_or_else(|| (Uid::from_raw(0), Gid::from_raw(0)));
+        let mut current = ContainerPath::new("/")?;
+        for component in wd.components().skip(1) {
+            current.push(component);
+            let host_path = current.to_host_path(&container_root);
+            match fs::symlink_metadata(&host_path) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    bail!(
+                        "{:?} is a symlink. Distrod doesn't create a working directory across symlinks.",
+                        current.as_path()
+                    );
+                }
+                Ok(metadata) if !metadata.is_dir() => {
+                    bail!("{:?} is not a directory.", current.as_path());
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to get the metadata of {:?}.", current.as_path())
+                    });
+                }
+            }
+            fs::create_dir(&host_path)
+                .with_context(|| format!("Failed to create {:?}.", current.as_path()))?;
+            nix::unistd::chown(host_path.as_path(), Some(uid), Some(gid))
+                .with_context(|| format!("Failed to chown {:?}.", current.as_path()))?;
+            log::debug!("Created the working directory {:?}.", current.as_path());
+        }
+        Ok(())
+    }
+
     pub fn stop(self, sigkill: bool) -> Result<()> {
         self.container.stop(sigkill)
     }
 }
 
+const NO_SYSTEMD_ENV_NAME: &str = "DISTROD_NO_SYSTEMD";
+
+/// Whether DISTROD_NO_SYSTEMD asks to run commands directly without the systemd container.
+pub fn is_systemd_disabled_by_env() -> bool {
+    matches!(std::env::var(NO_SYSTEMD_ENV_NAME), Ok(val) if !val.is_empty() && val != "0")
+}
+
+/// Whether the filesystem type is the one of the Windows drives, on which I/O is much slower
+/// than on the WSL's own filesystem.
+pub fn is_windows_fs_type(fstype: &str) -> bool {
+    fstype == "9p" || fstype == "drvfs"
+}
+
+/// Get the type of the filesystem where the path is if it's on a Windows drive.
+pub fn get_windows_fs_type<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    let mounts = get_mount_entries().with_context(|| "Failed to get the mount entries.")?;
+    Ok(find_mount_entry(&mounts, path)
+        .filter(|entry| is_windows_fs_type(&entry.fstype))
+        .map(|entry| entry.fstype.clone()))
+}
+
 pub fn is_inside_running_distro() -> bool {
     let mounts = get_mount_entries();
-    if mounts.is_err() {
+    let old_root = distrod_config::get_distro_old_root_path();
+    if mounts.is_err() || old_root.is_err() {
         return true;
     }
-    let mounts = mounts.unwrap();
-    mounts
-        .iter()
-        .any(|entry| entry.path.starts_with(DISTRO_OLD_ROOT_PATH))
+    is_old_root_mounted(&mounts.unwrap(), &old_root.unwrap())
+}
+
+fn is_old_root_mounted(mounts: &[MountEntry], old_root: &Path) -> bool {
+    mounts.iter().any(|entry| entry.path.starts_with(old_root))
 }
 
 pub fn initialize_distro_rootfs<P: AsRef<HostPath>>(
     rootfs: P,
     overwrites_potential_userfiles: bool,
+    dry_run: bool,
 ) -> Result<()> {
-    let rootfs = rootfs.as_ref();
-    do_distro_independent_initialization(rootfs, overwrites_potential_userfiles)?;
-    do_distro_specific_initialization(rootfs, overwrites_potential_userfiles)
+    initialize_distro_rootfs_with_hostname(rootfs, overwrites_potential_userfiles, None, dry_run)
 }
 
-fn do_distro_independent_initialization(
-    rootfs: &HostPath,
+/// Initialize the rootfs as `initialize_distro_rootfs` does, setting the hostname to the given
+/// one instead of the hostname of the WSL environment.
+/// If `dry_run` is true, the changes to the rootfs are only logged.
+pub fn initialize_distro_rootfs_with_hostname<P: AsRef<HostPath>>(
+    rootfs: P,
     overwrites_potential_userfiles: bool,
+    hostname: Option<&str>,
+    dry_run: bool,
 ) -> Result<()> {
-    fix_hostname(rootfs)?;
-    disable_incompatible_systemd_network_configuration(rootfs, overwrites_potential_userfiles)?;
-    disable_incompatible_systemd_services(rootfs);
-    disable_incompatible_systemd_service_options(rootfs);
-    create_per_user_envs_init_loader_script(rootfs)
-        .with_context(|| "Failed to create per-user WSL envs load script.")?;
-    Ok(())
+    let rootfs = rootfs.as_ref();
+    if let Some(hostname) = hostname {
+        validate_hostname(hostname)?;
+    }
+    check_rootfs_arch(rootfs)?;
+    do_distro_independent_initialization(
+        rootfs,
+        overwrites_potential_userfiles,
+        hostname,
+        dry_run,
+    )?;
+    do_distro_specific_initialization(rootfs, overwrites_potential_userfiles, dry_run)
 }
 
-fn disable_incompatible_systemd_network_configuration(
-    rootfs: &HostPath,
-    overwrites_potential_userfiles: bool,
-) -> Result<(), anyhow::Error> {
-    // Remove systemd network configurations
-    for path in glob::glob(
-        ContainerPath::new("/etc/systemd/network/*.network")?
-            .to_host_path(rootfs)
-            .as_os_str()
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to convert systemd network file paths."))?,
-    )? {
-        let path = path?;
-        fs::remove_file(&path).with_context(|| format!("Failed to remove '{:?}'.", &path))?;
+/// Bail if the executables in the rootfs are not for the host architecture,
+/// instead of failing with a confusing ENOEXEC when they are executed.
+fn check_rootfs_arch(rootfs: &HostPath) -> Result<()> {
+    let host_arch = match get_host_arch() {
+        Some(arch) => arch,
+        None => return Ok(()),
+    };
+    let image_arch = ["/sbin/init", "/bin/sh", "/usr/bin/sh"]
+        .iter()
+        .find_map(|path| probe_elf_arch(rootfs, path));
+    let image_arch = match image_arch {
+        Some(arch) => arch,
+        None => {
+            log::debug!(
+                "Couldn't detect the architecture of the rootfs {:?}.",
+                rootfs
+            );
+            return Ok(());
+        }
+    };
+    // 32-bit x86 binaries can run on x86-64.
+    if image_arch != host_arch && !(host_arch == "amd64" && image_arch == "i386") {
+        bail!(
+            "This image is {}, but the host is {}. Please use an image for {}.",
+            image_arch,
+            host_arch,
+            host_arch
+        );
     }
-    // Remove netplan network configurations
-    for path in glob::glob(
-        ContainerPath::new("/etc/netplan/*.yaml")?
-            .to_host_path(rootfs)
-            .as_os_str()
-            .to_str()
-            .ok_or_else(|| anyhow!("Failed to convert netplan network file paths."))?,
-    )? {
-        let path = path?;
-        fs::remove_file(&path).with_context(|| format!("Failed to remove '{:?}'.", &path))?;
+    Ok(())
+}
+
+fn get_host_arch() -> Option<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some("amd64"),
+        "x86" => Some("i386"),
+        "aarch64" => Some("arm64"),
+        "arm" => Some("armhf"),
+        _ => None,
     }
-    // Remove network-scripts configurations
-    let path_to_network =
-        ContainerPath::new("/etc/sysconfig/network-scripts/ifcfg-eth0")?.to_host_path(rootfs);
-    if path_to_network.exists() {
+}
+
+fn probe_elf_arch(rootfs: &HostPath, path: &str) -> Option<&'static str> {
+    let host_path = ContainerPath::new(path).ok()?.resolve_in_rootfs(rootfs)?;
+    let mut header = [0u8; 20];
+    File::open(&host_path).ok()?.read_exact(&mut header).ok()?;
+    get_elf_arch(&header)
+}
+
+/// The init systems which Distrod can tell apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSystem {
+    Systemd,
+    SysVinit,
+    OpenRc,
+    Busybox,
+    Unknown,
+}
+
+const SYSTEMD_PATHS: &[&str] = &["/lib/systemd/systemd", "/usr/lib/systemd/systemd"];
+
+/// Detect the init system of the rootfs, and return it with the path of the init to launch.
+fn detect_init_system(rootfs: &HostPath) -> (InitSystem, PathBuf) {
+    let existing_host_path = |path: &str| {
+        ContainerPath::new(path)
+            .ok()?
+            .resolve_in_rootfs(rootfs)
+            .filter(|host_path| host_path.as_path().is_file())
+    };
+    let systemd = SYSTEMD_PATHS
+        .iter()
+        .find_map(|path| existing_host_path(path).map(|host_path| (*path, host_path)));
+    let init = match existing_host_path("/sbin/init") {
+        Some(init) => init,
+        // Some minimal images have systemd but not /sbin/init.
+        None => {
+            return match systemd {
+                Some((path, _)) => (InitSystem::Systemd, PathBuf::from(path)),
+                None => (InitSystem::Unknown, PathBuf::from("/sbin/init")),
+            }
+        }
+    };
+    let init_system = match init.as_path().file_name().and_then(|name| name.to_str()) {
+        Some("systemd") => InitSystem::Systemd,
+        Some("busybox") => InitSystem::Busybox,
+        Some("openrc-init") => InitSystem::OpenRc,
+        // /sbin/init can be a hard link to systemd.
+        _ if matches!(systemd, Some((_, ref systemd)) if is_same_file(&init, systemd)) => {
+            InitSystem::Systemd
+        }
+        _ if ContainerPath::new("/etc/inittab")
+            .map(|inittab| inittab.to_host_path(rootfs).as_path().exists())
+            .unwrap_or(false) =>
+        {
+            InitSystem::SysVinit
+        }
+        _ => InitSystem::Unknown,
+    };
+    (init_system, PathBuf::from("/sbin/init"))
+}
+
+/// The directories where systemd looks for the system units, in the order of priority.
+const SYSTEMD_SYSTEM_UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/run/systemd/system",
+    "/usr/local/lib/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+];
+
+/// Parse the umask in octal such as "022" or "0077".
+pub fn parse_umask(umask: &str) -> Result<Mode> {
+    let is_octal =
+        !umask.is_empty() && umask.len() <= 4 && umask.chars().all(|c| ('0'..='7').contains(&c));
+    let mode = u32::from_str_radix(umask, 8)
+        .ok()
+        .filter(|bits| is_octal && *bits <= 0o777)
+        .and_then(|bits| Mode::from_bits(bits as nix::libc::mode_t));
+    mode.ok_or_else(|| {
+        anyhow!(
+            "'{}' is not a valid umask. Give it in octal from 000 to 777, such as 022.",
+            umask
+        )
+    })
+}
+
+/// Set the umask of the command in the child process just before exec.
+pub fn set_umask_on_exec(command: &mut Command, umask: Mode) {
+    unsafe {
+        command.pre_exec(move || {
+            nix::sys::stat::umask(umask);
+            Ok(())
+        });
+    }
+}
+
+/// Validate the name of a target unit, such as "graphical.target" or "rescue.target".
+pub fn validate_target_name(target: &str) -> Result<()> {
+    let prefix = target.strip_suffix(".target").unwrap_or("");
+    let is_valid = !prefix.is_empty()
+        && target.len() <= 255
+        && prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ":_.\\@-".contains(c));
+    if !is_valid {
+        bail!(
+            "'{}' is not a valid target name. It must end with '.target', such as 'graphical.target'.",
+            target
+        );
+    }
+    Ok(())
+}
+
+/// The slices which the resource limits are applied to. system.slice has the system services,
+/// and user.slice has the user sessions and their services.
+const RESOURCE_LIMITED_SLICES: &[&str] = &["system.slice", "user.slice"];
+/// MemoryMax and CPUQuota of the slices are applied by systemd 244 or later reliably.
+const MIN_SYSTEMD_VERSION_FOR_RESOURCE_LIMITS: u32 = 244;
+
+/// The resource limits of the slices. None means the limit is not changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub memory_max: Option<String>,
+    pub cpu_quota: Option<String>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.cpu_quota.is_none()
+    }
+}
+
+/// Validate a value of MemoryMax, such as "2G", "512M", "50%", or "infinity".
+pub fn validate_memory_max(memory_max: &str) -> Result<()> {
+    if memory_max == "infinity" {
+        return Ok(());
+    }
+    let is_valid = match memory_max.strip_suffix('%') {
+        Some(percentage) => parse_positive_number(percentage).map_or(false, |p| p <= 100.0),
+        None => {
+            let number = memory_max
+                .strip_suffix(|c| "KMGTPE".contains(c))
+                .unwrap_or(memory_max);
+            parse_positive_number(number).is_some()
+        }
+    };
+    if !is_valid {
+        bail!(
+            "'{}' is not a valid memory limit. It must be a size such as '2G' or '512M', \
+             a percentage of the physical memory such as '50%', or 'infinity'.",
+            memory_max
+        );
+    }
+    Ok(())
+}
+
+/// Validate a value of CPUQuota, such as "150%" for 1.5 CPUs, or "infinity".
+pub fn validate_cpu_quota(cpu_quota: &str) -> Result<()> {
+    if cpu_quota == "infinity" {
+        return Ok(());
+    }
+    let is_valid = cpu_quota
+        .strip_suffix('%')
+        .and_then(parse_positive_number)
+        .is_some();
+    if !is_valid {
+        bail!(
+            "'{}' is not a valid CPU quota. It must be a percentage of a CPU such as '150%', \
+             or 'infinity'.",
+            cpu_quota
+        );
+    }
+    Ok(())
+}
+
+fn parse_positive_number(number: &str) -> Option<f64> {
+    // f64::from_str accepts more forms than systemd such as "1e3" and "inf".
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    number.parse::<f64>().ok().filter(|n| *n > 0.0)
+}
+
+/// Write the resource limits to the drop-ins of the system and the user slices, so that the
+/// services stay in their own slices. The limits replace the ones written at the previous launch.
+fn write_resource_limits(rootfs: &HostPath, limits: &ResourceLimits) -> Result<()> {
+    match get_systemd_version(rootfs) {
+        Some(version) if version < MIN_SYSTEMD_VERSION_FOR_RESOURCE_LIMITS => log::warn!(
+            "The systemd of the distro is version {}, which may ignore the resource limits. \
+             Version {} or later is required.",
+            version,
+            MIN_SYSTEMD_VERSION_FOR_RESOURCE_LIMITS
+        ),
+        Some(_) => {}
+        None => log::warn!(
+            "Failed to get the version of the systemd of the distro. \
+             The resource limits require version {} or later.",
+            MIN_SYSTEMD_VERSION_FOR_RESOURCE_LIMITS
+        ),
+    }
+    for slice in RESOURCE_LIMITED_SLICES {
+        let mut slice_overrider = SystemdUnitOverride::default();
+        slice_overrider.put_section("Slice".to_owned());
+        if let Some(ref memory_max) = limits.memory_max {
+            slice_overrider.append_directive("Slice", "MemoryMax", memory_max.clone());
+        }
+        // CPUQuota doesn't accept "infinity". The CPU usage is not limited without it.
+        if let Some(cpu_quota) = limits.cpu_quota.as_ref().filter(|q| *q != "infinity") {
+            slice_overrider.append_directive("Slice", "CPUQuota", cpu_quota.clone());
+        }
+        slice_overrider
+            .write(rootfs, slice)
+            .with_context(|| format!("Failed to write the drop-in of {}.", slice))?;
+    }
+    Ok(())
+}
+
+/// Get the version of systemd in the rootfs by the name of its shared library, such as
+/// libsystemd-shared-245.so or libsystemd-shared-254.10-1.fc39.so.
+fn get_systemd_version(rootfs: &HostPath) -> Option<u32> {
+    const SYSTEMD_LIB_DIRS: &[&str] = &["/usr/lib/systemd", "/lib/systemd", "/usr/lib64/systemd"];
+    SYSTEMD_LIB_DIRS.iter().find_map(|dir| {
+        let dir = ContainerPath::new(dir).ok()?.to_host_path(rootfs);
+        fs::read_dir(dir.as_path()).ok()?.find_map(|entry| {
+            let name = entry.ok()?.file_name();
+            parse_systemd_shared_lib_version(name.to_str()?)
+        })
+    })
+}
+
+fn parse_systemd_shared_lib_version(file_name: &str) -> Option<u32> {
+    let version = file_name.strip_prefix("libsystemd-shared-")?;
+    if !file_name.ends_with(".so") {
+        return None;
+    }
+    let digits: String = version.chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok()
+}
+
+fn get_systemd_init_args(target: &str) -> Vec<String> {
+    vec![format!("--unit={}", target)]
+}
+
+fn is_systemd_unit_installed(rootfs: &HostPath, unit: &str) -> bool {
+    SYSTEMD_SYSTEM_UNIT_DIRS.iter().any(|dir| {
+        ContainerPath::new(dir)
+            .map(|dir| fs::symlink_metadata(dir.to_host_path(rootfs).as_path().join(unit)).is_ok())
+            .unwrap_or(false)
+    })
+}
+
+fn is_same_file(a: &HostPath, b: &HostPath) -> bool {
+    match (fs::metadata(a.as_path()), fs::metadata(b.as_path())) {
+        (Ok(a), Ok(b)) => a.st_dev() == b.st_dev() && a.st_ino() == b.st_ino(),
+        _ => false,
+    }
+}
+
+/// Get the architecture name of an ELF file from its header.
+fn get_elf_arch(header: &[u8]) -> Option<&'static str> {
+    const EM_386: u16 = 3;
+    const EM_ARM: u16 = 40;
+    const EM_X86_64: u16 = 62;
+    const EM_AARCH64: u16 = 183;
+    const EI_DATA: usize = 5;
+    const ELFDATA2LSB: u8 = 1;
+    const ELFDATA2MSB: u8 = 2;
+    const E_MACHINE: usize = 18;
+
+    if header.len() < E_MACHINE + 2 || !header.starts_with(b"\x7fELF") {
+        return None;
+    }
+    let e_machine = [header[E_MACHINE], header[E_MACHINE + 1]];
+    let machine = match header[EI_DATA] {
+        ELFDATA2LSB => u16::from_le_bytes(e_machine),
+        ELFDATA2MSB => u16::from_be_bytes(e_machine),
+        _ => return None,
+    };
+    match machine {
+        EM_386 => Some("i386"),
+        EM_ARM => Some("armhf"),
+        EM_X86_64 => Some("amd64"),
+        EM_AARCH64 => Some("arm64"),
+        _ => None,
+    }
+}
+
+fn do_distro_independent_initialization(
+    rootfs: &HostPath,
+    overwrites_potential_userfiles: bool,
+    hostname: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    let kept_services = KeptNetworkServices::from_config();
+    let syncs_hostname = DistrodConfig::get()
+        .map(|config| config.distrod.sync_hostname != Some(false))
+        .unwrap_or(true);
+    fix_hostname(rootfs, hostname, syncs_hostname, dry_run)?;
+    disable_incompatible_systemd_network_configuration(
+        rootfs,
+        overwrites_potential_userfiles,
+        kept_services,
+        dry_run,
+    )?;
+    disable_incompatible_systemd_services(rootfs, kept_services, dry_run);
+    if let Err(e) = write_custom_resolv_conf(rootfs, dry_run) {
+        log::warn!(
+            "Failed to write the resolv.conf for the custom DNS servers. {:?}",
+            e
+        );
+    }
+    disable_incompatible_systemd_service_options(rootfs, dry_run);
+    create_per_user_envs_init_loader_script(rootfs, dry_run)
+        .with_context(|| "Failed to create per-user WSL envs load script.")?;
+    Ok(())
+}
+
+/// The network services which the user opted in to keep enabled in the distro.
+#[derive(Debug, Clone, Copy, Default)]
+struct KeptNetworkServices {
+    networkd: bool,
+    resolved: bool,
+}
+
+impl KeptNetworkServices {
+    fn from_config() -> Self {
+        match DistrodConfig::get() {
+            Ok(config) => KeptNetworkServices {
+                networkd: config.distrod.keep_systemd_networkd,
+                resolved: config.distrod.keep_systemd_resolved,
+            },
+            Err(e) => {
+                log::debug!("Failed to get the Distrod config. {:?}", e);
+                KeptNetworkServices::default()
+            }
+        }
+    }
+}
+
+fn disable_incompatible_systemd_network_configuration(
+    rootfs: &HostPath,
+    overwrites_potential_userfiles: bool,
+    kept_services: KeptNetworkServices,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    // Remove systemd network configurations unless the user keeps systemd-networkd, in which case
+    // they are the user's configurations.
+    if !kept_services.networkd {
+        for path in glob::glob(
+            ContainerPath::new("/etc/systemd/network/*.network")?
+                .to_host_path(rootfs)
+                .as_os_str()
+                .to_str()
+                .ok_or_else(|| anyhow!("Failed to convert systemd network file paths."))?,
+        )? {
+            remove_file_unless_dry_run(&path?, dry_run)?;
+        }
+    }
+    // Remove netplan network configurations
+    for path in glob::glob(
+        ContainerPath::new("/etc/netplan/*.yaml")?
+            .to_host_path(rootfs)
+            .as_os_str()
+            .to_str()
+            .ok_or_else(|| anyhow!("Failed to convert netplan network file paths."))?,
+    )? {
+        remove_file_unless_dry_run(&path?, dry_run)?;
+    }
+    // Remove network-scripts configurations
+    let path_to_network =
+        ContainerPath::new("/etc/sysconfig/network-scripts/ifcfg-eth0")?.to_host_path(rootfs);
+    if path_to_network.exists() {
         let backup_name =
             ContainerPath::new("/etc/sysconfig/network-scripts/disabled-by-distrod.ifcfg-eth0")?
                 .to_host_path(rootfs);
-        fs::rename(&path_to_network, &backup_name).with_context(|| {
-            format!(
-                "Failed to move {:?} to {:?}",
-                &path_to_network, &backup_name
-            )
-        })?;
+        if dry_run {
+            log::info!("Would move {:?} to {:?}.", &path_to_network, &backup_name);
+        } else {
+            fs::rename(&path_to_network, &backup_name).with_context(|| {
+                format!(
+                    "Failed to move {:?} to {:?}",
+                    &path_to_network, &backup_name
+                )
+            })?;
+        }
     }
-    // Remove the link from /etc/resolv.conf to systemd
+    // Remove the link from /etc/resolv.conf to the files generated by the disabled network
+    // services. The link to systemd is kept if systemd-resolved is kept since it needs the link.
     if overwrites_potential_userfiles {
-        remove_systemd_resolv_conf(rootfs)
-            .with_context(|| "Failed to remove systemd's resolv.conf")?;
+        remove_runtime_resolv_conf_link(rootfs, kept_services.resolved, dry_run)
+            .with_context(|| "Failed to remove the link of resolv.conf")?;
     }
     Ok(())
 }
 
-fn remove_systemd_resolv_conf(rootfs: &HostPath) -> Result<()> {
+fn remove_runtime_resolv_conf_link(
+    rootfs: &HostPath,
+    keeps_resolved: bool,
+    dry_run: bool,
+) -> Result<()> {
     let resolv_conf_path = ContainerPath::new("/etc/resolv.conf")?.to_host_path(rootfs);
-    let metadata = fs::symlink_metadata(&resolv_conf_path)
-        .with_context(|| format!("Failed to get the symlink_metadata {:?}", &resolv_conf_path))?;
+    // The mounted file hides the link if any, and it can't be removed anyway.
+    if is_mounted_file(&resolv_conf_path) {
+        log::info!(
+            "Not replacing {:?} since a file is mounted on it.",
+            &resolv_conf_path
+        );
+        return Ok(());
+    }
+    let metadata = match fs::symlink_metadata(&resolv_conf_path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to get the symlink_metadata {:?}", &resolv_conf_path)
+            })
+        }
+    };
     if !metadata.file_type().is_symlink() {
         return Ok(());
     }
     let link_to = std::fs::read_link(&resolv_conf_path)
         .with_context(|| format!("Failed to read link {:?}", &resolv_conf_path))?;
-    if link_to.components().any(|name| matches!(name, std::path::Component::Normal(path) if path.to_str() == Some("systemd"))) {
-            fs::remove_file(&resolv_conf_path)
-                .with_context(|| format!("Failed to remove '{:?}'.", &resolv_conf_path))?;
-            // Touch /etc/resolv.conf so that WSL over-writes it or we can do bind-mount on it
-            File::create(&resolv_conf_path)
-                .with_context(|| format!("Failed to touch '{:?}'", &resolv_conf_path))?;
+    if is_runtime_resolv_conf_link(rootfs, &link_to, keeps_resolved) {
+        if dry_run {
+            log::info!(
+                "Would replace the link of {:?} to {:?} with an empty file.",
+                &resolv_conf_path,
+                &link_to
+            );
+            return Ok(());
         }
+        log::debug!(
+            "Replacing the link of /etc/resolv.conf to {:?} with a file.",
+            &link_to
+        );
+        fs::remove_file(&resolv_conf_path)
+            .with_context(|| format!("Failed to remove '{:?}'.", &resolv_conf_path))?;
+        // Touch /etc/resolv.conf so that WSL over-writes it or we can do bind-mount on it
+        File::create(&resolv_conf_path)
+            .with_context(|| format!("Failed to touch '{:?}'", &resolv_conf_path))?;
+    }
     Ok(())
 }
 
-fn fix_hostname(rootfs: &HostPath) -> Result<()> {
+/// Whether a file is mounted on the path, such as /etc/resolv.conf of the running distro or the one
+/// left by a previous run. Such a file can be neither removed nor renamed over.
+fn is_mounted_file(path: &HostPath) -> bool {
+    // Don't canonicalize the path itself, which can be a symlink to the mounted file.
+    let path = match (path.as_path().parent(), path.as_path().file_name()) {
+        (Some(parent), Some(file_name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(file_name),
+            Err(_) => return false,
+        },
+        _ => return false,
+    };
+    match get_mount_entries() {
+        Ok(mount_entries) => is_mountpoint(&mount_entries, &path),
+        Err(e) => {
+            log::debug!("Failed to get the mount entries. {:?}", e);
+            false
+        }
+    }
+}
+
+/// Whether the target of the /etc/resolv.conf symlink is a file generated at runtime by
+/// systemd-resolved or NetworkManager, or any other file which won't exist at runtime.
+pub fn is_runtime_resolv_conf_link(
+    rootfs: &HostPath,
+    link_to: &Path,
+    keeps_resolved: bool,
+) -> bool {
+    let has_component = |component: &str| {
+        link_to.components().any(|name| {
+            matches!(name, std::path::Component::Normal(path) if path.to_str() == Some(component))
+        })
+    };
+    if has_component("systemd") || link_to.file_name() == Some(OsStr::new("stub-resolv.conf")) {
+        return !keeps_resolved;
+    }
+    if has_component("NetworkManager") {
+        return true;
+    }
+    // /run is empty in the rootfs, so the links to the files in it are dangling here as well.
+    let target = match ContainerPath::new(Path::new("/etc").join(link_to)) {
+        Ok(target) => target,
+        Err(_) => return true,
+    };
+    !matches!(target.resolve_in_rootfs(rootfs), Some(target) if target.as_path().exists())
+}
+
+/// Set the hostname of the distro to the given one, or the one of the WSL environment.
+/// If `syncs_hostname` is false, the hostname is kept unless it's still the placeholder
+/// of the image.
+fn fix_hostname(
+    rootfs: &HostPath,
+    hostname: Option<&str>,
+    syncs_hostname: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let old_hostname = read_etc_hostname(rootfs)?;
+    if let Some(hostname) = hostname {
+        update_etc_hostname(rootfs, hostname, dry_run)
+            .with_context(|| "Failed to update /etc/hostname.")?;
+        update_etc_hosts(rootfs, old_hostname.as_deref(), hostname, dry_run)
+            .with_context(|| "Failed to update /etc/hosts.")?;
+        return Ok(());
+    }
+    if !syncs_hostname && !has_placeholder_hostname(rootfs)? {
+        log::debug!("Keeping the hostname of the distro since sync_hostname is false.");
+        return Ok(());
+    }
     let mut hostname_buf = vec![0; 64];
     let hostname = nix::unistd::gethostname(&mut hostname_buf)
         .with_context(|| "Failed to get hostname.")?
@@ -672,48 +1958,181 @@ fn fix_hostname(rootfs: &HostPath) -> Result<()> {
     let hostname = hostname
         .with_context(|| format!("Failed to convert hostname to string. {:#?}", &hostname))?;
 
-    update_etc_hostname(rootfs, hostname).with_context(|| "Failed to update /etc/hostname.")?;
-    update_etc_hosts(rootfs, hostname).with_context(|| "Failed to update /etc/hosts.")?;
+    update_etc_hostname(rootfs, hostname, dry_run)
+        .with_context(|| "Failed to update /etc/hostname.")?;
+    update_etc_hosts(rootfs, old_hostname.as_deref(), hostname, dry_run)
+        .with_context(|| "Failed to update /etc/hosts.")?;
 
     Ok(())
 }
 
-fn update_etc_hostname(rootfs: &HostPath, hostname: &str) -> Result<()> {
+fn read_etc_hostname(rootfs: &HostPath) -> Result<Option<String>> {
     let hostname_path = ContainerPath::new("/etc/hostname")?.to_host_path(rootfs);
-    fs::write(&hostname_path, hostname.as_bytes())
+    match fs::read_to_string(hostname_path.as_path()) {
+        Ok(cont) => Ok(Some(cont.trim().to_owned()).filter(|name| !name.is_empty())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {:?}.", &hostname_path)),
+    }
+}
+
+fn has_placeholder_hostname(rootfs: &HostPath) -> Result<bool> {
+    for path in &["/etc/hostname", "/etc/hosts"] {
+        let path = ContainerPath::new(path)?.to_host_path(rootfs);
+        match fs::read_to_string(path.as_path()) {
+            Ok(cont) if cont.contains(HOSTNAME_PLACEHOLDER) => return Ok(true),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", &path)),
+        }
+    }
+    Ok(false)
+}
+
+/// The hostname in the images of linuxcontainers.org, which is replaced with the actual one.
+const HOSTNAME_PLACEHOLDER: &str = "LXC_NAME";
+
+/// Bail if the hostname is not valid as per RFC 1123.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let is_valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    if hostname.len() > 253 || !hostname.split('.').all(is_valid_label) {
+        bail!(
+            "'{}' is not a valid hostname. Use alphanumerics and hyphens for each label \
+             of up to 63 characters separated by dots.",
+            hostname
+        );
+    }
+    Ok(())
+}
+
+/// Change the hostname of the distro in /etc/hostname and /etc/hosts.
+pub fn set_distro_hostname<P: AsRef<HostPath>>(rootfs: P, hostname: &str) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    validate_hostname(hostname)?;
+    let old_hostname = read_etc_hostname(rootfs)?;
+    update_etc_hostname(rootfs, hostname, false)
+        .with_context(|| "Failed to update /etc/hostname.")?;
+    update_etc_hosts(rootfs, old_hostname.as_deref(), hostname, false)
+        .with_context(|| "Failed to update /etc/hosts.")?;
+    Ok(())
+}
+
+fn update_etc_hostname(rootfs: &HostPath, hostname: &str, dry_run: bool) -> Result<()> {
+    let hostname_path = ContainerPath::new("/etc/hostname")?;
+    if dry_run && read_etc_hostname(rootfs)?.as_deref() == Some(hostname) {
+        return Ok(());
+    }
+    overwrite_file_unless_dry_run(rootfs, &hostname_path, hostname.as_bytes(), dry_run)
         .with_context(|| format!("Failed to write hostname to '{:?}'.", &hostname_path))?;
     Ok(())
 }
 
-fn update_etc_hosts(rootfs: &HostPath, hostname: &str) -> Result<()> {
-    // /etc/hosts has a line like
+/// Make /etc/hosts map the hostname to 127.0.1.1, which some programs such as sudo rely on.
+fn update_etc_hosts(
+    rootfs: &HostPath,
+    old_hostname: Option<&str>,
+    hostname: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let hosts_path = ContainerPath::new("/etc/hosts")?;
+    let current_hosts = read_file_in_rootfs(rootfs, &hosts_path)
+        .with_context(|| format!("Failed to read hosts file '{:?}'.", &hosts_path))?;
+    let new_hosts = update_hosts_content(&current_hosts, old_hostname, hostname)?;
+    if new_hosts != current_hosts {
+        overwrite_file_unless_dry_run(rootfs, &hosts_path, new_hosts.as_bytes(), dry_run)
+            .with_context(|| format!("Failed to write hostname to '{:?}'.", &hosts_path))?;
+    }
+    Ok(())
+}
+
+const HOSTNAME_LOOPBACK_ADDRESS: &str = "127.0.1.1";
+
+fn update_hosts_content(
+    current_hosts: &str,
+    old_hostname: Option<&str>,
+    hostname: &str,
+) -> Result<String> {
+    // /etc/hosts of the images of linuxcontainers.org has a line like
     // 127.0.1.1     LXC_NAME
     // We replace the LXC_NAME with the actual hostname.
+    let placeholder_pattern = regex::Regex::new(&format!(r#"\b{}\b"#, HOSTNAME_PLACEHOLDER))
+        .expect("Failed to compile the regex for /etc/hosts.");
+    let hosts = placeholder_pattern.replace_all(current_hosts, hostname);
 
-    let hosts_path = ContainerPath::new("/etc/hosts")?.to_host_path(rootfs);
-    let current_hosts = fs::read_to_string(hosts_path.as_path())
-        .with_context(|| format!("Failed to read hosts file '{:?}'.", &hosts_path))?;
-    let line_pattern =
-        regex::Regex::new(r#"\bLXC_NAME\b"#).expect("Failed to compile the regex for /etc/hosts.");
-    let new_hosts = line_pattern.replace_all(&current_hosts, hostname);
-    fs::write(&hosts_path, new_hosts.as_bytes())
-        .with_context(|| format!("Failed to write hostname to '{:?}'.", &hosts_path))?;
-    Ok(())
+    // Other images or the hosts edited by the user may not have the placeholder, so make sure
+    // that there is a line for the hostname, updating the one for the previous hostname if any.
+    let maps_to_loopback = |line: &str, name: &str| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        fields.next() == Some(HOSTNAME_LOOPBACK_ADDRESS) && fields.any(|field| field == name)
+    };
+    let mut lines: Vec<String> = hosts.lines().map(|line| line.to_owned()).collect();
+    let has_hostname_line = lines.iter().any(|line| maps_to_loopback(line, hostname));
+    if has_hostname_line {
+        return Ok(hosts.into_owned());
+    }
+    if let Some(old_hostname) = old_hostname {
+        let old_line = lines
+            .iter_mut()
+            .find(|line| maps_to_loopback(line, old_hostname));
+        if let Some(old_line) = old_line {
+            // Replace only the whole names outside the comment, not the ones like "ubuntu-ip6"
+            // for "ubuntu".
+            let (names, comment) = old_line.split_at(old_line.find('#').unwrap_or(old_line.len()));
+            let field_pattern =
+                regex::Regex::new(r#"[^ \t]+"#).expect("Failed to compile the regex for fields.");
+            let names = field_pattern.replace_all(names, |caps: &regex::Captures| {
+                if &caps[0] == old_hostname {
+                    hostname.to_owned()
+                } else {
+                    caps[0].to_owned()
+                }
+            });
+            *old_line = format!("{}{}", names, comment);
+            return Ok(lines.join("\n") + if hosts.ends_with('\n') { "\n" } else { "" });
+        }
+    }
+    let mut new_hosts = hosts.into_owned();
+    if !new_hosts.is_empty() && !new_hosts.ends_with('\n') {
+        new_hosts.push('\n');
+    }
+    new_hosts.push_str(&format!("{}\t{}\n", HOSTNAME_LOOPBACK_ADDRESS, hostname));
+    Ok(new_hosts)
 }
 
-fn disable_incompatible_systemd_services(rootfs: &HostPath) {
-    let to_be_disabled = [
+fn get_systemd_services_to_disable(kept_services: KeptNetworkServices) -> Vec<&'static str> {
+    let mut to_be_disabled = vec![
         "dhcpcd.service",
         "NetworkManager.service",
         "multipathd.service",
-        "systemd-networkd.service",
-        "systemd-resolved.service",
+    ];
+    if !kept_services.networkd {
+        to_be_disabled.push("systemd-networkd.service");
+    }
+    if !kept_services.resolved {
+        to_be_disabled.push("systemd-resolved.service");
+    }
+    to_be_disabled.extend(&[
         "networking.service",
         "fwupd-refresh.service",
         "fwupd-refresh.timer",
-    ];
-    for unit in &to_be_disabled {
-        let disabler = SystemdUnitDisabler::new(&rootfs.as_path(), unit);
+    ]);
+    to_be_disabled
+}
+
+fn disable_incompatible_systemd_services(
+    rootfs: &HostPath,
+    kept_services: KeptNetworkServices,
+    dry_run: bool,
+) {
+    for unit in &get_systemd_services_to_disable(kept_services) {
+        let mut disabler = SystemdUnitDisabler::new(&rootfs.as_path(), unit);
+        disabler.with_dry_run(dry_run);
         if matches!(disabler.is_masked(), Ok(true)) {
             continue;
         }
@@ -728,14 +2147,29 @@ fn disable_incompatible_systemd_services(rootfs: &HostPath) {
         "serial-getty@ttyS0.service",
         "console-getty.service",
     ];
-    for unit in &to_be_masked {
-        if let Err(err) = SystemdUnitDisabler::new(&rootfs.as_path(), unit).mask() {
+    let to_be_masked_if_installed = SYSTEMD_UNITS_TO_MASK_IF_INSTALLED
+        .iter()
+        .filter(|unit| is_systemd_unit_installed(rootfs, unit));
+    for unit in to_be_masked.iter().chain(to_be_masked_if_installed) {
+        let mut disabler = SystemdUnitDisabler::new(&rootfs.as_path(), unit);
+        if let Err(err) = disabler.with_dry_run(dry_run).mask() {
             log::warn!("Faled to mask {}. Error: {:?}", unit, err);
         }
     }
 }
 
-fn disable_incompatible_systemd_service_options(rootfs: &HostPath) {
+/// The units of newer systemd which fail under WSL. They are masked only if they are installed,
+/// not to leave masks of units which the distro doesn't have.
+/// systemd-oomd needs PSI, which the WSL kernel doesn't enable, and there is no pstore or
+/// hardware to update the database of in the container.
+const SYSTEMD_UNITS_TO_MASK_IF_INSTALLED: &[&str] = &[
+    "systemd-oomd.service",
+    "systemd-oomd.socket",
+    "systemd-pstore.service",
+    "systemd-hwdb-update.service",
+];
+
+fn disable_incompatible_systemd_service_options(rootfs: &HostPath, dry_run: bool) {
     let options = &[("systemd-sysusers.service", "Service", "LoadCredential")];
 
     for (service, section, option_directive) in options {
@@ -757,7 +2191,9 @@ fn disable_incompatible_systemd_service_options(rootfs: &HostPath) {
         }
 
         let mut overrider = SystemdUnitOverride::default();
-        overrider.unset_directive(*section, *option_directive);
+        overrider
+            .with_dry_run(dry_run)
+            .unset_directive(*section, *option_directive);
         if let Err(e) = overrider.write(rootfs, *service).with_context(|| {
             format!(
                 "Failed to disable option {:?} of {:?}",
@@ -769,44 +2205,118 @@ fn disable_incompatible_systemd_service_options(rootfs: &HostPath) {
     }
 }
 
-fn create_per_user_envs_init_loader_script(rootfs: &HostPath) -> Result<()> {
+const PER_USER_ENVS_INIT_LOADER_PATH: &str = "/etc/profile.d/distrod-user-wsl-envs.sh";
+/// The line in the loader script which tells that Distrod owns the file.
+const PER_USER_ENVS_INIT_LOADER_MARKER: &str =
+    "# This file is generated by Distrod. Remove this line to keep the file on uninstallation.";
+const DISTROD_RUNTIME_FILES_DIR: &str = "/run/distrod";
+
+fn create_per_user_envs_init_loader_script(rootfs: &HostPath, dry_run: bool) -> Result<()> {
+    let profile_dot_d_path = ContainerPath::new(PER_USER_ENVS_INIT_LOADER_PATH)?;
+    overwrite_file_unless_dry_run(
+        rootfs,
+        &profile_dot_d_path,
+        render_per_user_envs_init_loader_script().as_bytes(),
+        dry_run,
+    )
+    .with_context(|| format!("Failed to write to {:?}", &profile_dot_d_path))?;
+    Ok(())
+}
+
+fn render_per_user_envs_init_loader_script() -> String {
     let bytes = include_bytes!("../resources/load_per_user_wsl_envs.sh");
     let mut load_script = Template::new(String::from_utf8_lossy(bytes).into_owned());
     load_script.assign(
         "PER_USER_WSL_ENV_INIT_SCRIPT_PATH",
-        &get_per_user_envs_init_script_shellexp()?,
+        &get_per_user_envs_init_script_shellexp(),
     );
     load_script.assign(
         "ROOT_WSL_ENV_INIT_SCRIPT_PATH",
-        get_per_user_envs_init_script_path(&Credential::new(
-            Uid::from_raw(0),
-            Gid::from_raw(0),
-            vec![],
-        ))?
-        .to_str()
-        .ok_or_else(|| {
-            anyhow!("Failed to get the path to the per-user WSL env init script for root.")
-        })?,
+        &format!(
+            "{}/{}",
+            DISTROD_RUNTIME_FILES_DIR,
+            get_per_user_envs_init_script_name("0")
+        ),
     );
-    let profile_dot_d_path =
-        ContainerPath::new("/etc/profile.d/distrod-user-wsl-envs.sh")?.to_host_path(rootfs);
-    let mut profile_dot_d = BufWriter::new(
-        File::create(&profile_dot_d_path)
-            .with_context(|| format!("Failed to create {:?}", &profile_dot_d_path))?,
+    load_script.render()
+}
+
+/// Remove the loader script in /etc/profile.d unless the user has removed the marker line from it.
+fn is_per_user_envs_init_loader_script(cont: &str) -> bool {
+    if cont
+        .lines()
+        .any(|line| line == PER_USER_ENVS_INIT_LOADER_MARKER)
+    {
+        return true;
+    }
+    // The older versions of Distrod wrote the script without the marker.
+    let legacy_script = render_per_user_envs_init_loader_script().replacen(
+        &format!("{}\n", PER_USER_ENVS_INIT_LOADER_MARKER),
+        "",
+        1,
     );
-    profile_dot_d
-        .write_all(load_script.render().as_bytes())
-        .with_context(|| format!("Failed to write to {:?}", rootfs))?;
+    cont == legacy_script
+}
+
+fn remove_per_user_envs_init_loader_script(rootfs: &HostPath, dry_run: bool) -> Result<()> {
+    let profile_dot_d_path =
+        ContainerPath::new(PER_USER_ENVS_INIT_LOADER_PATH)?.to_host_path(rootfs);
+    let cont = match fs::read_to_string(&profile_dot_d_path) {
+        Ok(cont) => cont,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {:?}.", &profile_dot_d_path))
+        }
+    };
+    if !is_per_user_envs_init_loader_script(&cont) {
+        log::warn!(
+            "{:?} is left as it is because it's not marked as generated by Distrod.",
+            &profile_dot_d_path
+        );
+        return Ok(());
+    }
+    remove_file_unless_dry_run(&profile_dot_d_path, dry_run)
+}
+
+/// Remove the per-user scripts of the WSL environment variables that the last run of the distro
+/// left in the runtime directory. It's no-op while a distro is running since they are in use.
+pub fn cleanup_per_user_envs_init_scripts() -> Result<()> {
+    if DistroLauncher::get_running_distro()?.is_some() {
+        return Ok(());
+    }
+    remove_per_user_envs_init_scripts_in(Path::new(DISTROD_RUNTIME_FILES_DIR))
+}
+
+fn remove_per_user_envs_init_scripts_in(dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", dir)),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("Failed to read {:?}.", dir))?;
+        let is_script = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix(&get_per_user_envs_init_script_name("")))
+            .map_or(false, |uid| {
+                !uid.is_empty() && uid.chars().all(|c| c.is_ascii_digit())
+            });
+        if !is_script || !entry.file_type().map_or(false, |t| t.is_file()) {
+            continue;
+        }
+        fs::remove_file(entry.path())
+            .with_context(|| format!("Failed to remove {:?}.", entry.path()))?;
+    }
     Ok(())
 }
 
-fn get_per_user_envs_init_script_shellexp() -> Result<String> {
-    get_distrod_runtime_files_dir_path().map(|path| {
-        let mut path_string = path.to_string_lossy().to_string();
-        path_string += "/";
-        path_string += &get_per_user_envs_init_script_name("$(id -u)");
-        path_string
-    })
+fn get_per_user_envs_init_script_shellexp() -> String {
+    format!(
+        "{}/{}",
+        DISTROD_RUNTIME_FILES_DIR,
+        get_per_user_envs_init_script_name("$(id -u)")
+    )
 }
 
 fn get_per_user_envs_init_script_path(user: &Credential) -> Result<HostPath> {
@@ -825,19 +2335,24 @@ fn get_per_user_envs_init_script_name(uid: &str) -> String {
 fn do_distro_specific_initialization(
     rootfs: &HostPath,
     overwrites_potential_userfiles: bool,
+    dry_run: bool,
 ) -> Result<()> {
     use DistroName::*;
 
     match detect_distro(rootfs).with_context(|| "Failed to detect distro.")? {
-        Debian | Kali => initialize_debian_rootfs(rootfs, overwrites_potential_userfiles)
+        Debian | Kali => initialize_debian_rootfs(rootfs, overwrites_potential_userfiles, dry_run)
             .with_context(|| "Failed to do initialization for debian-based distros."),
-        _ => Ok(()),
+        // Ubuntu's /etc/pam.d/sudo already has pam_env.so, unlike its derivatives which may not.
+        Ubuntu => Ok(()),
+        Undetected => Ok(()),
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
 enum DistroName {
     Debian,
     Kali,
+    Ubuntu,
     Undetected,
 }
 
@@ -851,39 +2366,58 @@ fn detect_distro(rootfs: &HostPath) -> Result<DistroName> {
             return Ok(DistroName::Undetected);
         }
     }
-    match os_release?.get_env("ID").map(strip_quotes) {
-        Some("debian") => Ok(DistroName::Debian),
-        Some("kali") => Ok(DistroName::Kali),
-        _ => Ok(DistroName::Undetected),
+    Ok(detect_distro_from_os_release(&os_release?))
+}
+
+fn detect_distro_from_os_release(os_release: &EnvFile) -> DistroName {
+    match os_release.get_env("ID").map(strip_quotes) {
+        Some("debian") => return DistroName::Debian,
+        Some("kali") => return DistroName::Kali,
+        Some("ubuntu") => return DistroName::Ubuntu,
+        _ => {}
+    }
+    // Derivatives such as Linux Mint and Pop!_OS have their own ID, but tell their base by ID_LIKE.
+    let id_like = os_release
+        .get_env("ID_LIKE")
+        .map(strip_quotes)
+        .unwrap_or("");
+    if id_like
+        .split_whitespace()
+        .any(|id| id == "debian" || id == "ubuntu")
+    {
+        return DistroName::Debian;
     }
+    DistroName::Undetected
 }
 
+/// Strip the quotes around the value only if they are a matched pair of `"` or `'`.
 fn strip_quotes(s: &str) -> &str {
-    let mut result = s;
-    if s.starts_with('"') {
-        result = &result[1..result.len()];
-    }
-    if s.ends_with('"') {
-        result = &result[0..result.len() - 1];
+    for quote in ['"', '\''] {
+        if s.len() >= 2 && s.starts_with(quote) && s.ends_with(quote) {
+            return &s[1..s.len() - 1];
+        }
     }
-    result
+    s
 }
 
-fn initialize_debian_rootfs(rootfs: &HostPath, overwrites_potential_userfiles: bool) -> Result<()> {
+fn initialize_debian_rootfs(
+    rootfs: &HostPath,
+    overwrites_potential_userfiles: bool,
+    dry_run: bool,
+) -> Result<()> {
     if overwrites_potential_userfiles {
-        // Ubuntu doesn't need this.
-        put_readenv_in_sudo_pam(rootfs)
+        put_readenv_in_sudo_pam(rootfs, dry_run)
             .with_context(|| "Failed to put pam_env.so in /etc/pam.d/sudo.")?;
     }
     Ok(())
 }
 
-fn put_readenv_in_sudo_pam(rootfs: &HostPath) -> Result<()> {
+fn put_readenv_in_sudo_pam(rootfs: &HostPath, dry_run: bool) -> Result<()> {
     // Assume that the container's '/etc/pam.d/sudo' is not effective yet, so overwriting this is safe.
     // The calles must guarantee that the pam file is not currently used by the system, but it is initializing
     // a new rootfs.
-    let pam_sudo_path = ContainerPath::new("/etc/pam.d/sudo")?.to_host_path(rootfs);
-    let pam_cont = std::fs::read_to_string(&pam_sudo_path)
+    let pam_sudo_path = ContainerPath::new("/etc/pam.d/sudo")?;
+    let pam_cont = read_file_in_rootfs(rootfs, &pam_sudo_path)
         .with_context(|| format!("Failed to read {:?}", &pam_sudo_path))?;
     if pam_cont.contains("pam_env.so") {
         return Ok(());
@@ -898,52 +2432,171 @@ fn put_readenv_in_sudo_pam(rootfs: &HostPath) -> Result<()> {
         "# The following line of pam_env.so is inserted by Distrod",
     );
 
-    let mut pam_sudo = File::create(&pam_sudo_path)
-        .with_context(|| format!("Failed to open {:?}", &pam_sudo_path))?;
-    pam_sudo
-        .write_all(lines.join("\n").as_bytes())
+    overwrite_file_unless_dry_run(rootfs, &pam_sudo_path, lines.join("\n").as_bytes(), dry_run)
         .with_context(|| format!("Failed to update {:?}", &pam_sudo_path))?;
 
     Ok(())
 }
 
-pub fn cleanup_distro_rootfs<P: AsRef<HostPath>>(rootfs: P) -> Result<()> {
-    let rootfs = rootfs.as_ref();
-    cleanup_wsl_interop_envs_in_system_envs(rootfs).with_context(|| {
-        "Failed to clean up the WSL inter-op environment variables from system environment variables."
-    })?;
-    remove_distrod_bin_from_path(rootfs).with_context(|| "Failed to remove distrod bin path.")?;
-    Ok(())
+/// Overwrite the file as `overwrite_file_preserving_metadata` does, or only log it in the dry-run
+/// mode.
+fn overwrite_file_unless_dry_run(
+    rootfs: &HostPath,
+    path: &ContainerPath,
+    contents: &[u8],
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        log::info!("Would write {:?}.", path);
+        log::debug!(
+            "The new contents of {:?}:\n{}",
+            path,
+            String::from_utf8_lossy(contents)
+        );
+        return Ok(());
+    }
+    overwrite_file_preserving_metadata(rootfs, path, contents)
 }
 
-fn cleanup_wsl_interop_envs_in_system_envs(rootfs: &HostPath) -> Result<()> {
+/// Read the file in the rootfs, following the symlinks inside the rootfs.
+fn read_file_in_rootfs(rootfs: &HostPath, path: &ContainerPath) -> Result<String> {
+    let host_path = resolve_file_in_rootfs(rootfs, path)?;
+    fs::read_to_string(host_path.as_path())
+        .with_context(|| format!("Failed to read {:?}.", &host_path))
+}
+
+fn resolve_file_in_rootfs(rootfs: &HostPath, path: &ContainerPath) -> Result<HostPath> {
+    path.resolve_in_rootfs(rootfs)
+        .ok_or_else(|| anyhow!("Too many levels of symlinks in {:?}.", path))
+}
+
+fn remove_file_unless_dry_run<P: AsRef<Path>>(path: P, dry_run: bool) -> Result<()> {
+    if dry_run {
+        log::info!("Would remove {:?}.", path.as_ref());
+        return Ok(());
+    }
+    fs::remove_file(path.as_ref())
+        .with_context(|| format!("Failed to remove '{:?}'.", path.as_ref()))
+}
+
+/// Replace the content of the file in the rootfs atomically by renaming a temporary file to it.
+/// The mode and the owner of the original file are kept if it exists, so that rewriting
+/// files such as /etc/pam.d/sudo doesn't change their expected permissions.
+fn overwrite_file_preserving_metadata(
+    rootfs: &HostPath,
+    path: &ContainerPath,
+    contents: &[u8],
+) -> Result<()> {
+    // Write to the target of the symlink instead of replacing the symlink with a file.
+    // The symlinks are resolved in the rootfs, so that a link such as "/etc/hosts -> /etc/foo"
+    // doesn't make it write to the host's file.
+    let path = resolve_file_in_rootfs(rootfs, path)?.as_path().to_owned();
+    let metadata = fs::metadata(&path).ok();
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} is not a file path.", &path))?;
+    let mut tmp_file_name = OsString::from(".");
+    tmp_file_name.push(file_name);
+    tmp_file_name.push(".distrod-tmp");
+    let tmp_path = path.with_file_name(tmp_file_name);
+
+    let write_tmp = || -> Result<()> {
+        let mut tmp_file =
+            File::create(&tmp_path).with_context(|| format!("Failed to create {:?}", &tmp_path))?;
+        tmp_file
+            .write_all(contents)
+            .with_context(|| format!("Failed to write to {:?}", &tmp_path))?;
+        if let Some(ref metadata) = metadata {
+            tmp_file
+                .set_permissions(metadata.permissions())
+                .with_context(|| format!("Failed to set the permissions of {:?}", &tmp_path))?;
+            let tmp_metadata = tmp_file.metadata()?;
+            if (tmp_metadata.st_uid(), tmp_metadata.st_gid())
+                != (metadata.st_uid(), metadata.st_gid())
+            {
+                nix::unistd::fchown(
+                    tmp_file.as_raw_fd(),
+                    Some(Uid::from_raw(metadata.st_uid())),
+                    Some(Gid::from_raw(metadata.st_gid())),
+                )
+                .with_context(|| format!("Failed to change the owner of {:?}", &tmp_path))?;
+            }
+        }
+        tmp_file
+            .sync_all()
+            .with_context(|| format!("Failed to sync {:?}", &tmp_path))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}", &tmp_path, &path))
+    };
+    let result = write_tmp();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Remove what Distrod has put in the rootfs. If `dry_run` is true, the changes are only logged.
+pub fn cleanup_distro_rootfs<P: AsRef<HostPath>>(rootfs: P, dry_run: bool) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    cleanup_wsl_interop_envs_in_system_envs(rootfs, dry_run).with_context(|| {
+        "Failed to clean up the WSL inter-op environment variables from system environment variables."
+    })?;
+    remove_distrod_bin_from_path(rootfs, dry_run)
+        .with_context(|| "Failed to remove distrod bin path.")?;
+    remove_per_user_envs_init_loader_script(rootfs, dry_run)
+        .with_context(|| "Failed to remove the loader script of the per-user WSL envs.")?;
+    Ok(())
+}
+
+/// Get the names of the WSL inter-op variables and the PATH entries that Distrod puts
+/// in /etc/environment on launch, but are missing in the rootfs.
+pub fn get_missing_system_envs<P: AsRef<HostPath>>(rootfs: P) -> Result<Vec<String>> {
+    let env_file_path = ContainerPath::new("/etc/environment")?.to_host_path(rootfs.as_ref());
+    let env_file = EnvFile::open(&env_file_path)
+        .with_context(|| format!("Failed to open '{:?}'.", &env_file_path))?;
+    // The optional ones such as the WSLg variables may be missing if WSL doesn't set them.
+    let mut missing: Vec<String> = wsl_interop::REQUIRED_WSL_INTEROP_ENV_NAMES
+        .iter()
+        .map(|s| s.to_string())
+        .filter(|name| env_file.get_env(name).is_none())
+        .collect();
+    let distrod_bin_dir = distrod_config::get_distrod_bin_dir_path();
+    let has_distrod_bin = matches!(
+        env_file.get_env_unquoted("PATH"),
+        Some(path) if path.split(':').any(|p| p == distrod_bin_dir)
+    );
+    if !has_distrod_bin {
+        missing.push(format!("PATH={}", distrod_bin_dir));
+    }
+    Ok(missing)
+}
+
+fn cleanup_wsl_interop_envs_in_system_envs(rootfs: &HostPath, dry_run: bool) -> Result<()> {
     remove_from_system_env_files(
         rootfs,
         get_names_of_wsl_interop_envs_for_system_envs()
             .into_iter()
             .map(|s| s.to_string_lossy().to_string()),
         Vec::<String>::default(),
+        dry_run,
     )
     .with_context(|| "Failed to remove WSL interop envs from /etc/environment")?;
     Ok(())
 }
 
-fn remove_distrod_bin_from_path(rootfs: &HostPath) -> Result<()> {
+fn remove_distrod_bin_from_path(rootfs: &HostPath, dry_run: bool) -> Result<()> {
     remove_from_system_env_files(
         rootfs,
         Vec::<String>::default(),
         vec![distrod_config::get_distrod_bin_dir_path()],
+        dry_run,
     )
     .with_context(|| "Failed to remove the path to distrod bin from /etc/environement")?;
     Ok(())
 }
 
 fn get_names_of_wsl_interop_envs_for_system_envs() -> Vec<OsString> {
-    vec![
-        OsString::from("WSL_INTEROP"),
-        OsString::from("WSLENV"),
-        OsString::from("WSL_DISTRO_NAME"),
-    ]
+    wsl_interop::get_wsl_interop_env_names()
 }
 
 fn append_to_system_env_files(
@@ -955,7 +2608,9 @@ fn append_to_system_env_files(
     let mut env_file = EnvFile::open(&env_file_path)
         .with_context(|| format!("Failed to open '{:?}'.", &env_file_path))?;
     for (name, value) in envs {
-        env_file.put_env(name, value);
+        env_file
+            .put_env(name, value)
+            .with_context(|| format!("Failed to put an env in {:?}.", env_file_path))?;
     }
     for path in paths {
         env_file.put_path(path);
@@ -970,6 +2625,7 @@ fn remove_from_system_env_files<S1, S2, I1, I2>(
     rootfs_path: &HostPath,
     envs: I1,
     paths: I2,
+    dry_run: bool,
 ) -> Result<()>
 where
     S1: AsRef<str>,
@@ -980,19 +2636,41 @@ where
     let env_file_path = &ContainerPath::new("/etc/environment")?.to_host_path(rootfs_path);
     let mut env_file = EnvFile::open(&env_file_path)
         .with_context(|| format!("Failed to open '{:?}'.", &env_file_path))?;
+    let mut is_changed = false;
     for name in envs.into_iter() {
+        if dry_run && env_file.get_env(name.as_ref()).is_some() {
+            log::info!("Would remove {} from {:?}.", name.as_ref(), env_file_path);
+            is_changed = true;
+        }
         env_file.remove_env(name.as_ref());
     }
     for path in paths.into_iter() {
+        let has_path = matches!(
+            env_file.get_env_unquoted("PATH"),
+            Some(path_env) if path_env.split(':').any(|p| p == path.as_ref())
+        );
+        if dry_run && has_path {
+            log::info!(
+                "Would remove {} from PATH in {:?}.",
+                path.as_ref(),
+                env_file_path
+            );
+            is_changed = true;
+        }
         env_file.remove_path(path.as_ref());
     }
+    if dry_run && !is_changed {
+        return Ok(());
+    }
     env_file
+        .with_dry_run(dry_run)
         .write()
         .with_context(|| format!("Failed to write system env file on {:?}", env_file_path))?;
     Ok(())
 }
 
-fn export_distro_run_info(rootfs: &Path, init_pid: u32) -> Result<()> {
+fn export_distro_run_info(distro: &Distro) -> Result<()> {
+    let init_pid = distro.get_init_pid();
     if let Ok(Some(_)) = get_distro_run_info_file(false, false) {
         fs::remove_file(&get_distro_run_info_path()?)
             .with_context(|| "Failed to remove the existing run info file.")?;
@@ -1002,9 +2680,16 @@ fn export_distro_run_info(rootfs: &Path, init_pid: u32) -> Result<()> {
             .with_context(|| "Failed to create a run info file.")?
             .expect("[BUG] get_distro_run_info_file shuold return Some when create:true"),
     );
+    let init_start_time = ProcFile::from_pid(init_pid)?
+        .ok_or_else(|| anyhow!("The init process {} doesn't exist.", init_pid))?
+        .start_time()
+        .with_context(|| "Failed to get the start time of the init process.")?;
     let run_info = DistroRunInfo {
-        rootfs: rootfs.to_owned(),
+        rootfs: distro.rootfs.clone(),
         init_pid,
+        init_start_time: Some(init_start_time),
+        no_init: !distro.launches_init,
+        user_namespace: distro.user_namespace.clone(),
     };
     file.write_all(&serde_json::to_vec(&run_info)?)
         .with_context(|| "Failed to write to a distro run info file.")?;
@@ -1044,7 +2729,7 @@ fn get_distro_run_info_path() -> Result<HostPath> {
 }
 
 fn get_distrod_runtime_files_dir_path() -> Result<HostPath> {
-    let path = "/run/distrod";
+    let path = DISTROD_RUNTIME_FILES_DIR;
     if !Path::new(&path).exists() {
         fs::create_dir(&path)
             .with_context(|| format!("Failed to create {:?} directory.", &path))?;
@@ -1087,6 +2772,257 @@ mod test_sanity_check {
         assert!(!sanity_check_general_wsl_envs(&OsString::from(
             "Ubuntu-20.04\ntest"
         )));
+        assert!(sanity_check_general_wsl_envs(&OsString::from(
+            "Ubuntu_Dev+2,v1.0@home"
+        )));
+        assert!(sanity_check_general_wsl_envs(&OsString::from(
+            "USERPROFILE/up:GOPATH/l:TERM_PROGRAM=vscode"
+        )));
+        assert!(sanity_check_general_wsl_envs(&OsString::from(
+            "Ubuntu-日本語"
+        )));
+        for unsafe_value in [
+            "Ubuntu 20.04",
+            "Ubuntu;reboot",
+            "$(reboot)",
+            "`reboot`",
+            "Ubuntu\"",
+            "Ubuntu'",
+            "a|b",
+            "a&b",
+            "a>b",
+            "a\\b",
+        ] {
+            assert!(
+                !sanity_check_general_wsl_envs(&OsString::from(unsafe_value)),
+                "{:?} should be rejected",
+                unsafe_value
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_check_wslg_envs() {
+        for (key, value) in [
+            ("WSL2_GUI_APPS_ENABLED", "1"),
+            ("WAYLAND_DISPLAY", "wayland-0"),
+            ("DISPLAY", ":0"),
+            ("DISPLAY", "172.20.0.1:0.0"),
+            ("PULSE_SERVER", "/mnt/wslg/PulseServer"),
+            ("PULSE_SERVER", "unix:/mnt/wslg/PulseServer"),
+        ] {
+            assert!(
+                sanity_check_wsl_env(&OsString::from(key), &OsString::from(value)),
+                "{}={} should be accepted",
+                key,
+                value
+            );
+        }
+        for (key, value) in [
+            ("DISPLAY", ":0\nLD_PRELOAD=/tmp/evil.so"),
+            ("WAYLAND_DISPLAY", "wayland-0 systemd.unit=rescue.target"),
+            ("PULSE_SERVER", "$(reboot)"),
+        ] {
+            assert!(
+                !sanity_check_wsl_env(&OsString::from(key), &OsString::from(value)),
+                "{}={:?} should be rejected",
+                key,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_sanity_check_wsl_general_env_with_pattern() {
+        assert!(sanity_check_general_wsl_envs_with_pattern(
+            &OsString::from("Ubuntu(20.04)"),
+            "^[a-zA-Z0-9.()]*$"
+        ));
+        assert!(!sanity_check_general_wsl_envs_with_pattern(
+            &OsString::from("Ubuntu-20.04"),
+            "^[a-zA-Z]*$"
+        ));
+        // Newlines are rejected whatever the pattern is.
+        assert!(!sanity_check_general_wsl_envs_with_pattern(
+            &OsString::from("Ubuntu\nHOME=/etc"),
+            "(?s).*"
+        ));
+        // So are the whitespaces, the quotes, and a leading '='.
+        for unsafe_value in [
+            "Ubuntu 20.04",
+            "Ubuntu\t20.04",
+            "Ubuntu\"",
+            "Ubuntu'",
+            "Ubuntu\\",
+            "=Ubuntu",
+        ] {
+            assert!(
+                !sanity_check_general_wsl_envs_with_pattern(
+                    &OsString::from(unsafe_value),
+                    "(?s).*"
+                ),
+                "{:?} should be rejected",
+                unsafe_value
+            );
+        }
+        assert!(!sanity_check_general_wsl_envs_with_pattern(
+            &OsString::from("Ubuntu"),
+            "[invalid"
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test_systemd_setenv {
+    use super::*;
+
+    #[test]
+    fn test_append_systemd_setenv_args() {
+        let mut cmdline = b"initrd=\\initrd.img panic=-1".to_vec();
+        append_systemd_setenv_args(
+            &mut cmdline,
+            vec![
+                ("HTTP_PROXY", "http://proxy.example.com:8080"),
+                ("NO_PROXY", "localhost,.example.com"),
+            ],
+        );
+        assert_eq!(
+            "initrd=\\initrd.img panic=-1 \
+             systemd.setenv=HTTP_PROXY=http://proxy.example.com:8080 \
+             systemd.setenv=NO_PROXY=localhost,.example.com",
+            String::from_utf8(cmdline).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_env_assignment() {
+        assert_eq!(
+            ("KEY".to_owned(), "a=b".to_owned()),
+            parse_env_assignment("KEY=a=b").unwrap()
+        );
+        assert_eq!(
+            ("KEY".to_owned(), "".to_owned()),
+            parse_env_assignment("KEY=").unwrap()
+        );
+        assert!(parse_env_assignment("KEY").is_err());
+    }
+
+    #[test]
+    fn test_validate_systemd_setenv() {
+        assert!(validate_systemd_setenv("HTTP_PROXY", "http://proxy:8080").is_ok());
+        assert!(validate_systemd_setenv("_X1", "").is_ok());
+        assert!(validate_systemd_setenv("1X", "a").is_err());
+        assert!(validate_systemd_setenv("", "a").is_err());
+        assert!(validate_systemd_setenv("A B", "a").is_err());
+        assert!(validate_systemd_setenv("A=B", "a").is_err());
+        assert!(validate_systemd_setenv("KEY", "a systemd.unit=rescue.target").is_err());
+        assert!(validate_systemd_setenv("KEY", "a\nPATH=/tmp").is_err());
+        assert!(validate_systemd_setenv("KEY", "a\"b").is_err());
+        assert!(validate_systemd_setenv("KEY", "a'b").is_err());
+        assert!(validate_systemd_setenv("KEY", "=a").is_err());
+        // The other characters are allowed regardless of wsl_env_harmless_pattern.
+        assert!(validate_systemd_setenv("NO_PROXY", "localhost,*.local,[::1]").is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test_wslg_envs {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_resolve_wslg_envs() {
+        let wslg_dir = tempfile::tempdir().unwrap();
+        let wslg_dir = wslg_dir.path();
+        fs::create_dir(wslg_dir.join("runtime-dir")).unwrap();
+        let _wayland = UnixListener::bind(wslg_dir.join("runtime-dir/wayland-0")).unwrap();
+        let _pulse = UnixListener::bind(wslg_dir.join("PulseServer")).unwrap();
+
+        let mut envs = HashMap::new();
+        envs.insert(
+            OsString::from("WAYLAND_DISPLAY"),
+            OsString::from("wayland-0"),
+        );
+        resolve_wslg_envs(wslg_dir, &mut envs);
+        assert_eq!(
+            wslg_dir.join("runtime-dir/wayland-0").as_os_str(),
+            envs[OsStr::new("WAYLAND_DISPLAY")]
+        );
+        let mut pulse_server = OsString::from("unix:");
+        pulse_server.push(wslg_dir.join("PulseServer"));
+        assert_eq!(pulse_server, envs[OsStr::new("PULSE_SERVER")]);
+
+        // Keep the values which WSL sets if they don't point at WSLg.
+        let mut envs = HashMap::new();
+        envs.insert(
+            OsString::from("WAYLAND_DISPLAY"),
+            OsString::from("wayland-1"),
+        );
+        envs.insert(
+            OsString::from("PULSE_SERVER"),
+            OsString::from("tcp:1.2.3.4"),
+        );
+        resolve_wslg_envs(wslg_dir, &mut envs);
+        assert_eq!("wayland-1", envs[OsStr::new("WAYLAND_DISPLAY")]);
+        assert_eq!("tcp:1.2.3.4", envs[OsStr::new("PULSE_SERVER")]);
+    }
+
+    #[test]
+    fn test_resolve_wslg_envs_without_wslg() {
+        let mut envs = HashMap::new();
+        envs.insert(
+            OsString::from("WAYLAND_DISPLAY"),
+            OsString::from("wayland-0"),
+        );
+        resolve_wslg_envs(Path::new("/nonexistent/wslg"), &mut envs);
+        assert_eq!(1, envs.len());
+        assert_eq!("wayland-0", envs[OsStr::new("WAYLAND_DISPLAY")]);
+    }
+}
+
+#[cfg(test)]
+mod test_check_rootfs_arch {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn gen_elf_header(class: u8, machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..4].copy_from_slice(b"\x7fELF");
+        header[4] = class;
+        header[5] = 1; // little endian
+        header[6] = 1; // version
+        header[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn test_get_elf_arch() {
+        assert_eq!(Some("amd64"), get_elf_arch(&gen_elf_header(2, 62)));
+        assert_eq!(Some("arm64"), get_elf_arch(&gen_elf_header(2, 183)));
+        assert_eq!(Some("armhf"), get_elf_arch(&gen_elf_header(1, 40)));
+        assert_eq!(None, get_elf_arch(&gen_elf_header(2, 0xffff)));
+        assert_eq!(None, get_elf_arch(b"#!/bin/sh\necho not an elf\n"));
+        assert_eq!(None, get_elf_arch(b"\x7fELF"));
+    }
+
+    #[test]
+    fn test_probe_elf_arch_through_absolute_symlink() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fs::create_dir_all(tmpdir.path().join("sbin")).unwrap();
+        fs::create_dir_all(tmpdir.path().join("lib/systemd")).unwrap();
+        fs::write(
+            tmpdir.path().join("lib/systemd/systemd"),
+            gen_elf_header(2, 183),
+        )
+        .unwrap();
+        // The absolute link should be resolved in the rootfs, not in the host.
+        symlink("/lib/systemd/systemd", tmpdir.path().join("sbin/init")).unwrap();
+
+        assert_eq!(Some("arm64"), probe_elf_arch(&rootfs, "/sbin/init"));
+        assert_eq!(None, probe_elf_arch(&rootfs, "/bin/sh"));
     }
 }
 
@@ -1112,8 +3048,11 @@ mod test_cleanup_distro_rootfs {
         fs::write(&etc_environment_path, etc_environment.as_bytes())
             .expect("Failed to write the temporary /etc/environment file.");
 
-        cleanup_distro_rootfs(HostPath::new(tmpdir.path()).expect("Failed to create HostPath."))
-            .expect("Failed to cleanup the distro rootfs.");
+        cleanup_distro_rootfs(
+            HostPath::new(tmpdir.path()).expect("Failed to create HostPath."),
+            false,
+        )
+        .expect("Failed to cleanup the distro rootfs.");
 
         let new_etc_environment = fs::read_to_string(&etc_environment_path)
             .expect("Failed to read the new temporary /etc/environment file.");
@@ -1124,6 +3063,79 @@ mod test_cleanup_distro_rootfs {
             OTHER_ENV2=2\n"
         );
     }
+
+    #[test]
+    fn test_cleanup_removes_per_user_envs_loader() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc/profile.d")).unwrap();
+        fs::write(tmpdir.path().join("etc/environment"), "").unwrap();
+        let loader = tmpdir.path().join("etc/profile.d/distrod-user-wsl-envs.sh");
+        let others = tmpdir.path().join("etc/profile.d/others.sh");
+        fs::write(&others, "export FOO=1\n").unwrap();
+
+        create_per_user_envs_init_loader_script(&rootfs, false).unwrap();
+        assert!(loader.exists());
+        cleanup_distro_rootfs(&rootfs, false).unwrap();
+        assert!(!loader.exists());
+        assert!(others.exists());
+
+        // The loader is removed even if it's modified as long as it has the marker.
+        create_per_user_envs_init_loader_script(&rootfs, false).unwrap();
+        let modified = format!("{}export BAR=1\n", fs::read_to_string(&loader).unwrap());
+        fs::write(&loader, &modified).unwrap();
+        cleanup_distro_rootfs(&rootfs, false).unwrap();
+        assert!(!loader.exists());
+
+        // The loader written by the older versions without the marker is removed too.
+        create_per_user_envs_init_loader_script(&rootfs, false).unwrap();
+        let legacy = fs::read_to_string(&loader).unwrap().replacen(
+            &format!("{}\n", PER_USER_ENVS_INIT_LOADER_MARKER),
+            "",
+            1,
+        );
+        fs::write(&loader, &legacy).unwrap();
+        cleanup_distro_rootfs(&rootfs, false).unwrap();
+        assert!(!loader.exists());
+
+        // The loader whose marker is removed by the user is kept.
+        let unmarked = format!("{}export BAR=1\n", &legacy);
+        fs::write(&loader, &unmarked).unwrap();
+        cleanup_distro_rootfs(&rootfs, false).unwrap();
+        assert_eq!(unmarked, fs::read_to_string(&loader).unwrap());
+    }
+
+    #[test]
+    fn test_remove_per_user_envs_init_scripts() {
+        let tmpdir = TempDir::new().unwrap();
+        for name in &[
+            "distrod_wsl_env-uid0",
+            "distrod_wsl_env-uid1000",
+            "distrod_wsl_env-uid1000.bak",
+            "distrod_wsl_env-uid",
+            "run_info",
+        ] {
+            fs::write(tmpdir.path().join(name), "").unwrap();
+        }
+        fs::create_dir(tmpdir.path().join("distrod_wsl_env-uid1001")).unwrap();
+
+        remove_per_user_envs_init_scripts_in(tmpdir.path()).unwrap();
+        let mut remaining: Vec<_> = fs::read_dir(tmpdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            vec![
+                "distrod_wsl_env-uid",
+                "distrod_wsl_env-uid1000.bak",
+                "distrod_wsl_env-uid1001",
+                "run_info"
+            ],
+            remaining
+        );
+        remove_per_user_envs_init_scripts_in(&tmpdir.path().join("nonexistent")).unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -1146,7 +3158,9 @@ mod test_update_etc_hosts {
 
         update_etc_hosts(
             &HostPath::new(tmpdir.path()).expect("Failed to create HostPath."),
+            None,
             "ubuntu",
+            false,
         )
         .unwrap();
 
@@ -1158,4 +3172,1135 @@ mod test_update_etc_hosts {
             8.8.8.8       WEIRD_LXC_NAME_FOR_ANOTHER_MACHINE\n"
         );
     }
+
+    #[test]
+    fn test_update_etc_hosts_preserves_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmpdir = TempDir::new().unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc")).unwrap();
+        let etc_hosts_path = tmpdir.path().join("etc/hosts");
+        fs::write(&etc_hosts_path, "127.0.1.1     LXC_NAME\n").unwrap();
+        fs::set_permissions(&etc_hosts_path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        update_etc_hosts(
+            &HostPath::new(tmpdir.path()).unwrap(),
+            None,
+            "ubuntu",
+            false,
+        )
+        .unwrap();
+
+        let metadata = fs::metadata(&etc_hosts_path).unwrap();
+        assert_eq!(0o640, metadata.permissions().mode() & 0o7777);
+        assert_eq!(
+            "127.0.1.1     ubuntu\n",
+            fs::read_to_string(&etc_hosts_path).unwrap()
+        );
+        // No temporary file is left.
+        assert_eq!(1, fs::read_dir(tmpdir.path().join("etc")).unwrap().count());
+    }
+
+    #[test]
+    fn test_set_distro_hostname() {
+        let tmpdir = TempDir::new().unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc")).unwrap();
+        fs::write(tmpdir.path().join("etc/hostname"), "ubuntu\n").unwrap();
+        fs::write(
+            tmpdir.path().join("etc/hosts"),
+            "127.0.0.1     localhost\n127.0.1.1     ubuntu\n::1 ubuntu-ip6 ip6-localhost\n",
+        )
+        .unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+
+        set_distro_hostname(&rootfs, "ubuntu-clone").unwrap();
+        assert_eq!(
+            "ubuntu-clone",
+            fs::read_to_string(tmpdir.path().join("etc/hostname")).unwrap()
+        );
+        assert_eq!(
+            "127.0.0.1     localhost\n127.0.1.1     ubuntu-clone\n::1 ubuntu-ip6 ip6-localhost\n",
+            fs::read_to_string(tmpdir.path().join("etc/hosts")).unwrap()
+        );
+
+        assert!(set_distro_hostname(&rootfs, "invalid_name").is_err());
+        assert!(set_distro_hostname(&rootfs, "-invalid").is_err());
+
+        // /etc/hostname is created, and the line for the hostname is added if there's none.
+        fs::remove_file(tmpdir.path().join("etc/hostname")).unwrap();
+        fs::write(
+            tmpdir.path().join("etc/hosts"),
+            "127.0.0.1 localhost\n# ubuntu-clone\n",
+        )
+        .unwrap();
+        set_distro_hostname(&rootfs, "another").unwrap();
+        assert_eq!(
+            "another",
+            fs::read_to_string(tmpdir.path().join("etc/hostname")).unwrap()
+        );
+        assert_eq!(
+            "127.0.0.1 localhost\n# ubuntu-clone\n127.0.1.1\tanother\n",
+            fs::read_to_string(tmpdir.path().join("etc/hosts")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        for hostname in &["ubuntu", "1st-distro", "dev.example.com", "a"] {
+            assert!(validate_hostname(hostname).is_ok(), "{}", hostname);
+        }
+        let too_long_label = "a".repeat(64);
+        let too_long_name = vec!["a".repeat(63); 4].join(".");
+        for hostname in &[
+            "",
+            "invalid_name",
+            "-invalid",
+            "invalid-",
+            "dev..example",
+            "dev.example.",
+            "日本語",
+            too_long_label.as_str(),
+            too_long_name.as_str(),
+        ] {
+            assert!(validate_hostname(hostname).is_err(), "{}", hostname);
+        }
+    }
+
+    #[test]
+    fn test_update_hosts_content_with_placeholder() {
+        let hosts = "127.0.0.1 localhost\n127.0.1.1 LXC_NAME\n";
+        assert_eq!(
+            "127.0.0.1 localhost\n127.0.1.1 ubuntu\n",
+            update_hosts_content(hosts, Some("LXC_NAME"), "ubuntu").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_hosts_content_with_old_hostname() {
+        let hosts = "127.0.0.1 localhost\n\
+                     127.0.1.1 old-name.example.com old-name old-name # comment old-name\n\
+                     ::1 old-name-ip6\n\
+                     10.0.0.1 other old-name\n";
+        assert_eq!(
+            "127.0.0.1 localhost\n\
+             127.0.1.1 old-name.example.com new-name new-name # comment old-name\n\
+             ::1 old-name-ip6\n\
+             10.0.0.1 other old-name\n",
+            update_hosts_content(hosts, Some("old-name"), "new-name").unwrap()
+        );
+
+        // Nothing changes if the line already exists.
+        let hosts = "127.0.0.1 localhost\n127.0.1.1\tnew-name\n";
+        assert_eq!(
+            hosts,
+            update_hosts_content(hosts, Some("old-name"), "new-name").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_update_hosts_content_without_hostname_line() {
+        let hosts = "127.0.0.1 localhost\n# 127.0.1.1 new-name\n10.0.0.1 old-name";
+        assert_eq!(
+            "127.0.0.1 localhost\n# 127.0.1.1 new-name\n10.0.0.1 old-name\n\
+             127.0.1.1\tnew-name\n",
+            update_hosts_content(hosts, Some("old-name"), "new-name").unwrap()
+        );
+        assert_eq!(
+            "127.0.1.1\tnew-name\n",
+            update_hosts_content("", None, "new-name").unwrap()
+        );
+    }
+
+    fn make_lxc_rootfs(hostname: &str) -> TempDir {
+        let tmpdir = TempDir::new().unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc")).unwrap();
+        fs::write(
+            tmpdir.path().join("etc/hostname"),
+            format!("{}\n", hostname),
+        )
+        .unwrap();
+        fs::write(
+            tmpdir.path().join("etc/hosts"),
+            format!("127.0.0.1     localhost\n127.0.1.1     {}\n", hostname),
+        )
+        .unwrap();
+        tmpdir
+    }
+
+    #[test]
+    fn test_fix_hostname_with_custom_name() {
+        let tmpdir = make_lxc_rootfs("LXC_NAME");
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fix_hostname(&rootfs, Some("my-distro"), true, false).unwrap();
+        assert_eq!(
+            "my-distro",
+            fs::read_to_string(tmpdir.path().join("etc/hostname")).unwrap()
+        );
+        assert_eq!(
+            "127.0.0.1     localhost\n127.0.1.1     my-distro\n",
+            fs::read_to_string(tmpdir.path().join("etc/hosts")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_fix_hostname_without_sync() {
+        let tmpdir = make_lxc_rootfs("my-distro");
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fix_hostname(&rootfs, None, false, false).unwrap();
+        assert_eq!(
+            "my-distro\n",
+            fs::read_to_string(tmpdir.path().join("etc/hostname")).unwrap()
+        );
+        assert_eq!(
+            "127.0.0.1     localhost\n127.0.1.1     my-distro\n",
+            fs::read_to_string(tmpdir.path().join("etc/hosts")).unwrap()
+        );
+
+        // The placeholder of a new image is replaced even without sync.
+        let tmpdir = make_lxc_rootfs("LXC_NAME");
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fix_hostname(&rootfs, None, false, false).unwrap();
+        assert!(!fs::read_to_string(tmpdir.path().join("etc/hosts"))
+            .unwrap()
+            .contains("LXC_NAME"));
+    }
+
+    #[test]
+    fn test_overwrite_file_through_symlink() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        let target = tmpdir.path().join("target");
+        let link = tmpdir.path().join("link");
+        fs::write(&target, "old").unwrap();
+        std::os::unix::fs::symlink("target", &link).unwrap();
+
+        overwrite_file_preserving_metadata(&rootfs, &ContainerPath::new("/link").unwrap(), b"new")
+            .unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!("new", fs::read_to_string(&target).unwrap());
+    }
+
+    #[test]
+    fn test_overwrite_file_through_absolute_symlink_stays_in_rootfs() {
+        let host = TempDir::new().unwrap();
+        let outside = host.path().join("outside");
+        fs::write(&outside, "host").unwrap();
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc")).unwrap();
+        // An absolute link which points to the host's file if it's followed on the host.
+        std::os::unix::fs::symlink(&outside, tmpdir.path().join("etc/hosts")).unwrap();
+        let in_rootfs = tmpdir.path().join(outside.strip_prefix("/").unwrap());
+        fs::create_dir_all(in_rootfs.parent().unwrap()).unwrap();
+
+        overwrite_file_preserving_metadata(
+            &rootfs,
+            &ContainerPath::new("/etc/hosts").unwrap(),
+            b"new",
+        )
+        .unwrap();
+
+        assert_eq!("host", fs::read_to_string(&outside).unwrap());
+        assert_eq!("new", fs::read_to_string(&in_rootfs).unwrap());
+        assert!(fs::symlink_metadata(tmpdir.path().join("etc/hosts"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+}
+
+#[cfg(test)]
+mod test_extra_mounts {
+    use super::*;
+    use nix::mount::MsFlags;
+
+    fn entry(source: &str, target: &str, flags: &[&str]) -> ExtraMountEntry {
+        ExtraMountEntry {
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+            is_file: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_extra_mount() {
+        let (source, target, flags) =
+            validate_extra_mount(&entry("/tmp", "/mnt/host-tmp", &["rdonly", "rec"])).unwrap();
+        assert_eq!(Path::new("/tmp"), source.as_path());
+        assert_eq!(Path::new("/mnt/host-tmp"), target.as_path());
+        assert_eq!(
+            MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_REC,
+            flags
+        );
+    }
+
+    #[test]
+    fn test_reject_relative_target() {
+        assert!(validate_extra_mount(&entry("/tmp", "mnt/host-tmp", &[])).is_err());
+    }
+
+    #[test]
+    fn test_reject_missing_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("nonexistent");
+        assert!(
+            validate_extra_mount(&entry(source.to_str().unwrap(), "/mnt/missing", &[])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_reject_unknown_flag() {
+        assert!(validate_extra_mount(&entry("/tmp", "/mnt/host-tmp", &["shared"])).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_detect_distro {
+    use super::*;
+
+    fn detect(os_release: &str) -> DistroName {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir(rootfs.path().join("etc")).unwrap();
+        std::fs::write(rootfs.path().join("etc/os-release"), os_release).unwrap();
+        detect_distro(&HostPath::new(rootfs.path()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_detect_by_id() {
+        assert_eq!(DistroName::Debian, detect("ID=debian\n"));
+        assert_eq!(DistroName::Kali, detect("ID=kali\nID_LIKE=debian\n"));
+        assert_eq!(DistroName::Ubuntu, detect("ID=ubuntu\nID_LIKE=debian\n"));
+        assert_eq!(DistroName::Undetected, detect("ID=fedora\n"));
+    }
+
+    #[test]
+    fn test_detect_by_id_like() {
+        assert_eq!(
+            DistroName::Debian,
+            detect("ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n")
+        );
+        assert_eq!(
+            DistroName::Debian,
+            detect("ID=pop\nID_LIKE=\"ubuntu debian\"\n")
+        );
+        assert_eq!(
+            DistroName::Debian,
+            detect("ID=elementary\nID_LIKE=ubuntu\n")
+        );
+        assert_eq!(DistroName::Debian, detect("ID_LIKE=debian\n"));
+        assert_eq!(
+            DistroName::Undetected,
+            detect("ID=rocky\nID_LIKE=\"rhel centos fedora\"\n")
+        );
+    }
+
+    #[test]
+    fn test_strip_quotes() {
+        assert_eq!("debian", strip_quotes("\"debian\""));
+        assert_eq!("debian", strip_quotes("'debian'"));
+        assert_eq!("", strip_quotes("\"\""));
+        assert_eq!("\"debian", strip_quotes("\"debian"));
+        assert_eq!("debian'", strip_quotes("debian'"));
+        assert_eq!("\"debian'", strip_quotes("\"debian'"));
+        assert_eq!("\"", strip_quotes("\""));
+        assert_eq!("debian", strip_quotes("debian"));
+    }
+
+    #[test]
+    fn test_detect_malformed_id() {
+        assert_eq!(DistroName::Undetected, detect("ID=\"debian\n"));
+    }
+
+    #[test]
+    fn test_detect_without_os_release() {
+        let rootfs = tempfile::tempdir().unwrap();
+        assert_eq!(
+            DistroName::Undetected,
+            detect_distro(&HostPath::new(rootfs.path()).unwrap()).unwrap()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_kept_network_services {
+    use super::*;
+
+    #[test]
+    fn test_network_services_are_disabled_by_default() {
+        let to_be_disabled = get_systemd_services_to_disable(KeptNetworkServices::default());
+        assert!(to_be_disabled.contains(&"systemd-networkd.service"));
+        assert!(to_be_disabled.contains(&"systemd-resolved.service"));
+    }
+
+    #[test]
+    fn test_kept_network_services_are_not_disabled() {
+        let to_be_disabled = get_systemd_services_to_disable(KeptNetworkServices {
+            networkd: true,
+            resolved: false,
+        });
+        assert!(!to_be_disabled.contains(&"systemd-networkd.service"));
+        assert!(to_be_disabled.contains(&"systemd-resolved.service"));
+        assert!(to_be_disabled.contains(&"NetworkManager.service"));
+
+        let to_be_disabled = get_systemd_services_to_disable(KeptNetworkServices {
+            networkd: false,
+            resolved: true,
+        });
+        assert!(to_be_disabled.contains(&"systemd-networkd.service"));
+        assert!(!to_be_disabled.contains(&"systemd-resolved.service"));
+    }
+
+    #[test]
+    fn test_newer_units_are_masked_only_if_installed() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let unit_dir = rootfs.path().join("lib/systemd/system");
+        std::fs::create_dir_all(&unit_dir).unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc/systemd/system")).unwrap();
+        std::fs::write(unit_dir.join("systemd-oomd.service"), "[Service]\n").unwrap();
+
+        let rootfs_path = HostPath::new(rootfs.path()).unwrap();
+        disable_incompatible_systemd_services(&rootfs_path, KeptNetworkServices::default(), false);
+
+        let local_unit = |unit| rootfs.path().join("etc/systemd/system").join(unit);
+        assert_eq!(
+            Path::new("/dev/null"),
+            fs::read_link(local_unit("systemd-oomd.service")).unwrap()
+        );
+        for unit in &["systemd-pstore.service", "systemd-hwdb-update.service"] {
+            assert!(fs::symlink_metadata(local_unit(unit)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_kept_network_services_stay_enabled() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let wants_dir = rootfs
+            .path()
+            .join("etc/systemd/system/multi-user.target.wants");
+        std::fs::create_dir_all(&wants_dir).unwrap();
+        for unit in &["systemd-networkd.service", "systemd-resolved.service"] {
+            std::os::unix::fs::symlink(
+                Path::new("/lib/systemd/system").join(unit),
+                wants_dir.join(unit),
+            )
+            .unwrap();
+        }
+        std::fs::create_dir_all(rootfs.path().join("etc/systemd/network")).unwrap();
+        let network_file = rootfs.path().join("etc/systemd/network/eth0.network");
+        std::fs::write(&network_file, "[Match]\nName=eth0\n").unwrap();
+        let resolv_conf = rootfs.path().join("etc/resolv.conf");
+        std::os::unix::fs::symlink("../run/systemd/resolve/stub-resolv.conf", &resolv_conf)
+            .unwrap();
+
+        let rootfs = HostPath::new(rootfs.path()).unwrap();
+        let kept_services = KeptNetworkServices {
+            networkd: true,
+            resolved: true,
+        };
+        disable_incompatible_systemd_network_configuration(&rootfs, true, kept_services, false)
+            .unwrap();
+        disable_incompatible_systemd_services(&rootfs, kept_services, false);
+
+        for unit in &["systemd-networkd.service", "systemd-resolved.service"] {
+            assert!(fs::symlink_metadata(wants_dir.join(unit)).is_ok());
+        }
+        assert!(network_file.exists());
+        assert!(fs::symlink_metadata(&resolv_conf)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+}
+
+#[cfg(test)]
+mod test_utf8_env {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_to_utf8_env() {
+        assert_eq!(
+            Some(("WSLENV".to_owned(), "WT_SESSION::WT_PROFILE_ID".to_owned())),
+            to_utf8_env(
+                OsStr::new("WSLENV"),
+                OsStr::new("WT_SESSION::WT_PROFILE_ID")
+            )
+        );
+        assert_eq!(
+            None,
+            to_utf8_env(
+                OsStr::new("WSL_DISTRO_NAME"),
+                OsStr::from_bytes(b"distro\xff")
+            )
+        );
+        assert_eq!(
+            None,
+            to_utf8_env(OsStr::from_bytes(b"\xfe"), OsStr::new("value"))
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_resolv_conf_link {
+    use super::*;
+
+    fn setup_resolv_conf_link(link_to: &str) -> (tempfile::TempDir, PathBuf) {
+        let rootfs = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(rootfs.path().join("etc")).unwrap();
+        let resolv_conf = rootfs.path().join("etc/resolv.conf");
+        std::os::unix::fs::symlink(link_to, &resolv_conf).unwrap();
+        (rootfs, resolv_conf)
+    }
+
+    fn is_symlink(path: &Path) -> bool {
+        fs::symlink_metadata(path).unwrap().file_type().is_symlink()
+    }
+
+    #[test]
+    fn test_fedora_style_link_is_replaced() {
+        let (rootfs, resolv_conf) =
+            setup_resolv_conf_link("../run/systemd/resolve/stub-resolv.conf");
+        let rootfs = HostPath::new(rootfs.path()).unwrap();
+        remove_runtime_resolv_conf_link(&rootfs, false, false).unwrap();
+        assert!(!is_symlink(&resolv_conf));
+        assert_eq!("", fs::read_to_string(&resolv_conf).unwrap());
+    }
+
+    #[test]
+    fn test_network_manager_style_link_is_replaced() {
+        for link_to in &[
+            "/var/run/NetworkManager/resolv.conf",
+            "../run/NetworkManager/no-stub-resolv.conf",
+        ] {
+            let (rootfs, resolv_conf) = setup_resolv_conf_link(link_to);
+            let rootfs = HostPath::new(rootfs.path()).unwrap();
+            // NetworkManager is disabled even if systemd-resolved is kept.
+            remove_runtime_resolv_conf_link(&rootfs, true, false).unwrap();
+            assert!(!is_symlink(&resolv_conf));
+        }
+    }
+
+    #[test]
+    fn test_dangling_link_is_replaced() {
+        let (rootfs, resolv_conf) = setup_resolv_conf_link("../run/resolvconf/resolv.conf");
+        let rootfs = HostPath::new(rootfs.path()).unwrap();
+        remove_runtime_resolv_conf_link(&rootfs, false, false).unwrap();
+        assert!(!is_symlink(&resolv_conf));
+    }
+
+    #[test]
+    fn test_link_to_existing_file_is_kept() {
+        let (rootfs_dir, resolv_conf) = setup_resolv_conf_link("resolv.conf.static");
+        fs::write(
+            rootfs_dir.path().join("etc/resolv.conf.static"),
+            "nameserver 1.1.1.1\n",
+        )
+        .unwrap();
+        let rootfs = HostPath::new(rootfs_dir.path()).unwrap();
+        remove_runtime_resolv_conf_link(&rootfs, false, false).unwrap();
+        assert!(is_symlink(&resolv_conf));
+
+        let (rootfs_dir, resolv_conf) =
+            setup_resolv_conf_link("../run/systemd/resolve/stub-resolv.conf");
+        let rootfs = HostPath::new(rootfs_dir.path()).unwrap();
+        remove_runtime_resolv_conf_link(&rootfs, true, false).unwrap();
+        assert!(is_symlink(&resolv_conf));
+    }
+}
+
+#[cfg(test)]
+mod test_custom_dns {
+    use super::*;
+
+    #[test]
+    fn test_generate_resolv_conf() {
+        let servers = vec!["10.0.0.53".to_owned(), "2001:db8::53".to_owned()];
+        let search = vec!["corp.example.com".to_owned(), "example.com".to_owned()];
+        assert_eq!(
+            "# This file is generated by Distrod from dns_servers and dns_search in\n\
+             # /opt/distrod/conf/distrod.toml. Edit them instead of this file.\n\
+             nameserver 10.0.0.53\n\
+             nameserver 2001:db8::53\n\
+             search corp.example.com example.com\n",
+            generate_resolv_conf(&servers, &search).unwrap()
+        );
+
+        let resolv_conf = generate_resolv_conf(&servers[..1], &[]).unwrap();
+        assert!(resolv_conf.ends_with("\nnameserver 10.0.0.53\n"));
+    }
+
+    #[test]
+    fn test_generate_resolv_conf_with_invalid_values() {
+        let search = vec!["example.com".to_owned()];
+        assert!(generate_resolv_conf(&["dns.example.com".to_owned()], &search).is_err());
+        assert!(
+            generate_resolv_conf(&["10.0.0.53\nnameserver 1.1.1.1".to_owned()], &search).is_err()
+        );
+        assert!(generate_resolv_conf(&["10.0.0.53".to_owned()], &["a b".to_owned()]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_translate_working_directory {
+    use super::*;
+    use crate::mount_info::test_mount_info::mount_entry;
+
+    #[test]
+    fn test_translate_windows_drive_path() {
+        let host_mounts = vec![
+            mount_entry("/dev/sdb", "/", "ext4"),
+            mount_entry("C:\\", "/mnt/c", "9p"),
+        ];
+        let container_mounts = vec![
+            mount_entry("/dev/sdb", "/", "ext4"),
+            mount_entry("C:\\", "/mnt/c", "9p"),
+        ];
+        assert_eq!(
+            Some(PathBuf::from("/mnt/c/Users/user/src")),
+            translate_working_directory(
+                Path::new("/mnt/c/Users/user/src"),
+                Path::new("/"),
+                &host_mounts,
+                &container_mounts
+            )
+        );
+
+        // The drive is not mounted in the container yet.
+        assert_eq!(
+            None,
+            translate_working_directory(
+                Path::new("/mnt/c/Users/user/src"),
+                Path::new("/"),
+                &host_mounts,
+                &container_mounts[..1]
+            )
+        );
+
+        // Another filesystem is mounted on the path in the container.
+        let container_mounts = vec![
+            mount_entry("/dev/sdb", "/", "ext4"),
+            mount_entry("tmpfs", "/mnt", "tmpfs"),
+        ];
+        assert_eq!(
+            None,
+            translate_working_directory(
+                Path::new("/mnt/c"),
+                Path::new("/"),
+                &host_mounts,
+                &container_mounts
+            )
+        );
+    }
+
+    #[test]
+    fn test_translate_path_in_rootfs() {
+        let host_mounts = vec![mount_entry("/dev/sdb", "/", "ext4")];
+        let container_mounts = vec![mount_entry("/dev/sdc", "/", "ext4")];
+        let rootfs = Path::new("/var/lib/distrod/rootfs");
+        assert_eq!(
+            Some(PathBuf::from("/home/user")),
+            translate_working_directory(
+                Path::new("/var/lib/distrod/rootfs/home/user"),
+                rootfs,
+                &host_mounts,
+                &container_mounts
+            )
+        );
+        assert_eq!(
+            Some(PathBuf::from("/")),
+            translate_working_directory(rootfs, rootfs, &host_mounts, &container_mounts)
+        );
+        // Paths on the root filesystem of the host are passed through as they are.
+        assert_eq!(
+            Some(PathBuf::from("/home/user")),
+            translate_working_directory(
+                Path::new("/home/user"),
+                rootfs,
+                &host_mounts,
+                &container_mounts
+            )
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_distro_run_info {
+    use super::*;
+
+    fn run_info(init_pid: u32, init_start_time: Option<u64>) -> DistroRunInfo {
+        DistroRunInfo {
+            rootfs: PathBuf::from("/"),
+            init_pid,
+            init_start_time,
+            no_init: false,
+            user_namespace: None,
+        }
+    }
+
+    #[test]
+    fn test_is_init_alive() {
+        let pid = std::process::id();
+        let start_time = ProcFile::current_proc().unwrap().start_time().unwrap();
+        assert!(run_info(pid, Some(start_time)).is_init_alive().unwrap());
+        // The run info of the older versions.
+        assert!(run_info(pid, None).is_init_alive().unwrap());
+        // The PID is reused by another process.
+        assert!(!run_info(pid, Some(start_time + 1)).is_init_alive().unwrap());
+    }
+
+    #[test]
+    fn test_is_init_alive_with_bogus_pid() {
+        // Larger than the maximum of pid_max.
+        let bogus_pid = 1 << 23;
+        assert!(!run_info(bogus_pid, None).is_init_alive().unwrap());
+        assert!(!run_info(bogus_pid, Some(1)).is_init_alive().unwrap());
+    }
+
+    #[test]
+    fn test_deserialize_old_run_info() {
+        let run_info: DistroRunInfo =
+            serde_json::from_str(r#"{"rootfs":"/var/lib/distrod","init_pid":42}"#).unwrap();
+        assert_eq!(42, run_info.init_pid);
+        assert_eq!(None, run_info.init_start_time);
+        assert!(!run_info.no_init);
+        assert_eq!(None, run_info.user_namespace);
+    }
+
+    #[test]
+    fn test_serialize_run_info_of_no_init() {
+        let mut info = run_info(42, Some(1));
+        info.no_init = true;
+        info.user_namespace = Some(UserNamespace::root_to(
+            Uid::from_raw(1000),
+            Gid::from_raw(100),
+        ));
+        let deserialized: DistroRunInfo =
+            serde_json::from_slice(&serde_json::to_vec(&info).unwrap()).unwrap();
+        assert!(deserialized.no_init);
+        assert_eq!(info.user_namespace, deserialized.user_namespace);
+    }
+}
+
+#[cfg(test)]
+mod test_detect_init_system {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    fn make_rootfs(files: &[&str], symlinks: &[(&str, &str)]) -> TempDir {
+        let tmpdir = TempDir::new().unwrap();
+        for file in files {
+            let path = tmpdir.path().join(file.trim_start_matches('/'));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+        }
+        for (link, target) in symlinks {
+            let path = tmpdir.path().join(link.trim_start_matches('/'));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            symlink(target, &path).unwrap();
+        }
+        tmpdir
+    }
+
+    fn detect(tmpdir: &TempDir) -> (InitSystem, PathBuf) {
+        detect_init_system(&HostPath::new(tmpdir.path()).unwrap())
+    }
+
+    #[test]
+    fn test_detect_systemd() {
+        let rootfs = make_rootfs(
+            &["/lib/systemd/systemd"],
+            &[("/sbin/init", "/lib/systemd/systemd")],
+        );
+        assert_eq!(
+            (InitSystem::Systemd, PathBuf::from("/sbin/init")),
+            detect(&rootfs)
+        );
+
+        let rootfs = make_rootfs(
+            &["/usr/lib/systemd/systemd"],
+            &[("/sbin/init", "../usr/lib/systemd/systemd")],
+        );
+        assert_eq!(InitSystem::Systemd, detect(&rootfs).0);
+
+        // Without /sbin/init
+        let rootfs = make_rootfs(&["/usr/lib/systemd/systemd"], &[]);
+        assert_eq!(
+            (
+                InitSystem::Systemd,
+                PathBuf::from("/usr/lib/systemd/systemd")
+            ),
+            detect(&rootfs)
+        );
+
+        // A hard link
+        let rootfs = make_rootfs(&["/lib/systemd/systemd"], &[]);
+        fs::create_dir_all(rootfs.path().join("sbin")).unwrap();
+        fs::hard_link(
+            rootfs.path().join("lib/systemd/systemd"),
+            rootfs.path().join("sbin/init"),
+        )
+        .unwrap();
+        assert_eq!(InitSystem::Systemd, detect(&rootfs).0);
+    }
+
+    #[test]
+    fn test_detect_other_init_systems() {
+        let rootfs = make_rootfs(&["/sbin/init", "/etc/inittab"], &[]);
+        assert_eq!(
+            (InitSystem::SysVinit, PathBuf::from("/sbin/init")),
+            detect(&rootfs)
+        );
+
+        let rootfs = make_rootfs(&["/bin/busybox"], &[("/sbin/init", "/bin/busybox")]);
+        assert_eq!(InitSystem::Busybox, detect(&rootfs).0);
+
+        let rootfs = make_rootfs(&["/sbin/openrc-init"], &[("/sbin/init", "openrc-init")]);
+        assert_eq!(InitSystem::OpenRc, detect(&rootfs).0);
+
+        // systemd is installed but not used as init.
+        let rootfs = make_rootfs(&["/sbin/init", "/lib/systemd/systemd"], &[]);
+        assert_eq!(InitSystem::Unknown, detect(&rootfs).0);
+
+        let rootfs = make_rootfs(&[], &[]);
+        assert_eq!(
+            (InitSystem::Unknown, PathBuf::from("/sbin/init")),
+            detect(&rootfs)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_init_target {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_validate_target_name() {
+        assert!(validate_target_name("multi-user.target").is_ok());
+        assert!(validate_target_name("graphical.target").is_ok());
+        assert!(validate_target_name("getty@tty1.target").is_ok());
+        assert!(validate_target_name("graphical").is_err());
+        assert!(validate_target_name(".target").is_err());
+        assert!(validate_target_name("sshd.service").is_err());
+        assert!(validate_target_name("--unit=rescue.target").is_err());
+        assert!(validate_target_name("a b.target").is_err());
+    }
+
+    #[test]
+    fn test_systemd_init_args() {
+        assert_eq!(
+            vec!["--unit=multi-user.target"],
+            get_systemd_init_args(DEFAULT_INIT_TARGET)
+        );
+        assert_eq!(
+            vec!["--unit=graphical.target"],
+            get_systemd_init_args("graphical.target")
+        );
+    }
+
+    #[test]
+    fn test_is_systemd_unit_installed() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        let unit_dir = tmpdir.path().join("usr/lib/systemd/system");
+        fs::create_dir_all(&unit_dir).unwrap();
+        fs::write(unit_dir.join("graphical.target"), "").unwrap();
+        assert!(is_systemd_unit_installed(&rootfs, "graphical.target"));
+        assert!(!is_systemd_unit_installed(&rootfs, "custom.target"));
+
+        // A unit in /etc may be a dangling symlink in the host's view.
+        let etc_unit_dir = tmpdir.path().join("etc/systemd/system");
+        fs::create_dir_all(&etc_unit_dir).unwrap();
+        std::os::unix::fs::symlink("/dev/null", etc_unit_dir.join("custom.target")).unwrap();
+        assert!(is_systemd_unit_installed(&rootfs, "custom.target"));
+    }
+}
+
+#[cfg(test)]
+mod test_resource_limits {
+    use super::*;
+
+    #[test]
+    fn test_validate_memory_max() {
+        for valid in &["2G", "512M", "1.5G", "1073741824", "50%", "infinity"] {
+            assert!(validate_memory_max(valid).is_ok(), "{}", valid);
+        }
+        for invalid in &["", "2g", "2GB", "-1G", "0", "150%", "G", "1e3", "inf"] {
+            assert!(validate_memory_max(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_validate_cpu_quota() {
+        for valid in &["150%", "50%", "0.5%", "infinity"] {
+            assert!(validate_cpu_quota(valid).is_ok(), "{}", valid);
+        }
+        for invalid in &["", "150", "%", "0%", "-50%", "1.5"] {
+            assert!(validate_cpu_quota(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_write_resource_limits() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        let limits = ResourceLimits {
+            memory_max: Some("2G".to_owned()),
+            cpu_quota: Some("150%".to_owned()),
+        };
+        write_resource_limits(&rootfs, &limits).unwrap();
+        let read_drop_in = |slice: &str| {
+            fs::read_to_string(
+                tmpdir
+                    .path()
+                    .join(format!("etc/systemd/system/{}.d/override.conf", slice)),
+            )
+            .unwrap()
+        };
+        for slice in &["system.slice", "user.slice"] {
+            assert_eq!(
+                "[Slice]\nCPUQuota=150%\nMemoryMax=2G\n",
+                read_drop_in(slice)
+            );
+        }
+        // The services are not moved from their own slices.
+        assert!(!tmpdir.path().join("etc/systemd/system/service.d").exists());
+
+        // The limits replace the previous ones.
+        let limits = ResourceLimits {
+            memory_max: None,
+            cpu_quota: Some("infinity".to_owned()),
+        };
+        write_resource_limits(&rootfs, &limits).unwrap();
+        assert_eq!("[Slice]\n", read_drop_in("system.slice"));
+    }
+
+    #[test]
+    fn test_get_systemd_version() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        assert_eq!(None, get_systemd_version(&rootfs));
+        fs::create_dir_all(tmpdir.path().join("usr/lib/systemd")).unwrap();
+        fs::write(
+            tmpdir
+                .path()
+                .join("usr/lib/systemd/libsystemd-shared-245.so"),
+            "",
+        )
+        .unwrap();
+        assert_eq!(Some(245), get_systemd_version(&rootfs));
+
+        assert_eq!(
+            Some(254),
+            parse_systemd_shared_lib_version("libsystemd-shared-254.10-1.fc39.so")
+        );
+        assert_eq!(
+            None,
+            parse_systemd_shared_lib_version("libsystemd-shared.so")
+        );
+        assert_eq!(None, parse_systemd_shared_lib_version("systemd"));
+    }
+}
+
+#[cfg(test)]
+mod test_old_root {
+    use super::*;
+    use crate::distrod_config::{
+        resolve_distro_old_root_path, test_distrod_config::config_with_old_root_path,
+        DEFAULT_DISTRO_OLD_ROOT_PATH,
+    };
+    use crate::mount_info::test_mount_info::mount_entry;
+
+    #[test]
+    fn test_is_old_root_mounted() {
+        let default_old_root = resolve_distro_old_root_path(None).unwrap();
+        let config = config_with_old_root_path("/mnt/custom_root");
+        let custom_old_root = resolve_distro_old_root_path(Some(&config)).unwrap();
+
+        let default_mounts = vec![
+            mount_entry("none", "/", "tmpfs"),
+            mount_entry(
+                "none",
+                &format!("{}/proc", DEFAULT_DISTRO_OLD_ROOT_PATH),
+                "tmpfs",
+            ),
+        ];
+        assert!(is_old_root_mounted(&default_mounts, &default_old_root));
+        // A distro which uses /mnt/distrod_root by itself is not mistaken for the running one.
+        assert!(!is_old_root_mounted(&default_mounts, &custom_old_root));
+
+        let custom_mounts = vec![
+            mount_entry("none", "/", "tmpfs"),
+            mount_entry("none", "/mnt/custom_root/proc", "tmpfs"),
+        ];
+        assert!(is_old_root_mounted(&custom_mounts, &custom_old_root));
+        assert!(!is_old_root_mounted(&custom_mounts, &default_old_root));
+        assert!(!is_old_root_mounted(
+            &[mount_entry("none", "/mnt/custom_rootfs", "tmpfs")],
+            &custom_old_root
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test_system_running_state {
+    use super::*;
+
+    #[test]
+    fn test_to_system_running_state() {
+        assert_eq!(
+            Some(SystemRunningState::Running),
+            to_system_running_state("running").unwrap()
+        );
+        assert_eq!(
+            Some(SystemRunningState::Degraded),
+            to_system_running_state("degraded").unwrap()
+        );
+        for state in &["initializing", "starting", "offline", "unknown"] {
+            assert_eq!(None, to_system_running_state(state).unwrap());
+        }
+        assert!(to_system_running_state("maintenance").is_err());
+        assert!(to_system_running_state("stopping").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_failed_systemd_units {
+    use super::*;
+
+    fn unit(name: &str, load: &str, sub: &str, description: &str) -> FailedSystemdUnit {
+        FailedSystemdUnit {
+            name: name.to_owned(),
+            load: load.to_owned(),
+            sub: sub.to_owned(),
+            description: description.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_parse_plain_output() {
+        let output =
+            "systemd-oomd.service loaded failed failed Userspace Out-Of-Memory (OOM) Killer\n\
+                      foo.service          not-found failed failed foo.service\n";
+        assert_eq!(
+            vec![
+                unit(
+                    "systemd-oomd.service",
+                    "loaded",
+                    "failed",
+                    "Userspace Out-Of-Memory (OOM) Killer"
+                ),
+                unit("foo.service", "not-found", "failed", "foo.service"),
+            ],
+            parse_failed_systemd_units(output)
+        );
+    }
+
+    #[test]
+    fn test_parse_output_with_bullets() {
+        let output = "\u{25cf} systemd-pstore.service loaded failed failed Platform Persistent Storage Archival\n\
+                      * bar.service loaded activating auto-restart Bar\n";
+        assert_eq!(
+            vec![
+                unit(
+                    "systemd-pstore.service",
+                    "loaded",
+                    "failed",
+                    "Platform Persistent Storage Archival"
+                ),
+                unit("bar.service", "loaded", "auto-restart", "Bar"),
+            ],
+            parse_failed_systemd_units(output)
+        );
+    }
+
+    #[test]
+    fn test_parse_no_failed_units() {
+        assert!(parse_failed_systemd_units("").is_empty());
+        assert!(parse_failed_systemd_units("\n").is_empty());
+        // Printed by old systemd even with --no-legend.
+        assert!(parse_failed_systemd_units("0 loaded units listed.\n").is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_umask {
+    use super::*;
+
+    #[test]
+    fn test_parse_umask() {
+        assert_eq!(0o022, parse_umask("022").unwrap().bits());
+        assert_eq!(0o077, parse_umask("0077").unwrap().bits());
+        assert_eq!(0o002, parse_umask("2").unwrap().bits());
+        assert_eq!(0o777, parse_umask("777").unwrap().bits());
+        for invalid in ["", "8", "0o22", "-22", "1000", "00022", "22a", " 22"] {
+            assert!(
+                parse_umask(invalid).is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_dry_run {
+    use super::*;
+    use crate::systemdunit::test_systemd_unit_disabler::snapshot_dir;
+
+    #[test]
+    fn test_dry_run_changes_nothing() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let root = tmpdir.path();
+        for dir in &[
+            "etc/netplan",
+            "etc/systemd/network",
+            "etc/sysconfig/network-scripts",
+            "etc/systemd/system/multi-user.target.wants",
+            "lib/systemd/system",
+            "etc/pam.d",
+            "etc/profile.d",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let files = [
+            ("etc/hostname", "LXC_NAME\n"),
+            ("etc/hosts", "127.0.1.1 LXC_NAME\n"),
+            (
+                "etc/environment",
+                "PATH='/opt/distrod/bin':/usr/bin:/bin\nWSL_INTEROP=/run/WSL/1_interop\n",
+            ),
+            ("etc/netplan/x.yaml", "network:\n  version: 2\n"),
+            ("etc/systemd/network/x.network", "[Match]\nName=eth0\n"),
+            ("etc/sysconfig/network-scripts/ifcfg-eth0", "DEVICE=eth0\n"),
+            (
+                "lib/systemd/system/NetworkManager.service",
+                "[Service]\nExecStart=/usr/sbin/NetworkManager\n\n\
+                 [Install]\nWantedBy=multi-user.target\n",
+            ),
+            ("etc/os-release", "ID=debian\n"),
+            ("etc/pam.d/sudo", "session required pam_limits.so\n"),
+        ];
+        for (path, content) in &files {
+            fs::write(root.join(path), content).unwrap();
+        }
+        std::os::unix::fs::symlink(
+            "/lib/systemd/system/NetworkManager.service",
+            root.join("etc/systemd/system/multi-user.target.wants/NetworkManager.service"),
+        )
+        .unwrap();
+        let before = snapshot_dir(root);
+
+        let rootfs = HostPath::new(root).unwrap();
+        initialize_distro_rootfs(&rootfs, true, true).unwrap();
+        cleanup_distro_rootfs(&rootfs, true).unwrap();
+
+        assert_eq!(before, snapshot_dir(root));
+    }
 }
@@ -1,8 +1,12 @@
+use std::collections::VecDeque;
 use std::ffi::OsString;
+use std::sync::Mutex;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 
+use crate::http_client;
+
 pub type ListChooseFn<'a> =
     &'a (dyn Fn(DistroImageList) -> Result<Box<dyn DistroImageFetcher>> + Send + Sync);
 pub type PromptPath<'a> = &'a (dyn Fn(&str, Option<&str>) -> Result<OsString> + Send + Sync);
@@ -47,10 +51,18 @@ pub async fn fetch_image(
     choose_from_list: ListChooseFn<'_>,
     default_index: usize,
 ) -> Result<DistroImage> {
-    let mut distro_image_list = Box::new(DistroImageFetchersList {
+    let distro_image_list = Box::new(DistroImageFetchersList {
         fetchers,
         default_index,
     }) as Box<dyn DistroImageFetcher>;
+    fetch_image_from(distro_image_list, choose_from_list).await
+}
+
+/// Follow the lists from the given fetcher by choose_from_list until an image is reached.
+pub async fn fetch_image_from(
+    mut distro_image_list: Box<dyn DistroImageFetcher>,
+    choose_from_list: ListChooseFn<'_>,
+) -> Result<DistroImage> {
     loop {
         let fetched_image_list = distro_image_list.fetch().await?;
         match fetched_image_list {
@@ -64,6 +76,121 @@ pub async fn fetch_image(
     }
 }
 
+/// Follow the fetchers by the given names from the top of the list without prompting,
+/// and return the list or the image at the end.
+pub async fn fetch_image_list_by_names(
+    fetcher: Box<dyn DistroImageFetcher>,
+    names: &[&str],
+) -> Result<DistroImageList> {
+    let mut list = fetcher.fetch().await?;
+    for name in names {
+        let next_fetcher = match list {
+            DistroImageList::Fetcher(list_item_kind, fetchers, _) => fetchers
+                .into_iter()
+                .find(|fetcher| fetcher.get_name() == *name)
+                .ok_or_else(|| anyhow!("'{}' is not found in {}.", name, &list_item_kind))?,
+            DistroImageList::Image(image) => {
+                bail!(
+                    "'{}' is already an image, but '{}' is given.",
+                    image.name,
+                    name
+                )
+            }
+        };
+        list = next_fetcher.fetch().await?;
+    }
+    Ok(list)
+}
+
+/// The chooser which chooses the items of the nested lists by the given names in order without
+/// prompting. The default item is chosen for the lists after the names run out.
+pub struct ListChooserByNames {
+    names: Mutex<VecDeque<String>>,
+}
+
+impl ListChooserByNames {
+    pub fn new<I: IntoIterator<Item = String>>(names: I) -> Self {
+        ListChooserByNames {
+            names: Mutex::new(names.into_iter().collect()),
+        }
+    }
+
+    pub fn choose(&self, list: DistroImageList) -> Result<Box<dyn DistroImageFetcher>> {
+        let (list_item_kind, fetchers, default) = match list {
+            DistroImageList::Fetcher(list_item_kind, fetchers, default) => {
+                (list_item_kind, fetchers, default)
+            }
+            DistroImageList::Image(_) => bail!("Image should not be passed to choose."),
+        };
+        if fetchers.is_empty() {
+            bail!("Empty list of {}.", &list_item_kind);
+        }
+        let name = self
+            .names
+            .lock()
+            .map_err(|_| anyhow!("The names to choose are poisoned."))?
+            .pop_front();
+        let index = match name {
+            Some(name) => {
+                let item_names: Vec<&str> =
+                    fetchers.iter().map(|fetcher| fetcher.get_name()).collect();
+                find_item_by_name(&item_names, &name)
+                    .with_context(|| format!("Failed to choose {}.", &list_item_kind))?
+            }
+            None => {
+                let index = match default {
+                    DefaultImageFetcher::Index(index) => index,
+                    DefaultImageFetcher::Name(ref name) => fetchers
+                        .iter()
+                        .position(|fetcher| fetcher.get_name() == name)
+                        .ok_or_else(|| anyhow!("The default '{}' is not in the list.", name))?,
+                };
+                log::info!(
+                    "Choosing '{}' as {}.",
+                    fetchers[index].get_name(),
+                    &list_item_kind
+                );
+                index
+            }
+        };
+        Ok(fetchers.into_iter().nth(index).unwrap())
+    }
+}
+
+/// Find the item by its exact name, or by a part of its name if it matches only one item.
+fn find_item_by_name(item_names: &[&str], name: &str) -> Result<usize> {
+    if let Some(index) = item_names.iter().position(|item| *item == name) {
+        return Ok(index);
+    }
+    let lowercase_name = name.to_lowercase();
+    if let Some(index) = item_names
+        .iter()
+        .position(|item| item.to_lowercase() == lowercase_name)
+    {
+        return Ok(index);
+    }
+    let matched: Vec<usize> = (0..item_names.len())
+        .filter(|i| item_names[*i].to_lowercase().contains(&lowercase_name))
+        .collect();
+    match matched.len() {
+        0 => bail!(
+            "'{}' is not found. The candidates are: {}",
+            name,
+            item_names.join(", ")
+        ),
+        1 => Ok(matched[0]),
+        _ => bail!(
+            "'{}' is ambiguous. It matches: {}",
+            name,
+            matched
+                .iter()
+                .map(|i| item_names[*i])
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
 struct DistroImageFetchersList {
     fetchers: Vec<DistroImageFetcherGen>,
     default_index: usize,
@@ -94,10 +221,8 @@ where
     F: FnOnce(u64) -> indicatif::ProgressBar,
     W: std::io::Write,
 {
-    let client = reqwest::Client::builder().build()?;
-    let mut response = client
-        .get(url)
-        .send()
+    let client = http_client::build_http_client()?;
+    let mut response = http_client::get_with_retry(&client, url)
         .await
         .with_context(|| format!("Failed to download {}.", &url))?;
     let total_size = response
@@ -116,3 +241,190 @@ where
     progress_bar.finish();
     Ok(())
 }
+
+/// Check that the xz stream is complete by decompressing it to the end, which verifies the
+/// checksums of the blocks and the stream footer. This gives a clear error for a truncated
+/// download instead of a confusing one from the middle of unpacking.
+pub fn verify_xz_stream<R: std::io::Read>(tar_xz: R) -> Result<()> {
+    let mut decoder = xz2::read::XzDecoder::new(tar_xz);
+    match std::io::copy(&mut decoder, &mut std::io::sink()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e).with_context(|| "The image appears truncated or corrupted."),
+    }
+}
+
+/// Check that the gzip stream is complete by decompressing it to the end, which verifies the
+/// CRC32 and the size in its trailer.
+pub fn verify_gz_stream<R: std::io::Read>(tar_gz: R) -> Result<()> {
+    let mut decoder = flate2::read::GzDecoder::new(tar_gz);
+    match std::io::copy(&mut decoder, &mut std::io::sink()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e).with_context(|| "The archive appears truncated or corrupted."),
+    }
+}
+
+/// Compute the SHA-256 digest of the stream as a lowercase hex string.
+pub fn compute_sha256<R: std::io::Read>(mut reader: R) -> Result<String> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut reader, &mut hasher).with_context(|| "Failed to read the stream.")?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Parse a SHA-256 digest given as 64 hex digits, and return it in lowercase.
+pub fn parse_sha256(sha256: &str) -> Result<String> {
+    if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("'{}' is not a SHA-256 digest of 64 hex digits.", sha256);
+    }
+    Ok(sha256.to_ascii_lowercase())
+}
+
+/// Check that the SHA-256 digest of the stream is `expected`, a hex string.
+pub fn verify_sha256<R: std::io::Read>(reader: R, expected: &str) -> Result<()> {
+    let actual = compute_sha256(reader)?;
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(
+            "The SHA-256 digest doesn't match. expected: {}, actual: {}",
+            expected.trim(),
+            actual
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_distro_image {
+    use super::*;
+    use std::io::Write;
+
+    struct TestList {
+        name: String,
+    }
+
+    #[async_trait]
+    impl DistroImageFetcher for TestList {
+        fn get_name(&self) -> &str {
+            &self.name
+        }
+
+        async fn fetch(&self) -> Result<DistroImageList> {
+            Ok(DistroImageList::Image(DistroImage {
+                name: self.name.clone(),
+                image: DistroImageFile::Url(format!("https://example.com/{}", self.name)),
+            }))
+        }
+    }
+
+    fn test_list(kind: &str, names: &[&'static str], default: usize) -> DistroImageList {
+        DistroImageList::Fetcher(
+            kind.to_owned(),
+            names
+                .iter()
+                .map(|name| {
+                    Box::new(TestList {
+                        name: (*name).to_owned(),
+                    }) as Box<dyn DistroImageFetcher>
+                })
+                .collect(),
+            DefaultImageFetcher::Index(default),
+        )
+    }
+
+    #[test]
+    fn test_find_item_by_name() {
+        let items = &["alpine", "debian", "ubuntu", "ubuntu-core"];
+        assert_eq!(2, find_item_by_name(items, "ubuntu").unwrap());
+        assert_eq!(1, find_item_by_name(items, "Debian").unwrap());
+        assert_eq!(0, find_item_by_name(items, "alp").unwrap());
+        assert_eq!(3, find_item_by_name(items, "core").unwrap());
+        let err = find_item_by_name(items, "ubu").unwrap_err();
+        assert!(format!("{}", err).contains("ambiguous"), "{:?}", err);
+        let err = find_item_by_name(items, "gentoo").unwrap_err();
+        assert!(format!("{}", err).contains("not found"), "{:?}", err);
+    }
+
+    #[test]
+    fn test_list_chooser_by_names() {
+        let chooser = ListChooserByNames::new(vec!["ubuntu".to_owned(), "22.04".to_owned()]);
+        let distro = chooser
+            .choose(test_list("a distro", &["debian", "ubuntu"], 0))
+            .unwrap();
+        assert_eq!("ubuntu", distro.get_name());
+        let version = chooser
+            .choose(test_list("a version", &["20.04", "22.04"], 0))
+            .unwrap();
+        assert_eq!("22.04", version.get_name());
+        // The default is chosen after the names run out.
+        let variant = chooser
+            .choose(test_list("a variant", &["cloud", "default"], 1))
+            .unwrap();
+        assert_eq!("default", variant.get_name());
+
+        let chooser = ListChooserByNames::new(vec!["2".to_owned()]);
+        assert!(chooser
+            .choose(test_list("a version", &["20.04", "22.04"], 0))
+            .is_err());
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_verify_xz_stream() {
+        let data: Vec<u8> = (0..100000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let tar_xz = compress(&data);
+        assert!(verify_xz_stream(&tar_xz[..]).is_ok());
+
+        for len in [0, tar_xz.len() / 2, tar_xz.len() - 1] {
+            let err = verify_xz_stream(&tar_xz[..len]).unwrap_err();
+            assert!(
+                format!("{}", err).contains("truncated"),
+                "unexpected error for length {}: {:?}",
+                len,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let sha256_of_abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(sha256_of_abc, compute_sha256(&b"abc"[..]).unwrap());
+        assert!(verify_sha256(&b"abc"[..], sha256_of_abc).is_ok());
+        assert!(verify_sha256(&b"abc"[..], &sha256_of_abc.to_uppercase()).is_ok());
+        assert!(verify_sha256(&b"abd"[..], sha256_of_abc).is_err());
+
+        assert_eq!(
+            sha256_of_abc,
+            parse_sha256(&sha256_of_abc.to_uppercase()).unwrap()
+        );
+        assert!(parse_sha256(&sha256_of_abc[1..]).is_err());
+        assert!(parse_sha256(&sha256_of_abc.replace('a', "g")).is_err());
+    }
+
+    #[test]
+    fn test_verify_gz_stream() {
+        let data: Vec<u8> = (0..100000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let mut tar_gz = encoder.finish().unwrap();
+        assert!(verify_gz_stream(&tar_gz[..]).is_ok());
+
+        for len in [tar_gz.len() / 2, tar_gz.len() - 1] {
+            let err = verify_gz_stream(&tar_gz[..len]).unwrap_err();
+            assert!(
+                format!("{}", err).contains("truncated"),
+                "unexpected error for length {}: {:?}",
+                len,
+                err
+            );
+        }
+        // A corrupted CRC32 in the trailer.
+        let crc_pos = tar_gz.len() - 8;
+        tar_gz[crc_pos] ^= 0xff;
+        assert!(verify_gz_stream(&tar_gz[..]).is_err());
+    }
+}
@@ -8,7 +8,7 @@ use std::os::linux::fs::MetadataExt;
 use std::sync::{Arc, RwLock};
 use std::{
     fs::File,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
@@ -24,6 +24,93 @@ pub struct DistrodGlobalConfig {
     pub distro_images_dir: PathBuf,
     pub log_level: Option<String>,
     pub kmsg_log_level: Option<String>,
+    /// The file to append the logs to in addition to the terminal, used when --log-file is not given.
+    pub log_file: Option<PathBuf>,
+    /// The delay before distrod-exec launches systemd, used when
+    /// DISTROD_EXEC_INIT_LAUNCH_DELAY is not set.
+    pub init_launch_delay_sec: Option<u32>,
+    /// The CA certificate to trust in addition to the system's ones when downloading images,
+    /// used when DISTROD_CA_CERT is not set.
+    pub ca_cert_path: Option<PathBuf>,
+    /// The regex which the values of WSLENV and WSL_DISTRO_NAME must match to be propagated to
+    /// the distro. Newlines and the other control characters are rejected regardless.
+    pub wsl_env_harmless_pattern: Option<String>,
+    /// The WSL variables to be forwarded to the distro in addition to WSL_INTEROP, WSLENV, and
+    /// WSL_DISTRO_NAME. The WSLg variables are forwarded if not set.
+    pub forwarded_wsl_envs: Option<Vec<String>>,
+    /// Keep systemd-networkd enabled and the configurations in /etc/systemd/network.
+    #[serde(default)]
+    pub keep_systemd_networkd: bool,
+    /// Keep systemd-resolved enabled and /etc/resolv.conf linked to it, instead of the one
+    /// generated by WSL.
+    #[serde(default)]
+    pub keep_systemd_resolved: bool,
+    /// Set false to keep the hostname of the distros instead of copying the one of WSL into
+    /// /etc/hostname and /etc/hosts on initialization. True if not set.
+    pub sync_hostname: Option<bool>,
+    /// The nameservers to be written to /etc/resolv.conf of the distros instead of the one
+    /// generated by WSL.
+    pub dns_servers: Option<Vec<String>>,
+    /// The search domains written to /etc/resolv.conf along with dns_servers.
+    pub dns_search: Option<Vec<String>>,
+    /// The number of attempts of each request to download the images and their listings,
+    /// which are retried on connection errors and 5xx responses. 4 if not set.
+    pub http_retry_attempts: Option<u32>,
+    /// The systemd target to boot the distros with, used when --unit is not given.
+    /// multi-user.target if not set.
+    pub default_init_target: Option<String>,
+    /// The system-wide environment variables in the form of "KEY=VALUE", which are passed to
+    /// systemd by systemd.setenv in addition to --setenv.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system_envs: Vec<String>,
+    /// The path in the distros where the root of WSL is moved to. /mnt/distrod_root if not set.
+    /// Change it only while no distro is running.
+    pub old_root_path: Option<PathBuf>,
+    /// The layout of the rootfs of the distros created without --vhdx or --no-vhdx,
+    /// "directory" or "vhdx". "directory" if not set.
+    pub default_rootfs_format: Option<RootfsFormat>,
+    /// The size of the ext4 image file such as "64G", required when default_rootfs_format is
+    /// "vhdx".
+    pub default_vhdx_size: Option<String>,
+    // The arrays of tables have to be at the end, since TOML can't have a plain value after a
+    // table, and the fields are serialized in this order.
+    /// The ext4 image files to be mounted at the rootfs directories before launching the distros.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rootfs_images: Vec<RootfsImageEntry>,
+    /// The host paths to be bind-mounted into the container in addition to the WSL ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_mounts: Vec<ExtraMountEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RootfsFormat {
+    Directory,
+    Vhdx,
+}
+
+impl Default for RootfsFormat {
+    fn default() -> Self {
+        RootfsFormat::Directory
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RootfsImageEntry {
+    pub image: PathBuf,
+    pub mount_point: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExtraMountEntry {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// The mount flags in addition to MS_BIND, such as "rdonly", "rec", "nosuid", "nodev", and "noexec".
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Whether the source is a file rather than a directory.
+    #[serde(default)]
+    pub is_file: bool,
 }
 
 static DISTROD_ROOT_DIR: &str = "/opt/distrod";
@@ -64,6 +151,85 @@ impl DistrodConfig {
     }
 }
 
+impl DistrodGlobalConfig {
+    /// The size of the ext4 image file for a distro created without --vhdx or --no-vhdx,
+    /// or None if the rootfs should be unpacked into a plain directory.
+    pub fn get_default_vhdx_size(&self) -> Result<Option<&str>> {
+        let format = self.default_rootfs_format.unwrap_or_default();
+        match (format, self.default_vhdx_size.as_deref()) {
+            (RootfsFormat::Directory, None) => Ok(None),
+            (RootfsFormat::Directory, Some(_)) => bail!(
+                "default_vhdx_size is set, but default_rootfs_format is not \"vhdx\". \
+                 Set default_rootfs_format = \"vhdx\" or remove default_vhdx_size."
+            ),
+            (RootfsFormat::Vhdx, Some(size)) => Ok(Some(size)),
+            (RootfsFormat::Vhdx, None) => bail!(
+                "default_rootfs_format is \"vhdx\", but default_vhdx_size is not set. \
+                 Set the size of the image file such as default_vhdx_size = \"64G\"."
+            ),
+        }
+    }
+
+    /// Set the delay before distrod-exec launches systemd. Zero removes the setting, which disables
+    /// the delay except on Windows startup.
+    pub fn set_init_launch_delay_sec(&mut self, delay_sec: u32) {
+        self.init_launch_delay_sec = if delay_sec == 0 {
+            None
+        } else {
+            Some(delay_sec)
+        };
+    }
+}
+
+/// The default path in the distros where the root of WSL is moved to.
+pub const DEFAULT_DISTRO_OLD_ROOT_PATH: &str = "/mnt/distrod_root";
+
+/// The path in the distros where the root of WSL is moved to. A process is considered to be
+/// inside the running distro if this path is a mount point, so every user of the path should get
+/// it from here.
+pub fn get_distro_old_root_path() -> Result<PathBuf> {
+    let config = match DistrodConfig::get() {
+        Ok(config) => Some(config),
+        Err(e) => {
+            log::warn!(
+                "Using the default old_root_path since the Distrod config can't be read. {:?}",
+                e
+            );
+            None
+        }
+    };
+    resolve_distro_old_root_path(config.as_deref())
+}
+
+pub(crate) fn resolve_distro_old_root_path(config: Option<&DistrodConfig>) -> Result<PathBuf> {
+    let path = match config.and_then(|config| config.distrod.old_root_path.as_ref()) {
+        Some(path) => path,
+        None => return Ok(PathBuf::from(DEFAULT_DISTRO_OLD_ROOT_PATH)),
+    };
+    // /proc, /tmp, and /run are overmounted in the distros, which hides the old root.
+    let is_valid = path.has_root()
+        && path.components().count() > 1
+        && path
+            .components()
+            .all(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+        && !["/proc", "/tmp", "/run"]
+            .iter()
+            .any(|reserved| path.starts_with(reserved));
+    if !is_valid {
+        bail!(
+            "old_root_path {:?} is invalid. It must be a normalized absolute path other than / \
+             and outside of /proc, /tmp, and /run.",
+            path
+        );
+    }
+    Ok(path.clone())
+}
+
+/// The directory where Distrod is installed.
+pub fn get_distrod_root_dir() -> &'static str {
+    DISTROD_ROOT_DIR
+}
+
 static DISTROD_ALIAS_DIR: Lazy<String> = Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "alias"));
 
 /// The directory where the alias commands are stored.
@@ -113,6 +279,15 @@ pub fn get_distrod_conf_dir() -> &'static str {
     DISTROD_CONF_DIR_PAH.as_str()
 }
 
+static DISTROD_CACHE_DIR_PATH: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "cache"));
+
+/// The path to the directory where the cached data such as the image listings are stored.
+/// The files in this directory can be removed at any time.
+pub fn get_distrod_cache_dir() -> &'static str {
+    DISTROD_CACHE_DIR_PATH.as_str()
+}
+
 #[cfg(target_os = "linux")]
 fn read_distrod_config() -> Result<DistrodConfig> {
     let config_path = Path::new(&*DISTROD_CONF_DIR_PAH).join("distrod.toml");
@@ -148,15 +323,245 @@ fn read_distrod_config() -> Result<DistrodConfig> {
 
 fn write_distrod_config(config: &DistrodConfig) -> Result<()> {
     let config_path = Path::new(&*DISTROD_CONF_DIR_PAH).join("distrod.toml");
-    let mut config_file = BufWriter::new(File::create(&config_path).with_context(|| {
-        format!(
-            "Failed to open the distrod config file: '{:?}'.",
-            &config_path
-        )
-    })?);
+    write_distrod_config_to(config, &config_path)
+}
 
-    config_file
-        .write_all(&toml::to_vec(config).with_context(|| "Failed to serialize the new config.")?)
-        .with_context(|| format!("Failed to write the config to '{:?}'.", config_path))?;
+/// Write the config to a temporary file next to the path and rename it over the path, so that
+/// the config is never left half-written.
+fn write_distrod_config_to(config: &DistrodConfig, config_path: &Path) -> Result<()> {
+    // Serialize it before touching any file so that a serialization error changes nothing.
+    let serialized = toml::to_vec(config).with_context(|| "Failed to serialize the new config.")?;
+    let tmp_path = config_path.with_extension("toml.tmp");
+    let write_tmp = || -> Result<()> {
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create '{:?}'.", &tmp_path))?;
+        if let Ok(metadata) = std::fs::metadata(config_path) {
+            file.set_permissions(metadata.permissions())
+                .with_context(|| format!("Failed to set the permission of '{:?}'.", &tmp_path))?;
+        }
+        let mut writer = BufWriter::new(&file);
+        writer
+            .write_all(&serialized)
+            .with_context(|| format!("Failed to write the config to '{:?}'.", &tmp_path))?;
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write the config to '{:?}'.", &tmp_path))?;
+        drop(writer);
+        file.sync_all()
+            .with_context(|| format!("Failed to sync '{:?}'.", &tmp_path))?;
+        Ok(())
+    };
+    if let Err(e) = write_tmp() {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    std::fs::rename(&tmp_path, config_path).with_context(|| {
+        let _ = std::fs::remove_file(&tmp_path);
+        format!("Failed to replace '{:?}' with the new config.", config_path)
+    })?;
     Ok(())
 }
+
+#[cfg(test)]
+pub(crate) mod test_distrod_config {
+    use super::*;
+
+    #[test]
+    fn test_parse_extra_mounts() {
+        let config: DistrodConfig = toml::from_str(
+            r#"
+            [distrod]
+            default_distro_image = ""
+            distro_images_dir = "/var/lib/distrod"
+
+            [[distrod.extra_mounts]]
+            source = "/home/shared"
+            target = "/srv/shared"
+            flags = ["rdonly", "rec"]
+
+            [[distrod.extra_mounts]]
+            source = "/dev/kvm"
+            target = "/dev/kvm"
+            is_file = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ExtraMountEntry {
+                    source: PathBuf::from("/home/shared"),
+                    target: PathBuf::from("/srv/shared"),
+                    flags: vec!["rdonly".to_owned(), "rec".to_owned()],
+                    is_file: false,
+                },
+                ExtraMountEntry {
+                    source: PathBuf::from("/dev/kvm"),
+                    target: PathBuf::from("/dev/kvm"),
+                    flags: vec![],
+                    is_file: true,
+                },
+            ],
+            config.distrod.extra_mounts
+        );
+    }
+
+    pub(crate) fn config_with_old_root_path(path: &str) -> DistrodConfig {
+        toml::from_str(&format!(
+            r#"
+            [distrod]
+            default_distro_image = ""
+            distro_images_dir = "/var/lib/distrod"
+            old_root_path = "{}"
+            "#,
+            path
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_write_config_with_tables() {
+        let mut config = config_with_old_root_path("/mnt/distrod_root");
+        config.distrod.rootfs_images = vec![RootfsImageEntry {
+            image: PathBuf::from("/var/lib/distrod/ubuntu.img"),
+            mount_point: PathBuf::from("/var/lib/distrod/ubuntu"),
+        }];
+        config.distrod.extra_mounts = vec![ExtraMountEntry {
+            source: PathBuf::from("/home/shared"),
+            target: PathBuf::from("/srv/shared"),
+            flags: vec!["rdonly".to_owned()],
+            is_file: false,
+        }];
+        config.distrod.set_init_launch_delay_sec(5);
+        config.distrod.default_vhdx_size = Some("64G".to_owned());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("distrod.toml");
+        std::fs::write(&config_path, "[distrod]\n").unwrap();
+        write_distrod_config_to(&config, &config_path).unwrap();
+        let read_back: DistrodConfig =
+            toml::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+        assert_eq!(
+            config.distrod.rootfs_images,
+            read_back.distrod.rootfs_images
+        );
+        assert_eq!(config.distrod.extra_mounts, read_back.distrod.extra_mounts);
+        assert_eq!(Some(5), read_back.distrod.init_launch_delay_sec);
+        assert_eq!(Some("64G"), read_back.distrod.default_vhdx_size.as_deref());
+        // No temporary file is left.
+        assert_eq!(1, std::fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_set_init_launch_delay_sec() {
+        let mut config = config_with_old_root_path("/mnt/distrod_root");
+        config.distrod.set_init_launch_delay_sec(5);
+        let serialized = toml::to_string(&config).unwrap();
+        let read_back: DistrodConfig = toml::from_str(&serialized).unwrap();
+        assert_eq!(Some(5), read_back.distrod.init_launch_delay_sec);
+
+        config.distrod.set_init_launch_delay_sec(0);
+        let serialized = toml::to_string(&config).unwrap();
+        assert!(!serialized.contains("init_launch_delay_sec"));
+        let read_back: DistrodConfig = toml::from_str(&serialized).unwrap();
+        assert_eq!(None, read_back.distrod.init_launch_delay_sec);
+    }
+
+    #[test]
+    fn test_resolve_distro_old_root_path() {
+        assert_eq!(
+            PathBuf::from(DEFAULT_DISTRO_OLD_ROOT_PATH),
+            resolve_distro_old_root_path(None).unwrap()
+        );
+        let config = config_with_old_root_path("/mnt/custom_root");
+        assert_eq!(
+            PathBuf::from("/mnt/custom_root"),
+            resolve_distro_old_root_path(Some(&config)).unwrap()
+        );
+        for invalid in [
+            "/",
+            "mnt/root",
+            "/mnt/../root",
+            "/tmp/root",
+            "/run",
+            "/proc/1",
+        ] {
+            let config = config_with_old_root_path(invalid);
+            assert!(
+                resolve_distro_old_root_path(Some(&config)).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    fn parse_config_with(lines: &str) -> Result<DistrodConfig> {
+        Ok(toml::from_str(&format!(
+            r#"
+            [distrod]
+            default_distro_image = ""
+            distro_images_dir = "/var/lib/distrod"
+            {}
+            "#,
+            lines
+        ))?)
+    }
+
+    #[test]
+    fn test_default_rootfs_format() {
+        let config = parse_config_with("").unwrap();
+        assert_eq!(None, config.distrod.default_rootfs_format);
+        assert_eq!(None, config.distrod.get_default_vhdx_size().unwrap());
+
+        let config = parse_config_with(r#"default_rootfs_format = "directory""#).unwrap();
+        assert_eq!(
+            Some(RootfsFormat::Directory),
+            config.distrod.default_rootfs_format
+        );
+        assert_eq!(None, config.distrod.get_default_vhdx_size().unwrap());
+
+        let config = parse_config_with(
+            r#"
+            default_rootfs_format = "vhdx"
+            default_vhdx_size = "64G"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(RootfsFormat::Vhdx),
+            config.distrod.default_rootfs_format
+        );
+        assert_eq!(Some("64G"), config.distrod.get_default_vhdx_size().unwrap());
+    }
+
+    #[test]
+    fn test_invalid_default_rootfs_format() {
+        assert!(parse_config_with(r#"default_rootfs_format = "zip""#).is_err());
+        assert!(parse_config_with(r#"default_rootfs_format = "VHDX""#).is_err());
+
+        let config = parse_config_with(r#"default_rootfs_format = "vhdx""#).unwrap();
+        assert!(config.distrod.get_default_vhdx_size().is_err());
+        let config = parse_config_with(r#"default_vhdx_size = "64G""#).unwrap();
+        assert!(config.distrod.get_default_vhdx_size().is_err());
+        let config = parse_config_with(
+            r#"
+            default_rootfs_format = "directory"
+            default_vhdx_size = "64G"
+            "#,
+        )
+        .unwrap();
+        assert!(config.distrod.get_default_vhdx_size().is_err());
+    }
+
+    #[test]
+    fn test_extra_mounts_default_to_empty() {
+        let config: DistrodConfig = toml::from_str(
+            r#"
+            [distrod]
+            default_distro_image = ""
+            distro_images_dir = "/var/lib/distrod"
+            "#,
+        )
+        .unwrap();
+        assert!(config.distrod.extra_mounts.is_empty());
+    }
+}
@@ -1,12 +1,12 @@
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take, take_while, take_while1},
+    bytes::complete::{is_not, tag, take, take_while1},
     character::{
         complete::{char, line_ending, none_of, space0, space1},
-        is_alphabetic, is_digit, is_newline,
+        is_alphabetic, is_digit,
     },
-    combinator::{map_res, opt, recognize},
-    multi::{many1, separated_list0},
+    combinator::{map_res, not, opt, recognize},
+    multi::{many0, many1, separated_list0},
     sequence::{pair, separated_pair, terminated, tuple},
     IResult,
 };
@@ -19,7 +19,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 #[derive(Debug, Clone, Default)]
 pub struct EnvShellScript {
@@ -70,24 +70,21 @@ impl EnvShellScript {
         let mut paths: Vec<_> = self.paths.iter().collect();
         paths.sort();
         for (path, prepends) in paths {
+            // Compare whole colon-delimited components, and quote the candidate so that glob
+            // characters in it are matched literally. An empty PATH gets only the candidate,
+            // since an empty component would mean the current directory.
+            let new_path = if *prepends {
+                "${__CANDIDATE_PATH}:${PATH}"
+            } else {
+                "${PATH}:${__CANDIDATE_PATH}"
+            };
             script.push_str(&format!(
                 "__CANDIDATE_PATH={}\n\
-                 __COLON_PATH=\":${{PATH}}:\"\n",
-                single_quote_str_for_shell(path)
+                 case \":${{PATH:-}}:\" in *:\"${{__CANDIDATE_PATH}}\":*) ;; ::) export PATH=\"${{__CANDIDATE_PATH}}\" ;; *) export PATH=\"{}\" ;; esac\n\
+                 unset __CANDIDATE_PATH\n",
+                single_quote_str_for_shell(path),
+                new_path
             ));
-            if *prepends {
-                script.push_str(
-                 "if [ \"${__COLON_PATH#*:${__CANDIDATE_PATH}:}\" = \"${__COLON_PATH}\" ]; then export PATH=\"${__CANDIDATE_PATH}:${PATH}\"; fi\n"
-                );
-            } else {
-                script.push_str(
-                 "if [ \"${__COLON_PATH#*:${__CANDIDATE_PATH}:}\" = \"${__COLON_PATH}\" ]; then export PATH=\"${PATH}:${__CANDIDATE_PATH}\"; fi\n"
-                );
-            }
-            script.push_str(
-                "unset __CANDIDATE_PATH\n\
-                 unset __COLON_PATH\n",
-            );
         }
         script
     }
@@ -101,6 +98,9 @@ pub struct EnvFile {
     pub file_path: PathBuf,
     envs: HashMap<String, usize>,
     lines_with_metadata: Vec<EnvFileLineWithMetadata>,
+    // The line ending for new lines, which follows the style of the existing file.
+    line_ending: &'static str,
+    dry_run: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -124,6 +124,7 @@ struct EnvStatement {
     value: String,
     leading_characters: String,
     following_characters: String,
+    line_ending: String,
 }
 
 impl EnvFile {
@@ -134,6 +135,8 @@ impl EnvFile {
                 file_path: path.as_ref().to_owned(),
                 envs: HashMap::<String, usize>::default(),
                 lines_with_metadata: vec![],
+                line_ending: "\n",
+                dry_run: false,
             });
         }
 
@@ -144,6 +147,11 @@ impl EnvFile {
             .read_to_end(&mut buf)
             .with_context(|| format!("Failed to read {:?}", path.as_ref()))?;
 
+        let line_ending = if buf.windows(2).any(|w| w == b"\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
         let env_file_lines = EnvFileLines::parse(&buf)
             .map_err(|e| anyhow!("Failed to parse a line: {:?}", e))?
             .1;
@@ -163,9 +171,17 @@ impl EnvFile {
             file_path: path.as_ref().to_owned(),
             envs,
             lines_with_metadata,
+            line_ending,
+            dry_run: false,
         })
     }
 
+    /// Make `write` only log the new contents instead of writing them.
+    pub fn with_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
     pub fn get_env(&self, key: &str) -> Option<&str> {
         let val = match self.lines_with_metadata[*self.envs.get(key)?].line {
             EnvFileLine::Env(ref env_statement) => env_statement.value.as_str(),
@@ -174,11 +190,22 @@ impl EnvFile {
         Some(val)
     }
 
-    pub fn put_env(&mut self, key: String, value: String) {
-        // we don't allow values to contain newlines or escapes for safety,
+    /// Get the logical value of the variable, with the shell quotes removed and the escapes
+    /// resolved, while `get_env` returns the value as it is written in the file.
+    pub fn get_env_unquoted(&self, key: &str) -> Option<String> {
+        self.get_env(key).map(unquote_str_for_shell)
+    }
+
+    /// Put the variable with the value in single quotes, in which both pam_env.so and shells
+    /// read backslashes literally, so values such as Windows paths are stored as they are.
+    pub fn put_env(&mut self, key: String, value: String) -> Result<()> {
+        // We don't allow values to contain newlines for safety,
         // otherwise it will confuse pam_env.so and may let other variables be overwritten.
-        assert!(!value.contains('\n') && !value.contains('\\'));
-        self.put_env_with_no_sanity_check(key, single_quote_str_for_shell(&value))
+        if value.contains(&['\n', '\r'][..]) {
+            bail!("The value of {} contains a newline: {:?}", key, value);
+        }
+        self.put_env_with_no_sanity_check(key, single_quote_str_for_shell(&value));
+        Ok(())
     }
 
     pub fn remove_env(&mut self, key: &str) {
@@ -189,29 +216,51 @@ impl EnvFile {
         self.lines_with_metadata[index].is_removed = true;
     }
 
+    /// Prepend the path to PATH unless PATH already has it.
+    /// PATH is rewritten keeping its format, or in single quotes if PathVariable can't parse it.
     pub fn put_path(&mut self, path_val: String) {
         assert!(!path_val
             .chars()
             .any(|chr| ['"', '\'', '\\', '\n'].contains(&chr)));
         const DEFAULT_PATH: &str = "'/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin:/usr/games:/usr/local/games'";
-        let pathenv_value = {
-            let mut path_variable =
-                PathVariable::parse(self.get_env("PATH").unwrap_or(DEFAULT_PATH));
-            path_variable.put_path(&path_val);
-            path_variable.serialize()
+        let logical = self
+            .get_env_unquoted("PATH")
+            .unwrap_or_else(|| unquote_str_for_shell(DEFAULT_PATH));
+        let raw = self.get_env("PATH").unwrap_or(DEFAULT_PATH);
+        if logical.split(':').any(|path| path == path_val) {
+            return;
+        }
+        let pathenv_value = match parse_path_variable_if_faithful(raw, &logical) {
+            Some(mut path_variable) => {
+                path_variable.put_path(&path_val);
+                path_variable.serialize()
+            }
+            None => single_quote_str_for_shell(&format!("{}:{}", path_val, logical)),
         };
         self.put_env_with_no_sanity_check("PATH".to_owned(), pathenv_value);
     }
 
+    /// Remove the path from PATH. PATH is rewritten in the same way as `put_path`.
     pub fn remove_path<S: AsRef<str>>(&mut self, path_val: S) {
-        let path = match self.get_env("PATH") {
-            Some(path) => path,
-            None => return,
+        let (raw, logical) = match (self.get_env("PATH"), self.get_env_unquoted("PATH")) {
+            (Some(raw), Some(logical)) => (raw, logical),
+            _ => return,
         };
-        let pathenv_value = {
-            let mut path_variable = PathVariable::parse(path);
-            path_variable.remove_path(path_val.as_ref());
-            path_variable.serialize()
+        if !logical.split(':').any(|path| path == path_val.as_ref()) {
+            return;
+        }
+        let pathenv_value = match parse_path_variable_if_faithful(raw, &logical) {
+            Some(mut path_variable) => {
+                path_variable.remove_path(path_val.as_ref());
+                path_variable.serialize()
+            }
+            None => single_quote_str_for_shell(
+                &logical
+                    .split(':')
+                    .filter(|path| *path != path_val.as_ref())
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            ),
         };
         self.put_env_with_no_sanity_check("PATH".to_owned(), pathenv_value);
     }
@@ -235,6 +284,7 @@ impl EnvFile {
                         value,
                         leading_characters: String::new(),
                         following_characters: String::new(),
+                        line_ending: self.line_ending.to_owned(),
                     }),
                     is_removed: false,
                 };
@@ -245,6 +295,10 @@ impl EnvFile {
     }
 
     pub fn write(&mut self) -> Result<()> {
+        if self.dry_run {
+            log::info!("Would write {:?}.", &self.file_path);
+            return Ok(());
+        }
         let mut file = BufWriter::new(
             File::create(&self.file_path)
                 .with_context(|| format!("Failed to create {:?}.", &self.file_path))?,
@@ -267,13 +321,29 @@ impl EnvFile {
 }
 
 impl EnvFileLines {
+    /// Parse the lines one by one in a loop rather than by a combinator such as `many1`
+    /// so that a large file doesn't build a deep chain of parsers.
     pub fn parse(input: &[u8]) -> IResult<&[u8], EnvFileLines> {
-        if input.is_empty() {
-            return Ok((&[], EnvFileLines(vec![])));
+        let mut lines = vec![];
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (next, line) = match EnvFileLine::parse(rest) {
+                Ok(parsed) => parsed,
+                // Stop at the line which can't be parsed as `many1` does, unless it's the first.
+                Err(nom::Err::Error(_)) if !lines.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            if next.len() == rest.len() {
+                // Guard against an infinite loop by a parser which consumes nothing.
+                return Err(nom::Err::Error(nom::error::Error::new(
+                    rest,
+                    nom::error::ErrorKind::Many1,
+                )));
+            }
+            lines.push(line);
+            rest = next;
         }
-        map_res::<_, _, _, _, nom::Err<&[u8]>, _, _>(many1(EnvFileLine::parse), |lines| {
-            Ok(EnvFileLines(lines))
-        })(input)
+        Ok((rest, EnvFileLines(lines)))
     }
 
     pub fn serialize(&self) -> String {
@@ -329,12 +399,13 @@ impl EnvFileLine {
 
 impl EnvStatement {
     pub fn parse(line: &[u8]) -> IResult<&[u8], EnvStatement> {
-        let (rest, (leading_characters, (key, value), following_characters, _)) = tuple((
-            leading_characters,
-            separated_pair(declaration_key, tag("="), declaration_value),
-            following_characters,
-            opt(line_ending),
-        ))(line)?;
+        let (rest, (leading_characters, (key, value), following_characters, line_ending)) =
+            tuple((
+                leading_characters,
+                separated_pair(declaration_key, tag("="), declaration_value),
+                following_characters,
+                opt(line_ending),
+            ))(line)?;
         let to_string = |s: &[u8]| -> String { String::from_utf8_lossy(s).to_string() };
         Ok((
             rest,
@@ -343,6 +414,8 @@ impl EnvStatement {
                 value: to_string(value),
                 leading_characters: to_string(leading_characters),
                 following_characters: to_string(following_characters),
+                // A line without a line ending is terminated by LF when it is serialized.
+                line_ending: line_ending.map_or_else(|| "\n".to_owned(), to_string),
             },
         ))
     }
@@ -353,7 +426,7 @@ impl EnvStatement {
         serialized_line.push('=');
         serialized_line.push_str(&self.value);
         serialized_line.push_str(&self.following_characters);
-        serialized_line.push('\n');
+        serialized_line.push_str(&self.line_ending);
         serialized_line
     }
 }
@@ -369,7 +442,7 @@ fn declaration_key(line: &[u8]) -> IResult<&[u8], &[u8]> {
 fn declaration_value(line: &[u8]) -> IResult<&[u8], &[u8]> {
     //let regular_char = take_while(|c| !is_space(c) && !is_newline(c) && c != b'#');
     let escaped_char = recognize(pair(char('\\'), take(1u32)));
-    let regular_char = recognize(none_of("\n# \t\\"));
+    let regular_char = recognize(none_of("\r\n# \t\\"));
     recognize(separated_list0(
         space1,
         many1(alt((regular_char, escaped_char))),
@@ -377,7 +450,11 @@ fn declaration_value(line: &[u8]) -> IResult<&[u8], &[u8]> {
 }
 
 fn following_characters(line: &[u8]) -> IResult<&[u8], &[u8]> {
-    take_while(|c| !is_newline(c))(line)
+    // CR is a part of the following characters unless it's a part of CRLF.
+    recognize(many0(alt((
+        is_not("\r\n"),
+        terminated(tag("\r"), not(tag("\n"))),
+    ))))(line)
 }
 
 // PathVariable parses the value of PATH variable and provides the interface to modify it.
@@ -492,10 +569,55 @@ impl<'a> PathVariable<'a> {
     }
 }
 
+/// Parse the raw value of PATH by PathVariable, only if the paths it reads are the same as the
+/// ones of the logical value, which may not be the case with the complex quotes or escapes.
+fn parse_path_variable_if_faithful<'a>(raw: &'a str, logical: &str) -> Option<PathVariable<'a>> {
+    let path_variable = PathVariable::parse(raw);
+    let is_faithful = path_variable
+        .iter()
+        .map(unquote_str_for_shell)
+        .eq(logical.split(':').map(str::to_owned));
+    if is_faithful {
+        Some(path_variable)
+    } else {
+        None
+    }
+}
+
 fn single_quote_str_for_shell(s: &str) -> String {
     format!("'{}'", s.replace("'", "'\"'\"'"))
 }
 
+/// Remove the shell quotes from the string and resolve the escapes, e.g. `'it'"'"'s'` -> `it's`.
+fn unquote_str_for_shell(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut quote = None;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '\\') => match chars.next() {
+                // A backslash and a newline continue the line.
+                Some('\n') | None => {}
+                Some(escaped) => result.push(escaped),
+            },
+            (Some('"'), '\\') if chars.peek() == Some(&'\n') => {
+                chars.next();
+            }
+            (Some('"'), '\\') => match chars.peek() {
+                Some(&escaped) if ['$', '`', '"', '\\'].contains(&escaped) => {
+                    result.push(escaped);
+                    chars.next();
+                }
+                _ => result.push(c),
+            },
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
 fn unquote_path(s: &str) -> &str {
     for quote in ["'", "\""] {
         if s.starts_with(quote) && s.ends_with(quote) {
@@ -528,20 +650,14 @@ mod test_env_shell_script {
              if [ -z \"${var2:-}\" ]; then export var2='val2 again'; fi\n\
              if [ -z \"${var_space:-}\" ]; then export var_space='value with space'; fi\n\
              __CANDIDATE_PATH='/less_prio/path'\n\
-             __COLON_PATH=\":${PATH}:\"\n\
-             if [ \"${__COLON_PATH#*:${__CANDIDATE_PATH}:}\" = \"${__COLON_PATH}\" ]; then export PATH=\"${PATH}:${__CANDIDATE_PATH}\"; fi\n\
+             case \":${PATH:-}:\" in *:\"${__CANDIDATE_PATH}\":*) ;; ::) export PATH=\"${__CANDIDATE_PATH}\" ;; *) export PATH=\"${PATH}:${__CANDIDATE_PATH}\" ;; esac\n\
              unset __CANDIDATE_PATH\n\
-             unset __COLON_PATH\n\
              __CANDIDATE_PATH='/path/to/somewhere'\n\
-             __COLON_PATH=\":${PATH}:\"\n\
-             if [ \"${__COLON_PATH#*:${__CANDIDATE_PATH}:}\" = \"${__COLON_PATH}\" ]; then export PATH=\"${PATH}:${__CANDIDATE_PATH}\"; fi\n\
+             case \":${PATH:-}:\" in *:\"${__CANDIDATE_PATH}\":*) ;; ::) export PATH=\"${__CANDIDATE_PATH}\" ;; *) export PATH=\"${PATH}:${__CANDIDATE_PATH}\" ;; esac\n\
              unset __CANDIDATE_PATH\n\
-             unset __COLON_PATH\n\
              __CANDIDATE_PATH='/path/with space/somewhere'\n\
-             __COLON_PATH=\":${PATH}:\"\n\
-             if [ \"${__COLON_PATH#*:${__CANDIDATE_PATH}:}\" = \"${__COLON_PATH}\" ]; then export PATH=\"${__CANDIDATE_PATH}:${PATH}\"; fi\n\
-             unset __CANDIDATE_PATH\n\
-             unset __COLON_PATH\n",
+             case \":${PATH:-}:\" in *:\"${__CANDIDATE_PATH}\":*) ;; ::) export PATH=\"${__CANDIDATE_PATH}\" ;; *) export PATH=\"${__CANDIDATE_PATH}:${PATH}\" ;; esac\n\
+             unset __CANDIDATE_PATH\n",
             &script
         );
     }
@@ -577,6 +693,64 @@ mod test_env_shell_script {
             &String::from_utf8_lossy(&output.stdout)
         );
     }
+
+    #[test]
+    fn test_path_by_shell_with_adversarial_paths() {
+        let mut env_shell_script = EnvShellScript::new();
+        env_shell_script.put_path("/opt/distrod/bin".to_owned(), true);
+        let script = env_shell_script.gen_shell_script();
+
+        // Only a whole component counts as present.
+        for (path, expected) in &[
+            (
+                "/opt/distrod/bin2:/bin",
+                "/opt/distrod/bin:/opt/distrod/bin2:/bin",
+            ),
+            (
+                "/bin:/x/opt/distrod/bin",
+                "/opt/distrod/bin:/bin:/x/opt/distrod/bin",
+            ),
+            (
+                "/opt/distrod/bin/:/bin",
+                "/opt/distrod/bin:/opt/distrod/bin/:/bin",
+            ),
+            ("/opt/distrod/bin", "/opt/distrod/bin"),
+            ("/bin:/opt/distrod/bin", "/bin:/opt/distrod/bin"),
+            ("/bin:/opt/distrod/bin:/sbin", "/bin:/opt/distrod/bin:/sbin"),
+            ("", "/opt/distrod/bin"),
+        ] {
+            assert_eq!(*expected, run_path_script(&script, path), "PATH={:?}", path);
+        }
+
+        // Running the script again doesn't add the path twice.
+        let twice = format!("{}{}", &script, &script);
+        assert_eq!("/opt/distrod/bin:/bin", run_path_script(&twice, "/bin"));
+
+        // Glob characters in the candidate are matched literally.
+        let mut env_shell_script = EnvShellScript::new();
+        env_shell_script.put_path("/opt/*".to_owned(), false);
+        env_shell_script.put_path("/usr/[l]ib".to_owned(), false);
+        let script = env_shell_script.gen_shell_script();
+        assert_eq!(
+            "/opt/x:/usr/lib:/opt/*:/usr/[l]ib",
+            run_path_script(&script, "/opt/x:/usr/lib")
+        );
+        assert_eq!(
+            "/opt/*:/usr/[l]ib",
+            run_path_script(&script, "/opt/*:/usr/[l]ib")
+        );
+    }
+
+    fn run_path_script(script: &str, path: &str) -> String {
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!("{}printf '%s' \"$PATH\"", script))
+            .env("PATH", path)
+            .output()
+            .unwrap();
+        eprintln!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
 }
 
 #[cfg(test)]
@@ -919,6 +1093,23 @@ mod test_env_file_parsers {
         assert!(matches!(lines[5], EnvFileLine::Env(_)));
         assert_eq!(format!("{}\n", src), lines.serialize())
     }
+
+    #[test]
+    fn test_parse_env_file_lines_with_crlf() {
+        let src = "\
+        # This is comment\r\n\
+        VAR=VALUE\r\n\
+        \r\n\
+        PATH=path1:path2  # comment\r\n\
+        CR=in\rvalue\r\n";
+        let (_, lines) = EnvFileLines::parse(src.as_bytes()).unwrap();
+        assert_eq!(lines.len(), 5);
+        assert!(matches!(lines[1], EnvFileLine::Env(ref env) if env.value == "VALUE"));
+        assert!(
+            matches!(lines[3], EnvFileLine::Env(ref env) if env.value == "path1:path2" && env.following_characters == "  # comment")
+        );
+        assert_eq!(src, lines.serialize())
+    }
 }
 
 #[cfg(test)]
@@ -950,6 +1141,61 @@ mod test_env_file {
         );
     }
 
+    #[test]
+    fn test_get_unquoted() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        let cont = "\
+            SINGLE='single quoted'\n\
+            DOUBLE=\"double \\\"quoted\\\" \\$HOME\"\n\
+            UNQUOTED=un\\ quoted\n\
+            MIXED='it'\"'\"'s'\n\
+        ";
+        write!(&mut tmp, "{}", cont).unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+
+        assert_eq!(env.get_env_unquoted("None"), None);
+        assert_eq!(
+            env.get_env_unquoted("SINGLE"),
+            Some("single quoted".to_owned())
+        );
+        assert_eq!(
+            env.get_env_unquoted("DOUBLE"),
+            Some("double \"quoted\" $HOME".to_owned())
+        );
+        assert_eq!(
+            env.get_env_unquoted("UNQUOTED"),
+            Some("un quoted".to_owned())
+        );
+        assert_eq!(env.get_env_unquoted("MIXED"), Some("it's".to_owned()));
+
+        env.put_env("NEW".to_owned(), "isn't quoted".to_owned())
+            .unwrap();
+        assert_eq!(env.get_env("NEW"), Some("'isn'\"'\"'t quoted'"));
+        assert_eq!(env.get_env_unquoted("NEW"), Some("isn't quoted".to_owned()));
+    }
+
+    #[test]
+    fn test_put_env_and_save_with_crlf() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        let cont = "PATH=\"/usr/bin:/bin\"\r\nFOO=foo\r\n";
+        write!(&mut tmp, "{}", cont).unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+
+        assert_eq!(env.get_env("PATH"), Some("\"/usr/bin:/bin\""));
+        assert_eq!(env.get_env("FOO"), Some("foo"));
+
+        env.put_path("/new/path".to_owned());
+        env.put_env("BAR".to_owned(), "bar".to_owned()).unwrap();
+        env.write().unwrap();
+
+        let mut new_cont = String::new();
+        tmp.reopen().unwrap().read_to_string(&mut new_cont).unwrap();
+        assert_eq!(
+            "PATH=\"/new/path:/usr/bin:/bin\"\r\nFOO=foo\r\nBAR='bar'\r\n",
+            new_cont
+        );
+    }
+
     #[test]
     fn test_put_env_and_save() {
         let mut tmp = NamedTempFile::new().unwrap();
@@ -968,18 +1214,23 @@ mod test_env_file {
         write!(&mut tmp, "{}", cont).unwrap();
         let mut env = EnvFile::open(tmp.path()).unwrap();
 
-        env.put_env("NEW1".to_owned(), "TO_BE_OVERWRITTEN".to_owned());
+        env.put_env("NEW1".to_owned(), "TO_BE_OVERWRITTEN".to_owned())
+            .unwrap();
         env.put_env(
             "PATH".to_owned(),
             format!("path:{}", env.get_env("PATH").unwrap()),
-        );
-        env.put_env("FOO".to_owned(), "foo2".to_owned());
-        env.put_env("FOO".to_owned(), "foo3".to_owned());
-        env.put_env("BAR".to_owned(), "bar2".to_owned());
-        env.put_env("NEW1".to_owned(), "NEW1".to_owned());
-        env.put_env("QUOTED1".to_owned(), "quoted1".to_owned());
-        env.put_env("QUOTED2".to_owned(), "quoted2".to_owned());
-        env.put_env("WSL_INTEROP".to_owned(), "/run/bar".to_owned());
+        )
+        .unwrap();
+        env.put_env("FOO".to_owned(), "foo2".to_owned()).unwrap();
+        env.put_env("FOO".to_owned(), "foo3".to_owned()).unwrap();
+        env.put_env("BAR".to_owned(), "bar2".to_owned()).unwrap();
+        env.put_env("NEW1".to_owned(), "NEW1".to_owned()).unwrap();
+        env.put_env("QUOTED1".to_owned(), "quoted1".to_owned())
+            .unwrap();
+        env.put_env("QUOTED2".to_owned(), "quoted2".to_owned())
+            .unwrap();
+        env.put_env("WSL_INTEROP".to_owned(), "/run/bar".to_owned())
+            .unwrap();
 
         assert_eq!(env.get_env("None"), None);
         assert_eq!(env.get_env("NEW1"), Some("'NEW1'"));
@@ -1020,7 +1271,8 @@ mod test_env_file {
         env.remove_env("WSL_INTEROP");
         env.remove_path("/path/by/distrod");
         env.remove_path("/another/path/by/distrod");
-        env.put_env("TO_BE_DELETED".to_owned(), "TO_BE_DELETED".to_owned());
+        env.put_env("TO_BE_DELETED".to_owned(), "TO_BE_DELETED".to_owned())
+            .unwrap();
         env.remove_env("TO_BE_DELETED");
 
         assert_eq!(env.get_env("TO_BE_DELETED"), None);
@@ -1122,6 +1374,33 @@ mod test_env_file {
         assert_eq!(new_cont, expected);
     }
 
+    #[test]
+    fn test_put_path_with_complex_quotes() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        write!(&mut tmp, "PATH=/sbin:/b\"\"in:'/it'\"'\"'s'\n").unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+        assert_eq!(
+            Some("/sbin:/bin:/it's".to_owned()),
+            env.get_env_unquoted("PATH")
+        );
+
+        // The path is found in the logical value even if it's quoted in a complex way.
+        env.put_path("/bin".to_owned());
+        assert_eq!(Some("/sbin:/b\"\"in:'/it'\"'\"'s'"), env.get_env("PATH"));
+
+        // PATH is rewritten in single quotes if PathVariable can't parse it faithfully.
+        env.put_path("/new".to_owned());
+        assert_eq!(
+            Some("/new:/sbin:/bin:/it's".to_owned()),
+            env.get_env_unquoted("PATH")
+        );
+        env.remove_path("/sbin");
+        assert_eq!(
+            Some("/new:/bin:/it's".to_owned()),
+            env.get_env_unquoted("PATH")
+        );
+    }
+
     #[test]
     fn test_put_path_to_no_path_file() {
         let mut tmp = NamedTempFile::new().unwrap();
@@ -1156,7 +1435,7 @@ mod test_env_file {
         assert!(env.is_ok());
 
         let mut env = env.unwrap();
-        env.put_env("TEST".to_owned(), "VALUE".to_owned());
+        env.put_env("TEST".to_owned(), "VALUE".to_owned()).unwrap();
         env.write().unwrap();
         let expected = "\
 		    TEST='VALUE'\n\
@@ -1165,6 +1444,62 @@ mod test_env_file {
         assert_eq!(new_cont, expected);
     }
 
+    #[test]
+    fn test_large_env_file() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        let mut cont = String::new();
+        for i in 0..50000 {
+            match i % 3 {
+                0 => cont.push_str(&format!("VAR{}=value{}\n", i, i)),
+                1 => cont.push_str(&format!("# comment {}\n", i)),
+                _ => cont.push('\n'),
+            }
+        }
+        write!(&mut tmp, "{}", cont).unwrap();
+
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+        assert_eq!(Some("value0"), env.get_env("VAR0"));
+        assert_eq!(Some("value49998"), env.get_env("VAR49998"));
+        env.write().unwrap();
+        assert_eq!(cont, std::fs::read_to_string(tmp.path()).unwrap());
+    }
+
+    #[test]
+    fn test_put_env_with_backslashes() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+        let values = [
+            ("WIN_PATH", r"C:\Users\distrod"),
+            ("TRAILING", r"C:\Windows\"),
+            ("ESCAPE_LIKE", r"\n\t\\"),
+            ("MIXED", r"it's a\path"),
+        ];
+        for (key, value) in &values {
+            env.put_env(key.to_string(), value.to_string()).unwrap();
+        }
+        env.write().unwrap();
+        let cont = std::fs::read_to_string(tmp.path()).unwrap();
+        assert!(cont.starts_with("WIN_PATH='C:\\Users\\distrod'\nTRAILING='C:\\Windows\\'\n"));
+
+        let env = EnvFile::open(tmp.path()).unwrap();
+        for (key, value) in &values {
+            assert_eq!(Some(value.to_string()), env.get_env_unquoted(key));
+        }
+    }
+
+    #[test]
+    fn test_put_env_with_newline() {
+        let tmp = NamedTempFile::new().unwrap();
+        let mut env = EnvFile::open(tmp.path()).unwrap();
+        assert!(env
+            .put_env("INJECTED".to_owned(), "foo\nPATH=/tmp".to_owned())
+            .is_err());
+        assert!(env
+            .put_env("INJECTED".to_owned(), "foo\rPATH=/tmp".to_owned())
+            .is_err());
+        assert_eq!(None, env.get_env("INJECTED"));
+    }
+
     #[test]
     fn test_open_nonexistential_env_file() {
         let tmpdir = TempDir::new().unwrap();
@@ -1172,7 +1507,7 @@ mod test_env_file {
         assert!(env.is_ok());
 
         let mut env = env.unwrap();
-        env.put_env("TEST".to_owned(), "VALUE".to_owned());
+        env.put_env("TEST".to_owned(), "VALUE".to_owned()).unwrap();
         env.write().unwrap();
         let expected = "\
 		    TEST='VALUE'\n\
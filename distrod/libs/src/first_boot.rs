@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::container::{ContainerPath, HostPath};
+use crate::distro::Distro;
+
+/// The directory of the scripts which run once on the first launch of a distro.
+pub const FIRST_BOOT_SCRIPTS_DIR: &str = "/etc/distrod/first-boot.d";
+/// The stamp file which marks that the first-boot scripts have run.
+const FIRST_BOOT_STAMP_PATH: &str = "/var/lib/distrod/first-boot.done";
+const FIRST_BOOT_PATH_ENV: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Run the executable files in `FIRST_BOOT_SCRIPTS_DIR` in the lexical order as root, unless
+/// they have already run in the distro. A failing script is logged and doesn't stop the others.
+pub fn run_first_boot_scripts(distro: &Distro) -> Result<()> {
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+    let stamp_path = ContainerPath::new(FIRST_BOOT_STAMP_PATH)?.to_host_path(&rootfs);
+    if stamp_path.as_path().exists() {
+        return Ok(());
+    }
+
+    let scripts = list_first_boot_scripts(&rootfs)
+        .with_context(|| format!("Failed to list the scripts in {}.", FIRST_BOOT_SCRIPTS_DIR))?;
+    for script in scripts {
+        let script = Path::new(FIRST_BOOT_SCRIPTS_DIR).join(script);
+        log::info!("Running the first-boot script {:?}.", &script);
+        if let Err(e) = run_script(distro, &script) {
+            log::error!("The first-boot script {:?} failed. {:?}", &script, e);
+        }
+    }
+
+    // The scripts never run again, even if some of them failed.
+    let stamp_path = stamp_path.as_path();
+    if let Some(stamp_dir) = stamp_path.parent() {
+        std::fs::create_dir_all(stamp_dir)
+            .with_context(|| format!("Failed to create {:?}.", stamp_dir))?;
+    }
+    std::fs::write(stamp_path, "")
+        .with_context(|| format!("Failed to create the stamp file {:?}.", stamp_path))?;
+    Ok(())
+}
+
+/// Remove the stamp file so that the first-boot scripts run again on the next launch.
+pub fn reset_first_boot_stamp(rootfs: &HostPath) -> Result<()> {
+    let stamp_path = ContainerPath::new(FIRST_BOOT_STAMP_PATH)?.to_host_path(rootfs);
+    match std::fs::remove_file(stamp_path.as_path()) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {:?}.", stamp_path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// List the names of the executable files in `FIRST_BOOT_SCRIPTS_DIR` of the rootfs in the
+/// lexical order. The other files are skipped with a warning, as run-parts does.
+fn list_first_boot_scripts(rootfs: &HostPath) -> Result<Vec<PathBuf>> {
+    let resolve = |path: &Path| -> Result<HostPath> {
+        ContainerPath::new(path)?
+            .resolve_in_rootfs(rootfs)
+            .ok_or_else(|| anyhow!("Too many levels of symlinks in {:?}.", path))
+    };
+    let dir = resolve(Path::new(FIRST_BOOT_SCRIPTS_DIR))?;
+    let entries = match std::fs::read_dir(dir.as_path()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut scripts = vec![];
+    for entry in entries {
+        let entry = entry?;
+        // Follow the symlinks, which may point to the scripts shared by distros, in the rootfs
+        // as the scripts run in it.
+        let path = Path::new(FIRST_BOOT_SCRIPTS_DIR).join(entry.file_name());
+        let metadata = match resolve(&path)
+            .and_then(|host_path| Ok(std::fs::metadata(host_path.as_path())?))
+        {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                log::warn!("Skipping {:?}. {}", &path, e);
+                continue;
+            }
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        if metadata.permissions().mode() & 0o111 == 0 {
+            log::warn!(
+                "Skipping the first-boot script {:?}, which is not executable.",
+                entry.file_name()
+            );
+            continue;
+        }
+        scripts.push(PathBuf::from(entry.file_name()));
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+fn run_script(distro: &Distro, script: &Path) -> Result<()> {
+    let mut command = Command::new(script);
+    command
+        .current_dir("/")
+        .env_clear()
+        .env("PATH", FIRST_BOOT_PATH_ENV)
+        .env("HOME", "/root");
+    unsafe {
+        // distrod-exec is setuid, so make the real uid the root as well.
+        command.pre_exec(|| {
+            if nix::libc::setgid(0) != 0 || nix::libc::setuid(0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut waiter = distro.exec_std_command(command, None)?;
+    let exit_code = waiter.wait().to_exit_code();
+    if exit_code != 0 {
+        anyhow::bail!("It exited with {}.", exit_code);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_first_boot {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_list_first_boot_scripts() {
+        let root = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(root.path()).unwrap();
+        assert!(list_first_boot_scripts(&rootfs).unwrap().is_empty());
+
+        let dir = root.path().join("etc/distrod/first-boot.d");
+        fs::create_dir_all(&dir).unwrap();
+        let add = |name: &str, mode: u32| {
+            let path = dir.join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        };
+        add("20-user", 0o755);
+        add("10-network", 0o700);
+        add("README", 0o644);
+        fs::create_dir(dir.join("30-dir")).unwrap();
+        std::os::unix::fs::symlink("20-user", dir.join("15-link")).unwrap();
+        std::os::unix::fs::symlink("none", dir.join("16-dangling")).unwrap();
+        // The absolute links point to the files in the rootfs, not the ones on the host.
+        std::os::unix::fs::symlink(
+            "/etc/distrod/first-boot.d/20-user",
+            dir.join("17-absolute-link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("/bin/sh", dir.join("18-host")).unwrap();
+
+        assert_eq!(
+            vec![
+                PathBuf::from("10-network"),
+                PathBuf::from("15-link"),
+                PathBuf::from("17-absolute-link"),
+                PathBuf::from("20-user")
+            ],
+            list_first_boot_scripts(&rootfs).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reset_first_boot_stamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(dir.path()).unwrap();
+        reset_first_boot_stamp(&rootfs).unwrap();
+        let stamp_path = dir.path().join("var/lib/distrod/first-boot.done");
+        fs::create_dir_all(stamp_path.parent().unwrap()).unwrap();
+        fs::write(&stamp_path, "").unwrap();
+        reset_first_boot_stamp(&rootfs).unwrap();
+        assert!(!stamp_path.exists());
+    }
+}
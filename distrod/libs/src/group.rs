@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+/// GroupFile reads the entries of /etc/group.
+#[derive(Debug, Clone)]
+pub struct GroupFile {
+    file_cont: String,
+    path: PathBuf,
+}
+
+impl GroupFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GroupFile> {
+        let file_cont = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read '{:?}'.", path.as_ref()))?;
+        Ok(GroupFile {
+            file_cont,
+            path: PathBuf::from(path.as_ref()),
+        })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = Result<Group>> + '_ {
+        self.file_cont
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                Group::deserialize(line).with_context(|| format!("Invalid format line: '{}'", line))
+            })
+    }
+
+    pub fn get_ent_by_name(&self, name: &str) -> Result<Option<Group>> {
+        self.find(|group| group.name == name)
+    }
+
+    pub fn get_ent_by_gid(&self, gid: u32) -> Result<Option<Group>> {
+        self.find(|group| group.gid == gid)
+    }
+
+    /// Get the group by its name, or by its gid if no group has the name and it's a number.
+    pub fn get_ent_by_name_or_gid(&self, name_or_gid: &str) -> Result<Option<Group>> {
+        if let Some(group) = self.get_ent_by_name(name_or_gid)? {
+            return Ok(Some(group));
+        }
+        match name_or_gid.parse() {
+            Ok(gid) => self.get_ent_by_gid(gid),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Get the groups of the user like getgrouplist(3), which are the primary group followed by
+    /// the groups which have the user as a member. Malformed lines are skipped with a warning as
+    /// getgrouplist(3) does, so that a broken line doesn't lock the user out of the other groups.
+    pub fn get_group_list(&self, user_name: &str, primary_gid: u32) -> Vec<u32> {
+        let mut gids = vec![primary_gid];
+        for entry in self.entries() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("Skipping a line of {:?}. {:?}", &self.path, e);
+                    continue;
+                }
+            };
+            if entry.has_user(user_name, primary_gid) && !gids.contains(&entry.gid) {
+                gids.push(entry.gid);
+            }
+        }
+        gids
+    }
+
+    fn find<F: Fn(&Group) -> bool>(&self, pred: F) -> Result<Option<Group>> {
+        for entry in self.entries() {
+            let entry = entry.with_context(|| format!("Failed to parse {:?}.", &self.path))?;
+            if pred(&entry) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub passwd: String,
+    pub gid: u32,
+    /// The users who have the group as a supplementary group.
+    pub members: Vec<String>,
+}
+
+impl Group {
+    pub fn deserialize(line: &str) -> Result<Group> {
+        let mut ent = line.split(':');
+        let mut next_field = |field: &str| {
+            ent.next()
+                .ok_or_else(|| anyhow!("invalid {} format /etc/group.", field))
+        };
+        let name = next_field("name")?.to_owned();
+        let passwd = next_field("passwd")?.to_owned();
+        let gid = next_field("gid")?.parse().with_context(|| "invalid gid.")?;
+        let members = next_field("members")?
+            .split(',')
+            .filter(|member| !member.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Ok(Group {
+            name,
+            passwd,
+            gid,
+            members,
+        })
+    }
+
+    /// Whether the user belongs to the group, either as a member or by the primary gid.
+    pub fn has_user(&self, user_name: &str, primary_gid: u32) -> bool {
+        self.gid == primary_gid || self.members.iter().any(|member| member == user_name)
+    }
+}
+
+#[cfg(test)]
+mod test_group {
+    use super::*;
+    use std::io::Write;
+
+    static GROUP_FILE: &str = "root:x:0:\n\
+                               sudo:x:27:alice\n\
+                               docker:x:999:alice,bob\n\
+                               \n\
+                               alice:x:1000:\n";
+
+    fn open_group_file() -> (tempfile::NamedTempFile, GroupFile) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(GROUP_FILE.as_bytes()).unwrap();
+        let group_file = GroupFile::open(file.path()).unwrap();
+        (file, group_file)
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(
+            Group {
+                name: "docker".to_owned(),
+                passwd: "x".to_owned(),
+                gid: 999,
+                members: vec!["alice".to_owned(), "bob".to_owned()],
+            },
+            Group::deserialize("docker:x:999:alice,bob").unwrap()
+        );
+        assert!(Group::deserialize("root:x:0:").unwrap().members.is_empty());
+        assert!(Group::deserialize("root:x:0").is_err());
+        assert!(Group::deserialize("root:x:zero:").is_err());
+    }
+
+    #[test]
+    fn test_get_ent() {
+        let (_file, group_file) = open_group_file();
+        assert_eq!(27, group_file.get_ent_by_name("sudo").unwrap().unwrap().gid);
+        assert_eq!(
+            "docker",
+            group_file.get_ent_by_gid(999).unwrap().unwrap().name
+        );
+        assert!(group_file.get_ent_by_name("wheel").unwrap().is_none());
+        assert_eq!(
+            "alice",
+            group_file
+                .get_ent_by_name_or_gid("1000")
+                .unwrap()
+                .unwrap()
+                .name
+        );
+        assert!(group_file.get_ent_by_name_or_gid("1234").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_has_user() {
+        let (_file, group_file) = open_group_file();
+        let docker = group_file.get_ent_by_name("docker").unwrap().unwrap();
+        assert!(docker.has_user("bob", 1001));
+        assert!(!docker.has_user("carol", 1002));
+        assert!(docker.has_user("carol", 999));
+    }
+
+    #[test]
+    fn test_get_group_list() {
+        let (_file, group_file) = open_group_file();
+        assert_eq!(
+            vec![1000, 27, 999],
+            group_file.get_group_list("alice", 1000)
+        );
+        assert_eq!(vec![999], group_file.get_group_list("bob", 999));
+        assert_eq!(vec![1002], group_file.get_group_list("carol", 1002));
+    }
+
+    #[test]
+    fn test_get_group_list_with_malformed_lines() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"sudo:x:27:alice\nbroken\nwheel:x:ten:alice\ndocker:x:999:alice\n")
+            .unwrap();
+        let group_file = GroupFile::open(file.path()).unwrap();
+        assert_eq!(
+            vec![1000, 27, 999],
+            group_file.get_group_list("alice", 1000)
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::distrod_config::DistrodConfig;
+
+const CA_CERT_ENV_NAME: &str = "DISTROD_CA_CERT";
+const DEFAULT_RETRY_ATTEMPTS: u32 = 4;
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+
+/// Build the HTTP client to download the images and their listings.
+/// It honors HTTP_PROXY, HTTPS_PROXY, and NO_PROXY, and trusts the CA certificate
+/// given by DISTROD_CA_CERT or `ca_cert_path` in distrod.toml in addition to the system's ones,
+/// so that it works behind a TLS-intercepting proxy.
+pub fn build_http_client() -> Result<reqwest::Client> {
+    build_http_client_with(ProxyConfig::from_env(), get_ca_cert_path())
+}
+
+fn build_http_client_with(
+    proxy_config: ProxyConfig,
+    ca_cert_path: Option<PathBuf>,
+) -> Result<reqwest::Client> {
+    // Disable the proxies that reqwest detects by itself, which ignores NO_PROXY depending on its version.
+    let mut builder = reqwest::Client::builder().no_proxy();
+    if proxy_config.has_proxy() {
+        log::debug!("HTTP proxy config: {:?}", &proxy_config);
+        builder = builder.proxy(reqwest::Proxy::custom(move |url| {
+            proxy_config.get_proxy_for(url).map(str::to_owned)
+        }));
+    }
+    if let Some(ca_cert_path) = ca_cert_path {
+        let pem = std::fs::read(&ca_cert_path)
+            .with_context(|| format!("Failed to read the CA certificate {:?}.", &ca_cert_path))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("Invalid CA certificate {:?}.", &ca_cert_path))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    builder
+        .build()
+        .with_context(|| "Failed to build the HTTP client.")
+}
+
+/// GET the URL, retrying with exponential backoff on connection errors and 5xx responses,
+/// which are common right after WSL boots. The other error responses such as 404 are returned
+/// as errors without retrying.
+pub async fn get_with_retry(client: &reqwest::Client, url: &str) -> Result<reqwest::Response> {
+    get_with_retry_policy(client, url, &RetryPolicy::from_config()).await
+}
+
+#[derive(Debug, Clone)]
+struct RetryPolicy {
+    attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn from_config() -> Self {
+        let attempts = DistrodConfig::get()
+            .ok()
+            .and_then(|config| config.distrod.http_retry_attempts)
+            .unwrap_or(DEFAULT_RETRY_ATTEMPTS);
+        RetryPolicy {
+            attempts: attempts.max(1),
+            initial_delay: RETRY_INITIAL_DELAY,
+            max_delay: RETRY_MAX_DELAY,
+        }
+    }
+}
+
+async fn get_with_retry_policy(
+    client: &reqwest::Client,
+    url: &str,
+    policy: &RetryPolicy,
+) -> Result<reqwest::Response> {
+    let mut delay = policy.initial_delay;
+    let mut attempt = 1;
+    loop {
+        let result = client.get(url).send().await;
+        let retry_reason = match &result {
+            Ok(response) if response.status().is_server_error() => response.status().to_string(),
+            Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => e.to_string(),
+            _ => break result_to_response(result, url),
+        };
+        if attempt >= policy.attempts {
+            break result_to_response(result, url);
+        }
+        log::debug!(
+            "Retrying {} in {:?} ({}/{}). {}",
+            url,
+            delay,
+            attempt,
+            policy.attempts,
+            retry_reason
+        );
+        tokio::time::sleep(delay).await;
+        delay = std::cmp::min(delay * 2, policy.max_delay);
+        attempt += 1;
+    }
+}
+
+fn result_to_response(
+    result: reqwest::Result<reqwest::Response>,
+    url: &str,
+) -> Result<reqwest::Response> {
+    result
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("Failed to fetch {}.", url))
+}
+
+fn get_ca_cert_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os(CA_CERT_ENV_NAME).filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    DistrodConfig::get()
+        .ok()
+        .and_then(|config| config.distrod.ca_cert_path.clone())
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct ProxyConfig {
+    http_proxy: Option<String>,
+    https_proxy: Option<String>,
+    no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    fn from_env() -> Self {
+        ProxyConfig::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars<F: Fn(&str) -> Option<String>>(get_var: F) -> Self {
+        // The lower case names take precedence over the upper case ones.
+        let get = |name: &str| {
+            get_var(&name.to_lowercase())
+                .or_else(|| get_var(name))
+                .filter(|val| !val.is_empty())
+        };
+        let with_scheme = |proxy: String| {
+            if proxy.contains("://") {
+                proxy
+            } else {
+                format!("http://{}", proxy)
+            }
+        };
+        ProxyConfig {
+            http_proxy: get("HTTP_PROXY").map(with_scheme),
+            https_proxy: get("HTTPS_PROXY").map(with_scheme),
+            no_proxy: get("NO_PROXY")
+                .map(|hosts| {
+                    hosts
+                        .split(',')
+                        .map(|host| host.trim().to_owned())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    fn has_proxy(&self) -> bool {
+        self.http_proxy.is_some() || self.https_proxy.is_some()
+    }
+
+    fn get_proxy_for(&self, url: &reqwest::Url) -> Option<&str> {
+        let host = url.host_str()?;
+        if self
+            .no_proxy
+            .iter()
+            .any(|pattern| matches_no_proxy(pattern, host))
+        {
+            return None;
+        }
+        match url.scheme() {
+            "http" => self.http_proxy.as_deref(),
+            "https" => self.https_proxy.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+fn matches_no_proxy(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let host = host.to_ascii_lowercase();
+    let domain = pattern.trim_start_matches('.').to_ascii_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+#[cfg(test)]
+mod test_http_client {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn proxy_config_from(vars: &[(&str, &str)]) -> ProxyConfig {
+        let vars: HashMap<_, _> = vars.iter().cloned().collect();
+        ProxyConfig::from_vars(|name| vars.get(name).map(|val| val.to_string()))
+    }
+
+    #[test]
+    fn test_proxy_config_from_vars() {
+        let config = proxy_config_from(&[
+            ("HTTP_PROXY", "http://proxy.example.com:8080"),
+            ("https_proxy", "proxy.example.com:3128"),
+            ("NO_PROXY", "localhost, .internal.example.com,"),
+        ]);
+        assert_eq!(
+            ProxyConfig {
+                http_proxy: Some("http://proxy.example.com:8080".to_owned()),
+                https_proxy: Some("http://proxy.example.com:3128".to_owned()),
+                no_proxy: vec!["localhost".to_owned(), ".internal.example.com".to_owned()],
+            },
+            config
+        );
+        assert!(!proxy_config_from(&[("HTTPS_PROXY", "")]).has_proxy());
+    }
+
+    #[test]
+    fn test_get_proxy_for() {
+        let config = proxy_config_from(&[
+            ("HTTPS_PROXY", "http://proxy.example.com:3128"),
+            ("NO_PROXY", "internal.example.com"),
+        ]);
+        let get = |url: &str| config.get_proxy_for(&reqwest::Url::parse(url).unwrap());
+        assert_eq!(
+            Some("http://proxy.example.com:3128"),
+            get("https://images.linuxcontainers.org/images/")
+        );
+        assert_eq!(None, get("http://images.linuxcontainers.org/images/"));
+        assert_eq!(None, get("https://internal.example.com/"));
+        assert_eq!(None, get("https://mirror.internal.example.com/"));
+        assert_eq!(
+            Some("http://proxy.example.com:3128"),
+            get("https://notinternal.example.com/")
+        );
+        let no_proxy_all = proxy_config_from(&[
+            ("HTTPS_PROXY", "http://proxy.example.com:3128"),
+            ("NO_PROXY", "*"),
+        ]);
+        assert_eq!(
+            None,
+            no_proxy_all.get_proxy_for(
+                &reqwest::Url::parse("https://images.linuxcontainers.org/").unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_build_http_client_with_proxy() {
+        let config = proxy_config_from(&[("HTTPS_PROXY", "proxy.example.com:3128")]);
+        assert!(build_http_client_with(config, None).is_ok());
+
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("ca.pem");
+        std::fs::write(&cert_path, "not a certificate").unwrap();
+        assert!(build_http_client_with(ProxyConfig::default(), Some(cert_path)).is_err());
+        assert!(
+            build_http_client_with(ProxyConfig::default(), Some(dir.path().join("none"))).is_err()
+        );
+    }
+
+    /// Serve the given status lines in order, and return the URL and the number of the requests.
+    fn serve_statuses(statuses: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/images/", listener.local_addr().unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let server_count = count.clone();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                server_count.fetch_add(1, Ordering::SeqCst);
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    status
+                )
+                .unwrap();
+            }
+        });
+        (url, count)
+    }
+
+    fn test_policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_with_retry() {
+        let client = build_http_client_with(ProxyConfig::default(), None).unwrap();
+        let (url, count) =
+            serve_statuses(vec!["503 Service Unavailable", "502 Bad Gateway", "200 OK"]);
+        let response = get_with_retry_policy(&client, &url, &test_policy(4))
+            .await
+            .unwrap();
+        assert_eq!("ok", response.text().await.unwrap());
+        assert_eq!(3, count.load(Ordering::SeqCst));
+
+        // 404 is not retried.
+        let (url, count) = serve_statuses(vec!["404 Not Found", "200 OK"]);
+        assert!(get_with_retry_policy(&client, &url, &test_policy(4))
+            .await
+            .is_err());
+        assert_eq!(1, count.load(Ordering::SeqCst));
+
+        // The attempts are bounded.
+        let (url, count) = serve_statuses(vec!["500 Internal Server Error"; 3]);
+        assert!(get_with_retry_policy(&client, &url, &test_policy(2))
+            .await
+            .is_err());
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+}
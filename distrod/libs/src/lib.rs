@@ -2,7 +2,9 @@ pub mod cli_ui;
 pub mod container_org_image;
 pub mod distro_image;
 pub mod distrod_config;
+pub mod http_client;
 pub mod local_image;
+pub mod log_file;
 
 #[cfg(target_os = "linux")]
 pub mod command_alias;
@@ -13,16 +15,28 @@ pub mod distro;
 #[cfg(target_os = "linux")]
 pub mod envfile;
 #[cfg(target_os = "linux")]
+pub mod first_boot;
+#[cfg(target_os = "linux")]
+pub mod group;
+#[cfg(target_os = "linux")]
 pub mod mount_info;
 #[cfg(target_os = "linux")]
 pub mod multifork;
 #[cfg(target_os = "linux")]
+pub mod oci_image;
+#[cfg(target_os = "linux")]
 pub mod passwd;
 #[cfg(target_os = "linux")]
 pub mod procfile;
 #[cfg(target_os = "linux")]
+pub mod rootfs_image;
+#[cfg(target_os = "linux")]
 pub mod systemdunit;
 #[cfg(target_os = "linux")]
+pub mod tree_copy;
+#[cfg(target_os = "linux")]
+pub mod wsl_conf;
+#[cfg(target_os = "linux")]
 pub mod wsl_interop;
 
 #[cfg(target_os = "linux")]
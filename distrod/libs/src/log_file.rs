@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The size of the log file to be rotated at.
+pub const DEFAULT_LOG_FILE_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// The number of the rotated log files to keep, which are named <path>.1, <path>.2, ...
+pub const DEFAULT_LOG_FILE_ROTATIONS: usize = 3;
+
+/// A log file which is rotated when its size exceeds the limit.
+#[derive(Debug)]
+pub struct RotatingLogFile {
+    path: PathBuf,
+    max_size: u64,
+    rotations: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLogFile {
+    pub fn open<P: AsRef<Path>>(path: P, max_size: u64, rotations: usize) -> Result<Self> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}.", &path))?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("Failed to get the metadata of {:?}.", &path))?
+            .len();
+        Ok(RotatingLogFile {
+            path,
+            max_size,
+            rotations,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.rotations == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+        for i in (1..self.rotations).rev() {
+            let from = self.rotated_path(i);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated_path(i + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod test_log_file {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("distrod.log");
+        let mut log_file = RotatingLogFile::open(&path, 10, 2).unwrap();
+        for line in ["line1\n", "line2\n", "line3\n", "line4\n"] {
+            log_file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!("line4\n", std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "line3\n",
+            std::fs::read_to_string(dir.path().join("distrod.log.1")).unwrap()
+        );
+        assert_eq!(
+            "line2\n",
+            std::fs::read_to_string(dir.path().join("distrod.log.2")).unwrap()
+        );
+        assert!(!dir.path().join("distrod.log.3").exists());
+    }
+
+    #[test]
+    fn test_append_to_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("distrod.log");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut log_file = RotatingLogFile::open(&path, 100, 1).unwrap();
+        log_file.write_all(b"new\n").unwrap();
+        assert_eq!("old\nnew\n", std::fs::read_to_string(&path).unwrap());
+    }
+}
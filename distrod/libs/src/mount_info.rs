@@ -1,39 +1,413 @@
 use std::{
+    ffi::OsString,
     fs::File,
     io::{BufRead, BufReader},
-    path::PathBuf,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MountEntry {
     pub source: String,
     pub path: PathBuf,
     pub fstype: String,
-    pub attributes: String,
+    pub options: Vec<String>,
+}
+
+/// A line of /proc/<pid>/mountinfo, which tells which directory of which filesystem is mounted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfoEntry {
+    /// The major:minor of the device of the filesystem.
+    pub device: String,
+    /// The directory in the filesystem which forms the root of the mount.
+    pub root: PathBuf,
+    pub path: PathBuf,
+    /// The per-mount options.
+    pub options: Vec<String>,
 }
 
 pub fn get_mount_entries() -> Result<Vec<MountEntry>> {
-    let mounts = File::open("/proc/mounts").with_context(|| "Failed to open '/proc/mounts'")?;
+    read_mount_entries("/proc/mounts")
+}
+
+pub fn get_mountinfo_entries() -> Result<Vec<MountInfoEntry>> {
+    read_entries("/proc/self/mountinfo", parse_mountinfo_entry)
+}
+
+/// Get the mount entries in the mount namespace of the given process.
+pub fn get_mount_entries_of_process(pid: u32) -> Result<Vec<MountEntry>> {
+    read_mount_entries(format!("/proc/{}/mounts", pid))
+}
+
+fn read_mount_entries<P: AsRef<Path>>(path: P) -> Result<Vec<MountEntry>> {
+    read_entries(path, parse_mount_entry)
+}
+
+fn read_entries<P, T, F>(path: P, parse: F) -> Result<Vec<T>>
+where
+    P: AsRef<Path>,
+    F: Fn(&[u8]) -> Result<T>,
+{
+    let path = path.as_ref();
+    let mounts = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
     let reader = BufReader::new(mounts);
 
     let mut mount_entries = vec![];
-    for (_, line) in reader.lines().enumerate() {
+    for line in reader.split(b'\n') {
         let line = line?;
-        let row: Vec<&str> = line.split(' ').take(4).collect();
-        let (source, path, fstype, attributes) = (
-            row[0].to_owned(),
-            row[1].to_owned(),
-            row[2].to_owned(),
-            row[3].to_owned(),
-        );
-        mount_entries.push(MountEntry {
-            source,
-            path: PathBuf::from(path),
-            fstype,
-            attributes,
-        });
+        mount_entries
+            .push(parse(&line).with_context(|| {
+                format!("Failed to parse '{}'", String::from_utf8_lossy(&line))
+            })?);
     }
 
     Ok(mount_entries)
 }
+
+/// Whether the target is already a mountpoint of the same directory of the same filesystem as
+/// the one at the source, with the same options.
+pub fn is_identically_mounted<P1: AsRef<Path>, P2: AsRef<Path>>(
+    mountinfo_entries: &[MountInfoEntry],
+    source: P1,
+    target: P2,
+) -> bool {
+    // The last entry is the visible one if multiple mounts are stacked on the same path.
+    let find_entry = |path: &Path| mountinfo_entries.iter().rev().find(|e| e.path == path);
+    match (find_entry(source.as_ref()), find_entry(target.as_ref())) {
+        (Some(source), Some(target)) => {
+            source.device == target.device
+                && source.root == target.root
+                && source.options == target.options
+        }
+        _ => false,
+    }
+}
+
+/// Whether something is mounted on the path itself, such as a bind-mounted file.
+pub fn is_mountpoint<P: AsRef<Path>>(mount_entries: &[MountEntry], path: P) -> bool {
+    mount_entries.iter().any(|e| e.path == path.as_ref())
+}
+
+/// Get the mount points under the directory, excluding the directory itself.
+pub fn get_mount_points_under<'a, P: AsRef<Path>>(
+    mount_entries: &'a [MountEntry],
+    dir: P,
+) -> Vec<&'a Path> {
+    let dir = dir.as_ref();
+    mount_entries
+        .iter()
+        .map(|entry| entry.path.as_path())
+        .filter(|path| path.starts_with(dir) && *path != dir)
+        .collect()
+}
+
+/// Find the entry of the filesystem which the path belongs to.
+pub fn find_mount_entry<P: AsRef<Path>>(
+    mount_entries: &[MountEntry],
+    path: P,
+) -> Option<&MountEntry> {
+    // max_by_key returns the last one of the longest mountpoints, which is the visible one
+    // if multiple mounts are stacked on the same path.
+    mount_entries
+        .iter()
+        .filter(|e| path.as_ref().starts_with(&e.path))
+        .max_by_key(|e| e.path.components().count())
+}
+
+/// The layout of the cgroup hierarchy mounted on a system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    /// The controllers are mounted under a tmpfs. This includes the hybrid layout, which also has
+    /// a cgroup2 hierarchy at /sys/fs/cgroup/unified, as WSL does.
+    V1,
+    /// The unified cgroup2 hierarchy is mounted directly.
+    V2,
+}
+
+/// Detect the cgroup layout from the filesystem mounted on the cgroup root, such as /sys/fs/cgroup.
+pub fn detect_cgroup_version<P: AsRef<Path>>(
+    mount_entries: &[MountEntry],
+    cgroup_root: P,
+) -> Option<CgroupVersion> {
+    // The last entry is the visible one if multiple mounts are stacked on the same path.
+    let entry = mount_entries
+        .iter()
+        .rev()
+        .find(|e| e.path == cgroup_root.as_ref())?;
+    match entry.fstype.as_str() {
+        "cgroup2" => Some(CgroupVersion::V2),
+        "tmpfs" => Some(CgroupVersion::V1),
+        _ => None,
+    }
+}
+
+/// Parse a line of /proc/mounts.
+fn parse_mount_entry(line: &[u8]) -> Result<MountEntry> {
+    let row: Vec<&[u8]> = line.split(|c| *c == b' ').take(4).collect();
+    if row.len() < 4 {
+        bail!("A mount entry should have at least 4 fields.");
+    }
+    let to_string = |field: &[u8]| String::from_utf8_lossy(&unescape_octal(field)).to_string();
+    Ok(MountEntry {
+        source: to_string(row[0]),
+        path: PathBuf::from(OsString::from_vec(unescape_octal(row[1]))),
+        fstype: to_string(row[2]),
+        options: to_string(row[3]).split(',').map(str::to_owned).collect(),
+    })
+}
+
+/// Parse a line of /proc/<pid>/mountinfo, such as
+/// `36 35 98:0 /mnt1 /mnt/parent rw,noatime master:1 - ext3 /dev/root rw,errors=continue`.
+fn parse_mountinfo_entry(line: &[u8]) -> Result<MountInfoEntry> {
+    let row: Vec<&[u8]> = line.split(|c| *c == b' ').take(6).collect();
+    if row.len() < 6 {
+        bail!("A mountinfo entry should have at least 6 fields.");
+    }
+    let to_string = |field: &[u8]| String::from_utf8_lossy(&unescape_octal(field)).to_string();
+    Ok(MountInfoEntry {
+        device: to_string(row[2]),
+        root: PathBuf::from(OsString::from_vec(unescape_octal(row[3]))),
+        path: PathBuf::from(OsString::from_vec(unescape_octal(row[4]))),
+        options: to_string(row[5]).split(',').map(str::to_owned).collect(),
+    })
+}
+
+/// Decode the escapes such as `\040` for a space, which the kernel uses in /proc/mounts.
+fn unescape_octal(field: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\' && i + 4 <= field.len() {
+            let digits = &field[i + 1..i + 4];
+            if digits.iter().all(|c| (b'0'..=b'7').contains(c)) {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, c| acc * 8 + u32::from(c - b'0'));
+                if value <= u32::from(u8::MAX) {
+                    result.push(value as u8);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        result.push(field[i]);
+        i += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod test_mount_info {
+    use super::*;
+
+    pub(crate) fn mount_entry(source: &str, path: &str, fstype: &str) -> MountEntry {
+        MountEntry {
+            source: source.to_owned(),
+            path: PathBuf::from(path),
+            fstype: fstype.to_owned(),
+            options: vec!["rw".to_owned()],
+        }
+    }
+
+    #[test]
+    fn test_parse_mount_entry() {
+        let entry =
+            parse_mount_entry(b"proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0").unwrap();
+        assert_eq!(
+            MountEntry {
+                source: "proc".to_owned(),
+                path: PathBuf::from("/proc"),
+                fstype: "proc".to_owned(),
+                options: vec!["rw", "nosuid", "nodev", "noexec", "relatime"]
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+            },
+            entry
+        );
+        assert!(parse_mount_entry(b"proc /proc").is_err());
+    }
+
+    #[test]
+    fn test_is_mountpoint() {
+        let entries: Vec<MountEntry> = [
+            b"/dev/sdc / ext4 rw,relatime 0 0".as_ref(),
+            b"/dev/sdc /etc/resolv.conf ext4 rw,relatime 0 0".as_ref(),
+            b"none /mnt/wsl tmpfs rw,relatime 0 0".as_ref(),
+        ]
+        .iter()
+        .map(|line| parse_mount_entry(line).unwrap())
+        .collect();
+        assert!(is_mountpoint(&entries, "/etc/resolv.conf"));
+        assert!(is_mountpoint(&entries, "/mnt/wsl"));
+        assert!(!is_mountpoint(&entries, "/etc/hosts"));
+        assert!(!is_mountpoint(&entries, "/mnt/wsl/resolv.conf"));
+    }
+
+    #[test]
+    fn test_parse_mount_entry_with_escapes() {
+        let entry = parse_mount_entry(
+            b"C:\\134 /mnt/c/path\\040with\\040space 9p rw,noatime,aname=drvfs;path=C:\\134;uid=1000 0 0",
+        )
+        .unwrap();
+        assert_eq!("C:\\", entry.source);
+        assert_eq!(PathBuf::from("/mnt/c/path with space"), entry.path);
+        assert_eq!("9p", entry.fstype);
+        assert_eq!(
+            vec!["rw", "noatime", "aname=drvfs;path=C:\\;uid=1000"],
+            entry.options
+        );
+
+        let entry =
+            parse_mount_entry(b"D:\\134 /mnt/tab\\011new\\012line\\134back fuse.my\\040fs rw 0 0")
+                .unwrap();
+        assert_eq!(PathBuf::from("/mnt/tab\tnew\nline\\back"), entry.path);
+        assert_eq!("fuse.my fs", entry.fstype);
+
+        // Invalid escapes are left as they are.
+        let entry = parse_mount_entry(b"none /tmp/back\\slash\\9 tmpfs rw 0 0").unwrap();
+        assert_eq!(PathBuf::from("/tmp/back\\slash\\9"), entry.path);
+    }
+
+    #[test]
+    fn test_get_mount_points_under() {
+        let mount_entries = vec![
+            mount_entry("/dev/sdb", "/", "ext4"),
+            mount_entry("none", "/var/lib/distrod/ubuntu", "tmpfs"),
+            mount_entry("none", "/var/lib/distrod/ubuntu/home", "tmpfs"),
+            mount_entry("none", "/var/lib/distrod/ubuntu2", "tmpfs"),
+        ];
+        assert_eq!(
+            vec![Path::new("/var/lib/distrod/ubuntu/home")],
+            get_mount_points_under(&mount_entries, "/var/lib/distrod/ubuntu")
+        );
+        assert!(get_mount_points_under(&mount_entries, "/var/lib/distrod/ubuntu2").is_empty());
+    }
+
+    #[test]
+    fn test_find_mount_entry() {
+        let entries: Vec<_> = [
+            "/dev/sdb / ext4 rw 0 0",
+            "C:\\134 /mnt/c 9p rw,noatime 0 0",
+            "tmpfs /mnt/c/tmp tmpfs rw 0 0",
+            "D:\\134 /mnt/c 9p rw,noatime 0 0",
+        ]
+        .iter()
+        .map(|line| parse_mount_entry(line.as_bytes()).unwrap())
+        .collect();
+        assert_eq!(
+            "ext4",
+            find_mount_entry(&entries, "/home/user").unwrap().fstype
+        );
+        assert_eq!(
+            "D:\\",
+            find_mount_entry(&entries, "/mnt/c/distro").unwrap().source
+        );
+        assert_eq!(
+            "tmpfs",
+            find_mount_entry(&entries, "/mnt/c/tmp/a").unwrap().fstype
+        );
+        assert_eq!(
+            "ext4",
+            find_mount_entry(&entries, "/mnt/cc").unwrap().fstype
+        );
+    }
+
+    #[test]
+    fn test_parse_mountinfo_entry() {
+        let entry = parse_mountinfo_entry(
+            b"36 35 98:0 /mnt1\\040a /mnt/parent rw,noatime master:1 - ext3 /dev/root rw",
+        )
+        .unwrap();
+        assert_eq!(
+            MountInfoEntry {
+                device: "98:0".to_owned(),
+                root: PathBuf::from("/mnt1 a"),
+                path: PathBuf::from("/mnt/parent"),
+                options: vec!["rw".to_owned(), "noatime".to_owned()],
+            },
+            entry
+        );
+        assert!(parse_mountinfo_entry(b"36 35 98:0 / /mnt").is_err());
+    }
+
+    #[test]
+    fn test_is_identically_mounted() {
+        let entries: Vec<_> = [
+            "60 1 0:50 / /mnt/c rw,noatime - 9p C:\\134 rw",
+            "61 1 0:51 / /mnt/d rw,noatime - 9p D:\\134 rw",
+            "62 1 0:50 / /mnt/distrod/mnt/c rw,noatime - 9p C:\\134 rw",
+            "63 1 0:50 / /mnt/distrod/mnt/ro ro,noatime - 9p C:\\134 rw",
+            // A subdirectory of the same filesystem.
+            "64 1 0:50 /Users /mnt/distrod/mnt/users rw,noatime - 9p C:\\134 rw",
+        ]
+        .iter()
+        .map(|line| parse_mountinfo_entry(line.as_bytes()).unwrap())
+        .collect();
+        assert!(is_identically_mounted(
+            &entries,
+            "/mnt/c",
+            "/mnt/distrod/mnt/c"
+        ));
+        assert!(!is_identically_mounted(
+            &entries,
+            "/mnt/d",
+            "/mnt/distrod/mnt/c"
+        ));
+        assert!(!is_identically_mounted(
+            &entries,
+            "/mnt/c",
+            "/mnt/distrod/mnt/ro"
+        ));
+        assert!(!is_identically_mounted(
+            &entries,
+            "/mnt/c",
+            "/mnt/distrod/mnt/d"
+        ));
+        assert!(!is_identically_mounted(
+            &entries,
+            "/mnt/c",
+            "/mnt/distrod/mnt/users"
+        ));
+    }
+
+    #[test]
+    fn test_detect_cgroup_version() {
+        let parse = |lines: &[&str]| -> Vec<MountEntry> {
+            lines
+                .iter()
+                .map(|line| parse_mount_entry(line.as_bytes()).unwrap())
+                .collect()
+        };
+        let unified = parse(&[
+            "sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0",
+            "cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec,relatime,nsdelegate 0 0",
+        ]);
+        assert_eq!(
+            Some(CgroupVersion::V2),
+            detect_cgroup_version(&unified, "/sys/fs/cgroup")
+        );
+        let hybrid = parse(&[
+            "sysfs /sys sysfs rw,nosuid,nodev,noexec,noatime 0 0",
+            "tmpfs /sys/fs/cgroup tmpfs rw,nosuid,nodev,noexec,relatime,mode=755 0 0",
+            "cgroup2 /sys/fs/cgroup/unified cgroup2 rw,nosuid,nodev,noexec,relatime 0 0",
+            "cgroup /sys/fs/cgroup/cpu cgroup rw,nosuid,nodev,noexec,relatime,cpu 0 0",
+        ]);
+        assert_eq!(
+            Some(CgroupVersion::V1),
+            detect_cgroup_version(&hybrid, "/sys/fs/cgroup")
+        );
+        let none = parse(&["sysfs /sys sysfs rw,nosuid,nodev,noexec,noatime 0 0"]);
+        assert_eq!(None, detect_cgroup_version(&none, "/sys/fs/cgroup"));
+        let stacked = parse(&[
+            "tmpfs /sys/fs/cgroup tmpfs rw,nosuid,nodev,noexec,relatime,mode=755 0 0",
+            "cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec,relatime 0 0",
+        ]);
+        assert_eq!(
+            Some(CgroupVersion::V2),
+            detect_cgroup_version(&stacked, "/sys/fs/cgroup")
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use nix::fcntl::OFlag;
 use nix::libc::c_int;
 use nix::sys::signal;
@@ -7,12 +7,14 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::ops::Deref;
 use std::os::unix::io::FromRawFd;
-use std::os::unix::prelude::CommandExt;
+use std::os::unix::prelude::{CommandExt, ExitStatusExt};
 use std::process::Command;
 
 pub struct CommandByMultiFork<'a> {
-    command: Command,
+    /// None if a function runs in place of a command.
+    command: Option<Command>,
     pre_second_fork: Option<Box<dyn FnMut() -> Result<()> + 'a>>,
+    in_place_of_command: Option<Box<dyn FnOnce() -> Result<()> + 'a>>,
     proxy_process: Option<ProxyProcess>,
     does_triple_fork: bool,
 }
@@ -20,8 +22,24 @@ pub struct CommandByMultiFork<'a> {
 impl<'a> CommandByMultiFork<'a> {
     pub fn new(command: Command) -> CommandByMultiFork<'a> {
         CommandByMultiFork {
-            command,
+            command: Some(command),
             pre_second_fork: None,
+            in_place_of_command: None,
+            proxy_process: None,
+            does_triple_fork: false,
+        }
+    }
+
+    /// Run the function in the last forked process, instead of spawning a command.
+    /// The process exits when the function returns.
+    pub fn new_with_function<F>(f: F) -> CommandByMultiFork<'a>
+    where
+        F: FnOnce() -> Result<()> + 'a,
+    {
+        CommandByMultiFork {
+            command: None,
+            pre_second_fork: None,
+            in_place_of_command: Some(Box::new(f)),
             proxy_process: None,
             does_triple_fork: false,
         }
@@ -37,7 +55,10 @@ impl<'a> CommandByMultiFork<'a> {
     where
         F: FnMut() -> std::io::Result<()> + Send + Sync + 'static,
     {
-        self.command.pre_exec(f);
+        self.command
+            .as_mut()
+            .expect("pre_exec is only for a command.")
+            .pre_exec(f);
         self
     }
 
@@ -72,17 +93,33 @@ impl<'a> CommandByMultiFork<'a> {
                     log::debug!("The parent of the second of three forks exits.");
                     std::process::exit(0);
                 }
+                if let Some(f) = self.in_place_of_command.take() {
+                    if unsafe { nix::unistd::fork().with_context(|| "The last fork failed.")? }
+                        .is_child()
+                    {
+                        if let Err(err) = f() {
+                            log::error!("{:?}", err);
+                            std::process::exit(1);
+                        }
+                        std::process::exit(0);
+                    }
+                    return Ok(());
+                }
                 log::debug!("Spawning the command or the waiter.");
+                let command = self
+                    .command
+                    .as_mut()
+                    .expect("either a command or a function should be given.");
                 match self.proxy_process {
                     None => {
-                        self.command
+                        command
                             .spawn()
                             .with_context(|| "Failed to spawn the command.")?;
                     }
                     Some(proxy_process) => {
                         log::debug!("Spawning the waiter.");
                         proxy_process
-                            .spawn(&mut self.command)
+                            .spawn(command)
                             .with_context(|| "Failed to spawn the command.")?;
                     }
                 };
@@ -102,38 +139,101 @@ impl<'a> Deref for CommandByMultiFork<'a> {
     type Target = Command;
 
     fn deref(&self) -> &Self::Target {
-        &self.command
+        self.command
+            .as_ref()
+            .expect("a function runs in place of the command.")
     }
 }
 
 impl<'a> From<Command> for CommandByMultiFork<'a> {
     fn from(command: Command) -> Self {
-        CommandByMultiFork {
-            command,
-            pre_second_fork: None,
-            proxy_process: None,
-            does_triple_fork: false,
+        CommandByMultiFork::new(command)
+    }
+}
+
+/// How the command waited by Waiter has terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    Exited(i32),
+    Signaled(i32),
+}
+
+impl WaitStatus {
+    /// The exit code which shells report for the status, i.e., 128 + the signal number for
+    /// the commands killed by a signal.
+    pub fn to_exit_code(self) -> i32 {
+        match self {
+            WaitStatus::Exited(code) => code,
+            WaitStatus::Signaled(signal) => 128 + signal,
         }
     }
+
+    fn to_frame(self) -> [u8; WAIT_STATUS_FRAME_LEN] {
+        let (kind, value) = match self {
+            WaitStatus::Exited(code) => (WAIT_STATUS_EXITED, code),
+            WaitStatus::Signaled(signal) => (WAIT_STATUS_SIGNALED, signal),
+        };
+        let mut frame = [WAIT_STATUS_FRAME_MAGIC, kind, 0, 0, 0, 0];
+        frame[2..].copy_from_slice(&value.to_be_bytes());
+        frame
+    }
+
+    fn from_frame(frame: &[u8; WAIT_STATUS_FRAME_LEN]) -> Option<Self> {
+        let mut value = [0; 4];
+        value.copy_from_slice(&frame[2..]);
+        let value = i32::from_be_bytes(value);
+        match (frame[0], frame[1]) {
+            (WAIT_STATUS_FRAME_MAGIC, WAIT_STATUS_EXITED) => Some(WaitStatus::Exited(value)),
+            (WAIT_STATUS_FRAME_MAGIC, WAIT_STATUS_SIGNALED) => Some(WaitStatus::Signaled(value)),
+            _ => None,
+        }
+    }
+
+    /// Read the status sent by the proxy process. The proxy processes of the older versions
+    /// send only a byte of the exit code and close the pipe, so it's accepted as well.
+    fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut frame = [0; WAIT_STATUS_FRAME_LEN];
+        reader.read_exact(&mut frame[..1])?;
+        if frame[0] == WAIT_STATUS_FRAME_MAGIC {
+            match reader.read_exact(&mut frame[1..]) {
+                Ok(_) => {
+                    return WaitStatus::from_frame(&frame).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Invalid wait status frame: {:?}", frame),
+                        )
+                    })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(WaitStatus::Exited(frame[0] as i32))
+    }
 }
 
+/// A frame of a wait status is the magic byte, the kind byte, and the big endian i32 value.
+const WAIT_STATUS_FRAME_LEN: usize = 6;
+const WAIT_STATUS_FRAME_MAGIC: u8 = 0xff;
+const WAIT_STATUS_EXITED: u8 = b'E';
+const WAIT_STATUS_SIGNALED: u8 = b'S';
+
 pub struct Waiter {
     pipe_for_exitcode: File,
 }
 
 impl Waiter {
-    pub fn wait(&mut self) -> u32 {
-        let mut exit_code = vec![137]; // The exit code for SIGKILL
-        let res = self
-            .pipe_for_exitcode
-            .read_exact(&mut exit_code)
-            .with_context(|| "Failed to read the exit code from the pipe.");
-        if res.is_err() {
-            log::debug!(
-                "The pipe for wait has been closed. Possibly the proxy process has been killed by SIGKILL."
-            );
+    pub fn wait(&mut self) -> WaitStatus {
+        match WaitStatus::read_from(&mut self.pipe_for_exitcode) {
+            Ok(status) => status,
+            Err(e) => {
+                log::debug!(
+                    "The pipe for wait has been closed. Possibly the proxy process has been killed by SIGKILL. {}",
+                    e
+                );
+                WaitStatus::Signaled(signal::Signal::SIGKILL as i32)
+            }
         }
-        exit_code[0] as u32
     }
 }
 
@@ -168,12 +268,12 @@ impl ProxyProcess {
             let status = child
                 .wait()
                 .with_context(|| "Failed to wait wthe command.")?;
-            let exit_code = status
-                .code()
-                .ok_or_else(|| anyhow!("status.code() is None unexpectedly."))?
-                as u8;
-            let exit_code = vec![exit_code];
-            if let Err(e) = self.pipe_for_exitcode.write_all(&exit_code) {
+            let status = match (status.code(), status.signal()) {
+                (Some(code), _) => WaitStatus::Exited(code),
+                (None, Some(signal)) => WaitStatus::Signaled(signal),
+                (None, None) => bail!("The command has neither exited nor been signaled."),
+            };
+            if let Err(e) = self.pipe_for_exitcode.write_all(&status.to_frame()) {
                 log::debug!("Failed to write the exit code to the pipe. {}", e);
             }
             std::process::exit(0);
@@ -206,8 +306,46 @@ mod tests {
         let mut doublefork = CommandByMultiFork::new(command);
         let mut waiter = doublefork.insert_waiter_proxy().unwrap();
         let _ = doublefork.spawn().unwrap();
-        let exit_code = waiter.wait();
-        assert_eq!(42, exit_code);
+        let status = waiter.wait();
+        assert_eq!(WaitStatus::Exited(42), status);
+        assert_eq!(42, status.to_exit_code());
+    }
+
+    #[test]
+    fn test_insert_proxy_with_signaled_command() {
+        let mut command = Command::new("/bin/bash");
+        command.args(["-c", "kill -SIGTERM $$; sleep 10"]);
+        let mut doublefork = CommandByMultiFork::new(command);
+        let mut waiter = doublefork.insert_waiter_proxy().unwrap();
+        doublefork.spawn().unwrap();
+        let status = waiter.wait();
+        assert_eq!(WaitStatus::Signaled(signal::Signal::SIGTERM as i32), status);
+        assert_eq!(143, status.to_exit_code());
+    }
+
+    #[test]
+    fn test_read_wait_status() {
+        for status in &[
+            WaitStatus::Exited(0),
+            WaitStatus::Exited(300),
+            WaitStatus::Signaled(9),
+        ] {
+            let frame = status.to_frame();
+            assert_eq!(*status, WaitStatus::read_from(&mut &frame[..]).unwrap());
+        }
+
+        // A byte of the exit code sent by the older proxy processes.
+        assert_eq!(
+            WaitStatus::Exited(3),
+            WaitStatus::read_from(&mut &[3u8][..]).unwrap()
+        );
+        assert_eq!(
+            WaitStatus::Exited(255),
+            WaitStatus::read_from(&mut &[255u8][..]).unwrap()
+        );
+
+        assert!(WaitStatus::read_from(&mut &[][..]).is_err());
+        assert!(WaitStatus::read_from(&mut &[0xff, b'X', 0, 0, 0, 1][..]).is_err());
     }
 
     #[test]
@@ -220,7 +358,7 @@ mod tests {
         let mut doublefork = CommandByMultiFork::new(command);
         let mut waiter = doublefork.insert_waiter_proxy().unwrap();
         let _ = doublefork.spawn().unwrap();
-        let exit_code = waiter.wait();
-        assert_eq!(42, exit_code);
+        let status = waiter.wait();
+        assert_eq!(WaitStatus::Exited(42), status);
     }
 }
@@ -0,0 +1,1010 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use nix::unistd::{FchownatFlags, Gid, Uid};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::distro_image::verify_sha256;
+use crate::http_client;
+
+const DOCKER_HUB_DOMAIN: &str = "docker.io";
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+const DEFAULT_TAG: &str = "latest";
+
+const MANIFEST_MEDIA_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// The same limit as Linux's MAXSYMLINKS
+const MAX_SYMLINKS: usize = 40;
+
+/// A reference to an image in an OCI registry, such as "ubuntu:22.04" or
+/// "ghcr.io/owner/image@sha256:...".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciImageReference {
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a digest.
+    pub reference: String,
+}
+
+impl OciImageReference {
+    /// Parse an image reference in the same way as Docker. Images without a registry are pulled
+    /// from Docker Hub, and those without a tag are pulled by the "latest" tag.
+    pub fn parse(image: &str) -> Result<OciImageReference> {
+        let invalid = || anyhow!("'{}' is not a valid image reference.", image);
+        let (name, digest) = match image.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (image, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (name, None),
+        };
+        let (domain, repository) = match name.split_once('/') {
+            Some((domain, repository))
+                if domain.contains('.') || domain.contains(':') || domain == "localhost" =>
+            {
+                (domain, repository.to_owned())
+            }
+            _ => (DOCKER_HUB_DOMAIN, name.to_owned()),
+        };
+        let is_valid_name = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c))
+        };
+        if !is_valid_name(&repository) || repository.split('/').any(str::is_empty) {
+            return Err(invalid());
+        }
+        let reference = match (digest, tag) {
+            (Some(digest), _) if digest.starts_with("sha256:") => digest.to_owned(),
+            (Some(_), _) => return Err(invalid()),
+            (None, Some(tag)) if !tag.is_empty() => tag.to_owned(),
+            (None, Some(_)) => return Err(invalid()),
+            (None, None) => DEFAULT_TAG.to_owned(),
+        };
+        let (registry, repository) = if domain == DOCKER_HUB_DOMAIN {
+            let repository = if repository.contains('/') {
+                repository
+            } else {
+                format!("library/{}", repository)
+            };
+            (DOCKER_HUB_REGISTRY.to_owned(), repository)
+        } else {
+            (domain.to_owned(), repository)
+        };
+        Ok(OciImageReference {
+            registry,
+            repository,
+            reference,
+        })
+    }
+
+    /// Get the name of the distro created from the image, such as "oci-ubuntu-22.04".
+    pub fn get_distro_name(&self) -> String {
+        let name = self
+            .repository
+            .rsplit('/')
+            .next()
+            .unwrap_or(&self.repository);
+        let version = match self.reference.strip_prefix("sha256:") {
+            Some(digest) => &digest[..std::cmp::min(12, digest.len())],
+            None => &self.reference,
+        };
+        format!("oci-{}-{}", name, version)
+    }
+}
+
+impl std::fmt::Display for OciImageReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.reference.starts_with("sha256:") {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, separator, self.reference
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    manifests: Option<Vec<Descriptor>>,
+    layers: Option<Vec<Descriptor>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    digest: String,
+    size: u64,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+    variant: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Pull the image from the registry and return its layers from the bottom one.
+/// The image for the architecture, which is amd64, arm64, or armhf, is chosen if the image
+/// supports multiple platforms. Each layer is downloaded to a temporary file and verified by
+/// its digest.
+pub async fn pull_image_layers(reference: &OciImageReference, arch: &str) -> Result<Vec<File>> {
+    let mut registry = RegistryClient::new(reference)?;
+    let manifest = registry
+        .get(&format!("manifests/{}", reference.reference), true)
+        .await?;
+    if reference.reference.starts_with("sha256:") {
+        verify_digest(&manifest[..], &reference.reference)
+            .with_context(|| format!("Failed to verify the manifest of {}.", reference))?;
+    }
+    let mut manifest: Manifest = serde_json::from_slice(&manifest)
+        .with_context(|| format!("Failed to parse the manifest of {}.", reference))?;
+    if let Some(ref manifests) = manifest.manifests {
+        let digest = choose_manifest_for_arch(manifests, arch)
+            .with_context(|| format!("{} is not available for {}.", reference, arch))?
+            .digest
+            .clone();
+        let platform_manifest = registry.get(&format!("manifests/{}", digest), true).await?;
+        verify_digest(&platform_manifest[..], &digest)
+            .with_context(|| format!("Failed to verify the manifest of {}.", reference))?;
+        manifest = serde_json::from_slice(&platform_manifest)
+            .with_context(|| format!("Failed to parse the manifest of {}.", reference))?;
+    }
+    let layers = manifest.layers.ok_or_else(|| {
+        anyhow!(
+            "The manifest of {} has an unsupported format. Only OCI and Docker V2 schema 2 images are supported.",
+            reference
+        )
+    })?;
+
+    let mut blobs = vec![];
+    for (i, layer) in layers.iter().enumerate() {
+        log::info!(
+            "Downloading layer {}/{} ({} bytes)...",
+            i + 1,
+            layers.len(),
+            layer.size
+        );
+        let mut blob =
+            tempfile::tempfile().with_context(|| "Failed to create a temporary file.")?;
+        registry
+            .download(&format!("blobs/{}", layer.digest), &mut blob)
+            .await?;
+        let blob_size = blob.metadata()?.len();
+        if blob_size != layer.size {
+            bail!(
+                "The layer {} is {} bytes while it should be {} bytes. Re-run the command to retry.",
+                layer.digest,
+                blob_size,
+                layer.size
+            );
+        }
+        blob.seek(SeekFrom::Start(0))?;
+        verify_digest(BufReader::new(&blob), &layer.digest)
+            .with_context(|| format!("Failed to verify the layer {}.", layer.digest))?;
+        blobs.push(blob);
+    }
+    Ok(blobs)
+}
+
+/// Check the content against a digest such as "sha256:<hex>". Only SHA-256 is supported, which
+/// the registries use.
+fn verify_digest<R: Read>(content: R, digest: &str) -> Result<()> {
+    let hex = digest
+        .strip_prefix("sha256:")
+        .ok_or_else(|| anyhow!("The digest '{}' is not supported.", digest))?;
+    verify_sha256(content, hex)
+}
+
+fn choose_manifest_for_arch<'a>(manifests: &'a [Descriptor], arch: &str) -> Result<&'a Descriptor> {
+    let (architecture, variant) = match arch {
+        "armhf" => ("arm", Some("v7")),
+        arch => (arch, None),
+    };
+    manifests
+        .iter()
+        .find(|manifest| {
+            manifest.platform.as_ref().map_or(false, |platform| {
+                platform.os == "linux"
+                    && platform.architecture == architecture
+                    && (variant.is_none() || platform.variant.as_deref() == variant)
+            })
+        })
+        .ok_or_else(|| anyhow!("No image for linux/{} is found.", arch))
+}
+
+struct RegistryClient<'a> {
+    client: reqwest::Client,
+    reference: &'a OciImageReference,
+    token: Option<String>,
+}
+
+impl<'a> RegistryClient<'a> {
+    fn new(reference: &'a OciImageReference) -> Result<RegistryClient<'a>> {
+        Ok(RegistryClient {
+            client: http_client::build_http_client()?,
+            reference,
+            token: None,
+        })
+    }
+
+    async fn get(&mut self, path: &str, is_manifest: bool) -> Result<Vec<u8>> {
+        let url = self.get_url(path);
+        let bytes = self
+            .fetch(path, is_manifest)
+            .await?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to download {}.", url))?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Download the content at the path into the file without holding it in memory.
+    async fn download(&mut self, path: &str, out: &mut File) -> Result<()> {
+        let url = self.get_url(path);
+        let mut response = self.fetch(path, false).await?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed to download {}.", url))?
+        {
+            out.write_all(&chunk)
+                .with_context(|| format!("Failed to save {}.", url))?;
+        }
+        Ok(())
+    }
+
+    fn get_url(&self, path: &str) -> String {
+        format!(
+            "https://{}/v2/{}/{}",
+            self.reference.registry, self.reference.repository, path
+        )
+    }
+
+    /// GET the path under the repository. The anonymous token is requested if the registry
+    /// requires it.
+    async fn fetch(&mut self, path: &str, is_manifest: bool) -> Result<reqwest::Response> {
+        let url = self.get_url(path);
+        let mut response = self.send(&url, is_manifest).await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED && self.token.is_none() {
+            let challenge = response
+                .headers()
+                .get(reqwest::header::WWW_AUTHENTICATE)
+                .and_then(|challenge| challenge.to_str().ok())
+                .ok_or_else(|| anyhow!("{} requires unsupported authentication.", url))?
+                .to_owned();
+            self.token = Some(self.request_token(&challenge).await?);
+            response = self.send(&url, is_manifest).await?;
+        }
+        response
+            .error_for_status()
+            .with_context(|| format!("Failed to fetch {}.", url))
+    }
+
+    async fn send(&self, url: &str, is_manifest: bool) -> Result<reqwest::Response> {
+        let mut request = self.client.get(url);
+        if is_manifest {
+            request = request.header(reqwest::header::ACCEPT, MANIFEST_MEDIA_TYPES.join(", "));
+        }
+        if let Some(ref token) = self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch {}.", url))
+    }
+
+    async fn request_token(&self, challenge: &str) -> Result<String> {
+        let params = parse_bearer_challenge(challenge).ok_or_else(|| {
+            anyhow!(
+                "The registry {} requires unsupported authentication: {}",
+                self.reference.registry,
+                challenge
+            )
+        })?;
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| anyhow!("No realm is given by the registry: {}", challenge))?;
+        let scope = format!("repository:{}:pull", self.reference.repository);
+        let mut query = vec![("scope", scope.as_str())];
+        if let Some(service) = params.get("service") {
+            query.push(("service", service));
+        }
+        let body = self
+            .client
+            .get(realm)
+            .query(&query)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .with_context(|| format!("Failed to get the token to pull {}.", self.reference))?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to get the token to pull {}.", self.reference))?;
+        let response: TokenResponse = serde_json::from_slice(&body)
+            .with_context(|| format!("Failed to parse the token from {}.", realm))?;
+        response
+            .token
+            .or(response.access_token)
+            .ok_or_else(|| anyhow!("No token is returned from {}.", realm))
+    }
+}
+
+/// Parse the parameters of a WWW-Authenticate header of the Bearer scheme, such as
+/// `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`.
+fn parse_bearer_challenge(challenge: &str) -> Option<HashMap<String, String>> {
+    let (scheme, mut rest) = challenge.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let mut params = HashMap::new();
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if rest.is_empty() {
+            return Some(params);
+        }
+        let (key, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value.to_owned());
+        rest = next;
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveManifest {
+    layers: Vec<String>,
+}
+
+/// Read the layers of the image in a tarball made by `docker save`, from the bottom one.
+/// Each layer is copied to a temporary file, and verified if it's named by its digest.
+pub fn read_docker_archive_layers(path: &Path) -> Result<Vec<File>> {
+    let open = || -> Result<tar::Archive<File>> {
+        Ok(tar::Archive::new(File::open(path).with_context(|| {
+            format!("Failed to open the Docker archive {:?}.", path)
+        })?))
+    };
+    let read_error = || format!("Failed to read the Docker archive {:?}.", path);
+
+    // Older versions of Docker save the same layers as symlinks to the first one.
+    let mut manifests: Option<Vec<DockerArchiveManifest>> = None;
+    let mut links = HashMap::new();
+    for entry in open()?.entries().with_context(read_error)? {
+        let entry = entry.with_context(read_error)?;
+        let name = normalize_archive_path(&entry.path().with_context(read_error)?);
+        if entry.header().entry_type().is_symlink() {
+            if let Some(target) = entry.link_name().with_context(read_error)? {
+                let target = Path::new(&name)
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(target);
+                links.insert(name, normalize_archive_path(&target));
+            }
+        } else if name == "manifest.json" {
+            manifests = Some(
+                serde_json::from_reader(entry)
+                    .with_context(|| format!("Failed to parse manifest.json in {:?}.", path))?,
+            );
+        }
+    }
+    let mut manifests = manifests.ok_or_else(|| {
+        anyhow!(
+            "{:?} is not a Docker archive. manifest.json is not found.",
+            path
+        )
+    })?;
+    if manifests.len() != 1 {
+        bail!(
+            "The Docker archive {:?} should contain exactly one image, but it has {}.",
+            path,
+            manifests.len()
+        );
+    }
+    let layer_names = manifests
+        .remove(0)
+        .layers
+        .iter()
+        .map(|name| {
+            let mut name = normalize_archive_path(Path::new(name));
+            for _ in 0..links.len() {
+                match links.get(&name) {
+                    Some(target) => name = target.clone(),
+                    None => break,
+                }
+            }
+            name
+        })
+        .collect::<Vec<_>>();
+
+    let mut files = HashMap::new();
+    for entry in open()?.entries().with_context(read_error)? {
+        let mut entry = entry.with_context(read_error)?;
+        let name = normalize_archive_path(&entry.path().with_context(read_error)?);
+        if entry.header().entry_type().is_file() && layer_names.contains(&name) {
+            let mut file =
+                tempfile::tempfile().with_context(|| "Failed to create a temporary file.")?;
+            std::io::copy(&mut entry, &mut file).with_context(read_error)?;
+            // The newer versions of Docker name the layers by their digests.
+            if let Some(hex) = name.strip_prefix("blobs/sha256/") {
+                file.seek(SeekFrom::Start(0))?;
+                verify_sha256(BufReader::new(&file), hex)
+                    .with_context(|| format!("Failed to verify the layer {}.", name))?;
+            }
+            files.insert(name, file);
+        }
+    }
+    // The same layer may be used more than once, and the clones share it. apply_layer seeks
+    // to the start of the layer every time.
+    layer_names
+        .iter()
+        .map(|name| {
+            files
+                .get(name)
+                .ok_or_else(|| anyhow!("The layer {} is not found in {:?}.", name, path))?
+                .try_clone()
+                .with_context(|| format!("Failed to read the layer {}.", name))
+        })
+        .collect()
+}
+
+fn normalize_archive_path(path: &Path) -> String {
+    let mut normalized: Vec<_> = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name.to_string_lossy()),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => {}
+        }
+    }
+    normalized.join("/")
+}
+
+/// Unpack the layers from the bottom one into the rootfs, applying the whiteout files of each
+/// layer to the lower ones.
+pub fn flatten_layers(layers: &[File], rootfs: &Path) -> Result<()> {
+    for (i, layer) in layers.iter().enumerate() {
+        apply_layer(layer, rootfs)
+            .with_context(|| format!("Failed to apply the layer {}/{}.", i + 1, layers.len()))?;
+    }
+    Ok(())
+}
+
+/// Apply a layer, which is a tarball compressed by gzip or not, to the rootfs.
+/// The whiteouts are applied before the files are unpacked because they hide only the files
+/// of the lower layers, even if they come after the files of the same layer in the tarball.
+pub fn apply_layer(layer: &File, rootfs: &Path) -> Result<()> {
+    let rootfs = rootfs
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", rootfs))?;
+
+    let mut archive = open_layer(layer)?;
+    for entry in archive
+        .entries()
+        .with_context(|| "Failed to read the layer.")?
+    {
+        let entry = entry.with_context(|| "Failed to read the layer.")?;
+        let path = entry.path()?.into_owned();
+        match parse_whiteout(&path) {
+            Some(Whiteout::File(path)) => {
+                if let Some(path) = get_path_in_rootfs(&rootfs, &path, false)? {
+                    remove_path(&path)?;
+                }
+            }
+            Some(Whiteout::Opaque(dir)) => {
+                let dir = if dir.as_os_str().is_empty() {
+                    Some(rootfs.clone())
+                } else {
+                    get_path_in_rootfs(&rootfs, &dir, false)?
+                };
+                // Don't follow the symlink, which may point to the outside of the rootfs.
+                let is_dir =
+                    |dir: &PathBuf| fs::symlink_metadata(dir).map_or(false, |m| m.is_dir());
+                if let Some(dir) = dir.filter(is_dir) {
+                    for child in
+                        fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}.", &dir))?
+                    {
+                        remove_path(&child?.path())?;
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    let mut archive = open_layer(layer)?;
+    archive.set_preserve_permissions(true);
+    archive.set_unpack_xattrs(true);
+    for entry in archive
+        .entries()
+        .with_context(|| "Failed to read the layer.")?
+    {
+        let mut entry = entry.with_context(|| "Failed to read the layer.")?;
+        let path = entry.path()?.into_owned();
+        if parse_whiteout(&path).is_some() {
+            continue;
+        }
+        let entry_type = entry.header().entry_type();
+        // The path is resolved here rather than by unpack_in, which follows the symlinks in the
+        // rootfs as the host sees them.
+        let dst = match get_path_in_rootfs(&rootfs, &path, true)? {
+            Some(dst) => dst,
+            None => continue,
+        };
+        remove_conflicting_path(&dst, entry_type)?;
+        if entry_type.is_hard_link() {
+            let target = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("The hard link {:?} has no target.", &path))?
+                .into_owned();
+            let target = get_path_in_rootfs(&rootfs, &target, false)?
+                .ok_or_else(|| anyhow!("The target of the hard link {:?} is not found.", &path))?;
+            fs::hard_link(&target, &dst)
+                .with_context(|| format!("Failed to unpack {:?}.", &path))?;
+            continue;
+        }
+        entry
+            .unpack(&dst)
+            .with_context(|| format!("Failed to unpack {:?}.", &path))?;
+        set_owner(&dst, entry.header())?;
+    }
+    Ok(())
+}
+
+fn open_layer(mut layer: &File) -> Result<tar::Archive<Box<dyn Read + '_>>> {
+    const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    let mut magic = vec![];
+    layer.seek(SeekFrom::Start(0))?;
+    layer
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)
+        .with_context(|| "Failed to read the layer.")?;
+    layer.seek(SeekFrom::Start(0))?;
+    let reader = if magic.starts_with(GZIP_MAGIC) {
+        Box::new(GzDecoder::new(BufReader::new(layer))) as Box<dyn Read>
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        bail!("Layers compressed by zstd are not supported.");
+    } else {
+        Box::new(BufReader::new(layer)) as Box<dyn Read>
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Whiteout {
+    /// Remove the file or the directory of the lower layers.
+    File(PathBuf),
+    /// Remove all the children of the directory of the lower layers.
+    Opaque(PathBuf),
+}
+
+fn parse_whiteout(path: &Path) -> Option<Whiteout> {
+    let name = path.file_name()?.to_str()?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    if name == OPAQUE_WHITEOUT {
+        return Some(Whiteout::Opaque(dir.to_owned()));
+    }
+    name.strip_prefix(WHITEOUT_PREFIX)
+        .map(|name| Whiteout::File(dir.join(name)))
+}
+
+/// Get the path of the file in the layer on the host. The symlinks in the parent directories are
+/// followed as if the rootfs is the root directory, so the path never goes out of the rootfs.
+/// None is returned if a parent directory doesn't exist, unless `create_parents` is true, or if
+/// the path is the rootfs itself.
+fn get_path_in_rootfs(rootfs: &Path, path: &Path, create_parents: bool) -> Result<Option<PathBuf>> {
+    let mut names = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_owned()),
+            Component::ParentDir => bail!("{:?} in the layer is out of the rootfs.", path),
+            _ => {}
+        }
+    }
+    let name = match names.pop() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let parent_dir = OsString::from("..");
+    let mut pending: VecDeque<OsString> = names.into();
+    let mut resolved = rootfs.to_owned();
+    let mut n_symlinks = 0;
+    while let Some(name) = pending.pop_front() {
+        if name == parent_dir {
+            if resolved != rootfs {
+                resolved.pop();
+            }
+            continue;
+        }
+        let host_path = resolved.join(&name);
+        let metadata = match fs::symlink_metadata(&host_path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && create_parents => {
+                fs::create_dir(&host_path)
+                    .with_context(|| format!("Failed to create {:?}.", &host_path))?;
+                resolved = host_path;
+                continue;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to stat {:?}.", &host_path)),
+        };
+        if metadata.file_type().is_symlink() {
+            n_symlinks += 1;
+            if n_symlinks > MAX_SYMLINKS {
+                bail!("Too many levels of symlinks in {:?}.", path);
+            }
+            let target = fs::read_link(&host_path)
+                .with_context(|| format!("Failed to read the link {:?}.", &host_path))?;
+            if target.is_absolute() {
+                resolved = rootfs.to_owned();
+            }
+            for component in target.components().rev() {
+                match component {
+                    Component::Normal(name) => pending.push_front(name.to_owned()),
+                    Component::ParentDir => pending.push_front(parent_dir.clone()),
+                    _ => {}
+                }
+            }
+        } else if metadata.is_dir() {
+            resolved = host_path;
+        } else if create_parents {
+            bail!("{:?} in the layer is under a non-directory.", path);
+        } else {
+            return Ok(None);
+        }
+    }
+    Ok(Some(resolved.join(name)))
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    result.with_context(|| format!("Failed to remove {:?}.", path))
+}
+
+/// Remove the file of the lower layers which can't be overwritten by the new entry, such as a
+/// directory replaced by a file.
+fn remove_conflicting_path(path: &Path, entry_type: tar::EntryType) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+    if metadata.is_dir() != entry_type.is_dir() || entry_type.is_hard_link() {
+        remove_path(path)?;
+    }
+    Ok(())
+}
+
+fn set_owner(path: &Path, header: &tar::Header) -> Result<()> {
+    let (uid, gid) = match (header.uid(), header.gid()) {
+        (Ok(0), Ok(0)) => return Ok(()),
+        (Ok(uid), Ok(gid)) => (uid as u32, gid as u32),
+        _ => return Ok(()),
+    };
+    nix::unistd::fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("Failed to change the owner of {:?}.", path))?;
+    // chown clears the setuid and setgid bits, so set the mode again.
+    if !header.entry_type().is_symlink() {
+        if let Ok(mode) = header.mode() {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set the mode of {:?}.", path))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_oci_image {
+    use super::*;
+
+    fn to_file(data: &[u8]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    fn build_layer(entries: &[(&str, Option<&str>)]) -> File {
+        to_file(&build_tar(entries))
+    }
+
+    fn build_tar(entries: &[(&str, Option<&str>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            match content {
+                Some(content) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(content.len() as u64);
+                    header.set_mode(0o644);
+                    builder
+                        .append_data(&mut header, path, content.as_bytes())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, path, &[][..]).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_flatten_layers_with_whiteout() {
+        let rootfs = tempfile::tempdir().unwrap();
+        let lower = build_layer(&[
+            ("etc/", None),
+            ("etc/removed", Some("lower")),
+            ("etc/kept", Some("lower")),
+            ("etc/overwritten", Some("lower")),
+            ("opt/", None),
+            ("opt/app/", None),
+            ("opt/app/old", Some("lower")),
+            ("var/", None),
+            ("var/cache/", None),
+            ("var/cache/file", Some("lower")),
+        ]);
+        let upper = build_layer(&[
+            ("etc/.wh.removed", Some("")),
+            ("etc/overwritten", Some("upper")),
+            ("opt/app/new", Some("upper")),
+            ("opt/app/.wh..wh..opq", Some("")),
+            ("var/.wh.cache", Some("")),
+        ]);
+        flatten_layers(&[lower, upper], rootfs.path()).unwrap();
+
+        let path = |path: &str| rootfs.path().join(path);
+        assert!(!path("etc/removed").exists());
+        assert!(!path("etc/.wh.removed").exists());
+        assert_eq!("lower", fs::read_to_string(path("etc/kept")).unwrap());
+        assert_eq!(
+            "upper",
+            fs::read_to_string(path("etc/overwritten")).unwrap()
+        );
+        assert!(!path("opt/app/old").exists());
+        assert_eq!("upper", fs::read_to_string(path("opt/app/new")).unwrap());
+        assert!(!path("opt/app/.wh..wh..opq").exists());
+        assert!(path("var").is_dir());
+        assert!(!path("var/cache").exists());
+    }
+
+    #[test]
+    fn test_flatten_gzip_layer_replacing_dir_with_file() {
+        use flate2::write::GzEncoder;
+
+        let rootfs = tempfile::tempdir().unwrap();
+        let lower = build_layer(&[("data/", None), ("data/file", Some("lower"))]);
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder
+            .write_all(&build_tar(&[("data", Some("upper"))]))
+            .unwrap();
+        let upper = to_file(&encoder.finish().unwrap());
+        flatten_layers(&[lower, upper], rootfs.path()).unwrap();
+        assert_eq!(
+            "upper",
+            fs::read_to_string(rootfs.path().join("data")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_whiteout_out_of_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs).unwrap();
+        fs::write(dir.path().join("outside"), "").unwrap();
+        std::os::unix::fs::symlink(dir.path(), rootfs.join("escape")).unwrap();
+        std::os::unix::fs::symlink("../..", rootfs.join("parent")).unwrap();
+        let layer = build_layer(&[
+            ("escape/.wh.outside", Some("")),
+            ("parent/.wh.outside", Some("")),
+        ]);
+        apply_layer(&layer, &rootfs).unwrap();
+        assert!(dir.path().join("outside").exists());
+    }
+
+    #[test]
+    fn test_unpack_through_symlinks_in_rootfs() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib", rootfs.join("lib")).unwrap();
+        std::os::unix::fs::symlink(dir.path(), rootfs.join("escape")).unwrap();
+        let layer = build_layer(&[("lib/file", Some("lib")), ("escape/file", Some("escape"))]);
+        apply_layer(&layer, &rootfs).unwrap();
+
+        // The absolute symlinks point into the rootfs, not to the host.
+        assert_eq!(
+            "lib",
+            fs::read_to_string(rootfs.join("usr/lib/file")).unwrap()
+        );
+        assert!(!dir.path().join("file").exists());
+        let escaped = rootfs
+            .join(dir.path().strip_prefix("/").unwrap())
+            .join("file");
+        assert_eq!("escape", fs::read_to_string(escaped).unwrap());
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let digest = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(verify_digest(&b"abc"[..], digest).is_ok());
+        assert!(verify_digest(&b"abd"[..], digest).is_err());
+        assert!(verify_digest(&b"abc"[..], "sha512:abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_whiteout() {
+        assert_eq!(
+            Some(Whiteout::File(PathBuf::from("etc/foo"))),
+            parse_whiteout(Path::new("etc/.wh.foo"))
+        );
+        assert_eq!(
+            Some(Whiteout::Opaque(PathBuf::from("etc"))),
+            parse_whiteout(Path::new("etc/.wh..wh..opq"))
+        );
+        assert_eq!(None, parse_whiteout(Path::new("etc/foo.wh.bar")));
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        let parse = |image| OciImageReference::parse(image).unwrap();
+        assert_eq!(
+            OciImageReference {
+                registry: "registry-1.docker.io".to_owned(),
+                repository: "library/ubuntu".to_owned(),
+                reference: "latest".to_owned(),
+            },
+            parse("ubuntu")
+        );
+        assert_eq!(
+            OciImageReference {
+                registry: "registry-1.docker.io".to_owned(),
+                repository: "owner/image".to_owned(),
+                reference: "1.0".to_owned(),
+            },
+            parse("docker.io/owner/image:1.0")
+        );
+        assert_eq!(
+            OciImageReference {
+                registry: "localhost:5000".to_owned(),
+                repository: "image".to_owned(),
+                reference: "sha256:abcdef".to_owned(),
+            },
+            parse("localhost:5000/image@sha256:abcdef")
+        );
+        assert_eq!("ghcr.io", parse("ghcr.io/owner/image").registry);
+        assert_eq!("oci-ubuntu-22.04", parse("ubuntu:22.04").get_distro_name());
+        assert_eq!(
+            "registry-1.docker.io/library/ubuntu:22.04",
+            parse("ubuntu:22.04").to_string()
+        );
+
+        for invalid in ["", "Ubuntu", "ubuntu:", "ubuntu@latest", "a//b", "/ubuntu"] {
+            assert!(
+                OciImageReference::parse(invalid).is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let params = parse_bearer_challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!("https://auth.docker.io/token", params["realm"]);
+        assert_eq!("registry.docker.io", params["service"]);
+        assert_eq!("repository:library/ubuntu:pull,push", params["scope"]);
+        assert!(parse_bearer_challenge(r#"Basic realm="registry""#).is_none());
+    }
+
+    #[test]
+    fn test_choose_manifest_for_arch() {
+        let manifests: Vec<Descriptor> = serde_json::from_str(
+            r#"[
+                {"digest": "sha256:amd64", "size": 1, "platform": {"architecture": "amd64", "os": "linux"}},
+                {"digest": "sha256:armv6", "size": 1, "platform": {"architecture": "arm", "os": "linux", "variant": "v6"}},
+                {"digest": "sha256:armv7", "size": 1, "platform": {"architecture": "arm", "os": "linux", "variant": "v7"}},
+                {"digest": "sha256:arm64", "size": 1, "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}}
+            ]"#,
+        )
+        .unwrap();
+        let digest = |arch| &choose_manifest_for_arch(&manifests, arch).unwrap().digest;
+        assert_eq!("sha256:amd64", digest("amd64"));
+        assert_eq!("sha256:armv7", digest("armhf"));
+        assert_eq!("sha256:arm64", digest("arm64"));
+        assert!(choose_manifest_for_arch(&manifests, "riscv64").is_err());
+    }
+
+    #[test]
+    fn test_read_docker_archive_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("image.tar");
+        let mut builder = tar::Builder::new(File::create(&archive_path).unwrap());
+        let mut append = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("first/layer.tar", b"first");
+        append("second/layer.tar", b"second");
+        append(
+            "manifest.json",
+            br#"[{"Config": "config.json", "RepoTags": ["image:latest"], "Layers": ["second/layer.tar", "third/layer.tar"]}]"#,
+        );
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_link_name("../first/layer.tar").unwrap();
+        builder
+            .append_data(&mut header, "third/layer.tar", &[][..])
+            .unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+        let layers = read_docker_archive_layers(&archive_path)
+            .unwrap()
+            .iter_mut()
+            .map(|layer| {
+                let mut data = vec![];
+                layer.seek(SeekFrom::Start(0)).unwrap();
+                layer.read_to_end(&mut data).unwrap();
+                data
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![b"second".to_vec(), b"first".to_vec()], layers);
+    }
+}
@@ -1,9 +1,18 @@
-use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::{fs::File, io::Read};
+use std::sync::Mutex;
 
 use anyhow::{anyhow, bail, Context, Result};
+use nix::fcntl::FcntlArg;
 use nix::unistd::{Gid, Uid};
+use once_cell::sync::Lazy;
+
+use crate::distrod_config;
+use crate::group::GroupFile;
 
 #[derive(Debug, Clone)]
 pub struct Credential {
@@ -33,12 +42,13 @@ pub fn get_credential_from_passwd_file<P: AsRef<Path>>(
     uid: Option<u32>,
     passwd_file_path: P,
 ) -> Result<Option<Credential>> {
-    let mut passwd_file = PasswdFile::open(passwd_file_path.as_ref()).with_context(|| {
-        format!(
-            "Failed to open the passwd file. '{:?}'",
-            passwd_file_path.as_ref()
-        )
-    })?;
+    let mut passwd_file =
+        PasswdFile::open_cached(passwd_file_path.as_ref()).with_context(|| {
+            format!(
+                "Failed to open the passwd file. '{:?}'",
+                passwd_file_path.as_ref()
+            )
+        })?;
     let cred = match (name, uid) {
         (Some(name), _) => Credential::from_user(IdCredential::Name(name), &mut passwd_file)?,
         (_, Some(uid)) => Credential::from_user(IdCredential::Uid(uid), &mut passwd_file)?,
@@ -47,12 +57,89 @@ pub fn get_credential_from_passwd_file<P: AsRef<Path>>(
     Ok(Some(cred))
 }
 
+/// Split "user[:group]" given to `exec --user` into the user and the group.
+pub fn parse_user_spec(spec: &str) -> Result<(&str, Option<&str>)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    if user.is_empty() || matches!(group, Some(group) if group.is_empty() || group.contains(':')) {
+        bail!(
+            "Invalid user {:?}. Give a user or \"user:group\", each of which is a name or an id.",
+            spec
+        );
+    }
+    Ok((user, group))
+}
+
+/// Get the credential of "user[:group]" given to `exec --user`, where each of them is a name or
+/// an id. The group replaces the primary group of the user, who must be a member of the group
+/// unless `forces_group` is true.
+pub fn get_credential_from_user_spec<P1: AsRef<Path>, P2: AsRef<Path>>(
+    spec: &str,
+    passwd_file_path: P1,
+    group_file_path: P2,
+    forces_group: bool,
+) -> Result<Credential> {
+    let (user, group) = parse_user_spec(spec)?;
+    let mut passwd_file =
+        PasswdFile::open_cached(passwd_file_path.as_ref()).with_context(|| {
+            format!(
+                "Failed to open the passwd file. '{:?}'",
+                passwd_file_path.as_ref()
+            )
+        })?;
+    let passwd = match passwd_file.get_ent_by_name(user)? {
+        Some(passwd) => Some(passwd),
+        None => match user.parse() {
+            Ok(uid) => passwd_file.get_ent_by_uid(uid)?,
+            Err(_) => None,
+        },
+    }
+    .ok_or_else(|| anyhow!("The user '{}' doesn't exist.", user))?;
+    let mut cred = Credential::new(
+        Uid::from_raw(passwd.uid),
+        Gid::from_raw(passwd.gid),
+        get_group_list(group_file_path.as_ref(), passwd.name, passwd.gid)?,
+    );
+    if let Some(group) = group {
+        let group_file = GroupFile::open(group_file_path.as_ref())?;
+        let group = group_file
+            .get_ent_by_name_or_gid(group)?
+            .ok_or_else(|| anyhow!("The group '{}' doesn't exist.", group))?;
+        if !forces_group && !group.has_user(passwd.name, passwd.gid) {
+            bail!(
+                "'{}' is not a member of the group '{}'. \
+                 Give --force-group to run the command with the group anyway.",
+                passwd.name,
+                group.name
+            );
+        }
+        cred.set_primary_group(Gid::from_raw(group.gid));
+    }
+    Ok(cred)
+}
+
+/// Get the groups of the user as initgroups(3) does. Only the primary group is returned if the group
+/// file doesn't exist.
+fn get_group_list(group_file_path: &Path, user_name: &str, primary_gid: u32) -> Result<Vec<Gid>> {
+    if !group_file_path.exists() {
+        return Ok(vec![Gid::from_raw(primary_gid)]);
+    }
+    let group_file = GroupFile::open(group_file_path)?;
+    let gids = group_file.get_group_list(user_name, primary_gid);
+    Ok(gids.into_iter().map(Gid::from_raw).collect())
+}
+
 impl Credential {
     pub fn new(uid: Uid, gid: Gid, groups: Vec<Gid>) -> Credential {
         Credential { uid, gid, groups }
     }
 
+    /// Get the credential of the user with the supplementary groups in the group file next to the
+    /// passwd file.
     pub fn from_user(cred: IdCredential, passwd_file: &mut PasswdFile) -> Result<Credential> {
+        let group_file_path = passwd_file.path.with_file_name("group");
         let passwd = match cred {
             IdCredential::Name(name) => passwd_file.get_ent_by_name(name)?,
             IdCredential::Uid(uid) => passwd_file.get_ent_by_uid(uid)?,
@@ -64,10 +151,20 @@ impl Credential {
         Ok(Credential {
             uid: Uid::from_raw(passwd.uid),
             gid: Gid::from_raw(passwd.gid),
-            groups: vec![Gid::from_raw(passwd.gid)],
+            groups: get_group_list(&group_file_path, passwd.name, passwd.gid)?,
         })
     }
 
+    /// Make the group the primary group, keeping it in the supplementary groups as well.
+    pub fn set_primary_group(&mut self, gid: Gid) {
+        self.gid = gid;
+        if !self.groups.contains(&gid) {
+            self.groups.push(gid);
+        }
+    }
+
+    /// Switch to the credential as login does. The supplementary groups have to be set first, since
+    /// setgroups(2) is not permitted after the uid is changed.
     pub fn drop_privilege(&self) {
         let inner = || -> Result<()> {
             nix::unistd::setgroups(&self.groups)?;
@@ -83,23 +180,159 @@ impl Credential {
     }
 }
 
+/// How a command of Distrod gets the root privilege.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootPrivilege {
+    /// The command is run by root, typically through sudo.
+    RealRoot,
+    /// The command is run by any user, and gets the privilege by its setuid bit.
+    Setuid,
+}
+
+/// Bail with the way to get the root privilege unless the process has it.
+pub fn check_root_privilege(privilege: RootPrivilege) -> Result<()> {
+    let has_privilege = match privilege {
+        RootPrivilege::RealRoot => nix::unistd::getuid().is_root(),
+        RootPrivilege::Setuid => nix::unistd::geteuid().is_root(),
+    };
+    if has_privilege {
+        return Ok(());
+    }
+    let is_exec_setuid_root =
+        is_setuid_root(distrod_config::get_distrod_exec_bin_path()).unwrap_or(false);
+    bail!(
+        "{}",
+        get_root_privilege_hint(privilege, is_exec_setuid_root)
+    );
+}
+
+fn get_root_privilege_hint(privilege: RootPrivilege, is_exec_setuid_root: bool) -> String {
+    let distrod = distrod_config::get_distrod_bin_path();
+    let distrod_exec = distrod_config::get_distrod_exec_bin_path();
+    match privilege {
+        RootPrivilege::RealRoot => format!(
+            "Distrod needs the root permission. Run it by sudo, such as `sudo {} enable`. \
+             Non-root users can run commands in the distro through the aliases made by `enable`, \
+             which get the permission from the setuid {}.",
+            distrod, distrod_exec
+        ),
+        RootPrivilege::Setuid if is_exec_setuid_root => format!(
+            "{} has the setuid bit, but didn't get the root permission. \
+             Check that its file system isn't mounted with nosuid.",
+            distrod_exec
+        ),
+        RootPrivilege::Setuid => format!(
+            "{} needs the root permission from its setuid bit, but it's not a setuid binary \
+             owned by root. Run `sudo {} enable` again to fix its permission.",
+            distrod_exec, distrod
+        ),
+    }
+}
+
+/// Whether the file is owned by root and has the setuid bit set.
+pub fn is_setuid_root<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let metadata = std::fs::metadata(path.as_ref())
+        .with_context(|| format!("Failed to get the metadata of {:?}.", path.as_ref()))?;
+    Ok(metadata.uid() == 0 && metadata.mode() & nix::libc::S_ISUID != 0)
+}
+
+/// Make sure that only root can modify or replace the file, which requires that the file and all
+/// its ancestor directories are owned by root and not writable by the group or the others.
+pub fn check_only_root_can_modify<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path
+        .as_ref()
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", path.as_ref()))?;
+    for ancestor in path.ancestors() {
+        let metadata = std::fs::metadata(ancestor)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", ancestor))?;
+        if metadata.uid() != 0 {
+            bail!("{:?} is not owned by root.", ancestor);
+        }
+        if metadata.mode() & 0o022 != 0 {
+            bail!("{:?} is writable by non-root users.", ancestor);
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone)]
 pub struct PasswdFile {
     file_cont: String,
     path: PathBuf,
 }
 
+/// The contents of the passwd files read by `PasswdFile::open_cached`, with the stamps of the files
+/// when they were read.
+static PASSWD_FILE_CACHE: Lazy<Mutex<HashMap<PathBuf, (FileStamp, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The metadata which changes when a file is modified or replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl FileStamp {
+    fn from_file(file: &File) -> Result<FileStamp> {
+        let metadata = file.metadata()?;
+        Ok(FileStamp {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+}
+
 impl PasswdFile {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<PasswdFile> {
-        let mut passwd_file = File::open(path.as_ref())
+        let passwd_file = File::open(path.as_ref())
             .with_context(|| format!("Failed to open '{:?}'.", path.as_ref()))?;
+        Self::read_from(&passwd_file, path.as_ref())
+    }
+
+    /// Same as `open`, but reuse the contents read before in this process unless the file has
+    /// been changed since then.
+    pub fn open_cached<P: AsRef<Path>>(path: P) -> Result<PasswdFile> {
+        let passwd_file = File::open(path.as_ref())
+            .with_context(|| format!("Failed to open '{:?}'.", path.as_ref()))?;
+        // Get the stamp before reading so that a change during the read is seen next time.
+        let stamp = FileStamp::from_file(&passwd_file)
+            .with_context(|| format!("Failed to get the metadata of '{:?}'.", path.as_ref()))?;
+        let mut cache = PASSWD_FILE_CACHE
+            .lock()
+            .map_err(|_| anyhow!("The passwd file cache is poisoned."))?;
+        if let Some((cached_stamp, file_cont)) = cache.get(path.as_ref()) {
+            if *cached_stamp == stamp {
+                return Ok(PasswdFile {
+                    file_cont: file_cont.clone(),
+                    path: PathBuf::from(path.as_ref()),
+                });
+            }
+        }
+        let passwd_file = Self::read_from(&passwd_file, path.as_ref())?;
+        cache.insert(
+            PathBuf::from(path.as_ref()),
+            (stamp, passwd_file.file_cont.clone()),
+        );
+        Ok(passwd_file)
+    }
+
+    fn read_from(passwd_file: &File, path: &Path) -> Result<PasswdFile> {
+        // No lock is needed since the updates replace the file by rename(2).
         let mut cont = String::new();
-        passwd_file
+        (&*passwd_file)
             .read_to_string(&mut cont)
-            .with_context(|| format!("Failed to read the contents of '{:?}'.", path.as_ref()))?;
+            .with_context(|| format!("Failed to read the contents of '{:?}'.", path))?;
         Ok(PasswdFile {
             file_cont: cont,
-            path: PathBuf::from(path.as_ref()),
+            path: PathBuf::from(path),
         })
     }
 
@@ -129,13 +362,24 @@ impl PasswdFile {
         }
     }
 
+    /// Update the entries by the given updater. The whole read-modify-write is done while
+    /// holding the lock of lckpwdf(3) so that concurrent updates, including the ones by the
+    /// shadow tools such as useradd, don't clobber each other. The new contents are written to
+    /// "passwd+" and renamed over the file, as the shadow tools do, so that a crash never
+    /// leaves a truncated file.
     pub fn update(
         &mut self,
         updater: &mut dyn FnMut(PasswdView) -> Result<Option<Passwd>>,
     ) -> Result<()> {
+        let _lock = PasswdLock::acquire(&self.path.with_file_name(PASSWD_LOCK_FILE_NAME))?;
+
+        // Read the contents again because another process may have updated the file
+        // since it was opened.
+        let cur_cont = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read the contents of '{:?}'.", &self.path))?;
         let mut new_cont = String::new();
         {
-            for line in self.file_cont.lines() {
+            for line in cur_cont.lines() {
                 let update = updater(PasswdView::deserialize(line)?);
                 match update {
                     Ok(Some(passwd)) => {
@@ -154,17 +398,81 @@ impl PasswdFile {
                 }
             }
         }
-        let mut passwd_file = BufWriter::new(
-            File::create(&self.path).with_context(|| "Failed to create '/etc/passwd'.")?,
-        );
-        passwd_file
-            .write_all(new_cont.as_bytes())
-            .with_context(|| "Failed to write to the new /etc/passwd file.")?;
+        let mut new_path = self.path.as_os_str().to_owned();
+        new_path.push("+");
+        let new_path = PathBuf::from(new_path);
+        if let Err(e) = write_file_like(&new_path, &self.path, new_cont.as_bytes()) {
+            let _ = std::fs::remove_file(&new_path);
+            return Err(e);
+        }
+        if let Err(e) = std::fs::rename(&new_path, &self.path) {
+            let _ = std::fs::remove_file(&new_path);
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to replace '{:?}' with '{:?}'.",
+                    &self.path, &new_path
+                )
+            });
+        }
         self.file_cont = new_cont;
         Ok(())
     }
 }
 
+/// Write the contents to a new file at the path with the same mode and owner as the original.
+fn write_file_like(path: &Path, original: &Path, cont: &[u8]) -> Result<()> {
+    let metadata = std::fs::metadata(original)
+        .with_context(|| format!("Failed to get the metadata of '{:?}'.", original))?;
+    let mode = metadata.mode() & 0o7777;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .with_context(|| format!("Failed to create '{:?}'.", path))?;
+    nix::unistd::fchown(
+        file.as_raw_fd(),
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+    )
+    .with_context(|| format!("Failed to change the owner of '{:?}'.", path))?;
+    // The mode given to open(2) is masked by umask.
+    file.set_permissions(std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set the mode of '{:?}'.", path))?;
+    file.write_all(cont)
+        .with_context(|| format!("Failed to write to '{:?}'.", path))?;
+    file.sync_all()
+        .with_context(|| format!("Failed to sync '{:?}'.", path))?;
+    Ok(())
+}
+
+/// The lock file of lckpwdf(3), which is next to the passwd file.
+const PASSWD_LOCK_FILE_NAME: &str = ".pwd.lock";
+
+/// The write lock on the lock file of lckpwdf(3), which is released when this is dropped.
+/// It's an open file description lock, which conflicts with the POSIX record lock by lckpwdf(3)
+/// and also with the other threads of this process, unlike a POSIX record lock.
+struct PasswdLock {
+    _file: File,
+}
+
+impl PasswdLock {
+    fn acquire(lock_path: &Path) -> Result<PasswdLock> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .mode(0o600)
+            .open(lock_path)
+            .with_context(|| format!("Failed to open the lock file '{:?}'.", lock_path))?;
+        let mut lock: nix::libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = nix::libc::F_WRLCK as nix::libc::c_short;
+        lock.l_whence = nix::libc::SEEK_SET as nix::libc::c_short;
+        nix::fcntl::fcntl(file.as_raw_fd(), FcntlArg::F_OFD_SETLKW(&lock))
+            .with_context(|| format!("Failed to lock '{:?}'.", lock_path))?;
+        Ok(PasswdLock { _file: file })
+    }
+}
+
 pub struct PasswdIterator<'a> {
     passwd_lines: std::str::Split<'a, char>,
 }
@@ -173,7 +481,8 @@ impl<'a> Iterator for PasswdIterator<'a> {
     type Item = Result<PasswdView<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let line = self.passwd_lines.next()?;
+        // Skip the empty line after the last newline, so that a missing user is not an error.
+        let line = self.passwd_lines.find(|line| !line.is_empty())?;
         Some(
             PasswdView::deserialize(line)
                 .with_context(|| format!("Invalid format line: '{}'", line)),
@@ -271,12 +580,11 @@ impl PasswdView<'_> {
 
 #[cfg(test)]
 mod tests {
-    use crate::distrod_config;
-
     use super::*;
     use std::{
         io::{Seek, SeekFrom},
         path::Path,
+        sync::{Arc, Barrier},
     };
     use tempfile::*;
 
@@ -351,9 +659,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_open_cached_passwd_file() -> Result<()> {
+        let dir = tempdir()?;
+        let mut tmp = NamedTempFile::new_in(dir.path())?;
+        writeln!(&mut tmp, "root:x:0:0:root:/root:/bin/bash")?;
+        let mut passwd_file = PasswdFile::open_cached(tmp.path())?;
+        assert!(passwd_file.get_ent_by_name("nullpo")?.is_none());
+        let mut passwd_file = PasswdFile::open_cached(tmp.path())?;
+        assert_eq!(ROOT, passwd_file.get_ent_by_uid(0)?.unwrap());
+
+        // A modified file busts the cache.
+        writeln!(&mut tmp, "nullpo:x:1000:1000:,,,:/home/nullpo:/bin/bash")?;
+        let mut passwd_file = PasswdFile::open_cached(tmp.path())?;
+        assert_eq!(NULLPO, passwd_file.get_ent_by_name("nullpo")?.unwrap());
+
+        // So does the update by PasswdFile itself.
+        passwd_file.update(&mut |passwd| {
+            if passwd.name != "nullpo" {
+                return Ok(None);
+            }
+            Ok(Some(Passwd {
+                name: passwd.name.to_owned(),
+                passwd: passwd.passwd.to_owned(),
+                uid: passwd.uid,
+                gid: passwd.gid,
+                gecos: passwd.gecos.to_owned(),
+                dir: passwd.dir.to_owned(),
+                shell: "/bin/zsh".to_owned(),
+            }))
+        })?;
+        let mut passwd_file = PasswdFile::open_cached(tmp.path())?;
+        assert_eq!(
+            "/bin/zsh",
+            passwd_file.get_ent_by_name("nullpo")?.unwrap().shell
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_update_passwd_file_no_update() -> Result<()> {
-        let mut tmp = NamedTempFile::new()?;
+        let dir = tempdir()?;
+        let mut tmp = NamedTempFile::new_in(dir.path())?;
         writeln!(&mut tmp, "root:x:0:0:root:/root:/bin/bash")?;
         writeln!(&mut tmp, "nullpo:x:1000:1000:,,,:/home/nullpo:/bin/bash")?;
         writeln!(&mut tmp, "foo:x:1000:1000:,,,::/sbin/nologin")?;
@@ -369,9 +716,7 @@ mod tests {
         assert_eq!(NULLPO, entries.next().unwrap()?);
         assert_eq!(FOO, entries.next().unwrap()?);
 
-        let mut new_cont = String::new();
-        tmp.seek(SeekFrom::Start(0))?;
-        tmp.read_to_string(&mut new_cont)?;
+        let new_cont = std::fs::read_to_string(tmp.path())?;
         assert_eq!(orig_cont, new_cont);
 
         Ok(())
@@ -379,10 +724,12 @@ mod tests {
 
     #[test]
     fn test_update_passwd_file() -> Result<()> {
-        let mut tmp = NamedTempFile::new()?;
+        let dir = tempdir()?;
+        let mut tmp = NamedTempFile::new_in(dir.path())?;
         writeln!(&mut tmp, "root:x:0:0:root:/root:/bin/bash")?;
         writeln!(&mut tmp, "nullpo:x:1000:1000:,,,:/home/nullpo:/bin/bash")?;
         writeln!(&mut tmp, "foo:x:1000:1000:,,,::/sbin/nologin")?;
+        std::fs::set_permissions(tmp.path(), std::fs::Permissions::from_mode(0o644))?;
 
         let mut passwd_file = PasswdFile::open(tmp.path())?;
         passwd_file.update(&mut |passwd| {
@@ -406,7 +753,201 @@ mod tests {
         let mut file = File::open(tmp.path())?;
         file.read_to_string(&mut new_cont)?;
         assert_eq!(expected, new_cont);
+        // The file is replaced with the same mode, and no intermediate file is left.
+        assert_eq!(0o644, std::fs::metadata(tmp.path())?.mode() & 0o7777);
+        let mut new_path = tmp.path().as_os_str().to_owned();
+        new_path.push("+");
+        assert!(!Path::new(&new_path).exists());
 
         Ok(())
     }
+
+    #[test]
+    fn test_concurrent_update_passwd_file() -> Result<()> {
+        let dir = tempdir()?;
+        let mut tmp = NamedTempFile::new_in(dir.path())?;
+        writeln!(&mut tmp, "root:x:0:0:root:/root:/bin/bash")?;
+        writeln!(&mut tmp, "nullpo:x:1000:1000:,,,:/home/nullpo:/bin/bash")?;
+        writeln!(&mut tmp, "foo:x:1000:1000:,,,::/sbin/nologin")?;
+
+        // Both threads open the file before either updates it, so that an update based on
+        // the stale contents would clobber the other's.
+        let barrier = Arc::new(Barrier::new(2));
+        let handles: Vec<_> = [("root", "/bin/zsh"), ("nullpo", "/bin/fish")]
+            .iter()
+            .map(|(name, shell)| {
+                let path = tmp.path().to_owned();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    let mut passwd_file = PasswdFile::open(&path)?;
+                    barrier.wait();
+                    passwd_file.update(&mut |passwd| {
+                        if passwd.name != *name {
+                            return Ok(None);
+                        }
+                        let mut new_passwd = Passwd::from_view(passwd);
+                        new_passwd.shell = shell.to_string();
+                        Ok(Some(new_passwd))
+                    })
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+
+        let expected = "root:x:0:0:root:/root:/bin/zsh\n\
+                        nullpo:x:1000:1000:,,,:/home/nullpo:/bin/fish\n\
+                        foo:x:1000:1000:,,,::/sbin/nologin\n";
+        let mut new_cont = String::new();
+        let mut file = File::open(tmp.path())?;
+        file.read_to_string(&mut new_cont)?;
+        assert_eq!(expected, new_cont);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_setuid_root() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = NamedTempFile::new().unwrap();
+        std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(!is_setuid_root(file.path()).unwrap());
+        std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o4755)).unwrap();
+        // The temporary file is owned by the user running the test.
+        assert_eq!(
+            nix::unistd::getuid().is_root(),
+            is_setuid_root(file.path()).unwrap()
+        );
+        assert!(is_setuid_root(file.path().with_extension("nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_check_only_root_can_modify() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // The temporary directory is under the world-writable /tmp, or owned by a non-root user.
+        assert!(check_only_root_can_modify(&file).is_err());
+        assert!(check_only_root_can_modify(dir.path().join("nonexistent")).is_err());
+    }
+
+    #[test]
+    fn test_root_privilege_hint() {
+        assert!(get_root_privilege_hint(RootPrivilege::RealRoot, true).contains("sudo"));
+        assert!(get_root_privilege_hint(RootPrivilege::Setuid, false).contains("enable"));
+        assert!(get_root_privilege_hint(RootPrivilege::Setuid, true).contains("nosuid"));
+    }
+
+    #[test]
+    fn test_parse_user_spec() {
+        assert_eq!(("alice", None), parse_user_spec("alice").unwrap());
+        assert_eq!(
+            ("alice", Some("docker")),
+            parse_user_spec("alice:docker").unwrap()
+        );
+        assert_eq!(("1000", Some("999")), parse_user_spec("1000:999").unwrap());
+        for invalid in ["", ":docker", "alice:", "alice:docker:x"] {
+            assert!(
+                parse_user_spec(invalid).is_err(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_credential_with_supplementary_groups() {
+        let dir = tempdir().unwrap();
+        let passwd_path = dir.path().join("passwd");
+        std::fs::write(
+            &passwd_path,
+            "alice:x:1000:1000::/home/alice:/bin/bash\n\
+             bob:x:1001:1001::/home/bob:/bin/bash\n",
+        )
+        .unwrap();
+        let get = |uid| {
+            get_credential_from_passwd_file(None, Some(uid), &passwd_path)
+                .unwrap()
+                .unwrap()
+                .groups
+        };
+        let gids =
+            |gids: &[u32]| -> Vec<Gid> { gids.iter().map(|gid| Gid::from_raw(*gid)).collect() };
+
+        // Only the primary group without the group file.
+        assert_eq!(gids(&[1000]), get(1000));
+
+        std::fs::write(
+            dir.path().join("group"),
+            "alice:x:1000:\n\
+             sudo:x:27:alice\n\
+             docker:x:999:bob,alice\n\
+             bob:x:1001:\n",
+        )
+        .unwrap();
+        assert_eq!(gids(&[1000, 27, 999]), get(1000));
+        assert_eq!(gids(&[1001, 999]), get(1001));
+    }
+
+    #[test]
+    fn test_get_credential_from_user_spec() {
+        let dir = tempdir().unwrap();
+        let passwd_path = dir.path().join("passwd");
+        let group_path = dir.path().join("group");
+        std::fs::write(
+            &passwd_path,
+            "root:x:0:0:root:/root:/bin/bash\n\
+             alice:x:1000:1000::/home/alice:/bin/bash\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &group_path,
+            "root:x:0:\n\
+             alice:x:1000:\n\
+             docker:x:999:alice\n\
+             wheel:x:10:\n",
+        )
+        .unwrap();
+        let get = |spec, forces| {
+            get_credential_from_user_spec(spec, &passwd_path, &group_path, forces)
+                .map(|cred| (cred.uid.as_raw(), cred.gid.as_raw(), cred.groups))
+        };
+        let gids = |gids: &[u32]| gids.iter().map(|gid| Gid::from_raw(*gid)).collect();
+
+        assert_eq!(
+            (1000, 1000, gids(&[1000, 999])),
+            get("alice", false).unwrap()
+        );
+        assert_eq!(
+            (1000, 1000, gids(&[1000, 999])),
+            get("1000", false).unwrap()
+        );
+        assert_eq!(
+            (1000, 999, gids(&[1000, 999])),
+            get("alice:docker", false).unwrap()
+        );
+        assert_eq!(
+            (1000, 999, gids(&[1000, 999])),
+            get("1000:999", false).unwrap()
+        );
+        // The primary group of the user is a member of course.
+        assert_eq!(
+            (1000, 1000, gids(&[1000, 999])),
+            get("alice:alice", false).unwrap()
+        );
+
+        assert!(get("alice:wheel", false).is_err());
+        assert_eq!(
+            (1000, 10, gids(&[1000, 999, 10])),
+            get("alice:wheel", true).unwrap()
+        );
+        assert!(get("alice:nogroup", true).is_err());
+        assert!(get("bob", false).is_err());
+        assert!(get("bob:docker", true).is_err());
+    }
 }
@@ -36,6 +36,24 @@ impl ProcFile {
     }
 
     pub fn pid(&mut self) -> Result<u32> {
+        let stat_cont = self.read_stat()?;
+        let pid = stat_cont
+            .split(' ')
+            .next() // 0: PID
+            .ok_or_else(|| anyhow!("Failed to read pid from the stat file."))?
+            .parse()
+            .with_context(|| "Failed to parse the pid.")?;
+        Ok(pid)
+    }
+
+    /// The time the process started after the system boot, in clock ticks.
+    /// Together with the PID, it identifies a process even if the PID is reused.
+    pub fn start_time(&mut self) -> Result<u64> {
+        let stat_cont = self.read_stat()?;
+        parse_start_time(&stat_cont)
+    }
+
+    fn read_stat(&mut self) -> Result<String> {
         let statfd = nix::fcntl::openat(
             self.dir.as_raw_fd(),
             "stat",
@@ -50,13 +68,7 @@ impl ProcFile {
         let mut stat_cont = String::new();
         stat.read_to_string(&mut stat_cont)?;
         stat.seek(SeekFrom::Start(0))?;
-        let pid = stat_cont
-            .split(' ')
-            .next() // 0: PID
-            .ok_or_else(|| anyhow!("Failed to read pid from the stat file."))?
-            .parse()
-            .with_context(|| "Failed to parse the pid.")?;
-        Ok(pid)
+        Ok(stat_cont)
     }
 
     pub fn open_file_at(&self, name: &str) -> Result<File> {
@@ -89,6 +101,21 @@ impl ProcFile {
     }
 }
 
+fn parse_start_time(stat_cont: &str) -> Result<u64> {
+    // The second field is the command name in parentheses, which can contain spaces
+    // and parentheses. The fields after it are separated by spaces.
+    let after_comm = stat_cont
+        .rfind(')')
+        .map(|pos| &stat_cont[pos + 1..])
+        .ok_or_else(|| anyhow!("The stat file doesn't have the command name."))?;
+    after_comm
+        .split_whitespace()
+        .nth(19) // The 22nd field from the start, counted from the 3rd one.
+        .ok_or_else(|| anyhow!("Failed to read the start time from the stat file."))?
+        .parse()
+        .with_context(|| "Failed to parse the start time.")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -114,6 +141,18 @@ mod tests {
         assert_eq!(child.id(), child_procfile.pid().unwrap());
     }
 
+    #[test]
+    fn test_start_time() {
+        let mut current = ProcFile::current_proc().unwrap();
+        let start_time = current.start_time().unwrap();
+        assert_eq!(start_time, current.start_time().unwrap());
+
+        let stat = "1234 (a (weird) name) S 1 1234 1234 0 -1 4194560 100 0 0 0 0 0 0 0 20 0 1 0 \
+                    98765 4096 100 18446744073709551615";
+        assert_eq!(98765, parse_start_time(stat).unwrap());
+        assert!(parse_start_time("1234 (sleep) S 1").is_err());
+    }
+
     #[test]
     fn test_proc_liveness() {
         let mut child = Command::new("/bin/sleep");
@@ -0,0 +1,190 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::File;
+use std::path::Path;
+use std::process::Command;
+
+use crate::distrod_config::{DistrodConfig, RootfsImageEntry};
+use crate::mount_info::get_mount_entries;
+
+const LOOP_CONTROL_PATH: &str = "/dev/loop-control";
+
+/// Make sure that an ext4 image can be created and loop-mounted on this system.
+pub fn check_rootfs_image_support() -> Result<()> {
+    if !Path::new(LOOP_CONTROL_PATH).exists() {
+        bail!(
+            "Loop devices are not available ({} doesn't exist). \
+             Create the distro without --vhdx, or load the loop kernel module.",
+            LOOP_CONTROL_PATH
+        );
+    }
+    for command in ["mkfs.ext4", "mount"] {
+        Command::new(command)
+            .arg("-V")
+            .output()
+            .with_context(|| format!("{} is not available. Make sure it's installed.", command))?;
+    }
+    Ok(())
+}
+
+/// Create a sparse ext4 image file of the given size. The image file is removed on failure.
+pub fn create_rootfs_image<P: AsRef<Path>>(image: P, size: u64) -> Result<()> {
+    let image = image.as_ref();
+    if image.exists() {
+        bail!("{:?} already exists.", image);
+    }
+    let file = File::create(image).with_context(|| format!("Failed to create {:?}.", image))?;
+    let result = format_rootfs_image(&file, image, size);
+    if result.is_err() {
+        remove_rootfs_image(image);
+    }
+    result
+}
+
+fn format_rootfs_image(file: &File, image: &Path, size: u64) -> Result<()> {
+    file.set_len(size)
+        .with_context(|| format!("Failed to resize {:?}.", image))?;
+    let status = Command::new("mkfs.ext4")
+        .args(["-q", "-F"])
+        .arg(image)
+        .status()
+        .with_context(|| "Failed to run mkfs.ext4.")?;
+    if !status.success() {
+        bail!("mkfs.ext4 failed for {:?}. {:?}", image, status);
+    }
+    Ok(())
+}
+
+/// Remove the image file which failed to be set up, so that it doesn't block the next attempt.
+pub fn remove_rootfs_image<P: AsRef<Path>>(image: P) {
+    let image = image.as_ref();
+    if let Err(e) = std::fs::remove_file(image) {
+        log::warn!("Failed to remove {:?}. {}", image, e);
+    }
+}
+
+/// Loop-mount the ext4 image file at the mount point.
+pub fn mount_rootfs_image<P1: AsRef<Path>, P2: AsRef<Path>>(
+    image: P1,
+    mount_point: P2,
+) -> Result<()> {
+    let (image, mount_point) = (image.as_ref(), mount_point.as_ref());
+    let status = Command::new("mount")
+        .args(["-t", "ext4", "-o", "loop"])
+        .arg(image)
+        .arg(mount_point)
+        .status()
+        .with_context(|| "Failed to run mount.")?;
+    if !status.success() {
+        bail!(
+            "Failed to mount {:?} at {:?}. {:?}",
+            image,
+            mount_point,
+            status
+        );
+    }
+    Ok(())
+}
+
+/// Record the image in the Distrod config so that it's mounted again before the distro starts.
+pub fn register_rootfs_image<P1: AsRef<Path>, P2: AsRef<Path>>(
+    image: P1,
+    mount_point: P2,
+) -> Result<()> {
+    let entry = RootfsImageEntry {
+        image: canonicalize(image.as_ref())?,
+        mount_point: canonicalize(mount_point.as_ref())?,
+    };
+    let mut config = (*DistrodConfig::get()?).clone();
+    config
+        .distrod
+        .rootfs_images
+        .retain(|e| e.mount_point != entry.mount_point);
+    config.distrod.rootfs_images.push(entry);
+    config
+        .update()
+        .with_context(|| "Failed to record the rootfs image in the Distrod config.")
+}
+
+/// Mount the image registered for the rootfs if it's not mounted yet.
+/// It's a no-op for the rootfs which is a plain directory.
+pub fn mount_registered_rootfs_image<P: AsRef<Path>>(rootfs: P) -> Result<()> {
+    let rootfs = canonicalize(rootfs.as_ref())?;
+    let config = DistrodConfig::get()?;
+    let entry = match config
+        .distrod
+        .rootfs_images
+        .iter()
+        .find(|e| e.mount_point == rootfs)
+    {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    let is_mounted = get_mount_entries()
+        .with_context(|| "Failed to get the mount entries.")?
+        .iter()
+        .any(|e| e.path == rootfs);
+    if is_mounted {
+        return Ok(());
+    }
+    log::debug!(
+        "Mounting the rootfs image {:?} at {:?}.",
+        &entry.image,
+        &rootfs
+    );
+    mount_rootfs_image(&entry.image, &rootfs)
+}
+
+/// Parse a size such as "64G", "512M", or "1073741824". The units are binary.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let unit_pos = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_pos);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid size: {:?}.", size))?;
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => bail!("Invalid size unit: {:?}. Use K, M, G, or T.", unit),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("The size is too large: {:?}.", size))
+}
+
+fn canonicalize(path: &Path) -> Result<std::path::PathBuf> {
+    path.canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", path))
+}
+
+#[cfg(test)]
+mod test_rootfs_image {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(1024, parse_size("1024").unwrap());
+        assert_eq!(512 << 20, parse_size("512M").unwrap());
+        assert_eq!(64 << 30, parse_size("64G").unwrap());
+        assert_eq!(2 << 30, parse_size("2GiB").unwrap());
+        assert_eq!(1 << 40, parse_size("1t").unwrap());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("64X").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_create_rootfs_image_removes_image_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("rootfs.img");
+        // mkfs.ext4 fails on an empty file, and so does running it if it's not installed.
+        assert!(create_rootfs_image(&image, 0).is_err());
+        assert!(!image.exists());
+    }
+}
@@ -1,12 +1,17 @@
 use anyhow::{anyhow, bail, Context, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 pub use systemd_parser::items::SystemdUnit;
 
+/// The types of the units which activate the service of the same name.
+const COMPANION_UNIT_TYPES: &[&str] = &["socket", "timer", "path"];
+
 pub struct SystemdUnitDisabler {
     pub name: String,
     rootfs_path: PathBuf,
+    disables_companion_units: bool,
+    dry_run: bool,
 }
 
 impl SystemdUnitDisabler {
@@ -14,22 +19,62 @@ impl SystemdUnitDisabler {
         SystemdUnitDisabler {
             name: service_name.to_owned(),
             rootfs_path: rootfs_path.as_ref().to_owned(),
+            disables_companion_units: true,
+            dry_run: false,
         }
     }
 
+    /// Set whether `disable` and `mask` only log the files they would change.
+    pub fn with_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Set whether `disable` also disables the .socket, .timer, and .path units of the same name
+    /// as the service, which would activate the service again. Enabled by default.
+    pub fn with_companion_units(&mut self, disables_companion_units: bool) -> &mut Self {
+        self.disables_companion_units = disables_companion_units;
+        self
+    }
+
     pub fn disable(&self) -> Result<()> {
+        self.disable_unless_visited(&mut HashSet::new())
+    }
+
+    // Aliases are found through the symlinks of the unit. A dry run leaves them in place, so
+    // remember the visited units not to follow the aliases back and forth.
+    fn disable_unless_visited(&self, visited: &mut HashSet<String>) -> Result<()> {
+        if !visited.insert(self.name.clone()) {
+            return Ok(());
+        }
         if self.is_masked()? {
             bail!("{} is already masked.", self.name);
         }
         let company_units = self.get_company_units()?;
+        let companion_units = self.get_companion_units();
         self.remove_unit_symlinks()?;
         for company_unit in company_units {
-            company_unit.disable().with_context(|| {
-                format!(
-                    "Failed to disable a company unit of {}, '{}'.",
-                    &self.name, &company_unit.name
-                )
-            })?;
+            company_unit
+                .disable_unless_visited(visited)
+                .with_context(|| {
+                    format!(
+                        "Failed to disable a company unit of {}, '{}'.",
+                        &self.name, &company_unit.name
+                    )
+                })?;
+        }
+        for companion_unit in companion_units {
+            if companion_unit.is_masked()? {
+                continue;
+            }
+            companion_unit
+                .disable_unless_visited(visited)
+                .with_context(|| {
+                    format!(
+                        "Failed to disable a companion unit of {}, '{}'.",
+                        &self.name, &companion_unit.name
+                    )
+                })?;
         }
 
         Ok(())
@@ -65,6 +110,14 @@ impl SystemdUnitDisabler {
 
     fn make_masked_unit_symlink(&self) -> Result<()> {
         let local_unit_path = &self.get_local_unit_path();
+        if self.dry_run {
+            log::info!(
+                "Would mask {} by linking {:?} to /dev/null.",
+                &self.name,
+                local_unit_path
+            );
+            return Ok(());
+        }
         if local_unit_path.exists() {
             fs::remove_file(&local_unit_path)
                 .with_context(|| format!("Failed to remove {:?}", &local_unit_path))?;
@@ -75,17 +128,69 @@ impl SystemdUnitDisabler {
     }
 
     fn remove_unit_symlinks(&self) -> Result<()> {
-        for link in self
+        let mut links = self
             .collect_unit_symlinks()
-            .with_context(|| "Failed to collect unit symlinks to remove.")?
-        {
+            .with_context(|| "Failed to collect unit symlinks to remove.")?;
+        for link in self.collect_install_target_symlinks().with_context(|| {
+            format!(
+                "Failed to collect the WantedBy / RequiredBy symlinks of {}.",
+                &self.name
+            )
+        })? {
+            if !links.contains(&link) {
+                links.push(link);
+            }
+        }
+        for link in links {
+            if self.dry_run {
+                log::info!("Would remove {:?} to disable {}.", &link, &self.name);
+                continue;
+            }
             fs::remove_file(&link).with_context(|| format!("Failed to remove '{:?}'.", &link))?;
         }
         Ok(())
     }
 
+    /// Collect the symlinks in the .wants/ and .requires/ directories of the targets given by
+    /// WantedBy and RequiredBy in the [Install] section.
+    fn collect_install_target_symlinks(&self) -> Result<Vec<PathBuf>> {
+        let unit = match self.read_install_unit()? {
+            Some(unit) => unit,
+            None => return Ok(vec![]),
+        };
+        let link_name = match self.get_default_instance_name()? {
+            Some(default_instance) => default_instance,
+            None => self.name.clone(),
+        };
+        let mut links = vec![];
+        for (key, dir_suffix) in &[("WantedBy", "wants"), ("RequiredBy", "requires")] {
+            for target in lookup_install_values(&unit, &[key]) {
+                let link =
+                    get_local_unit_path(&self.rootfs_path, &format!("{}.{}", target, dir_suffix))
+                        .join(&link_name);
+                // The link may be dangling.
+                if fs::symlink_metadata(&link).is_ok() {
+                    links.push(link);
+                }
+            }
+        }
+        Ok(links)
+    }
+
     fn collect_unit_symlinks(&self) -> Result<Vec<PathBuf>> {
-        let local_unit_path = self.get_local_unit_path();
+        let mut links = self.glob_unit_symlinks(&self.name)?;
+        // A template unit is enabled as its DefaultInstance, e.g. getty@.service as getty@tty1.service.
+        if let Some(default_instance) = self
+            .get_default_instance_name()
+            .with_context(|| format!("Failed to get the DefaultInstance of {}.", &self.name))?
+        {
+            links.extend(self.glob_unit_symlinks(&default_instance)?);
+        }
+        Ok(links)
+    }
+
+    fn glob_unit_symlinks(&self, unit_name: &str) -> Result<Vec<PathBuf>> {
+        let local_unit_path = get_local_unit_path(&self.rootfs_path, unit_name);
         glob::glob(&format!(
             "{}/**/{}",
             local_unit_path
@@ -105,72 +210,167 @@ impl SystemdUnitDisabler {
         .collect()
     }
 
-    fn get_company_units(&self) -> Result<Vec<SystemdUnitDisabler>> {
-        let service_file = self
-            .collect_unit_symlinks()
-            .with_context(|| "Failed to collect symlinks to get company units from")?;
-        if service_file.is_empty() {
-            return Ok(vec![]);
+    fn get_default_instance_name(&self) -> Result<Option<String>> {
+        if !is_template_name(&self.name) {
+            return Ok(None);
         }
-        let unit_path = service_file
+        let unit = match read_existing_unit(&self.rootfs_path, &self.name)? {
+            Some(unit) => unit,
+            None => return Ok(None),
+        };
+        Ok(lookup_install_values(&unit, &["DefaultInstance"])
             .first()
-            .expect("service_file should not be empty.");
-        let unit = match read_unit_content(&self.rootfs_path, unit_path)
-            .with_context(|| format!("Failed to read a unit path {:?}.", unit_path))?
+            .and_then(|instance| instantiate_template_name(&self.name, instance)))
+    }
+
+    fn get_company_units(&self) -> Result<Vec<SystemdUnitDisabler>> {
+        let unit = match self
+            .read_install_unit()
+            .with_context(|| format!("Failed to read the unit file of {}.", &self.name))?
         {
             Some(unit) => unit,
             None => return Ok(vec![]),
         };
-        let parsed_systemd_unit = systemd_parser::parse_string(&unit)
-            .with_context(|| format!("Failed to parse unit file '{:?}'.", unit_path))?;
-
-        let install = parsed_systemd_unit.lookup_by_category("Install");
-        let company_units = install
-            .into_iter()
-            .filter_map(|e| {
-                let company_unit_directives = ["Alias", "Also"];
-                match e {
-                    systemd_parser::items::DirectiveEntry::Many(directives) => {
-                        let key = directives
-                            .first()
-                            .expect("Many has at least one value.")
-                            .key();
-                        if company_unit_directives.contains(&key) {
-                            let val = directives
-                                .iter()
-                                .filter_map(|d| d.value().map(|s| s.split(' ')))
-                                .flatten()
-                                .collect::<Vec<_>>();
-                            Some(val)
-                        } else {
-                            None
-                        }
-                    }
-                    systemd_parser::items::DirectiveEntry::Solo(directive) => {
-                        if company_unit_directives.contains(&directive.key()) {
-                            directive.value().map(|v| v.split(' ').collect())
-                        } else {
-                            None
-                        }
-                    }
-                }
-            })
-            .flatten();
+        let instance = split_instance_name(&self.name).map(|(_, instance)| instance);
 
         let mut result = vec![];
-        for company_unit in company_units {
-            let unit = SystemdUnitDisabler::new(&self.rootfs_path, company_unit);
-            result.push(unit);
+        for company_unit in lookup_install_values(&unit, &["Alias", "Also"]) {
+            // The [Install] section of a template may refer to the instance name by %i.
+            let company_unit = match instance {
+                Some(instance) => company_unit.replace("%i", instance),
+                None => company_unit,
+            };
+            result.push(self.new_related_disabler(&company_unit));
         }
 
         Ok(result)
     }
 
+    fn get_companion_units(&self) -> Vec<SystemdUnitDisabler> {
+        if !self.disables_companion_units {
+            return vec![];
+        }
+        let basename = match self.name.strip_suffix(".service") {
+            Some(basename) => basename,
+            None => return vec![],
+        };
+        COMPANION_UNIT_TYPES
+            .iter()
+            .map(|unit_type| format!("{}.{}", basename, unit_type))
+            .filter(|name| get_existing_unit_path(&self.rootfs_path, name).is_some())
+            .map(|name| self.new_related_disabler(&name))
+            .collect()
+    }
+
+    fn new_related_disabler(&self, unit_name: &str) -> SystemdUnitDisabler {
+        let mut disabler = SystemdUnitDisabler::new(&self.rootfs_path, unit_name);
+        disabler
+            .with_companion_units(self.disables_companion_units)
+            .with_dry_run(self.dry_run);
+        disabler
+    }
+
+    /// Read the unit file which has the [Install] section of the unit.
+    fn read_install_unit(&self) -> Result<Option<SystemdUnit>> {
+        let service_file = self
+            .collect_unit_symlinks()
+            .with_context(|| "Failed to collect symlinks to get company units from")?;
+        if let Some(unit_path) = service_file.first() {
+            return read_and_parse_unit(&self.rootfs_path, unit_path);
+        }
+        // An instance without its own unit file, such as getty@tty1.service, is defined by its
+        // template, getty@.service.
+        match split_instance_name(&self.name) {
+            Some((template, _))
+                if get_existing_unit_path(&self.rootfs_path, &self.name).is_none() =>
+            {
+                read_existing_unit(&self.rootfs_path, &template)
+            }
+            _ => Ok(None),
+        }
+    }
+
     fn get_local_unit_path(&self) -> PathBuf {
         get_local_unit_path(&self.rootfs_path, &self.name)
     }
 }
 
+fn read_existing_unit(rootfs: &Path, unit_name: &str) -> Result<Option<SystemdUnit>> {
+    match get_existing_unit_path(rootfs, unit_name) {
+        Some(unit_path) => read_and_parse_unit(rootfs, &unit_path),
+        None => Ok(None),
+    }
+}
+
+fn read_and_parse_unit(rootfs: &Path, unit_path: &Path) -> Result<Option<SystemdUnit>> {
+    let unit = match read_unit_content(rootfs, unit_path)
+        .with_context(|| format!("Failed to read a unit path {:?}.", unit_path))?
+    {
+        Some(unit) => unit,
+        None => return Ok(None),
+    };
+    Ok(Some(systemd_parser::parse_string(&unit).with_context(
+        || format!("Failed to parse unit file '{:?}'.", unit_path),
+    )?))
+}
+
+/// Get the space-separated values of the directives in the [Install] section.
+fn lookup_install_values(unit: &SystemdUnit, keys: &[&str]) -> Vec<String> {
+    unit.lookup_by_category("Install")
+        .into_iter()
+        .filter_map(|e| match e {
+            systemd_parser::items::DirectiveEntry::Many(directives) => {
+                let key = directives
+                    .first()
+                    .expect("Many has at least one value.")
+                    .key();
+                if keys.contains(&key) {
+                    let val = directives
+                        .iter()
+                        .filter_map(|d| d.value().map(|s| s.split(' ')))
+                        .flatten()
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>();
+                    Some(val)
+                } else {
+                    None
+                }
+            }
+            systemd_parser::items::DirectiveEntry::Solo(directive) => {
+                if keys.contains(&directive.key()) {
+                    directive
+                        .value()
+                        .map(|v| v.split(' ').map(str::to_owned).collect())
+                } else {
+                    None
+                }
+            }
+        })
+        .flatten()
+        .filter(|val| !val.is_empty())
+        .collect()
+}
+
+fn is_template_name(unit_name: &str) -> bool {
+    unit_name.contains("@.")
+}
+
+/// Split an instance name such as "getty@tty1.service" into its template name, "getty@.service",
+/// and the instance, "tty1".
+fn split_instance_name(unit_name: &str) -> Option<(String, &str)> {
+    let (prefix, rest) = unit_name.split_once('@')?;
+    let (instance, suffix) = rest.rsplit_once('.')?;
+    if instance.is_empty() {
+        return None;
+    }
+    Some((format!("{}@.{}", prefix, suffix), instance))
+}
+
+fn instantiate_template_name(template_name: &str, instance: &str) -> Option<String> {
+    let (prefix, suffix) = template_name.split_once("@.")?;
+    Some(format!("{}@{}.{}", prefix, instance, suffix))
+}
+
 fn read_unit_content(rootfs: &Path, unit_path: &Path) -> Result<Option<String>> {
     if fs::symlink_metadata(&unit_path)
         .with_context(|| format!("Failed to get the symlink_metadata of {:?}", &unit_path))?
@@ -208,6 +408,7 @@ fn read_unit_content(rootfs: &Path, unit_path: &Path) -> Result<Option<String>>
 #[derive(Debug, Clone, Default)]
 pub struct SystemdUnitOverride {
     sections: HashMap<String, SystemdUnitSection>,
+    dry_run: bool,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -234,6 +435,19 @@ impl SystemdUnitOverride {
         self
     }
 
+    /// Append the value to the directive without unsetting it first, so that the value is added to
+    /// the list given by the original unit, not replacing it.
+    pub fn append_directive(
+        &mut self,
+        section_name: &str,
+        directive_name: &str,
+        value: String,
+    ) -> &mut Self {
+        self.get_mut_section(section_name)
+            .append_directive(directive_name, value);
+        self
+    }
+
     pub fn unset_directive(&mut self, section_name: &str, directive_name: &str) -> &mut Self {
         self.get_mut_section(section_name)
             .unset_directive(directive_name);
@@ -249,9 +463,24 @@ impl SystemdUnitOverride {
             .expect("[BUG] put_section should be callsed beforehand.")
     }
 
+    /// Make `write` only log the override instead of writing it.
+    pub fn with_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
     pub fn write<P: AsRef<Path>>(&mut self, rootfs_path: P, service_name: &str) -> Result<()> {
         let serialized = self.serialize();
         let override_path = get_override_conf_path(rootfs_path, service_name);
+        if self.dry_run {
+            log::info!(
+                "Would write {:?} to override {}:\n{}",
+                &override_path,
+                service_name,
+                serialized
+            );
+            return Ok(());
+        }
         let override_conf_dir = override_path
             .parent()
             .expect("[BUG] get_override_conf_path should return a dir.");
@@ -283,6 +512,10 @@ impl SystemdUnitSection {
         self.insert_directive(directive_name, value)
     }
 
+    pub fn append_directive(&mut self, directive_name: &str, value: String) -> &mut Self {
+        self.insert_directive(directive_name, value)
+    }
+
     pub fn unset_directive(&mut self, directive_name: &str) -> &mut Self {
         if let Some(directives) = self.directives.get_mut(directive_name) {
             directives.clear();
@@ -308,17 +541,12 @@ impl SystemdUnitSection {
 
     fn serialize(&self) -> String {
         let mut result = String::new();
-        for (directive_name, values) in &self.directives {
-            result.push_str(
-                &values
-                    .iter()
-                    .map(|value| format!("{}={}", directive_name, value))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-            );
-        }
-        if !result.is_empty() {
-            result.push('\n');
+        let mut directives = self.directives.iter().collect::<Vec<_>>();
+        directives.sort_by_key(|(directive_name, _)| *directive_name);
+        for (directive_name, values) in directives {
+            for value in values {
+                result.push_str(&format!("{}={}\n", directive_name, value));
+            }
         }
         result
     }
@@ -403,16 +631,44 @@ mod test_systemd_unit_override {
             overrider.serialize()
         );
     }
+
+    #[test]
+    fn test_append_directive() {
+        let mut overrider = SystemdUnitOverride::default();
+        overrider.append_directive("Unit", "After", "network.target".to_owned());
+        assert_eq!("[Unit]\nAfter=network.target\n", overrider.serialize());
+        overrider.append_directive("Unit", "After", "local-fs.target".to_owned());
+        assert_eq!(
+            "[Unit]\nAfter=network.target\nAfter=local-fs.target\n",
+            overrider.serialize()
+        );
+
+        // Replacing and appending directives can be mixed in a section.
+        overrider.push_directive("Unit", "Wants", "network.target".to_owned());
+        assert_eq!(
+            "[Unit]\nAfter=network.target\nAfter=local-fs.target\nWants=\nWants=network.target\n",
+            overrider.serialize()
+        );
+
+        // Unsetting clears the appended values too.
+        overrider.unset_directive("Unit", "After");
+        assert_eq!(
+            "[Unit]\nAfter=\nWants=\nWants=network.target\n",
+            overrider.serialize()
+        );
+    }
 }
 
 #[cfg(test)]
-mod test_systemd_unit_disabler {
+pub(crate) mod test_systemd_unit_disabler {
     use super::*;
     use flate2::bufread::GzDecoder;
     use tempfile::*;
 
     static SYSTEMD_DIR: &str = "etc/systemd/system";
     static MULTI_USER_UNIT_NAME: &str = "multi-user.target.wants";
+    static SOCKETS_UNIT_NAME: &str = "sockets.target.wants";
+    static GETTY_UNIT_NAME: &str = "getty.target.wants";
 
     #[test]
     fn test_simple_unit() {
@@ -536,6 +792,43 @@ mod test_systemd_unit_disabler {
         }
     }
 
+    #[test]
+    fn test_companion_socket_unit() {
+        let service = "socket_activated.service";
+        let socket = "socket_activated.socket";
+
+        let (tempdir, unitdir_path) = setup_unit_dir().unwrap();
+        assert!(unitdir_path.join(SOCKETS_UNIT_NAME).join(socket).exists());
+
+        let disabler = SystemdUnitDisabler::new(&tempdir, service);
+        disabler.disable().unwrap();
+
+        assert!(!unitdir_path
+            .join(MULTI_USER_UNIT_NAME)
+            .join(service)
+            .exists());
+        assert!(!unitdir_path.join(socket).exists());
+        assert!(!unitdir_path.join(SOCKETS_UNIT_NAME).join(socket).exists());
+    }
+
+    #[test]
+    fn test_companion_socket_unit_opted_out() {
+        let service = "socket_activated.service";
+        let socket = "socket_activated.socket";
+
+        let (tempdir, unitdir_path) = setup_unit_dir().unwrap();
+
+        let mut disabler = SystemdUnitDisabler::new(&tempdir, service);
+        disabler.with_companion_units(false).disable().unwrap();
+
+        assert!(!unitdir_path
+            .join(MULTI_USER_UNIT_NAME)
+            .join(service)
+            .exists());
+        assert!(unitdir_path.join(socket).exists());
+        assert!(unitdir_path.join(SOCKETS_UNIT_NAME).join(socket).exists());
+    }
+
     #[test]
     fn test_mask() {
         let existing_unit = "systemd-system1.service";
@@ -561,6 +854,160 @@ mod test_systemd_unit_disabler {
         );
     }
 
+    #[test]
+    fn test_template_instance_unit() {
+        let (tempdir, unitdir_path) = setup_template_unit_dir().unwrap();
+        let wants_dir = unitdir_path.join(GETTY_UNIT_NAME);
+        assert!(wants_dir.join("getty@tty1.service").exists());
+        assert!(wants_dir.join("getty-sub@tty1.service").exists());
+
+        let disabler = SystemdUnitDisabler::new(&tempdir, "getty@tty1.service");
+        disabler.disable().unwrap();
+
+        assert!(!wants_dir.join("getty@tty1.service").exists());
+        // Also=getty-sub@%i.service in the template refers to the instance.
+        assert!(!wants_dir.join("getty-sub@tty1.service").exists());
+        assert!(wants_dir.join("getty@tty2.service").exists());
+        assert!(wants_dir.join("getty-sub@tty3.service").exists());
+    }
+
+    #[test]
+    fn test_instance_unit_without_own_unit_file() {
+        let (tempdir, unitdir_path) = setup_template_unit_dir().unwrap();
+        let wants_dir = unitdir_path.join(GETTY_UNIT_NAME);
+        assert!(!wants_dir.join("getty@tty3.service").exists());
+        assert!(wants_dir.join("getty-sub@tty3.service").exists());
+
+        // The [Install] section is read from the template getty@.service.
+        let disabler = SystemdUnitDisabler::new(&tempdir, "getty@tty3.service");
+        disabler.disable().unwrap();
+
+        assert!(!wants_dir.join("getty-sub@tty3.service").exists());
+        assert!(wants_dir.join("getty@tty1.service").exists());
+        assert!(wants_dir.join("getty-sub@tty1.service").exists());
+    }
+
+    #[test]
+    fn test_template_default_instance() {
+        let (tempdir, unitdir_path) = setup_template_unit_dir().unwrap();
+        let wants_dir = unitdir_path.join(GETTY_UNIT_NAME);
+
+        let disabler = SystemdUnitDisabler::new(&tempdir, "getty@.service");
+        disabler.disable().unwrap();
+
+        // DefaultInstance=tty1
+        assert!(!wants_dir.join("getty@tty1.service").exists());
+        assert!(wants_dir.join("getty@tty2.service").exists());
+    }
+
+    #[test]
+    fn test_required_by_unit() {
+        let unit = "required_unit.service";
+        let (tempdir, unitdir_path) = setup_unit_dir().unwrap();
+        let lib_unit_dir = tempdir.path().join("lib/systemd/system");
+        fs::create_dir_all(&lib_unit_dir).unwrap();
+        fs::write(
+            lib_unit_dir.join(unit),
+            "[Unit]\nDescription=Required unit\n\n[Service]\nExecStart=/bin/true\n\n\
+             [Install]\nWantedBy=multi-user.target\nRequiredBy=remote-fs.target local-fs.target\n",
+        )
+        .unwrap();
+        let link_dirs = [
+            unitdir_path.join(MULTI_USER_UNIT_NAME),
+            unitdir_path.join("remote-fs.target.requires"),
+            unitdir_path.join("local-fs.target.requires"),
+        ];
+        for link_dir in &link_dirs {
+            fs::create_dir_all(link_dir).unwrap();
+            std::os::unix::fs::symlink(
+                Path::new("/lib/systemd/system").join(unit),
+                link_dir.join(unit),
+            )
+            .unwrap();
+        }
+        // A unit which is not referred to by the [Install] section is not touched.
+        std::os::unix::fs::symlink(
+            "/lib/systemd/system/unrelated.service",
+            link_dirs[1].join("unrelated.service"),
+        )
+        .unwrap();
+
+        let disabler = SystemdUnitDisabler::new(&tempdir, unit);
+        disabler.disable().unwrap();
+
+        for link_dir in &link_dirs {
+            assert!(fs::symlink_metadata(link_dir.join(unit)).is_err());
+        }
+        assert!(fs::symlink_metadata(link_dirs[1].join("unrelated.service")).is_ok());
+        assert!(lib_unit_dir.join(unit).exists());
+    }
+
+    #[test]
+    fn test_split_instance_name() {
+        assert_eq!(
+            Some(("getty@.service".to_owned(), "tty1")),
+            split_instance_name("getty@tty1.service")
+        );
+        assert_eq!(
+            Some((
+                "systemd-fsck@.service".to_owned(),
+                "dev-disk-by\\x2duuid-1234"
+            )),
+            split_instance_name("systemd-fsck@dev-disk-by\\x2duuid-1234.service")
+        );
+        assert_eq!(None, split_instance_name("getty@.service"));
+        assert_eq!(None, split_instance_name("getty.service"));
+        assert_eq!(
+            Some("getty@tty1.service".to_owned()),
+            instantiate_template_name("getty@.service", "tty1")
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (tempdir, unitdir_path) = setup_unit_dir().unwrap();
+        let before = snapshot_dir(tempdir.path());
+
+        for unit in &["simple_unit.service", "multiple_alias.service"] {
+            let mut disabler = SystemdUnitDisabler::new(&tempdir, unit);
+            disabler.with_dry_run(true);
+            disabler.disable().unwrap();
+            disabler.mask().unwrap();
+        }
+        let mut overrider = SystemdUnitOverride::default();
+        overrider
+            .with_dry_run(true)
+            .unset_directive("Service", "LoadCredential");
+        overrider.write(&tempdir, "simple_unit.service").unwrap();
+
+        assert!(unitdir_path.join("simple_unit.service").exists());
+        assert_eq!(before, snapshot_dir(tempdir.path()));
+    }
+
+    /// Collect the paths under the directory with the contents of the files and the targets of
+    /// the symlinks, to assert that nothing is changed.
+    pub(crate) fn snapshot_dir(dir: &Path) -> Vec<(PathBuf, String)> {
+        let mut entries = vec![];
+        let mut dirs = vec![dir.to_owned()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                let metadata = fs::symlink_metadata(&path).unwrap();
+                let content = if metadata.file_type().is_symlink() {
+                    format!("-> {:?}", fs::read_link(&path).unwrap())
+                } else if metadata.is_dir() {
+                    dirs.push(path.clone());
+                    "dir".to_owned()
+                } else {
+                    String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned()
+                };
+                entries.push((path, content));
+            }
+        }
+        entries.sort();
+        entries
+    }
+
     fn setup_unit_dir() -> Result<(TempDir, PathBuf)> {
         let temp_dir = tempdir()?;
         let unit_dir = temp_dir.path().join(SYSTEMD_DIR);
@@ -572,4 +1019,37 @@ mod test_systemd_unit_disabler {
 
         Ok((temp_dir, unit_dir))
     }
+
+    /// Set up the unit dir with the template units in /lib/systemd/system and their instances
+    /// enabled in getty.target.wants.
+    fn setup_template_unit_dir() -> Result<(TempDir, PathBuf)> {
+        let (temp_dir, unit_dir) = setup_unit_dir()?;
+        let lib_unit_dir = temp_dir.path().join("lib/systemd/system");
+        fs::create_dir_all(&lib_unit_dir)?;
+        fs::write(
+            lib_unit_dir.join("getty@.service"),
+            "[Unit]\nDescription=Getty on %I\n\n[Service]\nExecStart=/sbin/agetty --noclear %I\n\n\
+             [Install]\nWantedBy=getty.target\nDefaultInstance=tty1\nAlso=getty-sub@%i.service\n",
+        )?;
+        fs::write(
+            lib_unit_dir.join("getty-sub@.service"),
+            "[Unit]\nDescription=Sub service of getty on %I\n\n[Install]\nWantedBy=getty.target\n",
+        )?;
+
+        let wants_dir = unit_dir.join(GETTY_UNIT_NAME);
+        fs::create_dir_all(&wants_dir)?;
+        for (template, instance) in &[
+            ("getty@.service", "getty@tty1.service"),
+            ("getty@.service", "getty@tty2.service"),
+            ("getty-sub@.service", "getty-sub@tty1.service"),
+            ("getty-sub@.service", "getty-sub@tty3.service"),
+        ] {
+            std::os::unix::fs::symlink(
+                Path::new("../../../../lib/systemd/system").join(template),
+                wants_dir.join(instance),
+            )?;
+        }
+
+        Ok((temp_dir, unit_dir))
+    }
 }
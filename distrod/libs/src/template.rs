@@ -1,18 +1,207 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A simple template which replaces `{{NAME}}` with the assigned values.
+/// `{{#if NAME}}...{{else}}...{{/if}}` blocks are rendered if NAME is assigned a non-empty value.
+/// The blocks can be nested, and `{{else}}` is optional.
+/// The blocks are parsed before the values are substituted, so the values are rendered as they are
+/// even if they contain the tags or the placeholders.
 pub struct Template {
     cont: String,
+    values: HashMap<String, String>,
+}
+
+const IF_TAG_PREFIX: &str = "{{#if ";
+const ELSE_TAG: &str = "{{else}}";
+const END_IF_TAG: &str = "{{/if}}";
+
+enum BlockTag<'a> {
+    If(&'a str),
+    Else,
+    EndIf,
 }
 
 impl Template {
     pub fn new(cont: String) -> Self {
-        Template { cont }
+        Template {
+            cont,
+            values: HashMap::new(),
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let cont = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read the template {:?}.", path.as_ref()))?;
+        Ok(Template::new(cont))
     }
 
     pub fn assign(&mut self, name: &str, val: &str) -> &mut Self {
-        self.cont = self.cont.replace(&format!("{{{{{}}}}}", name), val);
+        self.values.insert(name.to_owned(), val.to_owned());
         self
     }
 
     pub fn render(&self) -> String {
-        self.cont.clone()
+        let mut rendered = String::with_capacity(self.cont.len());
+        // Whether each of the nested blocks is rendered.
+        let mut blocks: Vec<bool> = vec![];
+        let mut rest = self.cont.as_str();
+        let mut is_at_line_start = true;
+        while let Some((pos, tag, tag_len)) = find_block_tag(rest) {
+            let text = &rest[..pos];
+            if blocks.iter().all(|rendered| *rendered) {
+                self.substitute(text, &mut rendered);
+            }
+            is_at_line_start = text.ends_with('\n') || (text.is_empty() && is_at_line_start);
+            match tag {
+                BlockTag::If(name) => blocks.push(self.is_truthy(name)),
+                BlockTag::Else => {
+                    if let Some(block) = blocks.last_mut() {
+                        *block = !*block;
+                    }
+                }
+                BlockTag::EndIf => {
+                    blocks.pop();
+                }
+            }
+            rest = &rest[pos + tag_len..];
+            // Don't leave an empty line for a tag on its own line.
+            if is_at_line_start && rest.starts_with('\n') {
+                rest = &rest[1..];
+            }
+        }
+        if blocks.iter().all(|rendered| *rendered) {
+            self.substitute(rest, &mut rendered);
+        }
+        rendered
+    }
+
+    fn is_truthy(&self, name: &str) -> bool {
+        matches!(self.values.get(name), Some(val) if !val.is_empty())
+    }
+
+    /// Replace the placeholders of the assigned names in the text. The others are left as they are.
+    fn substitute(&self, text: &str, rendered: &mut String) {
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => break,
+            };
+            match self.values.get(&rest[start + 2..end]) {
+                Some(val) => {
+                    rendered.push_str(&rest[..start]);
+                    rendered.push_str(val);
+                    rest = &rest[end + 2..];
+                }
+                // The placeholder may start after these braces, such as in "{{ {{NAME}}".
+                None => {
+                    rendered.push_str(&rest[..start + 2]);
+                    rest = &rest[start + 2..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+    }
+}
+
+/// Find the first block tag, and return its position, the tag, and its length.
+fn find_block_tag(cont: &str) -> Option<(usize, BlockTag<'_>, usize)> {
+    let mut pos = 0;
+    while let Some(found) = cont[pos..].find("{{") {
+        pos += found;
+        let rest = &cont[pos..];
+        if rest.starts_with(ELSE_TAG) {
+            return Some((pos, BlockTag::Else, ELSE_TAG.len()));
+        }
+        if rest.starts_with(END_IF_TAG) {
+            return Some((pos, BlockTag::EndIf, END_IF_TAG.len()));
+        }
+        if let (true, Some(end)) = (rest.starts_with(IF_TAG_PREFIX), rest.find("}}")) {
+            let name = rest[IF_TAG_PREFIX.len()..end].trim();
+            return Some((pos, BlockTag::If(name), end + 2));
+        }
+        pos += 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test_template {
+    use super::*;
+
+    #[test]
+    fn test_assign() {
+        let mut template = Template::new("{{A}} and {{B}} and {{A}}, {{C}}".to_owned());
+        template.assign("A", "a").assign("B", "b");
+        assert_eq!("a and b and a, {{C}}", template.render());
+        assert_eq!(
+            "{{ c}}",
+            Template::new("{{ {{C}}}}".to_owned())
+                .assign("C", "c")
+                .render()
+        );
+    }
+
+    #[test]
+    fn test_conditional_block() {
+        let cont = "begin\n\
+                    {{#if VAR}}\n\
+                    var is {{VAR}}\n\
+                    {{else}}\n\
+                    var is not set\n\
+                    {{/if}}\n\
+                    end\n";
+        let mut template = Template::new(cont.to_owned());
+        template.assign("VAR", "set");
+        assert_eq!("begin\nvar is set\nend\n", template.render());
+
+        let template = Template::new(cont.to_owned());
+        assert_eq!("begin\nvar is not set\nend\n", template.render());
+
+        let mut template = Template::new(cont.to_owned());
+        template.assign("VAR", "");
+        assert_eq!("begin\nvar is not set\nend\n", template.render());
+    }
+
+    #[test]
+    fn test_inline_and_nested_conditional_blocks() {
+        let cont = "a{{#if X}}x{{#if Y}}y{{else}}-{{/if}}{{/if}}b";
+        let render = |assigned: &[&str]| {
+            let mut template = Template::new(cont.to_owned());
+            for name in assigned {
+                template.assign(name, "1");
+            }
+            template.render()
+        };
+        assert_eq!("ab", render(&[]));
+        assert_eq!("ab", render(&["Y"]));
+        assert_eq!("ax-b", render(&["X"]));
+        assert_eq!("axyb", render(&["X", "Y"]));
+    }
+
+    #[test]
+    fn test_values_are_not_parsed() {
+        let mut template =
+            Template::new("{{A}}{{#if B}}b{{/if}}{{B}}, {{#if C}}c{{else}}-{{/if}}".to_owned());
+        template
+            .assign("A", "{{else}}{{B}}")
+            .assign("B", "{{/if}}")
+            .assign("C", "");
+        assert_eq!("{{else}}{{B}}b{{/if}}, -", template.render());
+    }
+
+    #[test]
+    fn test_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("template.sh");
+        std::fs::write(&path, "echo {{MSG}}\n{{#if DEBUG}}\nset -x\n{{/if}}\n").unwrap();
+        let mut template = Template::from_file(&path).unwrap();
+        template.assign("MSG", "hello");
+        assert_eq!("echo hello\n", template.render());
+        template.assign("DEBUG", "1");
+        assert_eq!("echo hello\nset -x\n", template.render());
+
+        assert!(Template::from_file(dir.path().join("nonexistent")).is_err());
     }
 }
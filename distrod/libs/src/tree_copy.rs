@@ -0,0 +1,230 @@
+use anyhow::{Context, Result};
+use nix::sys::stat::{Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{FchownatFlags, Gid, Uid};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+// FICLONE is _IOW(0x94, 9, int). See include/uapi/linux/fs.h.
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Copies a directory tree preserving the owners, the permissions, the timestamps, the hard links,
+/// and the special files, so that the copy of a rootfs works as the original does.
+/// The regular files are cloned by reflink if the filesystem supports it, which shares the data
+/// blocks until they are modified.
+pub struct TreeCopier {
+    uses_reflink: bool,
+    has_reflinked: bool,
+    hard_links: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Default for TreeCopier {
+    fn default() -> Self {
+        TreeCopier {
+            uses_reflink: true,
+            has_reflinked: false,
+            hard_links: HashMap::new(),
+        }
+    }
+}
+
+impl TreeCopier {
+    /// Copy the contents of `src` into `dst`, which is created if it doesn't exist.
+    pub fn copy<P1: AsRef<Path>, P2: AsRef<Path>>(&mut self, src: P1, dst: P2) -> Result<()> {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        let metadata = fs::symlink_metadata(src)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", src))?;
+        if !dst.exists() {
+            fs::create_dir(dst).with_context(|| format!("Failed to create {:?}.", dst))?;
+        }
+        self.copy_dir_contents(src, dst)?;
+        copy_metadata(&metadata, dst)
+    }
+
+    /// Whether any regular file has been cloned by reflink so far.
+    pub fn has_used_reflink(&self) -> bool {
+        self.has_reflinked
+    }
+
+    fn copy_dir_contents(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        for entry in fs::read_dir(src).with_context(|| format!("Failed to read {:?}.", src))? {
+            let entry = entry.with_context(|| format!("Failed to read an entry of {:?}.", src))?;
+            self.copy_entry(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        Ok(())
+    }
+
+    fn copy_entry(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        let metadata = fs::symlink_metadata(src)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", src))?;
+        let file_type = metadata.file_type();
+        if !file_type.is_dir() && metadata.nlink() > 1 {
+            let key = (metadata.dev(), metadata.ino());
+            if let Some(first) = self.hard_links.get(&key) {
+                return fs::hard_link(first, dst)
+                    .with_context(|| format!("Failed to link {:?} to {:?}.", dst, first));
+            }
+            self.hard_links.insert(key, dst.to_owned());
+        }
+
+        if file_type.is_dir() {
+            // Create it writable first so that its contents can be copied even if it's read-only.
+            fs::create_dir(dst).with_context(|| format!("Failed to create {:?}.", dst))?;
+            self.copy_dir_contents(src, dst)?;
+        } else if file_type.is_symlink() {
+            let target =
+                fs::read_link(src).with_context(|| format!("Failed to read link {:?}.", src))?;
+            std::os::unix::fs::symlink(&target, dst)
+                .with_context(|| format!("Failed to create a symlink {:?}.", dst))?;
+        } else if file_type.is_file() {
+            self.copy_file(src, dst)?;
+        } else if file_type.is_socket() {
+            // Sockets are created by the running processes and can't be copied.
+            return Ok(());
+        } else {
+            let kind = if file_type.is_char_device() {
+                SFlag::S_IFCHR
+            } else if file_type.is_block_device() {
+                SFlag::S_IFBLK
+            } else {
+                SFlag::S_IFIFO
+            };
+            nix::sys::stat::mknod(
+                dst,
+                kind,
+                Mode::from_bits_truncate(metadata.mode()),
+                metadata.rdev(),
+            )
+            .with_context(|| format!("Failed to mknod {:?}.", dst))?;
+        }
+        copy_metadata(&metadata, dst)
+    }
+
+    fn copy_file(&mut self, src: &Path, dst: &Path) -> Result<()> {
+        let mut src_file = File::open(src).with_context(|| format!("Failed to open {:?}.", src))?;
+        let mut dst_file =
+            File::create(dst).with_context(|| format!("Failed to create {:?}.", dst))?;
+        if self.uses_reflink {
+            match unsafe { ficlone(dst_file.as_raw_fd(), src_file.as_raw_fd() as _) } {
+                Ok(_) => {
+                    self.has_reflinked = true;
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::debug!(
+                        "Failed to reflink {:?}. Falling back to copying. {:?}",
+                        src,
+                        e
+                    );
+                    // Don't try again for the rest, since the filesystem doesn't support it.
+                    self.uses_reflink = false;
+                }
+            }
+        }
+        std::io::copy(&mut src_file, &mut dst_file)
+            .with_context(|| format!("Failed to copy {:?} to {:?}.", src, dst))?;
+        Ok(())
+    }
+}
+
+fn copy_metadata(metadata: &Metadata, dst: &Path) -> Result<()> {
+    nix::unistd::fchownat(
+        None,
+        dst,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("Failed to chown {:?}.", dst))?;
+    if !metadata.file_type().is_symlink() {
+        // Set the permission after chown, which clears the setuid and setgid bits.
+        fs::set_permissions(dst, fs::Permissions::from_mode(metadata.mode()))
+            .with_context(|| format!("Failed to chmod {:?}.", dst))?;
+    }
+    let to_timespec = |sec: i64, nsec: i64| {
+        TimeSpec::from(nix::libc::timespec {
+            tv_sec: sec as nix::libc::time_t,
+            tv_nsec: nsec as _,
+        })
+    };
+    nix::sys::stat::utimensat(
+        None,
+        dst,
+        &to_timespec(metadata.atime(), metadata.atime_nsec()),
+        &to_timespec(metadata.mtime(), metadata.mtime_nsec()),
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .with_context(|| format!("Failed to set the timestamps of {:?}.", dst))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_tree_copy {
+    use super::*;
+
+    #[test]
+    fn test_copy_tree() {
+        let src = tempfile::tempdir().unwrap();
+        let src = src.path();
+        fs::create_dir_all(src.join("etc/ssh")).unwrap();
+        fs::write(src.join("etc/hostname"), "distrod\n").unwrap();
+        fs::set_permissions(src.join("etc/hostname"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::hard_link(src.join("etc/hostname"), src.join("etc/hostname.hardlink")).unwrap();
+        std::os::unix::fs::symlink("hostname", src.join("etc/hostname.symlink")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent", src.join("etc/dangling")).unwrap();
+        nix::unistd::mkfifo(&src.join("etc/fifo"), Mode::from_bits_truncate(0o600)).unwrap();
+        fs::set_permissions(src.join("etc/ssh"), fs::Permissions::from_mode(0o500)).unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        let dst = dst.path().join("clone");
+        TreeCopier::default().copy(src, &dst).unwrap();
+
+        assert_eq!(
+            "distrod\n",
+            fs::read_to_string(dst.join("etc/hostname")).unwrap()
+        );
+        let metadata = fs::metadata(dst.join("etc/hostname")).unwrap();
+        assert_eq!(0o640, metadata.mode() & 0o7777);
+        assert_eq!(
+            fs::metadata(src.join("etc/hostname")).unwrap().mtime(),
+            metadata.mtime()
+        );
+        assert_eq!(
+            metadata.ino(),
+            fs::metadata(dst.join("etc/hostname.hardlink"))
+                .unwrap()
+                .ino()
+        );
+        assert_eq!(
+            Path::new("hostname"),
+            fs::read_link(dst.join("etc/hostname.symlink")).unwrap()
+        );
+        assert_eq!(
+            Path::new("/nonexistent"),
+            fs::read_link(dst.join("etc/dangling")).unwrap()
+        );
+        assert!(fs::symlink_metadata(dst.join("etc/fifo"))
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        assert_eq!(
+            0o500,
+            fs::metadata(dst.join("etc/ssh")).unwrap().mode() & 0o7777
+        );
+
+        // Nothing is cloned from a tree without regular files.
+        let empty = tempfile::tempdir().unwrap();
+        let mut copier = TreeCopier::default();
+        copier
+            .copy(empty.path(), dst.with_file_name("empty"))
+            .unwrap();
+        assert!(!copier.has_used_reflink());
+
+        // Make them removable by the tempdir.
+        fs::set_permissions(src.join("etc/ssh"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(dst.join("etc/ssh"), fs::Permissions::from_mode(0o700)).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+pub const WSL_CONF_PATH: &str = "/etc/wsl.conf";
+
+/// /etc/wsl.conf, which WSL reads when it launches the distro.
+/// Only the updated lines are rewritten so that the comments and the layout are kept.
+#[derive(Debug, Clone)]
+pub struct WslConf {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl WslConf {
+    /// Open wsl.conf. It's treated as empty if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WslConf> {
+        let path = path.as_ref();
+        let cont = match std::fs::read_to_string(path) {
+            Ok(cont) => cont,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", path)),
+        };
+        Ok(WslConf {
+            path: path.to_owned(),
+            lines: cont.lines().map(str::to_owned).collect(),
+        })
+    }
+
+    pub fn get_value(&self, section: &str, key: &str) -> Option<&str> {
+        let (start, end) = self.find_section(section)?;
+        self.lines[start..end]
+            .iter()
+            .find_map(|line| parse_key_value(line).filter(|(k, _)| *k == key))
+            .map(|(_, value)| value)
+    }
+
+    pub fn set_value(&mut self, section: &str, key: &str, value: &str) -> &mut Self {
+        let new_line = format!("{} = {}", key, value);
+        let (start, end) = match self.find_section(section) {
+            Some(range) => range,
+            None => {
+                if matches!(self.lines.last(), Some(line) if !line.trim().is_empty()) {
+                    self.lines.push(String::new());
+                }
+                self.lines.push(format!("[{}]", section));
+                self.lines.push(new_line);
+                return self;
+            }
+        };
+        if let Some(i) = (start..end)
+            .find(|i| matches!(parse_key_value(&self.lines[*i]), Some((k, _)) if k == key))
+        {
+            self.lines[i] = new_line;
+            return self;
+        }
+        // Insert after the last non-blank line of the section, not after the blank lines
+        // separating it from the next section.
+        let insert_at = (start..end)
+            .rev()
+            .find(|i| !self.lines[*i].trim().is_empty())
+            .map_or(start, |i| i + 1);
+        self.lines.insert(insert_at, new_line);
+        self
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let mut cont = self.lines.join("\n");
+        cont.push('\n');
+        // Write in place because /etc/wsl.conf is bind-mounted into the container.
+        std::fs::write(&self.path, cont)
+            .with_context(|| format!("Failed to write {:?}.", &self.path))
+    }
+
+    /// Get the range of the lines of the section, excluding its header.
+    fn find_section(&self, section: &str) -> Option<(usize, usize)> {
+        let header = self
+            .lines
+            .iter()
+            .position(|line| parse_section_header(line) == Some(section))?;
+        let end = self.lines[header + 1..]
+            .iter()
+            .position(|line| parse_section_header(line).is_some())
+            .map_or(self.lines.len(), |i| header + 1 + i);
+        Some((header + 1, end))
+    }
+}
+
+fn parse_section_header(line: &str) -> Option<&str> {
+    let line = line.trim();
+    line.strip_prefix('[')?.strip_suffix(']').map(str::trim)
+}
+
+fn parse_key_value(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    Some((key.trim(), value.trim()))
+}
+
+#[cfg(test)]
+mod test_wsl_conf {
+    use super::*;
+    use std::io::Write;
+
+    fn open_with(cont: &str) -> (tempfile::NamedTempFile, WslConf) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(cont.as_bytes()).unwrap();
+        let conf = WslConf::open(file.path()).unwrap();
+        (file, conf)
+    }
+
+    #[test]
+    fn test_get_value() {
+        let (_file, conf) = open_with(
+            "# comment\n[automount]\nenabled = true\n\n[user]\n#default = foo\ndefault=bar\n",
+        );
+        assert_eq!(Some("bar"), conf.get_value("user", "default"));
+        assert_eq!(Some("true"), conf.get_value("automount", "enabled"));
+        assert_eq!(None, conf.get_value("automount", "default"));
+        assert_eq!(None, conf.get_value("network", "hostname"));
+    }
+
+    #[test]
+    fn test_set_existing_value() {
+        let (file, mut conf) = open_with("[user]\n# The default user\ndefault = foo\n");
+        conf.set_value("user", "default", "bar").write().unwrap();
+        assert_eq!(
+            "[user]\n# The default user\ndefault = bar\n",
+            std::fs::read_to_string(file.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_new_value() {
+        let (file, mut conf) = open_with("[user]\n\n[automount]\nenabled = true\n");
+        conf.set_value("user", "default", "foo");
+        conf.set_value("network", "hostname", "bar");
+        conf.write().unwrap();
+        assert_eq!(
+            "[user]\ndefault = foo\n\n[automount]\nenabled = true\n\n[network]\nhostname = bar\n",
+            std::fs::read_to_string(file.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_open_nonexistent_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wsl.conf");
+        let mut conf = WslConf::open(&path).unwrap();
+        assert_eq!(None, conf.get_value("user", "default"));
+        conf.set_value("user", "default", "foo").write().unwrap();
+        assert_eq!(
+            "[user]\ndefault = foo\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+}
@@ -1,14 +1,15 @@
 use std::{
     collections::{HashMap, HashSet},
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     iter::FromIterator,
-    path::PathBuf,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use procfs::process;
 
-use crate::{envfile::PathVariable, mount_info::get_mount_entries};
+use crate::{distrod_config::DistrodConfig, envfile::PathVariable, mount_info::get_mount_entries};
 
 pub fn get_wsl_drive_path(drive_letter: &str) -> Result<Option<PathBuf>> {
     let entries = get_mount_entries().with_context(|| "Failed to get the mount entries.")?;
@@ -24,8 +25,9 @@ pub fn get_wsl_drive_path(drive_letter: &str) -> Result<Option<PathBuf>> {
         }
         // Windows 11
         if e.source == "drvfs"
-            && e.attributes
-                .contains(&format!("path={}:\\", drive_letter.to_uppercase()))
+            && e.options
+                .iter()
+                .any(|option| option.contains(&format!("path={}:\\", drive_letter.to_uppercase())))
         {
             return Some(e.path);
         }
@@ -100,11 +102,46 @@ pub fn collect_wsl_env_vars() -> Result<HashMap<OsString, OsString>> {
     bail!("Couldn't find WSL envs");
 }
 
-fn get_wsl_interop_env_names() -> Vec<OsString> {
-    ["WSL_INTEROP", "WSLENV", "WSL_DISTRO_NAME"]
+/// The variables which WSL sets for the inter-op with Windows.
+pub const REQUIRED_WSL_INTEROP_ENV_NAMES: [&str; 3] = ["WSL_INTEROP", "WSLENV", "WSL_DISTRO_NAME"];
+
+/// The variables which WSLg sets so that GUI apps can connect to its servers. They are forwarded
+/// by default so that the GUI apps launched by Systemd services work as well. They only tell where
+/// the display and the sound servers of WSLg are, and their values must match
+/// `wsl_env_harmless_pattern` as WSLENV does before they are passed to Systemd.
+pub const DEFAULT_FORWARDED_WSL_ENV_NAMES: [&str; 4] = [
+    "WSL2_GUI_APPS_ENABLED",
+    "WAYLAND_DISPLAY",
+    "DISPLAY",
+    "PULSE_SERVER",
+];
+
+/// Get the names of the variables to be forwarded into the distro, which are the required WSL
+/// inter-op variables and `forwarded_wsl_envs` in the config, or the WSLg variables by default.
+pub fn get_wsl_interop_env_names() -> Vec<OsString> {
+    let config = DistrodConfig::get().ok();
+    let forwarded = config
+        .as_ref()
+        .and_then(|config| config.distrod.forwarded_wsl_envs.as_deref());
+    get_wsl_interop_env_names_with(forwarded)
+}
+
+fn get_wsl_interop_env_names_with(forwarded: Option<&[String]>) -> Vec<OsString> {
+    let forwarded: Vec<&str> = match forwarded {
+        Some(forwarded) => forwarded.iter().map(String::as_str).collect(),
+        None => DEFAULT_FORWARDED_WSL_ENV_NAMES.to_vec(),
+    };
+    let mut names: Vec<OsString> = REQUIRED_WSL_INTEROP_ENV_NAMES
         .iter()
         .map(OsString::from)
-        .collect()
+        .collect();
+    for name in forwarded {
+        let name = OsString::from(name);
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
 }
 
 pub fn collect_wsl_paths() -> Result<Vec<String>> {
@@ -114,14 +151,118 @@ pub fn collect_wsl_paths() -> Result<Vec<String>> {
         return Ok(vec![]);
     }
     let wsl_mount_point = wsl_mount_point.unwrap();
-    let wsl_mount_point = wsl_mount_point.to_string_lossy();
+    let path = match std::env::var_os("PATH") {
+        Some(path) => path,
+        None => return Ok(vec![]),
+    };
+    Ok(filter_wsl_paths(&path, &wsl_mount_point))
+}
 
-    let path = std::env::var("PATH")?;
-    let path = PathVariable::parse(&path);
-    let wsl_paths = path
+/// Get the paths under the WSL drive mount point in the PATH variable.
+/// The paths which are not valid UTF-8 are skipped with a warning because they can't be written
+/// to the env script without being corrupted.
+fn filter_wsl_paths(path: &OsStr, wsl_mount_point: &Path) -> Vec<String> {
+    let mut utf8_paths = vec![];
+    for entry in path.as_bytes().split(|b| *b == b':') {
+        match std::str::from_utf8(entry) {
+            Ok(entry) => utf8_paths.push(entry),
+            Err(_) => {
+                if Path::new(OsStr::from_bytes(entry)).starts_with(wsl_mount_point) {
+                    log::warn!(
+                        "Skipping a WSL path which is not valid UTF-8: {:?}",
+                        OsStr::from_bytes(entry)
+                    );
+                }
+            }
+        }
+    }
+    let utf8_path = utf8_paths.join(":");
+    let wsl_mount_point = wsl_mount_point.to_string_lossy();
+    PathVariable::parse(&utf8_path)
         .iter()
         .filter(|path| path.starts_with(wsl_mount_point.as_ref()))
         .map(|p| p.to_owned())
-        .collect();
-    Ok(wsl_paths)
+        .collect()
+}
+
+/// The namespaces which Distrod relies on, and which WSL1 doesn't implement.
+const REQUIRED_NAMESPACES: &[&str] = &["mnt", "pid", "uts"];
+
+/// Fail with a hint to convert the distro to WSL2 if the kernel lacks the features which Distrod
+/// requires, which means that the distro most likely runs on WSL1.
+pub fn check_wsl2_kernel_features() -> Result<()> {
+    let missing = find_missing_namespaces(Path::new("/proc"));
+    if !missing.is_empty() {
+        bail!(
+            "The kernel doesn't support the {} namespace(s), which Distrod requires. \
+             Distrod works only on WSL2. If this distro is a WSL1 distro, convert it by running \
+             `wsl --set-version <distro name> 2` on Windows.",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn find_missing_namespaces(proc: &Path) -> Vec<&'static str> {
+    let ns_dir = proc.join("self/ns");
+    REQUIRED_NAMESPACES
+        .iter()
+        .filter(|ns| !ns_dir.join(ns).exists())
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod test_wsl_interop {
+    use super::*;
+
+    #[test]
+    fn test_filter_wsl_paths() {
+        let path = OsStr::from_bytes(
+            b"/usr/bin:/mnt/c/Windows:/mnt/c/Users/\xff\xfe/bin:/mnt/c/Program Files/app:/bin",
+        );
+        assert_eq!(
+            vec!["/mnt/c/Windows", "/mnt/c/Program Files/app"],
+            filter_wsl_paths(path, Path::new("/mnt"))
+        );
+        // A non-UTF-8 path outside of the WSL drives doesn't matter.
+        let path = OsStr::from_bytes(b"/opt/\xff:/mnt/d/tools");
+        assert_eq!(
+            vec!["/mnt/d/tools"],
+            filter_wsl_paths(path, Path::new("/mnt"))
+        );
+    }
+
+    #[test]
+    fn test_find_missing_namespaces() {
+        let proc = tempfile::tempdir().unwrap();
+        assert_eq!(
+            REQUIRED_NAMESPACES.to_vec(),
+            find_missing_namespaces(proc.path())
+        );
+        std::fs::create_dir_all(proc.path().join("self/ns")).unwrap();
+        std::fs::write(proc.path().join("self/ns/mnt"), "").unwrap();
+        assert_eq!(vec!["pid", "uts"], find_missing_namespaces(proc.path()));
+    }
+
+    #[test]
+    fn test_get_wsl_interop_env_names() {
+        let names = get_wsl_interop_env_names_with(None);
+        for name in REQUIRED_WSL_INTEROP_ENV_NAMES
+            .iter()
+            .chain(DEFAULT_FORWARDED_WSL_ENV_NAMES.iter())
+        {
+            assert!(names.contains(&OsString::from(name)), "{} is missing", name);
+        }
+
+        let forwarded = vec!["DISPLAY".to_owned(), "WSLENV".to_owned()];
+        assert_eq!(
+            vec!["WSL_INTEROP", "WSLENV", "WSL_DISTRO_NAME", "DISPLAY"],
+            get_wsl_interop_env_names_with(Some(&forwarded))
+        );
+        assert_eq!(
+            REQUIRED_WSL_INTEROP_ENV_NAMES.to_vec(),
+            get_wsl_interop_env_names_with(Some(&[]))
+        );
+    }
 }
@@ -0,0 +1,230 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::metrics::MetricsRegistry;
+
+/// The state of a listening socket in /proc/net/tcp. See include/net/tcp_states.h.
+const TCP_LISTEN: u8 = 0x0A;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListeningSocket {
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+pub struct AutoForwardConfig {
+    pub bind_addr: IpAddr,
+    pub dest_host: String,
+    pub proc_net_dir: PathBuf,
+    pub excluded_ports: BTreeSet<u16>,
+    pub interval: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Forward the ports that are listened to in the distro, starting and stopping the forwarders
+/// as the services come and go.
+pub async fn run_auto_forwarding(
+    config: AutoForwardConfig,
+    registry: Arc<MetricsRegistry>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    drain_tx: mpsc::Sender<()>,
+) {
+    let mut forwarders: HashMap<u16, JoinHandle<()>> = HashMap::new();
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown_rx.changed() => return,
+        }
+        let ports = match discover_forwardable_ports(&config).await {
+            Ok(ports) => ports,
+            Err(e) => {
+                log::warn!("Failed to discover the listening ports. {:?}", e);
+                continue;
+            }
+        };
+        let stopped_ports: Vec<u16> = forwarders
+            .keys()
+            .filter(|port| !ports.contains(port))
+            .cloned()
+            .collect();
+        for port in stopped_ports {
+            log::info!("Port {} is no longer listened. Stop forwarding it.", port);
+            if let Some(handle) = forwarders.remove(&port) {
+                handle.abort();
+            }
+        }
+        for port in ports {
+            if forwarders.contains_key(&port) {
+                continue;
+            }
+            log::info!("Port {} is listened. Start forwarding it.", port);
+            forwarders.insert(
+                port,
+                crate::spawn_forwarder(
+                    SocketAddr::new(config.bind_addr, port),
+                    &config.dest_host,
+                    &registry,
+                    config.idle_timeout,
+                    shutdown_rx.clone(),
+                    drain_tx.clone(),
+                ),
+            );
+        }
+    }
+}
+
+async fn discover_forwardable_ports(config: &AutoForwardConfig) -> Result<BTreeSet<u16>> {
+    let mut sockets = vec![];
+    for name in &["tcp", "tcp6"] {
+        let path = config.proc_net_dir.join(name);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(cont) => sockets.extend(
+                parse_proc_net_tcp(&cont)
+                    .with_context(|| format!("Failed to parse {:?}", &path))?,
+            ),
+            // tcp6 doesn't exist if IPv6 is disabled.
+            Err(e) if *name == "tcp6" && e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", &path)),
+        }
+    }
+    Ok(sockets
+        .into_iter()
+        .filter(|socket| !socket.addr.is_loopback())
+        .map(|socket| socket.port)
+        .filter(|port| !config.excluded_ports.contains(port))
+        .collect())
+}
+
+/// Parse the content of /proc/net/tcp or /proc/net/tcp6, and return the listening sockets.
+pub fn parse_proc_net_tcp(cont: &str) -> Result<Vec<ListeningSocket>> {
+    let mut sockets = vec![];
+    // The first line is the header.
+    for line in cont.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 {
+            continue;
+        }
+        let state = u8::from_str_radix(fields[3], 16)
+            .with_context(|| format!("Invalid state: '{}'", fields[3]))?;
+        if state != TCP_LISTEN {
+            continue;
+        }
+        sockets.push(parse_local_address(fields[1])?);
+    }
+    Ok(sockets)
+}
+
+/// Parse an address such as "0100007F:0016", whose IP address is written as the hex of
+/// 32-bit words in the host byte order, which is little endian on x86 and arm.
+fn parse_local_address(field: &str) -> Result<ListeningSocket> {
+    let (addr, port) = match field.split_once(':') {
+        Some(pair) => pair,
+        None => bail!("Invalid address: '{}'", field),
+    };
+    let port =
+        u16::from_str_radix(port, 16).with_context(|| format!("Invalid port: '{}'", port))?;
+    let mut bytes = vec![];
+    for i in (0..addr.len()).step_by(8) {
+        let word = addr
+            .get(i..i + 8)
+            .and_then(|word| u32::from_str_radix(word, 16).ok())
+            .with_context(|| format!("Invalid IP address: '{}'", addr))?;
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    let addr = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&bytes);
+            let addr = Ipv6Addr::from(octets);
+            // Treat IPv4-mapped addresses such as ::ffff:127.0.0.1 as IPv4 ones.
+            addr.to_ipv4()
+                .filter(|_| addr.segments()[..6] == [0, 0, 0, 0, 0, 0xffff])
+                .map_or(IpAddr::V6(addr), IpAddr::V4)
+        }
+        _ => bail!("Invalid IP address: '{}'", addr),
+    };
+    Ok(ListeningSocket { addr, port })
+}
+
+#[cfg(test)]
+mod test_auto {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_net_tcp() {
+        let cont = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21101 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0CEA 00000000:0000 0A 00000000:00000000 00:00000000 00000000   106        0 23456 1 0000000000000000 100 0 0 10 0
+   2: 6401A8C0:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 34567 1 0000000000000000 100 0 0 10 0
+   3: 6401A8C0:0016 0101A8C0:D431 01 00000000:00000000 02:0009A4B2 00000000     0        0 45678 4 0000000000000000 20 4 29 10 -1
+";
+        assert_eq!(
+            vec![
+                ListeningSocket {
+                    addr: "0.0.0.0".parse().unwrap(),
+                    port: 22
+                },
+                ListeningSocket {
+                    addr: "127.0.0.1".parse().unwrap(),
+                    port: 3306
+                },
+                ListeningSocket {
+                    addr: "192.168.1.100".parse().unwrap(),
+                    port: 8080
+                },
+            ],
+            parse_proc_net_tcp(cont).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_proc_net_tcp6() {
+        let cont = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000000000000:0016 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21103 1 0000000000000000 100 0 0 10 0
+   1: 00000000000000000000000001000000:0277 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21104 1 0000000000000000 100 0 0 10 0
+   2: 0000000000000000FFFF00000100007F:1F90 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 21105 1 0000000000000000 100 0 0 10 0
+";
+        assert_eq!(
+            vec![
+                ListeningSocket {
+                    addr: "::".parse().unwrap(),
+                    port: 22
+                },
+                ListeningSocket {
+                    addr: "::1".parse().unwrap(),
+                    port: 631
+                },
+                ListeningSocket {
+                    addr: "127.0.0.1".parse().unwrap(),
+                    port: 8080
+                },
+            ],
+            parse_proc_net_tcp(cont).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_proc_net_tcp() {
+        let header = "  sl  local_address rem_address   st\n";
+        assert!(
+            parse_proc_net_tcp(&format!("{}   0: 0000:0016 00000000:0000 0A\n", header)).is_err()
+        );
+        assert!(
+            parse_proc_net_tcp(&format!("{}   0: 00000000 00000000:0000 0A\n", header)).is_err()
+        );
+        assert!(
+            parse_proc_net_tcp(&format!("{}   0: 00000000:0016 00000000:0000 ZZ\n", header))
+                .is_err()
+        );
+        assert!(parse_proc_net_tcp(header).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::ProxyOpts;
+
+/// The options of `portproxy proxy`, which can be loaded from a TOML file by `--config`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub dest_addr: String,
+    pub tcp4: Vec<u16>,
+    pub bind: IpAddr,
+    pub metrics_addr: Option<String>,
+    pub metrics_log_interval: Option<u64>,
+    pub auto: bool,
+    pub proc_net_dir: PathBuf,
+    pub exclude: Vec<u16>,
+    pub auto_interval: u64,
+    pub idle_timeout: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            dest_addr: String::new(),
+            tcp4: vec![],
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            metrics_addr: None,
+            metrics_log_interval: None,
+            auto: false,
+            proc_net_dir: PathBuf::from("/proc/net"),
+            exclude: vec![],
+            auto_interval: 5,
+            idle_timeout: 0,
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let cont = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the config file {:?}.", path))?;
+        toml::from_str(&cont).with_context(|| format!("Failed to parse {:?}.", path))
+    }
+
+    /// Load the config file given by `--config` if any, and override it with the other options
+    /// given in the command line.
+    pub fn from_opts(opts: &ProxyOpts) -> Result<Self> {
+        let mut config = match opts.config {
+            Some(ref path) => ProxyConfig::from_file(path)?,
+            None => ProxyConfig::default(),
+        };
+        config.override_with(opts);
+        if config.dest_addr.is_empty() {
+            bail!(
+                "The destination address is given neither in the command line nor the config file."
+            );
+        }
+        Ok(config)
+    }
+
+    fn override_with(&mut self, opts: &ProxyOpts) {
+        if let Some(ref dest_addr) = opts.dest_addr {
+            self.dest_addr = dest_addr.clone();
+        }
+        if !opts.tcp4.is_empty() {
+            self.tcp4 = opts.tcp4.clone();
+        }
+        if let Some(bind) = opts.bind {
+            self.bind = bind;
+        }
+        if opts.metrics_addr.is_some() {
+            self.metrics_addr = opts.metrics_addr.clone();
+        }
+        if opts.metrics_log_interval.is_some() {
+            self.metrics_log_interval = opts.metrics_log_interval;
+        }
+        self.auto |= opts.auto;
+        if let Some(ref proc_net_dir) = opts.proc_net_dir {
+            self.proc_net_dir = proc_net_dir.clone();
+        }
+        if !opts.exclude.is_empty() {
+            self.exclude = opts.exclude.clone();
+        }
+        if let Some(auto_interval) = opts.auto_interval {
+            self.auto_interval = auto_interval;
+        }
+        if let Some(idle_timeout) = opts.idle_timeout {
+            self.idle_timeout = idle_timeout;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+    use crate::{Opts, Subcommand};
+    use structopt::StructOpt;
+
+    const CONFIG: &str = r#"
+dest_addr = "172.29.0.1"
+tcp4 = [22, 80]
+bind = "172.29.0.2"
+idle_timeout = 600
+auto = true
+exclude = [8080]
+"#;
+
+    fn parse_opts(args: &[&str]) -> ProxyOpts {
+        match Opts::from_iter(["portproxy", "proxy"].iter().chain(args)).command {
+            Subcommand::Proxy(proxy_opts) => proxy_opts,
+            _ => panic!("Not a proxy command."),
+        }
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: ProxyConfig = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            ProxyConfig {
+                dest_addr: "172.29.0.1".to_owned(),
+                tcp4: vec![22, 80],
+                bind: "172.29.0.2".parse().unwrap(),
+                idle_timeout: 600,
+                auto: true,
+                exclude: vec![8080],
+                ..ProxyConfig::default()
+            },
+            config
+        );
+
+        assert_eq!(ProxyConfig::default(), toml::from_str("").unwrap());
+        assert!(toml::from_str::<ProxyConfig>("unknown_key = 1").is_err());
+        assert!(toml::from_str::<ProxyConfig>("bind = \"eth0\"").is_err());
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("portproxy.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = ProxyConfig::from_opts(&parse_opts(&["--config", path])).unwrap();
+        assert_eq!(toml::from_str::<ProxyConfig>(CONFIG).unwrap(), config);
+
+        let config = ProxyConfig::from_opts(&parse_opts(&[
+            "--config",
+            path,
+            "172.29.0.100",
+            "-t",
+            "443",
+            "--idle-timeout",
+            "0",
+        ]))
+        .unwrap();
+        assert_eq!("172.29.0.100", config.dest_addr);
+        assert_eq!(vec![443], config.tcp4);
+        assert_eq!(0, config.idle_timeout);
+        // The values not given in the command line are kept.
+        assert_eq!("172.29.0.2".parse::<IpAddr>().unwrap(), config.bind);
+        assert_eq!(vec![8080], config.exclude);
+        assert!(config.auto);
+    }
+
+    #[test]
+    fn test_dest_addr_is_required() {
+        assert!(ProxyConfig::from_opts(&parse_opts(&["-t", "22"])).is_err());
+        let config = ProxyConfig::from_opts(&parse_opts(&["172.29.0.1", "-t", "22"])).unwrap();
+        assert_eq!("0.0.0.0".parse::<IpAddr>().unwrap(), config.bind);
+        assert_eq!(5, config.auto_interval);
+    }
+}
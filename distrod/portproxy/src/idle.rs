@@ -0,0 +1,101 @@
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead, ReadBuf};
+use tokio::time::Instant;
+
+/// The last time when bytes flowed through a connection in either direction.
+pub struct ConnectionActivity {
+    last_active: Mutex<Instant>,
+}
+
+impl ConnectionActivity {
+    pub fn new() -> Self {
+        ConnectionActivity {
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().expect("activity lock is poisoned") = Instant::now();
+    }
+
+    pub fn last_active(&self) -> Instant {
+        *self.last_active.lock().expect("activity lock is poisoned")
+    }
+}
+
+/// Wait until no bytes flow for the given duration.
+pub async fn wait_until_idle(activity: &ConnectionActivity, idle_timeout: Duration) {
+    loop {
+        let deadline = activity.last_active() + idle_timeout;
+        if Instant::now() >= deadline {
+            return;
+        }
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// A reader which records the time when it reads bytes to the activity, if any.
+pub struct ActivityReader<'a, R> {
+    inner: R,
+    activity: Option<&'a ConnectionActivity>,
+}
+
+impl<'a, R> ActivityReader<'a, R> {
+    pub fn new(inner: R, activity: Option<&'a ConnectionActivity>) -> Self {
+        ActivityReader { inner, activity }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ActivityReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(activity)) = (&result, self.activity) {
+            if buf.filled().len() > filled_before {
+                activity.touch();
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test_idle {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_wait_until_idle() {
+        let activity = ConnectionActivity::new();
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        activity.touch();
+        wait_until_idle(&activity, Duration::from_millis(500)).await;
+        assert!(start.elapsed() >= Duration::from_millis(800));
+    }
+
+    #[tokio::test]
+    async fn test_activity_reader() {
+        let activity = ConnectionActivity::new();
+        let start = activity.last_active();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut empty = ActivityReader::new(&b""[..], Some(&activity));
+        let mut buf = vec![];
+        empty.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(start, activity.last_active());
+
+        let mut reader = ActivityReader::new(&b"hello"[..], Some(&activity));
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"hello", buf.as_slice());
+        assert!(activity.last_active() > start);
+    }
+}
@@ -1,10 +1,35 @@
 use anyhow::{Context, Result};
+use auto::AutoForwardConfig;
+use config::ProxyConfig;
+use idle::{wait_until_idle, ActivityReader, ConnectionActivity};
 use libs::cli_ui::init_logger;
+use metrics::{CountingReader, MetricsRegistry, PortMetrics};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 use strum::{EnumString, EnumVariantNames};
 use tokio::io::AsyncWriteExt;
 use tokio::io::{self, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+mod auto;
+mod config;
+mod idle;
+mod metrics;
+
+/// How long in-flight connections are allowed to drain after a shutdown signal.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How many times binding a port is tried. The port may be still held by the previous process or
+/// the forwarder which has just been stopped, and be released soon.
+const BIND_ATTEMPTS: u32 = 6;
+
+/// The delay before the first retry of binding a port, which is doubled on every retry.
+const BIND_RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "portproxy", rename_all = "kebab")]
@@ -25,9 +50,49 @@ pub enum Subcommand {
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
 pub struct ProxyOpts {
-    pub dest_addr: String,
+    /// The address to forward to. Required unless it's given in the config file.
+    pub dest_addr: Option<String>,
+    /// Load the options from this TOML file. The options in the command line override them.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
     #[structopt(short, long)]
     pub tcp4: Vec<u16>,
+    /// The address to listen on, e.g. the address of the WSL NAT interface. [default: 0.0.0.0]
+    #[structopt(long, parse(try_from_str = parse_bind_addr))]
+    pub bind: Option<IpAddr>,
+    /// Serve the connection metrics in text/plain at this address, e.g. 127.0.0.1:9100.
+    #[structopt(long)]
+    pub metrics_addr: Option<String>,
+    /// Log the connection metrics at this interval in seconds.
+    #[structopt(long)]
+    pub metrics_log_interval: Option<u64>,
+    /// Forward the ports that are listened to on non-loopback addresses in the distro,
+    /// following the services as they start and stop.
+    #[structopt(long)]
+    pub auto: bool,
+    /// The directory of the distro's /proc/net, which --auto reads to find the listening ports.
+    /// [default: /proc/net]
+    #[structopt(long, parse(from_os_str))]
+    pub proc_net_dir: Option<PathBuf>,
+    /// The ports which --auto doesn't forward.
+    #[structopt(long)]
+    pub exclude: Vec<u16>,
+    /// The interval in seconds at which --auto looks for the listening ports. [default: 5]
+    #[structopt(long)]
+    pub auto_interval: Option<u64>,
+    /// Close the connections through which no bytes flow for this many seconds.
+    /// 0 disables the timeout. [default: 0]
+    #[structopt(long)]
+    pub idle_timeout: Option<u64>,
+}
+
+fn parse_bind_addr(addr: &str) -> std::result::Result<IpAddr, String> {
+    addr.parse().map_err(|_| {
+        format!(
+            "'{}' is not an IP address. Give an IPv4 or IPv6 address such as 0.0.0.0 or ::.",
+            addr
+        )
+    })
 }
 
 #[derive(Debug, StructOpt)]
@@ -45,7 +110,7 @@ pub enum ShowItem {
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = Opts::from_args();
-    init_logger("PortProxy".to_owned(), opts.log_level.clone());
+    init_logger("PortProxy".to_owned(), opts.log_level.clone(), false);
 
     if let Err(e) = run(opts).await {
         log::error!("{:?}", e);
@@ -57,7 +122,10 @@ async fn main() -> Result<()> {
 
 async fn run(opts: Opts) -> Result<()> {
     match opts.command {
-        Subcommand::Proxy(proxy_opts) => run_proxy(proxy_opts).await,
+        Subcommand::Proxy(proxy_opts) => {
+            let config = ProxyConfig::from_opts(&proxy_opts)?;
+            run_proxy(config).await
+        }
         Subcommand::Show(show_opts) => run_show(show_opts)?,
     };
     log::trace!("Exiting run.");
@@ -93,57 +161,276 @@ fn run_show(_opts: ShowOpts) -> Result<()> {
     bail!("Show command is not implemented on Windows.");
 }
 
-async fn run_proxy(opts: ProxyOpts) {
+async fn run_proxy(opts: ProxyConfig) {
     let mut handles = vec![];
-    for tcp_port in opts.tcp4 {
+    // Every listener and connection task holds a clone of `drain_tx`, so that `drain_rx`
+    // gets closed when all of them have finished.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    let registry = Arc::new(MetricsRegistry::default());
+    let idle_timeout = Some(Duration::from_secs(opts.idle_timeout)).filter(|t| !t.is_zero());
+    for &tcp_port in &opts.tcp4 {
         if tcp_port == 0 {
             log::info!("Skipping port 0");
             continue;
         }
-        let dest_addr = format!("{}:{}", &opts.dest_addr, tcp_port);
-        handles.push(tokio::spawn(async move {
-            if let Err(e) = proxy_tcp_port(tcp_port, dest_addr).await {
+        handles.push(spawn_forwarder(
+            SocketAddr::new(opts.bind, tcp_port),
+            &opts.dest_addr,
+            &registry,
+            idle_timeout,
+            shutdown_rx.clone(),
+            drain_tx.clone(),
+        ));
+    }
+    if opts.auto {
+        let config = AutoForwardConfig {
+            bind_addr: opts.bind,
+            dest_host: opts.dest_addr.clone(),
+            proc_net_dir: opts.proc_net_dir.clone(),
+            // The ports given explicitly are already forwarded.
+            excluded_ports: opts
+                .exclude
+                .iter()
+                .chain(opts.tcp4.iter())
+                .cloned()
+                .collect(),
+            interval: Duration::from_secs(opts.auto_interval.max(1)),
+            idle_timeout,
+        };
+        handles.push(tokio::spawn(auto::run_auto_forwarding(
+            config,
+            registry.clone(),
+            shutdown_rx.clone(),
+            drain_tx.clone(),
+        )));
+    }
+    drop(drain_tx);
+    if let Some(interval) = opts.metrics_log_interval.filter(|interval| *interval > 0) {
+        tokio::spawn(metrics::log_metrics_periodically(
+            registry.clone(),
+            Duration::from_secs(interval),
+        ));
+    }
+    if let Some(metrics_addr) = opts.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(metrics_addr, registry).await {
                 log::error!("{:?}", e);
             }
-        }));
+        });
+    }
+
+    let wait_listeners = async {
+        for handle in handles {
+            let _ = handle.await;
+        }
+    };
+    tokio::select! {
+        _ = wait_listeners => return,
+        _ = wait_for_shutdown_signal() => {},
+    }
+    log::info!("Shutting down. Waiting for the connections to be closed.");
+    let _ = shutdown_tx.send(true);
+    if tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, drain_rx.recv())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Some connections are still open after {} seconds. Closing them.",
+            SHUTDOWN_GRACE_PERIOD.as_secs()
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn wait_for_shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            log::warn!("Failed to listen to SIGTERM. {:?}", e);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = wait_for_ctrl_c() => {},
     }
-    for handle in handles {
-        let _ = handle.await;
+}
+
+#[cfg(target_os = "windows")]
+async fn wait_for_shutdown_signal() {
+    wait_for_ctrl_c().await
+}
+
+async fn wait_for_ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Failed to listen to Ctrl-C. {:?}", e);
+        std::future::pending::<()>().await;
     }
 }
 
-async fn proxy_tcp_port(port: u16, dest_addr: String) -> Result<()> {
-    let listen_addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&listen_addr)
+fn spawn_forwarder(
+    listen_addr: SocketAddr,
+    dest_host: &str,
+    registry: &MetricsRegistry,
+    idle_timeout: Option<Duration>,
+    shutdown_rx: watch::Receiver<bool>,
+    drain_tx: mpsc::Sender<()>,
+) -> JoinHandle<()> {
+    let port = listen_addr.port();
+    let dest_addr = format!("{}:{}", dest_host, port);
+    let metrics = registry.get_or_register(port);
+    tokio::spawn(async move {
+        if let Err(e) = proxy_tcp_port(
+            listen_addr,
+            dest_addr,
+            metrics,
+            idle_timeout,
+            shutdown_rx,
+            drain_tx,
+        )
         .await
-        .with_context(|| format!("Failed to bind {}.", &listen_addr))?;
-    println!("Forwarding {} to {}", &listen_addr, &dest_addr);
+        {
+            log::error!("{:?}", e);
+        }
+    })
+}
+
+async fn proxy_tcp_port(
+    listen_addr: SocketAddr,
+    dest_addr: String,
+    metrics: Arc<PortMetrics>,
+    idle_timeout: Option<Duration>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    drain_tx: mpsc::Sender<()>,
+) -> Result<()> {
+    let port = listen_addr.port();
+    let listener = match bind_with_backoff(
+        listen_addr,
+        BIND_ATTEMPTS,
+        BIND_RETRY_INITIAL_DELAY,
+        &mut shutdown_rx,
+    )
+    .await?
+    {
+        Some(listener) => listener,
+        None => return Ok(()),
+    };
+    println!("Forwarding {} to {}", listen_addr, &dest_addr);
     loop {
-        let (stream, _) = listener
-            .accept()
-            .await
-            .with_context(|| format!("Failed to accept on the port {}.", port))?;
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => {
+                accepted.with_context(|| format!("Failed to accept on the port {}.", port))?
+            },
+            _ = shutdown_rx.changed() => {
+                log::debug!("Stopped accepting on the port {}.", port);
+                return Ok(());
+            },
+        };
         let dest = dest_addr.clone();
+        let metrics = metrics.clone();
+        let drain_tx = drain_tx.clone();
         tokio::spawn(async move {
-            if let Err(e) = proxy_tcp_stream(stream, dest).await {
+            if let Err(e) = proxy_tcp_stream(stream, dest, &metrics, idle_timeout).await {
                 log::error!("{:?}", e);
             }
+            drop(drain_tx);
         });
     }
 }
 
-async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Result<()> {
-    let buf_size = 1 << 16;
+/// Bind the address, retrying with exponential backoff on failure. None if the shutdown is
+/// requested while waiting for a retry.
+async fn bind_with_backoff(
+    listen_addr: SocketAddr,
+    attempts: u32,
+    initial_delay: Duration,
+    shutdown_rx: &mut watch::Receiver<bool>,
+) -> Result<Option<TcpListener>> {
+    let mut delay = initial_delay;
+    for attempt in 1.. {
+        let e = match TcpListener::bind(listen_addr).await {
+            Ok(listener) => return Ok(Some(listener)),
+            Err(e) => e,
+        };
+        if attempt >= attempts {
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to bind {} after {} attempts.",
+                    listen_addr, attempts
+                )
+            });
+        }
+        log::warn!(
+            "Failed to bind {}. Retrying in {} seconds. {}",
+            listen_addr,
+            delay.as_secs_f32(),
+            e
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = shutdown_rx.changed() => return Ok(None),
+        }
+        delay *= 2;
+    }
+    unreachable!()
+}
+
+async fn proxy_tcp_stream(
+    mut client: TcpStream,
+    upstream_addr: String,
+    metrics: &PortMetrics,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let _connection = metrics.start_connection();
 
     let mut upstream = TcpStream::connect(upstream_addr)
         .await
         .with_context(|| "Failed to connect to the upstream.")?;
 
+    let idle_timeout = match idle_timeout {
+        Some(idle_timeout) => idle_timeout,
+        None => return copy_bidirectional(&mut client, &mut upstream, metrics, None).await,
+    };
+    let activity = ConnectionActivity::new();
+    let is_idle = tokio::select! {
+        result = copy_bidirectional(&mut client, &mut upstream, metrics, Some(&activity)) => {
+            result?;
+            false
+        },
+        _ = wait_until_idle(&activity, idle_timeout) => true,
+    };
+    if is_idle {
+        log::debug!(
+            "Closing the connection idle for {} seconds.",
+            idle_timeout.as_secs_f64()
+        );
+        let _ = client.shutdown().await;
+        let _ = upstream.shutdown().await;
+    }
+    Ok(())
+}
+
+async fn copy_bidirectional(
+    client: &mut TcpStream,
+    upstream: &mut TcpStream,
+    metrics: &PortMetrics,
+    activity: Option<&ConnectionActivity>,
+) -> Result<()> {
+    let buf_size = 1 << 16;
     let (client_read, mut client_write) = client.split();
     let (upstream_read, mut upstream_write) = upstream.split();
 
     let client_to_upstream = async {
-        let mut buf_read = BufReader::with_capacity(buf_size, client_read);
+        let mut buf_read = BufReader::with_capacity(
+            buf_size,
+            CountingReader::new(
+                ActivityReader::new(client_read, activity),
+                &metrics.bytes_to_upstream,
+            ),
+        );
         io::copy_buf(&mut buf_read, &mut upstream_write)
             .await
             .with_context(|| "Copy to the upstream failed.")?;
@@ -155,7 +442,13 @@ async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Resul
     };
 
     let upstream_to_client = async {
-        let mut buf_read = BufReader::with_capacity(buf_size, upstream_read);
+        let mut buf_read = BufReader::with_capacity(
+            buf_size,
+            CountingReader::new(
+                ActivityReader::new(upstream_read, activity),
+                &metrics.bytes_to_client,
+            ),
+        );
         io::copy(&mut buf_read, &mut client_write)
             .await
             .with_context(|| "Copy to the client failed.")?;
@@ -170,3 +463,154 @@ async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Resul
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test_proxy_tcp_stream {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn connect_through_proxy() -> (TcpStream, TcpStream) {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(proxy.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = proxy.accept().await.unwrap();
+        (client, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_with_stalled_upstream() {
+        // The upstream accepts the connection but never responds nor closes it.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let stalled_upstream = tokio::spawn(async move {
+            let (_stream, _) = upstream.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let (mut client, accepted) = connect_through_proxy().await;
+        let metrics = PortMetrics::new(0);
+        client.write_all(b"hello").await.unwrap();
+        let proxied = proxy_tcp_stream(
+            accepted,
+            upstream_addr,
+            &metrics,
+            Some(Duration::from_millis(300)),
+        );
+        tokio::time::timeout(Duration::from_secs(10), proxied)
+            .await
+            .expect("The idle connection was not closed.")
+            .unwrap();
+
+        // The client sees EOF.
+        let mut buf = [0; 16];
+        assert_eq!(0, client.read(&mut buf).await.unwrap());
+        stalled_upstream.abort();
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_keeps_active_connection() {
+        // The upstream echoes the bytes.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            let _ = io::copy(&mut read, &mut write).await;
+        });
+
+        let (mut client, accepted) = connect_through_proxy().await;
+        let proxied = tokio::spawn(async move {
+            let metrics = PortMetrics::new(0);
+            proxy_tcp_stream(
+                accepted,
+                upstream_addr,
+                &metrics,
+                Some(Duration::from_millis(300)),
+            )
+            .await
+        });
+        // The total duration exceeds the timeout, but no interval does.
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0; 4];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"ping", &buf);
+        }
+        client.shutdown().await.unwrap();
+        proxied.await.unwrap().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_bind_with_backoff {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_after_port_is_released() {
+        let holder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap();
+        let (_shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(holder);
+        });
+        let listener = bind_with_backoff(addr, 10, Duration::from_millis(50), &mut shutdown_rx)
+            .await
+            .unwrap();
+        assert_eq!(addr, listener.unwrap().local_addr().unwrap());
+        release.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_gives_up() {
+        let holder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap();
+        let (_shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        assert!(
+            bind_with_backoff(addr, 3, Duration::from_millis(10), &mut shutdown_rx)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_stops_on_shutdown() {
+        let holder = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = holder.local_addr().unwrap();
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        shutdown_tx.send(true).unwrap();
+        let listener = bind_with_backoff(addr, 3, Duration::from_secs(60), &mut shutdown_rx);
+        let listener = tokio::time::timeout(Duration::from_secs(10), listener)
+            .await
+            .expect("The shutdown didn't stop the retries.")
+            .unwrap();
+        assert!(listener.is_none());
+    }
+}
+
+#[cfg(test)]
+mod test_opts {
+    use super::*;
+
+    #[test]
+    fn test_bind_option() {
+        let parse = |args: &[&str]| -> std::result::Result<ProxyOpts, structopt::clap::Error> {
+            let base = ["portproxy", "proxy", "172.29.0.1", "-t", "22"];
+            match Opts::from_iter_safe(base.iter().chain(args))?.command {
+                Subcommand::Proxy(proxy_opts) => Ok(proxy_opts),
+                _ => panic!("Not a proxy command."),
+            }
+        };
+        let ip = |addr: &str| addr.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            ip("172.29.0.2"),
+            parse(&["--bind", "172.29.0.2"]).unwrap().bind.unwrap()
+        );
+        assert_eq!(ip("::1"), parse(&["--bind", "::1"]).unwrap().bind.unwrap());
+        assert_eq!(None, parse(&[]).unwrap().bind);
+        let err = parse(&["--bind", "eth0"]).unwrap_err();
+        assert!(err.message.contains("'eth0' is not an IP address."));
+    }
+}
@@ -0,0 +1,237 @@
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The connection metrics of a forwarded port.
+/// They are updated by atomics so that the accounting doesn't slow down the copy loops.
+#[derive(Debug, Default)]
+pub struct PortMetrics {
+    pub port: u16,
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    pub bytes_to_upstream: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+}
+
+impl PortMetrics {
+    pub fn new(port: u16) -> Self {
+        PortMetrics {
+            port,
+            ..Default::default()
+        }
+    }
+
+    /// Count a new connection as active until the returned guard is dropped.
+    pub fn start_connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard { metrics: self }
+    }
+}
+
+pub struct ConnectionGuard<'a> {
+    metrics: &'a PortMetrics,
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The metrics of all the forwarded ports. The lock is taken only when a port is registered
+/// or the metrics are reported, not in the copy loops.
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    ports: Mutex<Vec<Arc<PortMetrics>>>,
+}
+
+impl MetricsRegistry {
+    /// Get the metrics of the port, registering them on the first call so that the counters
+    /// are kept even if the forwarder of the port is restarted.
+    pub fn get_or_register(&self, port: u16) -> Arc<PortMetrics> {
+        let mut ports = self.ports.lock().expect("metrics lock is poisoned");
+        if let Some(metrics) = ports.iter().find(|metrics| metrics.port == port) {
+            return metrics.clone();
+        }
+        let metrics = Arc::new(PortMetrics::new(port));
+        ports.push(metrics.clone());
+        metrics
+    }
+
+    pub fn snapshot(&self) -> Vec<Arc<PortMetrics>> {
+        self.ports.lock().expect("metrics lock is poisoned").clone()
+    }
+}
+
+/// Render the metrics of the ports in the Prometheus text format.
+pub fn render_metrics(ports: &[Arc<PortMetrics>]) -> String {
+    let mut text = String::new();
+    for port in ports {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let _ = writeln!(
+            text,
+            "portproxy_active_connections{{port=\"{}\"}} {}",
+            port.port,
+            load(&port.active_connections)
+        );
+        let _ = writeln!(
+            text,
+            "portproxy_connections_total{{port=\"{}\"}} {}",
+            port.port,
+            load(&port.total_connections)
+        );
+        let _ = writeln!(
+            text,
+            "portproxy_bytes_total{{port=\"{}\",direction=\"to_upstream\"}} {}",
+            port.port,
+            load(&port.bytes_to_upstream)
+        );
+        let _ = writeln!(
+            text,
+            "portproxy_bytes_total{{port=\"{}\",direction=\"to_client\"}} {}",
+            port.port,
+            load(&port.bytes_to_client)
+        );
+    }
+    text
+}
+
+pub async fn log_metrics_periodically(registry: Arc<MetricsRegistry>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        for port in registry.snapshot() {
+            log::info!(
+                "port {}: {} active connections, {} connections in total, {} bytes to upstream, {} bytes to client",
+                port.port,
+                port.active_connections.load(Ordering::Relaxed),
+                port.total_connections.load(Ordering::Relaxed),
+                port.bytes_to_upstream.load(Ordering::Relaxed),
+                port.bytes_to_client.load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+/// Serve the metrics in text/plain on every HTTP request to the address.
+pub async fn serve_metrics(addr: String, registry: Arc<MetricsRegistry>) -> Result<()> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("Failed to bind {} for the metrics.", &addr))?;
+    println!("Serving the metrics on {}", &addr);
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .with_context(|| format!("Failed to accept on {}.", &addr))?;
+        let body = render_metrics(&registry.snapshot());
+        tokio::spawn(async move {
+            if let Err(e) = respond_metrics(stream, body).await {
+                log::debug!("Failed to respond the metrics. {:?}", e);
+            }
+        });
+    }
+}
+
+async fn respond_metrics(mut stream: TcpStream, body: String) -> Result<()> {
+    // The request is not interpreted. Just read the head of it so that the client doesn't
+    // see a connection reset.
+    let mut buf = [0; 1024];
+    let _ = stream.read(&mut buf).await?;
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// A reader which adds the number of the bytes it reads to the counter.
+pub struct CountingReader<'a, R> {
+    inner: R,
+    counter: &'a AtomicU64,
+}
+
+impl<'a, R> CountingReader<'a, R> {
+    pub fn new(inner: R, counter: &'a AtomicU64) -> Self {
+        CountingReader { inner, counter }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.counter.fetch_add(
+                (buf.filled().len() - filled_before) as u64,
+                Ordering::Relaxed,
+            );
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_counting_reader() {
+        let counter = AtomicU64::new(0);
+        let mut reader = CountingReader::new(&b"hello, world"[..], &counter);
+        let mut out = vec![];
+        io::copy(&mut reader, &mut out).await.unwrap();
+        assert_eq!(b"hello, world", out.as_slice());
+        assert_eq!(12, counter.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_metrics_registry() {
+        let registry = MetricsRegistry::default();
+        let metrics = registry.get_or_register(22);
+        metrics.bytes_to_client.fetch_add(5, Ordering::Relaxed);
+        registry.get_or_register(80);
+        assert_eq!(
+            5,
+            registry
+                .get_or_register(22)
+                .bytes_to_client
+                .load(Ordering::Relaxed)
+        );
+        assert_eq!(2, registry.snapshot().len());
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let port = Arc::new(PortMetrics::new(22));
+        {
+            let _first = port.start_connection();
+            let _second = port.start_connection();
+            port.bytes_to_upstream.fetch_add(10, Ordering::Relaxed);
+            assert!(render_metrics(std::slice::from_ref(&port))
+                .contains("portproxy_active_connections{port=\"22\"} 2\n"));
+        }
+        let text = render_metrics(&[port]);
+        assert!(text.contains("portproxy_active_connections{port=\"22\"} 0\n"));
+        assert!(text.contains("portproxy_connections_total{port=\"22\"} 2\n"));
+        assert!(text.contains("portproxy_bytes_total{port=\"22\",direction=\"to_upstream\"} 10\n"));
+        assert!(text.contains("portproxy_bytes_total{port=\"22\",direction=\"to_client\"} 0\n"));
+    }
+}